@@ -0,0 +1,281 @@
+//! Partial updates of table entries, applied by the server & by the mock host of moth-wasm
+//!
+//! A patch which is an array is a JSON Patch (RFC 6902): a list of `add`,
+//! `remove`, `replace`, `move`, `copy` & `test` operations on JSON pointers.
+//! Any other patch is a JSON Merge Patch (RFC 7396). Missing entries are
+//! patched as `null`.
+//!
+//! Strings are dumped with the JSON string helper of the caller.
+
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+
+/// Appends a string to JSON text, quoted & escaped
+pub type PushJsonStr = fn(&mut String, &str);
+
+/// An owned JSON value, since patches reorder arrays
+#[derive(Debug, Clone)]
+enum Tree {
+    Null,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Tree>),
+    Object(Vec<(String, Tree)>),
+}
+
+impl Tree {
+    fn from_file(file: &JsonFile, path: &JsonPath) -> Self {
+        match file.get(path) {
+            JsonValue::Null => Self::Null,
+            JsonValue::Boolean(boolean) => Self::Boolean(*boolean),
+            JsonValue::Number(number) => Self::Number(*number),
+            JsonValue::String(string) => Self::String(string.to_string()),
+            JsonValue::Array(length) => Self::Array((0..*length).map(|i| Self::from_file(file, &path.clone().i_num(i))).collect()),
+            JsonValue::Object(keys) => {
+                let props = keys.iter().map(|key| (key.to_string(), Self::from_file(file, &path.clone().i_str(key))));
+                Self::Object(props.collect())
+            },
+        }
+    }
+
+    fn parse(json: &str) -> Option<Self> {
+        let file = JsonFile::new(Some(json)).ok()?;
+        Some(Self::from_file(&file, &JsonPath::new()))
+    }
+
+    fn dump(&self, json: &mut String, push_json_str: PushJsonStr) {
+        match self {
+            Self::Null => *json += "null",
+            Self::Boolean(boolean) => *json += &boolean.to_string(),
+            Self::Number(number) => *json += &number.to_string(),
+            Self::String(string) => push_json_str(json, string),
+            Self::Array(items) => {
+                json.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+
+                    item.dump(json, push_json_str);
+                }
+
+                json.push(']');
+            },
+            Self::Object(props) => {
+                json.push('{');
+                for (i, (key, value)) in props.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+
+                    push_json_str(json, key);
+                    json.push(':');
+                    value.dump(json, push_json_str);
+                }
+
+                json.push('}');
+            },
+        }
+    }
+
+    fn prop(&self, key: &str) -> Option<&Self> {
+        match self {
+            Self::Object(props) => props.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn get(&self, tokens: &[String]) -> Option<&Self> {
+        let Some((token, rest)) = tokens.split_first() else { return Some(self) };
+        let child = match self {
+            Self::Object(_) => self.prop(token),
+            Self::Array(items) => items.get(index(token)?),
+            _ => None,
+        };
+
+        child?.get(rest)
+    }
+
+    fn get_mut(&mut self, tokens: &[String]) -> Option<&mut Self> {
+        let Some((token, rest)) = tokens.split_first() else { return Some(self) };
+        let child = match self {
+            Self::Object(props) => props.iter_mut().find(|(k, _)| k == token).map(|(_, value)| value),
+            Self::Array(items) => items.get_mut(index(token)?),
+            _ => None,
+        };
+
+        child?.get_mut(rest)
+    }
+
+    /// Object equality ignores the order of properties
+    fn equals(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Null, Self::Null) => true,
+            (Self::Boolean(a), Self::Boolean(b)) => a == b,
+            (Self::Number(a), Self::Number(b)) => a == b,
+            (Self::String(a), Self::String(b)) => a == b,
+            (Self::Array(a), Self::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.equals(b)),
+            (Self::Object(a), Self::Object(b)) => {
+                let found = |(key, value): &(String, Self)| other.prop(key).is_some_and(|v| v.equals(value));
+                a.len() == b.len() && a.iter().all(found)
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Array index of a pointer token; leading zeros aren't allowed
+fn index(token: &str) -> Option<usize> {
+    let valid = token.bytes().all(|b| b.is_ascii_digit()) && (token == "0" || !token.starts_with('0'));
+    valid.then(|| token.parse().ok()).flatten()
+}
+
+/// Tokens of a JSON pointer (RFC 6901)
+fn pointer(pointer: &str) -> Option<Vec<String>> {
+    if pointer.is_empty() {
+        return Some(Vec::new());
+    }
+
+    let tokens = pointer.strip_prefix('/')?.split('/');
+    Some(tokens.map(|token| token.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+fn add(root: &mut Tree, tokens: &[String], value: Tree) -> Option<()> {
+    let Some((last, parent)) = tokens.split_last() else {
+        *root = value;
+        return Some(());
+    };
+
+    match root.get_mut(parent)? {
+        Tree::Object(props) => match props.iter_mut().find(|(k, _)| k == last) {
+            Some((_, existing)) => *existing = value,
+            None => props.push((last.clone(), value)),
+        },
+        Tree::Array(items) if last == "-" => items.push(value),
+        Tree::Array(items) => {
+            let i = index(last).filter(|i| *i <= items.len())?;
+            items.insert(i, value);
+        },
+        _ => return None,
+    }
+
+    Some(())
+}
+
+fn remove(root: &mut Tree, tokens: &[String]) -> Option<Tree> {
+    let Some((last, parent)) = tokens.split_last() else {
+        return Some(core::mem::replace(root, Tree::Null));
+    };
+
+    match root.get_mut(parent)? {
+        Tree::Object(props) => {
+            let i = props.iter().position(|(k, _)| k == last)?;
+            Some(props.remove(i).1)
+        },
+        Tree::Array(items) => {
+            let i = index(last).filter(|i| *i < items.len())?;
+            Some(items.remove(i))
+        },
+        _ => None,
+    }
+}
+
+/// RFC 7396
+fn merge(target: &mut Tree, patch: Tree) {
+    let Tree::Object(patch_props) = patch else {
+        *target = patch;
+        return;
+    };
+
+    if !matches!(target, Tree::Object(_)) {
+        *target = Tree::Object(Vec::new());
+    }
+
+    let Tree::Object(props) = target else { unreachable!() };
+    for (key, value) in patch_props {
+        let existing = props.iter().position(|(k, _)| *k == key);
+        match (existing, value) {
+            (Some(i), Tree::Null) => {
+                props.remove(i);
+            },
+            (None, Tree::Null) => (),
+            (Some(i), value) => merge(&mut props[i].1, value),
+            (None, value) => {
+                let mut new = Tree::Null;
+                merge(&mut new, value);
+                props.push((key, new));
+            },
+        }
+    }
+}
+
+/// RFC 6902; returns None if an operation can't be applied
+fn apply_operations(root: &mut Tree, operations: Vec<Tree>) -> Result<Option<()>, String> {
+    for (i, operation) in operations.iter().enumerate() {
+        let fail = |what: &str| format!("operation {}: {}", i, what);
+        let string = |key: &str| match operation.prop(key) {
+            Some(Tree::String(string)) => Ok(string.as_str()),
+            _ => Err(fail(&format!("{} must be a string", key))),
+        };
+
+        let path = pointer(string("path")?).ok_or_else(|| fail("path isn't a JSON pointer"))?;
+        let from = || pointer(string("from")?).ok_or_else(|| fail("from isn't a JSON pointer"));
+        let value = || operation.prop("value").cloned().ok_or_else(|| fail("value is missing"));
+
+        let applied = match string("op")? {
+            "add" => add(root, &path, value()?),
+            "remove" => remove(root, &path).map(drop),
+            "replace" => {
+                let value = value()?;
+                root.get_mut(&path).map(|existing| *existing = value)
+            },
+            "move" => {
+                let from = from()?;
+                // a value can't be moved into itself
+                let into_itself = path.len() > from.len() && path.starts_with(&from);
+                match into_itself {
+                    true => None,
+                    false => remove(root, &from).and_then(|value| add(root, &path, value)),
+                }
+            },
+            "copy" => {
+                let value = root.get(&from()?).cloned();
+                value.and_then(|value| add(root, &path, value))
+            },
+            "test" => {
+                let value = value()?;
+                root.get(&path).filter(|existing| existing.equals(&value)).map(drop)
+            },
+            op => return Err(fail(&format!("unknown op {:?}", op))),
+        };
+
+        if applied.is_none() {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(()))
+}
+
+/// Returns the patched entry, or None if a JSON Patch operation couldn't be
+/// applied (`test` failures, missing values); errors are for invalid patches
+pub fn apply(entry: Option<&str>, patch: &str, push_json_str: PushJsonStr) -> Result<Option<String>, String> {
+    let mut root = match entry {
+        Some(json) => Tree::parse(json).ok_or("the entry isn't valid JSON")?,
+        None => Tree::Null,
+    };
+
+    let patched = match Tree::parse(patch).ok_or("the patch isn't valid JSON")? {
+        Tree::Array(operations) => apply_operations(&mut root, operations)?,
+        patch => {
+            merge(&mut root, patch);
+            Some(())
+        },
+    };
+
+    Ok(patched.map(|()| {
+        let mut json = String::new();
+        root.dump(&mut json, push_json_str);
+        json
+    }))
+}
@@ -0,0 +1,107 @@
+//! Interface between the moth server and site modules
+//!
+//! Any language which compiles to WebAssembly can implement a site, as
+//! long as its module follows these conventions:
+//!
+//! - All integers are `u64` (`i64` in wasm), including pointers & lengths.
+//! - Strings are UTF-8, passed as `(length, pointer)` pairs.
+//! - Host functions are imported from the [`IMPORT_MODULE`] module; their
+//!   first parameter is an opaque token which the guest received from the
+//!   host in the callback it is executing.
+//! - Buffers returned by host functions are allocated with the guest's
+//!   [`MALLOC`] export and must be released by the guest; when their length
+//!   isn't implied, the host writes it at an `out_len_ptr` parameter.
+//! - JSON values (request bodies, script results) are owned by the guest and
+//!   identified by opaque pointers: the host turns text into such a pointer
+//!   with [`PARSE_JSON`] and back into text with [`DUMP_JSON`].
+//! - Route callbacks are exported under their name in `config.json`, as
+//!   `fn(token, body_json_ptr, (ptr, len) for each path parameter) -> json_ptr`,
+//!   returning 0 if the callback produced no JSON.
+//! - The module exports [`VERSION_EXPORT`], returning [`VERSION`]; modules
+//!   implementing another version are rejected at deployment.
+
+#[cfg(feature = "json-patch")]
+pub mod json_patch;
+
+/// Version of this interface; incremented on every breaking change
+pub const VERSION: u32 = 1;
+
+/// `fn() -> u32`: version implemented by the module
+pub const VERSION_EXPORT: &str = "__moth_abi_version";
+
+/// Module name of host function imports
+pub const IMPORT_MODULE: &str = "host";
+
+/// `fn(size) -> ptr`
+pub const MALLOC: &str = "__rs_malloc";
+/// `fn(ptr, size)`
+pub const FREE: &str = "__rs_free";
+/// `fn(str_ptr, str_len) -> json_ptr`
+pub const PARSE_JSON: &str = "__parse_json";
+/// `fn(json_ptr) -> dump_ptr`
+pub const DUMP_JSON: &str = "__dump_json";
+/// `fn(dump_ptr) -> len`
+pub const JSON_DUMP_LEN: &str = "__json_dump_len";
+/// `fn(dump_ptr) -> str_ptr`
+pub const JSON_DUMP_PTR: &str = "__json_dump_ptr";
+/// `fn(dump_ptr)`
+pub const FREE_JSON_DUMP: &str = "__free_json_dump";
+/// Optional `fn(token)`, called once per instance
+pub const INIT: &str = "__moth_init";
+
+/// Functions which the host provides in [`IMPORT_MODULE`]
+pub const HOST_FUNCTIONS: &[&str] = &[
+    "read_table_entry",
+    "read_table_entries",
+    "json_open",
+    "json_new",
+    "json_get_str",
+    "json_get_num",
+    "json_set_str",
+    "json_set_num",
+    "json_save",
+    "json_close",
+    "write_table_entry",
+    "increment_counter",
+    "entry_hash",
+    "cas_entry",
+    "patch_table_entry",
+    "entry_history",
+    "read_table_entry_at",
+    "sql_query",
+    "search_table",
+    "write_blob",
+    "read_blob",
+    "read_blob_type",
+    "query_table",
+    "read_table_page",
+    "db_sync_status",
+    "request_id",
+    "request_method",
+    "request_url",
+    "request_route",
+    "request_remainder",
+    "site_routes",
+    "request_subdomain",
+    "request_body",
+    "request_client_ip",
+    "request_scheme",
+    "request_csrf_token",
+    "request_locale",
+    "request_header",
+    "env_var",
+    "cache_get",
+    "cache_put",
+    "erase_subject",
+    "verify_captcha",
+    "verify_webhook",
+    "send_email",
+    "call_service",
+    "publish",
+    "upload_token",
+    "translate",
+    "absolute_url",
+    "set_template_name",
+    "set_template_param",
+    "set_template_param_raw",
+];
@@ -1,6 +1,8 @@
 use proc_macro::{TokenStream};
-use syn::{parse_macro_input, ItemFn, Ident};
+use syn::{parse_macro_input, ItemFn, Ident, Lit, Token, token, braced, bracketed};
+use syn::{parse::{Parse, ParseStream}, punctuated::Punctuated};
 use quote::{quote, format_ident};
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
 
 #[proc_macro_attribute]
 pub fn moth_callback(args: TokenStream, input: TokenStream) -> TokenStream {
@@ -18,9 +20,21 @@ pub fn moth_callback(args: TokenStream, input: TokenStream) -> TokenStream {
         lens.push(format_ident!("p{}_len", arg));
     }
 
+    // "name:arity\n" entries of the custom section, for the host to check routes at deploy time
+    let entry = format!("{}:{}\n", orig_name, ptrs.len());
+    let entry = syn::LitByteStr::new(entry.as_bytes(), orig_span);
+    let entry_len = entry.value().len();
+
     quote! {
+        #[cfg(target_arch = "wasm32")]
+        const _: () = {
+            #[used]
+            #[link_section = "moth_callbacks"]
+            static CALLBACK: [u8; #entry_len] = *#entry;
+        };
+
         #[no_mangle]
-        extern "C" fn #orig_name(req_ptr: u64, req_token: u64, #(#ptrs: u64, #lens: u64)*) -> u64 {
+        extern "C" fn #orig_name(req_token: u64, req_ptr: u64, #(#ptrs: u64, #lens: u64)*) -> u64 {
             #func
 
             let mut request = unsafe { moth_wasm::Request::new(req_token, req_ptr) };
@@ -34,4 +48,296 @@ pub fn moth_callback(args: TokenStream, input: TokenStream) -> TokenStream {
             }
         }
     }.into()
-}
\ No newline at end of file
+}
+#[proc_macro_attribute]
+pub fn moth_init(args: TokenStream, input: TokenStream) -> TokenStream {
+    assert!(args.is_empty(), "This macro attribute has no argument");
+
+    let mut func = parse_macro_input!(input as ItemFn);
+    assert_eq!(func.sig.inputs.len(), 1, "Init functions take a single Request parameter");
+
+    let orig_span = func.sig.ident.span();
+    func.sig.ident = Ident::new("init", orig_span);
+
+    quote! {
+        #[no_mangle]
+        extern "C" fn __moth_init(req_token: u64) {
+            #func
+
+            let request = moth_wasm::Request::without_body(req_token);
+            init(request);
+        }
+    }.into()
+}
+
+/// Routes of the service, declared next to their callbacks:
+///
+/// ```text
+/// moth_routes! {
+///     "/" => ro index,
+///     "/blog/[param]" => ro post { template: "post.html" },
+///     "/api/comments" => rw add_comment { methods: ["POST"], max_body_kb: 64 },
+/// }
+/// ```
+///
+/// Callbacks must exist, and the script routes of `bundle/config.json` must be
+/// exactly these ones, with the same access & callback; the build fails
+/// otherwise. `ROUTES_JSON` holds the routes in the format of config.json.
+#[proc_macro]
+pub fn moth_routes(input: TokenStream) -> TokenStream {
+    let routes = parse_macro_input!(input as Routes);
+
+    let mut root = Node::default();
+    for route in &routes.0 {
+        let path = route.path.value();
+        let steps: Vec<_> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let reserved = |s: &&str| s.starts_with('[') && *s != "[param]";
+        if !path.starts_with('/') || steps.iter().any(reserved) {
+            return syn::Error::new(route.path.span(), "Routes must be absolute paths, with [param] steps").to_compile_error().into();
+        }
+
+        let node = steps.iter().fold(&mut root, |node, step| node.child(step));
+        if node.endpoint.replace(route.endpoint_json()).is_some() {
+            return syn::Error::new(route.path.span(), "Duplicate route").to_compile_error().into();
+        }
+    }
+
+    let mut checks = Vec::new();
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let config_path = std::path::Path::new(&manifest_dir).join("bundle/config.json");
+    if let Ok(config) = std::fs::read_to_string(&config_path) {
+        let config_path = config_path.display().to_string();
+        // rebuilds when config.json changes
+        checks.push(quote! { const _: &[u8] = include_bytes!(#config_path); });
+
+        if let Err(error) = check_config(&config, &routes) {
+            checks.push(quote! { compile_error!(#error); });
+        }
+    }
+
+    let callbacks = routes.0.iter().map(|route| &route.callback);
+    let json = root.json();
+
+    quote! {
+        #(#checks)*
+
+        const _: () = {
+            fn check_callbacks() {
+                #(let _ = #callbacks;)*
+            }
+        };
+
+        /// Routes of the service, in the format of config.json
+        pub const ROUTES_JSON: &str = #json;
+    }.into()
+}
+
+struct Route {
+    path: syn::LitStr,
+    access: Ident,
+    callback: Ident,
+    /// (option, JSON value)
+    options: Vec<(String, String)>,
+}
+
+struct Routes(Vec<Route>);
+
+/// Literals, arrays & objects, as JSON text
+struct Json(String);
+
+#[derive(Default)]
+struct Node {
+    endpoint: Option<String>,
+    children: Vec<(String, Node)>,
+}
+
+impl Parse for Routes {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut routes = Vec::new();
+        while !input.is_empty() {
+            let path = input.parse()?;
+            input.parse::<Token![=>]>()?;
+
+            let access: Ident = input.parse()?;
+            if access != "ro" && access != "rw" {
+                return Err(syn::Error::new(access.span(), "Access must be ro or rw"));
+            }
+
+            let callback = input.parse()?;
+            let options = match input.peek(token::Brace) {
+                true => parse_object(input)?,
+                false => Vec::new(),
+            };
+
+            routes.push(Route { path, access, callback, options });
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+
+        Ok(Self(routes))
+    }
+}
+
+impl Parse for Json {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(token::Brace) {
+            let items: Vec<_> = parse_object(input)?.into_iter().map(|(key, value)| format!("{}:{}", json_str(&key), value)).collect();
+            return Ok(Self(format!("{{{}}}", items.join(","))));
+        }
+
+        if input.peek(token::Bracket) {
+            let content;
+            bracketed!(content in input);
+            let items = Punctuated::<Json, Token![,]>::parse_terminated(&content)?;
+            let items: Vec<_> = items.into_iter().map(|item| item.0).collect();
+            return Ok(Self(format!("[{}]", items.join(","))));
+        }
+
+        match input.parse()? {
+            Lit::Str(s) => Ok(Self(json_str(&s.value()))),
+            Lit::Int(i) => Ok(Self(i.base10_digits().into())),
+            Lit::Float(f) => Ok(Self(f.base10_digits().into())),
+            Lit::Bool(b) => Ok(Self(b.value.to_string())),
+            lit => Err(syn::Error::new(lit.span(), "Expected a string, number or boolean")),
+        }
+    }
+}
+
+/// `{ key: value, "key": value }`
+fn parse_object(input: ParseStream) -> syn::Result<Vec<(String, String)>> {
+    let content;
+    braced!(content in input);
+
+    let mut items = Vec::new();
+    while !content.is_empty() {
+        let key = match content.peek(syn::LitStr) {
+            true => content.parse::<syn::LitStr>()?.value(),
+            false => content.parse::<Ident>()?.to_string(),
+        };
+
+        content.parse::<Token![:]>()?;
+        items.push((key, content.parse::<Json>()?.0));
+        if !content.is_empty() {
+            content.parse::<Token![,]>()?;
+        }
+    }
+
+    Ok(items)
+}
+
+impl Route {
+    fn endpoint_json(&self) -> String {
+        let mut json = format!("{{\"callback\":{},\"access\":\"{}\"", json_str(&self.callback.to_string()), self.access);
+        for (option, value) in &self.options {
+            json += &format!(",{}:{}", json_str(option), value);
+        }
+
+        json + "}"
+    }
+}
+
+impl Node {
+    fn child(&mut self, step: &str) -> &mut Node {
+        let i = match self.children.iter().position(|(s, _)| s == step) {
+            Some(i) => i,
+            None => {
+                self.children.push((step.into(), Node::default()));
+                self.children.len() - 1
+            },
+        };
+
+        &mut self.children[i].1
+    }
+
+    /// The endpoint of a directory is its `[empty]` item
+    fn json(&self) -> String {
+        let endpoint = self.endpoint.clone().unwrap_or_else(|| "{}".into());
+        if self.children.is_empty() {
+            return endpoint;
+        }
+
+        let mut items: Vec<_> = self.children.iter().map(|(step, node)| format!("{}:{}", json_str(step), node.json())).collect();
+        if let Some(endpoint) = &self.endpoint {
+            items.push(format!("\"[empty]\":{}", endpoint));
+        }
+
+        format!("{{{}}}", items.join(","))
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json += "\\\"",
+            '\\' => json += "\\\\",
+            c if (c as u32) < 0x20 => json += &format!("\\u{:04x}", c as u32),
+            c => json.push(c),
+        }
+    }
+
+    json + "\""
+}
+
+/// Compares the script routes of config.json with the declared ones
+fn check_config(config: &str, routes: &Routes) -> Result<(), String> {
+    let file = JsonFile::new(Some(config)).map_err(|e| format!("Invalid bundle/config.json: {:?}", e))?;
+    let mut configured = Vec::new();
+    walk_config(&file, &JsonPath::new().i_str("routes"), "", &mut configured);
+
+    let mut errors = Vec::new();
+    for route in &routes.0 {
+        let path = match route.path.value().trim_end_matches('/') {
+            "" => "/".to_string(),
+            path => path.to_string(),
+        };
+
+        let expected = (route.access.to_string(), route.callback.to_string());
+        match configured.iter().find(|(p, ..)| *p == path) {
+            Some((_, access, callback)) if (access, callback) != (&expected.0, &expected.1) => {
+                errors.push(format!("{} is [\"{}\", \"{}\"] in bundle/config.json", path, access, callback));
+            },
+            Some(_) => (),
+            None => errors.push(format!("{} is missing from bundle/config.json", path)),
+        }
+    }
+
+    for (path, _, callback) in &configured {
+        if !routes.0.iter().any(|r| r.path.value().trim_end_matches('/') == path.trim_end_matches('/')) {
+            errors.push(format!("{} ({}) is missing from moth_routes!", path, callback));
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(format!("Routes differ from bundle/config.json:\n{}", errors.join("\n"))),
+    }
+}
+
+/// (route, access, callback) of script routes
+fn walk_config(file: &JsonFile, path: &JsonPath, route: &str, routes: &mut Vec<(String, String, String)>) {
+    let string = |path: JsonPath| file.get(&path).as_string().map(|s| s.to_string());
+    let url = match route.is_empty() {
+        true => "/".to_string(),
+        false => route.to_string(),
+    };
+
+    let (access, callback) = match file.get(path) {
+        JsonValue::Array(_) => (string(path.clone().i_num(0)), string(path.clone().i_num(1))),
+        JsonValue::Object(_) => (string(path.clone().i_str("access")), string(path.clone().i_str("callback"))),
+        _ => return,
+    };
+
+    match (access, callback, file.get(path)) {
+        (Some(access), Some(callback), _) if access == "ro" || access == "rw" => routes.push((url, access, callback)),
+        (_, _, JsonValue::Object(keys)) => for key in keys.iter() {
+            match &**key {
+                "[allow_ips]" | "[deny_ips]" | "[auth]" | "[headers]" => (),
+                "[empty]" => walk_config(file, &path.clone().i_str(key), route, routes),
+                step => walk_config(file, &path.clone().i_str(key), &format!("{}/{}", route, step), routes),
+            }
+        },
+        _ => (),
+    }
+}
@@ -6,14 +6,24 @@ pub use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
 use lmfu::{strpool::Pool, ArcStr};
 use core::ptr::NonNull;
 
-pub use moth_wasm_macros::moth_callback;
+pub use moth_wasm_macros::{moth_callback, moth_init, moth_routes};
 
 pub fn param(ptr: u64, len: u64) -> &'static str {
     use core::{slice, str};
     str::from_utf8(unsafe { slice::from_raw_parts(ptr as _, len as _) }).unwrap()
 }
 
+#[cfg(feature = "mock-host")]
+pub mod mock;
+
+#[cfg(feature = "mock-host")]
+use mock::*;
+
+// see moth_abi::IMPORT_MODULE & moth_abi::HOST_FUNCTIONS
+#[cfg(not(feature = "mock-host"))]
+#[link(wasm_import_module = "host")]
 extern "C" {
+    #[link_name = "read_table_entry"]
     fn __read_table_entry(
         db_token: u64,
         in_tn_len: u64,
@@ -22,6 +32,77 @@ extern "C" {
         in_key_ptr: u64,
     ) -> /* out_json_ptr */ u64;
 
+    #[link_name = "read_table_entries"]
+    fn __read_table_entries(
+        db_token: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        in_keys_len: u64,
+        in_keys_ptr: u64,
+    ) -> /* out_json_ptr */ u64;
+
+    #[link_name = "json_open"]
+    fn __json_open(
+        db_token: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+    ) -> /* json handle */ u64;
+
+    #[link_name = "json_new"]
+    fn __json_new(db_token: u64) -> /* json handle */ u64;
+
+    #[link_name = "json_get_str"]
+    fn __json_get_str(
+        db_token: u64,
+        json: u64,
+        in_path_len: u64,
+        in_path_ptr: u64,
+        out_len_ptr: u64,
+    ) -> /* out_str_ptr */ u64;
+
+    #[link_name = "json_get_num"]
+    fn __json_get_num(
+        db_token: u64,
+        json: u64,
+        in_path_len: u64,
+        in_path_ptr: u64,
+    ) -> /* NaN if missing */ f64;
+
+    #[link_name = "json_set_str"]
+    fn __json_set_str(
+        db_token: u64,
+        json: u64,
+        in_path_len: u64,
+        in_path_ptr: u64,
+        in_value_len: u64,
+        in_value_ptr: u64,
+    );
+
+    #[link_name = "json_set_num"]
+    fn __json_set_num(
+        db_token: u64,
+        json: u64,
+        in_path_len: u64,
+        in_path_ptr: u64,
+        value: f64,
+    );
+
+    #[link_name = "json_save"]
+    fn __json_save(
+        db_token: u64,
+        json: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+    );
+
+    #[link_name = "json_close"]
+    fn __json_close(db_token: u64, json: u64);
+
+    #[link_name = "write_table_entry"]
     fn __write_table_entry(
         db_token: u64,
         in_tn_len: u64,
@@ -32,12 +113,298 @@ extern "C" {
         in_json_ptr: u64,
     );
 
+    #[link_name = "increment_counter"]
+    fn __increment_counter(
+        db_token: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        delta: i64,
+    ) -> /* new_value */ i64;
+
+    #[link_name = "entry_hash"]
+    fn __entry_hash(
+        db_token: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+    ) -> u64;
+
+    #[link_name = "cas_entry"]
+    fn __cas_entry(
+        db_token: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        expected_hash: u64,
+        in_json_len: u64,
+        in_json_ptr: u64,
+    ) -> /* swapped */ u64;
+
+    #[link_name = "patch_table_entry"]
+    fn __patch_table_entry(
+        db_token: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        in_patch_len: u64,
+        in_patch_ptr: u64,
+    ) -> /* applied */ u64;
+
+    #[link_name = "entry_history"]
+    fn __entry_history(
+        db_token: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        limit: u64,
+    ) -> /* out_json_ptr */ u64;
+
+    #[link_name = "read_table_entry_at"]
+    fn __read_table_entry_at(
+        db_token: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        in_rev_len: u64,
+        in_rev_ptr: u64,
+    ) -> /* out_json_ptr */ u64;
+
+    #[link_name = "sql_query"]
+    fn __sql_query(
+        db_token: u64,
+        in_sql_len: u64,
+        in_sql_ptr: u64,
+        in_params_len: u64,
+        in_params_ptr: u64,
+    ) -> /* out_json_ptr */ u64;
+
+    #[link_name = "search_table"]
+    fn __search_table(
+        db_token: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        in_query_len: u64,
+        in_query_ptr: u64,
+        limit: u64,
+    ) -> /* out_json_ptr */ u64;
+
+    #[link_name = "write_blob"]
+    fn __write_blob(
+        db_token: u64,
+        in_table_len: u64,
+        in_table_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        in_type_len: u64,
+        in_type_ptr: u64,
+        in_bytes_len: u64,
+        in_bytes_ptr: u64,
+    );
+
+    #[link_name = "read_blob"]
+    fn __read_blob(
+        db_token: u64,
+        in_table_len: u64,
+        in_table_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        out_len_ptr: u64,
+    ) -> /* out_bytes_ptr, 0 if missing */ u64;
+
+    #[link_name = "read_blob_type"]
+    fn __read_blob_type(
+        db_token: u64,
+        in_table_len: u64,
+        in_table_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        out_len_ptr: u64,
+    ) -> /* out_str_ptr, 0 if missing */ u64;
+
+    #[link_name = "query_table"]
+    fn __query_table(
+        db_token: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        in_filter_len: u64,
+        in_filter_ptr: u64,
+    ) -> /* out_json_ptr */ u64;
+
+    #[link_name = "read_table_page"]
+    fn __read_table_page(
+        db_token: u64,
+        in_tn_len: u64,
+        in_tn_ptr: u64,
+        offset: u64,
+        limit: u64,
+        in_sort_len: u64,
+        in_sort_ptr: u64,
+    ) -> /* out_json_ptr */ u64;
+
+    #[link_name = "db_sync_status"]
+    fn __db_sync_status(db_token: u64) -> /* out_json_ptr */ u64;
+
+    #[link_name = "request_id"]
+    fn __request_id(db_token: u64, out_len_ptr: u64) -> /* out_str_ptr */ u64;
+    #[link_name = "request_method"]
+    fn __request_method(db_token: u64, out_len_ptr: u64) -> /* out_str_ptr */ u64;
+    #[link_name = "request_url"]
+    fn __request_url(db_token: u64, out_len_ptr: u64) -> /* out_str_ptr */ u64;
+    #[link_name = "request_route"]
+    fn __request_route(db_token: u64, out_len_ptr: u64) -> /* out_str_ptr */ u64;
+    #[link_name = "request_remainder"]
+    fn __request_remainder(db_token: u64, out_len_ptr: u64) -> /* out_str_ptr */ u64;
+    #[link_name = "site_routes"]
+    fn __site_routes(db_token: u64, out_len_ptr: u64) -> /* out_str_ptr, one route per line */ u64;
+    #[link_name = "request_subdomain"]
+    fn __request_subdomain(db_token: u64, out_len_ptr: u64) -> /* out_str_ptr */ u64;
+    #[link_name = "request_body"]
+    fn __request_body(db_token: u64, out_len_ptr: u64) -> /* out_ptr */ u64;
+    #[link_name = "request_client_ip"]
+    fn __request_client_ip(db_token: u64, out_len_ptr: u64) -> /* out_str_ptr */ u64;
+    #[link_name = "request_scheme"]
+    fn __request_scheme(db_token: u64, out_len_ptr: u64) -> /* out_str_ptr */ u64;
+    #[link_name = "request_csrf_token"]
+    fn __request_csrf_token(db_token: u64, out_len_ptr: u64) -> /* out_str_ptr */ u64;
+    #[link_name = "request_locale"]
+    fn __request_locale(db_token: u64, out_len_ptr: u64) -> /* out_str_ptr */ u64;
+
+    #[link_name = "request_header"]
+    fn __request_header(
+        db_token: u64,
+        in_name_len: u64,
+        in_name_ptr: u64,
+        out_len_ptr: u64,
+    ) -> /* out_str_ptr, 0 if missing */ u64;
+
+    #[link_name = "env_var"]
+    fn __env_var(
+        db_token: u64,
+        in_name_len: u64,
+        in_name_ptr: u64,
+        out_len_ptr: u64,
+    ) -> /* out_str_ptr, 0 if missing */ u64;
+
+    #[link_name = "cache_get"]
+    fn __cache_get(
+        db_token: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        out_len_ptr: u64,
+    ) -> /* out_str_ptr, 0 if missing */ u64;
+
+    #[link_name = "cache_put"]
+    fn __cache_put(
+        db_token: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        in_value_len: u64,
+        in_value_ptr: u64,
+        ttl_secs: u64,
+    );
+
+    #[link_name = "erase_subject"]
+    fn __erase_subject(
+        db_token: u64,
+        in_prefix_len: u64,
+        in_prefix_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+    ) -> /* number of erased files */ u64;
+
+    #[link_name = "verify_captcha"]
+    fn __verify_captcha(
+        db_token: u64,
+        in_token_len: u64,
+        in_token_ptr: u64,
+    ) -> /* 1 if valid, 0 otherwise */ u64;
+
+    #[link_name = "verify_webhook"]
+    fn __verify_webhook(
+        db_token: u64,
+        in_provider_len: u64,
+        in_provider_ptr: u64,
+        in_signature_len: u64,
+        in_signature_ptr: u64,
+    ) -> /* 1 if valid, 0 otherwise */ u64;
+
+    #[link_name = "translate"]
+    fn __translate(
+        db_token: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        out_len_ptr: u64,
+    ) -> /* out_str_ptr */ u64;
+
+    #[link_name = "send_email"]
+    fn __send_email(
+        db_token: u64,
+        in_to_len: u64,
+        in_to_ptr: u64,
+        in_subject_len: u64,
+        in_subject_ptr: u64,
+        in_body_len: u64,
+        in_body_ptr: u64,
+    ) -> /* 1 if sent, 0 otherwise */ u64;
+
+    #[link_name = "call_service"]
+    fn __call_service(
+        db_token: u64,
+        in_hostname_len: u64,
+        in_hostname_ptr: u64,
+        in_callback_len: u64,
+        in_callback_ptr: u64,
+        in_json_len: u64,
+        in_json_ptr: u64,
+    ) -> /* out_json_ptr */ u64;
+
+    #[link_name = "publish"]
+    fn __publish(
+        db_token: u64,
+        in_topic_len: u64,
+        in_topic_ptr: u64,
+        in_json_len: u64,
+        in_json_ptr: u64,
+    );
+
+    #[link_name = "upload_token"]
+    fn __upload_token(
+        db_token: u64,
+        in_table_len: u64,
+        in_table_ptr: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        in_type_len: u64,
+        in_type_ptr: u64,
+        max_size: u64,
+        in_callback_len: u64,
+        in_callback_ptr: u64,
+        out_len_ptr: u64,
+    ) -> /* out_str_ptr */ u64;
+
+    #[link_name = "absolute_url"]
+    fn __absolute_url(
+        db_token: u64,
+        in_path_len: u64,
+        in_path_ptr: u64,
+        out_len_ptr: u64,
+    ) -> /* out_str_ptr */ u64;
+
+    #[link_name = "set_template_name"]
     fn __set_template_name(
         db_token: u64,
         in_name_len: u64,
         in_name_ptr: u64,
     );
 
+    #[link_name = "set_template_param"]
     fn __set_template_param(
         db_token: u64,
         in_key_len: u64,
@@ -45,6 +412,99 @@ extern "C" {
         in_value_len: u64,
         in_value_ptr: u64,
     );
+
+    #[link_name = "set_template_param_raw"]
+    fn __set_template_param_raw(
+        db_token: u64,
+        in_key_len: u64,
+        in_key_ptr: u64,
+        in_value_len: u64,
+        in_value_ptr: u64,
+    );
+}
+
+/// Raw file stored next to table entries
+pub struct Blob {
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+/// JSON document kept by the host, from [`Request::open_json`] or [`Request::new_json`]
+///
+/// Values are accessed by dot-separated path, where numeric steps index
+/// arrays (`items.0.name`), without moving the whole document to the guest.
+/// The document is dropped by the host when the callback returns.
+pub struct HostJson<'a> {
+    db_token: u64,
+    handle: u64,
+    request: core::marker::PhantomData<&'a Request>,
+}
+
+impl<'a> HostJson<'a> {
+    pub fn get_str(&self, path: &str) -> Option<String> {
+        let mut len: u64 = 0;
+        unsafe {
+            let ptr = __json_get_str(
+                self.db_token,
+                self.handle,
+                path.len() as _,
+                path.as_ptr() as _,
+                &mut len as *mut u64 as _,
+            );
+
+            match ptr {
+                0 => None,
+                _ => Some(String::from_raw_parts(ptr as *mut u8, len as _, len as _)),
+            }
+        }
+    }
+
+    pub fn get_num(&self, path: &str) -> Option<f64> {
+        let value = unsafe { __json_get_num(self.db_token, self.handle, path.len() as _, path.as_ptr() as _) };
+        Some(value).filter(|value| !value.is_nan())
+    }
+
+    /// Creates missing objects & arrays on the way; items are appended
+    /// to arrays using their length as index
+    ///
+    /// The callback fails if the path crosses another type of value.
+    pub fn set_str(&self, path: &str, value: &str) {
+        unsafe {
+            __json_set_str(
+                self.db_token,
+                self.handle,
+                path.len() as _,
+                path.as_ptr() as _,
+                value.len() as _,
+                value.as_ptr() as _,
+            );
+        }
+    }
+
+    /// Same as [`Self::set_str`], for numbers
+    pub fn set_num(&self, path: &str, value: f64) {
+        unsafe { __json_set_num(self.db_token, self.handle, path.len() as _, path.as_ptr() as _, value) }
+    }
+
+    /// Writes the document to an entry, like [`Request::write_table_entry`]
+    pub fn save(&self, table: &str, key: &str) {
+        unsafe {
+            __json_save(
+                self.db_token,
+                self.handle,
+                table.len() as _,
+                table.as_ptr() as _,
+                key.len() as _,
+                key.as_ptr() as _,
+            );
+        }
+    }
+}
+
+impl<'a> Drop for HostJson<'a> {
+    fn drop(&mut self) {
+        unsafe { __json_close(self.db_token, self.handle) }
+    }
 }
 
 pub struct Request {
@@ -53,6 +513,9 @@ pub struct Request {
 }
 
 impl Request {
+    /// # Safety
+    ///
+    /// `body_ptr` must come from `__parse_json` and must not be used afterwards.
     pub unsafe fn new(db_token: u64, body_ptr: u64) -> Self {
         Self {
             db_token,
@@ -60,18 +523,47 @@ impl Request {
         }
     }
 
+    pub fn without_body(db_token: u64) -> Self {
+        Self {
+            db_token,
+            body: None,
+        }
+    }
+
+    /// JSON body of the request; requests without a body (such as
+    /// most GET requests) get a JSON `null`
     pub fn take_body(&mut self) -> Box<JsonFile> {
         self.body.take().expect("Request body was already taken")
     }
 
+    /// Raw request body, for routes with a `text` or `bytes` body mode
+    pub fn body_bytes(&self) -> Vec<u8> {
+        let mut len: u64 = 0;
+        unsafe {
+            let ptr = __request_body(self.db_token, &mut len as *mut u64 as _);
+            Vec::from_raw_parts(ptr as *mut u8, len as _, len as _)
+        }
+    }
+
+    /// Raw request body, for routes with a `text` body mode
+    ///
+    /// Invalid UTF-8 sequences (only possible with the `bytes`
+    /// body mode) are replaced with U+FFFD.
+    pub fn body_text(&self) -> String {
+        match String::from_utf8(self.body_bytes()) {
+            Ok(text) => text,
+            Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+        }
+    }
+
     pub fn read_table_entry(&self, table: &str, key: &str) -> Option<Box<JsonFile>> {
         unsafe {
             let json_ptr = __read_table_entry(
                 self.db_token,
-                table.as_ptr() as _,
                 table.len() as _,
-                key.as_ptr() as _,
+                table.as_ptr() as _,
                 key.len() as _,
+                key.as_ptr() as _,
             );
 
             match json_ptr {
@@ -81,39 +573,677 @@ impl Request {
         }
     }
 
+    /// Reads several entries of a table with a single host call
+    ///
+    /// Returns a JSON object mapping each key to its entry, or to `null` if
+    /// it's missing. Keys can't contain line breaks.
+    pub fn read_table_entries(&self, table: &str, keys: &[&str]) -> Box<JsonFile> {
+        assert!(keys.iter().all(|key| !key.contains('\n')), "Invalid key");
+        let keys = keys.join("\n");
+
+        unsafe {
+            let json_ptr = __read_table_entries(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                keys.len() as _,
+                keys.as_ptr() as _,
+            );
+
+            Box::from_raw(json_ptr as *mut JsonFile)
+        }
+    }
+
+    /// Opens an entry as a document kept by the host, `None` if it's missing
+    ///
+    /// Cheaper than [`Self::read_table_entry`] for large entries of which
+    /// only a few values are read or changed.
+    pub fn open_json(&self, table: &str, key: &str) -> Option<HostJson<'_>> {
+        let handle = unsafe {
+            __json_open(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                key.len() as _,
+                key.as_ptr() as _,
+            )
+        };
+
+        match handle {
+            0 => None,
+            handle => Some(HostJson { db_token: self.db_token, handle, request: core::marker::PhantomData }),
+        }
+    }
+
+    /// Creates an empty (`null`) document kept by the host
+    pub fn new_json(&self) -> HostJson<'_> {
+        let handle = unsafe { __json_new(self.db_token) };
+        HostJson { db_token: self.db_token, handle, request: core::marker::PhantomData }
+    }
+
     pub fn write_table_entry(&self, table: &str, key: &str, json: &str) {
         unsafe {
             __write_table_entry(
                 self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                key.len() as _,
+                key.as_ptr() as _,
+                json.len() as _,
+                json.as_ptr() as _,
+            );
+        }
+    }
+
+    /// Adds `delta` to an integer entry (missing entries count as zero), returning the new value
+    ///
+    /// Unlike `write_table_entry`, this is applied immediately, even if the script fails later.
+    /// Use it for view counts or sequence IDs.
+    pub fn increment_counter(&self, table: &str, key: &str, delta: i64) -> i64 {
+        unsafe {
+            __increment_counter(
+                self.db_token,
+                table.len() as _,
                 table.as_ptr() as _,
+                key.len() as _,
+                key.as_ptr() as _,
+                delta,
+            )
+        }
+    }
+
+    /// Opaque hash of an entry, to use with `cas_entry`; zero if the entry is missing
+    ///
+    /// Writes pending in this script are ignored. Hashes are only valid until the server restarts.
+    pub fn entry_hash(&self, table: &str, key: &str) -> u64 {
+        unsafe {
+            __entry_hash(
+                self.db_token,
                 table.len() as _,
+                table.as_ptr() as _,
+                key.len() as _,
                 key.as_ptr() as _,
+            )
+        }
+    }
+
+    /// Replaces an entry if its hash is still `expected_hash`; returns false otherwise
+    ///
+    /// Like `increment_counter`, this is applied immediately.
+    pub fn cas_entry(&self, table: &str, key: &str, expected_hash: u64, json: &str) -> bool {
+        unsafe {
+            __cas_entry(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
                 key.len() as _,
-                json.as_ptr() as _,
+                key.as_ptr() as _,
+                expected_hash,
                 json.len() as _,
+                json.as_ptr() as _,
+            ) != 0
+        }
+    }
+
+    /// Updates part of an entry, without reading & rewriting all of it
+    ///
+    /// If `json_patch` is an array, it's a JSON Patch (RFC 6902), for instance
+    /// `[{ "op": "add", "path": "/tags/-", "value": "rust" }]`; otherwise it's a
+    /// JSON Merge Patch (RFC 7396), like `{ "title": "New title", "draft": null }`.
+    /// Missing entries are patched as `null`. Returns false, leaving the entry
+    /// untouched, if an operation of a JSON Patch fails (`test`, missing paths).
+    /// The script traps if the patch is invalid.
+    ///
+    /// Like `increment_counter`, this is applied immediately, so concurrent
+    /// scripts can patch different parts of the same entry.
+    pub fn patch_table_entry(&self, table: &str, key: &str, json_patch: &str) -> bool {
+        unsafe {
+            __patch_table_entry(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                key.len() as _,
+                key.as_ptr() as _,
+                json_patch.len() as _,
+                json_patch.as_ptr() as _,
+            ) != 0
+        }
+    }
+
+    /// Lists the `limit` latest revisions of an entry, newest first
+    ///
+    /// Returns a JSON array of `{ "revision", "timestamp", "message", "erased" }`,
+    /// one for each database commit which changed the entry; `erased` is true if
+    /// the commit removed it. Writes which weren't synced yet aren't listed, nor
+    /// are revisions older than the last 500 commits.
+    ///
+    /// The history is fetched from the database remote on each call: this is
+    /// slow, and meant for audit trails or "edited at" pages.
+    pub fn entry_history(&self, table: &str, key: &str, limit: usize) -> Box<JsonFile> {
+        unsafe {
+            let json_ptr = __entry_history(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                key.len() as _,
+                key.as_ptr() as _,
+                limit as _,
+            );
+
+            Box::from_raw(json_ptr as *mut JsonFile)
+        }
+    }
+
+    /// Reads an entry as it was at a revision returned by `entry_history`
+    ///
+    /// Returns None if the entry didn't exist then. The script traps if the revision is unknown.
+    pub fn read_table_entry_at(&self, table: &str, key: &str, revision: &str) -> Option<Box<JsonFile>> {
+        unsafe {
+            let json_ptr = __read_table_entry_at(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                key.len() as _,
+                key.as_ptr() as _,
+                revision.len() as _,
+                revision.as_ptr() as _,
+            );
+
+            match json_ptr {
+                0 => None,
+                p => Some(Box::from_raw(p as *mut JsonFile)),
+            }
+        }
+    }
+
+    /// Runs a statement on the SQLite database of the site (`database.sqlite` in config.json)
+    ///
+    /// `params_json` is an array for `?1`, `?2`… or an object for `:name`
+    /// parameters; it can be empty. Returns a JSON array of the rows, as
+    /// objects by column name, with blobs as base64 strings.
+    ///
+    /// Statements are applied immediately, even if the script fails later.
+    /// `ro` scripts can only run read-only statements; errors trap the script.
+    pub fn sql_query(&self, sql: &str, params_json: &str) -> Box<JsonFile> {
+        unsafe {
+            let json_ptr = __sql_query(
+                self.db_token,
+                sql.len() as _,
+                sql.as_ptr() as _,
+                params_json.len() as _,
+                params_json.as_ptr() as _,
+            );
+
+            Box::from_raw(json_ptr as *mut JsonFile)
+        }
+    }
+
+    /// Full-text search in a table listed in `database.search` (config.json)
+    ///
+    /// Returns a JSON array of at most `limit` `{ "key", "score", "snippet" }`,
+    /// best first; `snippet` is the text around the first match. Entries match
+    /// if they have any of the words of `query`, case-insensitively. Writes of
+    /// the running script aren't searched.
+    pub fn search(&self, table: &str, query: &str, limit: usize) -> Box<JsonFile> {
+        unsafe {
+            let json_ptr = __search_table(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                query.len() as _,
+                query.as_ptr() as _,
+                limit as _,
+            );
+
+            Box::from_raw(json_ptr as *mut JsonFile)
+        }
+    }
+
+    /// Stores raw bytes, without going through JSON
+    ///
+    /// Traps if `bytes` is larger than the site's `max_blob_kb`.
+    pub fn write_blob(&self, table: &str, key: &str, content_type: &str, bytes: &[u8]) {
+        unsafe {
+            __write_blob(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                key.len() as _,
+                key.as_ptr() as _,
+                content_type.len() as _,
+                content_type.as_ptr() as _,
+                bytes.len() as _,
+                bytes.as_ptr() as _,
             );
         }
     }
 
+    pub fn read_blob(&self, table: &str, key: &str) -> Option<Blob> {
+        let mut len: u64 = 0;
+        unsafe {
+            let ptr = __read_blob(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                key.len() as _,
+                key.as_ptr() as _,
+                &mut len as *mut u64 as _,
+            );
+
+            if ptr == 0 {
+                return None;
+            }
+
+            let bytes = Vec::from_raw_parts(ptr as *mut u8, len as _, len as _);
+
+            let ptr = __read_blob_type(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                key.len() as _,
+                key.as_ptr() as _,
+                &mut len as *mut u64 as _,
+            );
+
+            let content_type = match ptr {
+                0 => String::from("application/octet-stream"),
+                _ => String::from_raw_parts(ptr as *mut u8, len as _, len as _),
+            };
+
+            Some(Blob { content_type, bytes })
+        }
+    }
+
+    /// Returns an array of the entries of `table` which match `filter_json`
+    ///
+    /// The filter is one condition object, or an array of them, which must all match:
+    /// - `{ "path": "author.name", "equals": "bob" }`
+    /// - `{ "path": "age", "min": 18, "max": 30 }` (inclusive, numbers or strings)
+    /// - `{ "path": "tags", "contains": "rust" }` (array item or substring)
+    ///
+    /// The script traps if the filter is invalid.
+    pub fn query_table(&self, table: &str, filter_json: &str) -> Box<JsonFile> {
+        unsafe {
+            let json_ptr = __query_table(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                filter_json.len() as _,
+                filter_json.as_ptr() as _,
+            );
+
+            Box::from_raw(json_ptr as *mut JsonFile)
+        }
+    }
+
+    /// Returns an array of at most `limit` entries of `table`, skipping the first `offset` ones
+    ///
+    /// If `sort_key` is empty, entries are ordered by key and only the returned
+    /// ones are read. Otherwise, it's a path in entries (example: `author.name`)
+    /// which all entries of the table must be read for. In both cases, a `-`
+    /// prefix reverses the order.
+    pub fn read_table_page(&self, table: &str, offset: usize, limit: usize, sort_key: &str) -> Box<JsonFile> {
+        unsafe {
+            let json_ptr = __read_table_page(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                offset as _,
+                limit as _,
+                sort_key.len() as _,
+                sort_key.as_ptr() as _,
+            );
+
+            Box::from_raw(json_ptr as *mut JsonFile)
+        }
+    }
+
+    /// Health of the database synchronization with its git remote:
+    /// `last_push` & `last_pull` (unix timestamps, 0 if never),
+    /// `failures` (since the last successful sync) & `pending_entries`
+    /// (entries written since the last push)
+    pub fn db_sync_status(&self) -> Box<JsonFile> {
+        unsafe { Box::from_raw(__db_sync_status(self.db_token) as *mut JsonFile) }
+    }
+
+    /// Unique ID of the request (or scheduled job execution), found in server log lines
+    pub fn id(&self) -> String {
+        unsafe { host_string(__request_id, self.db_token) }
+    }
+
+    /// HTTP method, in uppercase (`GET`, `POST`...); empty for scheduled jobs
+    pub fn method(&self) -> String {
+        unsafe { host_string(__request_method, self.db_token) }
+    }
+
+    /// Full original URL, including the query string
+    pub fn url(&self) -> String {
+        unsafe { host_string(__request_url, self.db_token) }
+    }
+
+    /// Path of the URL, without the query string; example: `/users/42/posts`
+    pub fn path(&self) -> String {
+        let mut url = self.url();
+        if let Some(query) = url.find('?') {
+            url.truncate(query);
+        }
+
+        url
+    }
+
+    /// Route which matched the URL; example: `/users/[param]/posts`
+    pub fn route(&self) -> String {
+        unsafe { host_string(__request_route, self.db_token) }
+    }
+
+    /// Part of the URL which wasn't matched by the route, including the query string
+    pub fn remainder(&self) -> String {
+        unsafe { host_string(__request_remainder, self.db_token) }
+    }
+
+    /// All routes of the site, in the format of [`Request::route`]; useful to
+    /// generate a sitemap in a callback
+    pub fn routes(&self) -> Vec<String> {
+        let routes = unsafe { host_string(__site_routes, self.db_token) };
+        routes.lines().map(String::from).collect()
+    }
+
+    /// Part of the host matched by a wildcard hostname of the site; example:
+    /// `blog` for `blog.example.com` with `*.example.com`. Empty otherwise.
+    pub fn subdomain(&self) -> String {
+        unsafe { host_string(__request_subdomain, self.db_token) }
+    }
+
+    /// IP address of the client; behind trusted proxies, it comes from `X-Forwarded-For`.
+    /// Empty for scheduled jobs.
+    pub fn client_ip(&self) -> String {
+        unsafe { host_string(__request_client_ip, self.db_token) }
+    }
+
+    /// `http` or `https`; behind trusted proxies, it comes from `X-Forwarded-Proto`.
+    /// Empty for scheduled jobs.
+    pub fn scheme(&self) -> String {
+        unsafe { host_string(__request_scheme, self.db_token) }
+    }
+
+    /// Token which the client must send in an `X-CSRF-Token` header to routes
+    /// with `"csrf": true`; tied to its session cookie. Empty if the site has
+    /// no such routes, and for scheduled jobs.
+    pub fn csrf_token(&self) -> String {
+        unsafe { host_string(__request_csrf_token, self.db_token) }
+    }
+
+    /// Locale of the site's `i18n/` catalogs which best matches the client's
+    /// `Accept-Language` header, or the default locale. Empty if the site has
+    /// no catalogs.
+    pub fn locale(&self) -> String {
+        unsafe { host_string(__request_locale, self.db_token) }
+    }
+
+    /// Value of a header of the request; the name is case-insensitive.
+    /// None for scheduled jobs.
+    pub fn header(&self, name: &str) -> Option<String> {
+        let mut len: u64 = 0;
+        unsafe {
+            let ptr = __request_header(self.db_token, name.len() as _, name.as_ptr() as _, &mut len as *mut u64 as _);
+            match ptr {
+                0 => None,
+                _ => Some(String::from_raw_parts(ptr as *mut u8, len as _, len as _)),
+            }
+        }
+    }
+
+    /// Text of `key` in the request's locale, falling back to the default
+    /// locale, then to `key` itself
+    pub fn translate(&self, key: &str) -> String {
+        let mut len: u64 = 0;
+        unsafe {
+            let ptr = __translate(
+                self.db_token,
+                key.len() as _,
+                key.as_ptr() as _,
+                &mut len as *mut u64 as _,
+            );
+
+            String::from_raw_parts(ptr as *mut u8, len as _, len as _)
+        }
+    }
+
+    /// Environment variable of the site, from the secrets of the server or
+    /// the `env` object of config.json; secrets take precedence
+    pub fn env(&self, name: &str) -> Option<String> {
+        let mut len: u64 = 0;
+        unsafe {
+            let ptr = __env_var(self.db_token, name.len() as _, name.as_ptr() as _, &mut len as *mut u64 as _);
+            match ptr {
+                0 => None,
+                _ => Some(String::from_raw_parts(ptr as *mut u8, len as _, len as _)),
+            }
+        }
+    }
+
+    /// Reads a value of the site's cache, which all script threads share
+    pub fn cache_get(&self, key: &str) -> Option<String> {
+        let mut len: u64 = 0;
+        unsafe {
+            let ptr = __cache_get(self.db_token, key.len() as _, key.as_ptr() as _, &mut len as *mut u64 as _);
+            match ptr {
+                0 => None,
+                _ => Some(String::from_raw_parts(ptr as *mut u8, len as _, len as _)),
+            }
+        }
+    }
+
+    /// Stores a value in the site's cache for `ttl_secs` seconds
+    ///
+    /// With a `ttl_secs` of zero, the value stays until it's evicted.
+    /// Least recently used values are evicted when the cache is full.
+    pub fn cache_put(&self, key: &str, value: &str, ttl_secs: u64) {
+        unsafe {
+            __cache_put(
+                self.db_token,
+                key.len() as _,
+                key.as_ptr() as _,
+                value.len() as _,
+                value.as_ptr() as _,
+                ttl_secs,
+            );
+        }
+    }
+
+    /// Erases the entries & blobs named `subject_key` in all tables starting with `table_prefix`
+    ///
+    /// Unlike table writes, this happens immediately, even if the script fails later.
+    /// Returns the number of erased files.
+    pub fn erase_subject(&self, table_prefix: &str, subject_key: &str) -> usize {
+        unsafe {
+            __erase_subject(
+                self.db_token,
+                table_prefix.len() as _,
+                table_prefix.as_ptr() as _,
+                subject_key.len() as _,
+                subject_key.as_ptr() as _,
+            ) as _
+        }
+    }
+
+    /// Checks the response token of a CAPTCHA widget with the site's provider
+    ///
+    /// Traps if the site has no `captcha` config.
+    pub fn verify_captcha(&self, provider_token: &str) -> bool {
+        unsafe { __verify_captcha(self.db_token, provider_token.len() as _, provider_token.as_ptr() as _) == 1 }
+    }
+
+    /// Checks the signature header of a webhook against the raw body of the request
+    ///
+    /// `provider` is `github` (pass the `X-Hub-Signature-256` header) or `stripe`
+    /// (pass the `Stripe-Signature` header); the route must have a `text` or
+    /// `bytes` body mode, so the body is kept as sent. Traps if the provider
+    /// isn't in the site's `webhooks` config or if its secret is missing.
+    pub fn verify_webhook(&self, provider: &str, signature: &str) -> bool {
+        unsafe {
+            __verify_webhook(
+                self.db_token,
+                provider.len() as _,
+                provider.as_ptr() as _,
+                signature.len() as _,
+                signature.as_ptr() as _,
+            ) == 1
+        }
+    }
+
+    /// Sends a plain text email through the SMTP server of the site's `email` config
+    ///
+    /// Returns false if the server refused it or if the hourly limit of the
+    /// site is reached. Traps if the site has no `email` config.
+    pub fn send_email(&self, to: &str, subject: &str, body: &str) -> bool {
+        unsafe {
+            __send_email(
+                self.db_token,
+                to.len() as _,
+                to.as_ptr() as _,
+                subject.len() as _,
+                subject.as_ptr() as _,
+                body.len() as _,
+                body.as_ptr() as _,
+            ) == 1
+        }
+    }
+
+    /// Runs a callback of another site of this server, with `body_json` as request body
+    ///
+    /// The other site must list this one among the callers of `callback`, in
+    /// the `services` object of its config.json. The callback gets the request
+    /// info of this script, and runs read-only if this script is `ro`. Returns
+    /// its JSON response; the script traps if the call is refused or fails.
+    pub fn call_service(&self, hostname: &str, callback: &str, body_json: &str) -> Box<JsonFile> {
+        unsafe {
+            let json_ptr = __call_service(
+                self.db_token,
+                hostname.len() as _,
+                hostname.as_ptr() as _,
+                callback.len() as _,
+                callback.as_ptr() as _,
+                body_json.len() as _,
+                body_json.as_ptr() as _,
+            );
+
+            Box::from_raw(json_ptr as *mut JsonFile)
+        }
+    }
+
+    /// Publishes a JSON message on a topic, once this script returns successfully
+    ///
+    /// The callbacks which sites of this server listed for `topic` in the
+    /// `subscriptions` object of their config.json then run in the background,
+    /// with `{ "topic", "publisher", "message" }` as request body. The script
+    /// traps if `message_json` is invalid.
+    pub fn publish(&self, topic: &str, message_json: &str) {
+        unsafe {
+            __publish(
+                self.db_token,
+                topic.len() as _,
+                topic.as_ptr() as _,
+                message_json.len() as _,
+                message_json.as_ptr() as _,
+            );
+        }
+    }
+
+    /// Token for the client to upload a file into a blob
+    ///
+    /// The client sends the file as the body of a request to an `[upload]`
+    /// route of the site, followed by the token: `/files/upload/<token>`, or
+    /// in chunks: `/files/upload/<token>/<index>` then `.../<token>/done`;
+    /// `.../<token>/progress` tells what was received, to resume. Once
+    /// received, it's stored like with [`Self::write_blob`]; `callback` then
+    /// runs in the background with `{ "table", "key", "size" }` as body.
+    /// Tokens expire after an hour without chunks. Traps if `max_size` is
+    /// larger than the site's `max_upload_kb`.
+    pub fn upload_token(&self, table: &str, key: &str, content_type: &str, max_size: usize, callback: Option<&str>) -> String {
+        let callback = callback.unwrap_or("");
+        let mut len: u64 = 0;
+        unsafe {
+            let ptr = __upload_token(
+                self.db_token,
+                table.len() as _,
+                table.as_ptr() as _,
+                key.len() as _,
+                key.as_ptr() as _,
+                content_type.len() as _,
+                content_type.as_ptr() as _,
+                max_size as _,
+                callback.len() as _,
+                callback.as_ptr() as _,
+                &mut len as *mut u64 as _,
+            );
+
+            String::from_raw_parts(ptr as *mut u8, len as _, len as _)
+        }
+    }
+
+    /// Prefixes `path` with the canonical scheme & host of the site
+    pub fn absolute_url(&self, path: &str) -> String {
+        let mut len: u64 = 0;
+        unsafe {
+            let ptr = __absolute_url(
+                self.db_token,
+                path.len() as _,
+                path.as_ptr() as _,
+                &mut len as *mut u64 as _,
+            );
+
+            String::from_raw_parts(ptr as *mut u8, len as _, len as _)
+        }
+    }
+
+    /// If the callback also returns JSON, requests whose `Accept` header
+    /// prefers `application/json` over `text/html` get the JSON instead
     pub fn set_template_name(&self, name: &str) {
         unsafe {
-            __set_template_name(self.db_token, name.as_ptr() as _, name.len() as _);
+            __set_template_name(self.db_token, name.len() as _, name.as_ptr() as _);
         }
     }
 
+    /// The value is HTML-escaped by the host
     pub fn set_template_param(&self, key: &str, value: &str) {
         unsafe {
             __set_template_param(
                 self.db_token,
-                key.as_ptr() as _,
                 key.len() as _,
+                key.as_ptr() as _,
+                value.len() as _,
                 value.as_ptr() as _,
+            );
+        }
+    }
+
+    /// Same as `set_template_param`, without HTML escaping: never use this with user input
+    pub fn set_template_param_raw(&self, key: &str, value: &str) {
+        unsafe {
+            __set_template_param_raw(
+                self.db_token,
+                key.len() as _,
+                key.as_ptr() as _,
                 value.len() as _,
+                value.as_ptr() as _,
             );
         }
     }
 }
 
+/// Takes ownership of a string which the host allocated with `__rs_malloc`
+unsafe fn host_string(host_fn: unsafe extern "C" fn(u64, u64) -> u64, db_token: u64) -> String {
+    let mut len: u64 = 0;
+    let ptr = host_fn(db_token, &mut len as *mut u64 as _);
+    String::from_raw_parts(ptr as *mut u8, len as _, len as _)
+}
+
+#[no_mangle]
+extern "C" fn __moth_abi_version() -> u32 {
+    moth_abi::VERSION
+}
+
 #[no_mangle]
 extern "C" fn __rs_malloc(size: u64) -> /* ptr */ u64 {
     (Box::into_raw(vec![0u8; size as _].into_boxed_slice()) as *mut u8) as _
@@ -122,7 +1252,7 @@ extern "C" fn __rs_malloc(size: u64) -> /* ptr */ u64 {
 #[no_mangle]
 extern "C" fn __rs_free(ptr: u64, size: u64) {
     let slice_ptr = core::ptr::slice_from_raw_parts_mut(ptr as *mut u8, size as _);
-    unsafe { Box::from_raw(slice_ptr) };
+    drop(unsafe { Box::from_raw(slice_ptr) });
 }
 
 #[no_mangle]
@@ -0,0 +1,904 @@
+//! In-memory host, to test callbacks natively with `cargo test`
+//!
+//! Enabled by the `mock-host` feature, which replaces the host imports:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! moth-wasm = { version = "1.0.0", features = [ "mock-host" ] }
+//! ```
+//!
+//! State is per thread, so tests don't see each other's writes. Exported
+//! callbacks take `(token, body_ptr)` then `(ptr, len)` for each path
+//! parameter; use [`token`] and [`body`], then [`response`] on the result:
+//! `mock::response(my_callback(mock::token(), mock::body("{}")))`.
+//!
+//! Each write is a revision of its own, listed by `Request::entry_history`.
+//! SQL results, service responses & searched properties are set by tests
+//! beforehand; search scores count matching words instead of using BM25.
+//! Sorted pages aren't supported and panic.
+
+use super::{JsonFile, JsonPath, JsonValue, __parse_json};
+use lmfu::json::parse_path;
+use core::cmp::Ordering;
+use std::{cell::RefCell, collections::BTreeMap, hash::{Hash, Hasher}, time::SystemTime};
+
+/// What scripts can learn about the request being processed
+#[derive(Debug, Clone, Default)]
+pub struct MockRequest {
+    pub id: String,
+    pub method: String,
+    pub url: String,
+    pub route: String,
+    pub remainder: String,
+    pub subdomain: String,
+    pub client_ip: String,
+    pub scheme: String,
+    pub body: Vec<u8>,
+    pub csrf_token: String,
+    pub locale: String,
+    /// (name, value)
+    pub headers: Vec<(String, String)>,
+}
+
+/// An upload which a callback allowed with `Request::upload_token`
+#[derive(Debug, Clone)]
+pub struct MockUpload {
+    pub token: String,
+    pub table: String,
+    pub key: String,
+    pub content_type: String,
+    pub max_size: usize,
+    pub callback: Option<String>,
+}
+
+/// A database commit, changing entries
+struct MockRevision {
+    hash: String,
+    timestamp: u64,
+    message: String,
+    /// (table, key) => JSON, None if erased
+    changes: Vec<((String, String), Option<String>)>,
+}
+
+#[derive(Default)]
+struct MockHost {
+    request: MockRequest,
+    /// (table, key) => JSON
+    entries: BTreeMap<(String, String), String>,
+    /// (table, key) => (content type, bytes)
+    blobs: BTreeMap<(String, String), (String, Vec<u8>)>,
+    cache: BTreeMap<String, String>,
+    env: BTreeMap<String, String>,
+    /// key => text, in the locale of the request
+    translations: BTreeMap<String, String>,
+    captcha_valid: bool,
+    webhook_valid: bool,
+    routes: Vec<String>,
+    /// (to, subject, body)
+    emails: Vec<(String, String, String)>,
+    /// (topic, json)
+    messages: Vec<(String, String)>,
+    template: Option<String>,
+    template_params: BTreeMap<String, String>,
+    /// Documents of `Request::open_json` & `Request::new_json`
+    docs: Vec<Option<JsonFile>>,
+    /// Oldest first
+    revisions: Vec<MockRevision>,
+    /// statement => rows
+    sql_results: BTreeMap<String, String>,
+    /// (statement, params)
+    sql_statements: Vec<(String, String)>,
+    /// table => indexed properties
+    search: BTreeMap<String, Vec<String>>,
+    /// (hostname, callback) => response
+    services: BTreeMap<(String, String), String>,
+    /// (hostname, callback, json)
+    service_calls: Vec<(String, String, String)>,
+    uploads: Vec<MockUpload>,
+    /// Tokens given so far
+    upload_tokens: u64,
+}
+
+thread_local! {
+    static HOST: RefCell<MockHost> = RefCell::new(MockHost::default());
+}
+
+fn with_host<T>(f: impl FnOnce(&mut MockHost) -> T) -> T {
+    HOST.with(|host| f(&mut host.borrow_mut()))
+}
+
+impl MockHost {
+    fn commit(&mut self, changes: Vec<((String, String), Option<String>)>) {
+        let elapsed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        self.revisions.push(MockRevision {
+            hash: format!("{:040x}", self.revisions.len() + 1),
+            timestamp: elapsed.map(|elapsed| elapsed.as_secs()).unwrap_or(0),
+            message: format!("moth: {} updated entries", changes.len()),
+            changes,
+        });
+    }
+}
+
+/// Clears the database, the cache, the request & the template of this thread
+pub fn reset() {
+    with_host(|host| *host = MockHost::default());
+}
+
+pub fn set_request(request: MockRequest) {
+    with_host(|host| host.request = request);
+}
+
+/// Result of `Request::verify_captcha` (default: false)
+pub fn set_captcha_valid(valid: bool) {
+    with_host(|host| host.captcha_valid = valid);
+}
+
+/// Result of `Request::verify_webhook` (default: false)
+pub fn set_webhook_valid(valid: bool) {
+    with_host(|host| host.webhook_valid = valid);
+}
+
+/// Routes returned by `Request::routes`
+pub fn set_routes(routes: &[&str]) {
+    with_host(|host| host.routes = routes.iter().map(|r| r.to_string()).collect());
+}
+
+/// Value returned by `Request::env(name)`
+pub fn set_env(name: &str, value: &str) {
+    with_host(|host| host.env.insert(name.into(), value.into()));
+}
+
+/// Result of `Request::translate(key)`, which otherwise returns `key`
+pub fn insert_translation(key: &str, text: &str) {
+    with_host(|host| host.translations.insert(key.into(), text.into()));
+}
+
+/// Emails sent with `Request::send_email`: (to, subject, body)
+pub fn sent_emails() -> Vec<(String, String, String)> {
+    with_host(|host| host.emails.clone())
+}
+
+/// Messages of `Request::publish`: (topic, json)
+pub fn published_messages() -> Vec<(String, String)> {
+    with_host(|host| host.messages.clone())
+}
+
+/// Rows returned by `Request::sql_query(sql, _)`, as a JSON array (default: `[]`)
+pub fn set_sql_result(sql: &str, rows_json: &str) {
+    with_host(|host| host.sql_results.insert(sql.into(), rows_json.into()));
+}
+
+/// Statements run with `Request::sql_query`: (sql, params)
+pub fn sql_statements() -> Vec<(String, String)> {
+    with_host(|host| host.sql_statements.clone())
+}
+
+/// Lets `Request::search` search `table`, like `database.search` in config.json
+pub fn set_search_properties(table: &str, properties: &[&str]) {
+    let properties = properties.iter().map(|p| p.to_string()).collect();
+    with_host(|host| host.search.insert(table.into(), properties));
+}
+
+/// Response of `Request::call_service(hostname, callback, _)`; other calls panic
+pub fn set_service_response(hostname: &str, callback: &str, json: &str) {
+    with_host(|host| host.services.insert((hostname.into(), callback.into()), json.into()));
+}
+
+/// Calls of `Request::call_service`: (hostname, callback, json)
+pub fn service_calls() -> Vec<(String, String, String)> {
+    with_host(|host| host.service_calls.clone())
+}
+
+/// Uploads allowed with `Request::upload_token`
+pub fn uploads() -> Vec<MockUpload> {
+    with_host(|host| host.uploads.clone())
+}
+
+/// Stores `bytes` like the host does once an upload is received; returns
+/// the upload, for tests to run its callback, or None if the token is
+/// unknown or `bytes` is too large
+pub fn complete_upload(token: &str, bytes: &[u8]) -> Option<MockUpload> {
+    with_host(|host| {
+        let i = host.uploads.iter().position(|upload| upload.token == token)?;
+        if bytes.len() > host.uploads[i].max_size {
+            return None;
+        }
+
+        let upload = host.uploads.remove(i);
+        let blob = (upload.content_type.clone(), bytes.to_vec());
+        host.blobs.insert((upload.table.clone(), upload.key.clone()), blob);
+        Some(upload)
+    })
+}
+
+pub fn insert_entry(table: &str, key: &str, json: &str) {
+    with_host(|host| {
+        host.entries.insert((table.into(), key.into()), json.into());
+        host.commit(vec![((table.into(), key.into()), Some(json.into()))]);
+    });
+}
+
+/// JSON text of an entry
+pub fn entry(table: &str, key: &str) -> Option<String> {
+    with_host(|host| host.entries.get(&(table.into(), key.into())).cloned())
+}
+
+/// Template set by the last callback
+pub fn template() -> Option<String> {
+    with_host(|host| host.template.clone())
+}
+
+/// Template parameter set by the last callback, as the host would render it
+pub fn template_param(key: &str) -> Option<String> {
+    with_host(|host| host.template_params.get(key).cloned())
+}
+
+/// First parameter of exported callbacks
+pub fn token() -> u64 {
+    0
+}
+
+/// Second parameter of exported callbacks; panics if `json` is invalid
+pub fn body(json: &str) -> u64 {
+    let ptr = __parse_json(json.as_ptr() as _, json.len() as _);
+    assert_ne!(ptr, 0, "Invalid JSON body");
+    ptr
+}
+
+unsafe fn string<'a>(len: u64, ptr: u64) -> &'a str {
+    let bytes = core::slice::from_raw_parts(ptr as *const u8, len as _);
+    core::str::from_utf8(bytes).unwrap()
+}
+
+unsafe fn bytes<'a>(len: u64, ptr: u64) -> &'a [u8] {
+    core::slice::from_raw_parts(ptr as *const u8, len as _)
+}
+
+/// Gives a buffer to the guest, which frees it like one from `__rs_malloc`
+unsafe fn give(bytes: &[u8], out_len_ptr: u64) -> u64 {
+    *(out_len_ptr as *mut u64) = bytes.len() as _;
+    Box::into_raw(bytes.to_vec().into_boxed_slice()) as *mut u8 as _
+}
+
+fn parse(json: &str) -> u64 {
+    __parse_json(json.as_ptr() as _, json.len() as _)
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+    json
+}
+
+fn hash(json: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    json.hash(&mut hasher);
+    hasher.finish().max(1)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __read_table_entry(_: u64, tl: u64, tp: u64, kl: u64, kp: u64) -> u64 {
+    match entry(string(tl, tp), string(kl, kp)) {
+        Some(json) => parse(&json),
+        None => 0,
+    }
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __read_table_entries(_: u64, tl: u64, tp: u64, kl: u64, kp: u64) -> u64 {
+    let table = string(tl, tp);
+    let mut keys: Vec<&str> = Vec::new();
+    for key in string(kl, kp).split('\n').filter(|key| !key.is_empty()) {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    let entries: Vec<String> = keys.iter().map(|key| {
+        let json = entry(table, key).unwrap_or_else(|| "null".into());
+        format!("{}:{}", json_string(key), json)
+    }).collect();
+
+    parse(&format!("{{{}}}", entries.join(",")))
+}
+
+fn open_doc(file: JsonFile) -> u64 {
+    with_host(|host| {
+        host.docs.push(Some(file));
+        host.docs.len() as u64
+    })
+}
+
+fn with_doc<T>(handle: u64, f: impl FnOnce(&mut JsonFile) -> T) -> T {
+    with_host(|host| f(host.docs[handle as usize - 1].as_mut().expect("Invalid JSON handle")))
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __json_open(_: u64, tl: u64, tp: u64, kl: u64, kp: u64) -> u64 {
+    match entry(string(tl, tp), string(kl, kp)) {
+        Some(json) => open_doc(JsonFile::new(Some(&json)).expect("Invalid entry")),
+        None => 0,
+    }
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __json_new(_: u64) -> u64 {
+    open_doc(JsonFile::new(None).unwrap())
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __json_get_str(_: u64, json: u64, pl: u64, pp: u64, out_len_ptr: u64) -> u64 {
+    let path = JsonPath::from(parse_path(string(pl, pp)));
+    match with_doc(json, |file| file.get(&path).as_string().cloned()) {
+        Some(value) => give(value.as_bytes(), out_len_ptr),
+        None => 0,
+    }
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __json_get_num(_: u64, json: u64, pl: u64, pp: u64) -> f64 {
+    let path = JsonPath::from(parse_path(string(pl, pp)));
+    with_doc(json, |file| file.get(&path).as_num()).unwrap_or(f64::NAN)
+}
+
+/// Walks `path` like the host does, creating missing objects & arrays
+fn doc_path(file: &mut JsonFile, path: &str) -> JsonPath {
+    let mut current = JsonPath::new();
+    for step in path.split('.').filter(|step| !step.is_empty()) {
+        let index = step.parse::<usize>().ok();
+        if let JsonValue::Null = file.get(&current) {
+            match index {
+                Some(_) => file.set_array(&current),
+                None => file.set_object(&current),
+            }
+        }
+
+        current = match (file.get(&current), index) {
+            (JsonValue::Array(length), Some(index)) if index < *length => current.i_num(index),
+            (JsonValue::Array(length), Some(index)) if index == *length => file.push(current),
+            (JsonValue::Object(_), None) => file.prop(current, step),
+            _ => panic!("Can't set JSON value at {}", path),
+        };
+    }
+
+    current
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __json_set_str(_: u64, json: u64, pl: u64, pp: u64, vl: u64, vp: u64) {
+    let (path, value) = (string(pl, pp), string(vl, vp));
+    with_doc(json, |file| {
+        let path = doc_path(file, path);
+        file.set_string(&path, value.into());
+    });
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __json_set_num(_: u64, json: u64, pl: u64, pp: u64, value: f64) {
+    let path = string(pl, pp);
+    with_doc(json, |file| {
+        let path = doc_path(file, path);
+        file.set_number(&path, value);
+    });
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __json_save(_: u64, json: u64, tl: u64, tp: u64, kl: u64, kp: u64) {
+    let dump = with_doc(json, |file| file.dump(&JsonPath::new()).unwrap());
+    insert_entry(string(tl, tp), string(kl, kp), &dump);
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __json_close(_: u64, json: u64) {
+    // documents opened before `reset` are already gone
+    with_host(|host| host.docs.get_mut(json as usize - 1).map(Option::take));
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __write_table_entry(_: u64, tl: u64, tp: u64, kl: u64, kp: u64, jl: u64, jp: u64) {
+    insert_entry(string(tl, tp), string(kl, kp), string(jl, jp));
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __increment_counter(_: u64, tl: u64, tp: u64, kl: u64, kp: u64, delta: i64) -> i64 {
+    let (table, key) = (string(tl, tp), string(kl, kp));
+    let value = entry(table, key).map(|json| json.trim().parse::<i64>().expect("Not a counter"));
+    let value = value.unwrap_or(0) + delta;
+    insert_entry(table, key, &value.to_string());
+    value
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __entry_hash(_: u64, tl: u64, tp: u64, kl: u64, kp: u64) -> u64 {
+    entry(string(tl, tp), string(kl, kp)).map(|json| hash(&json)).unwrap_or(0)
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __cas_entry(_: u64, tl: u64, tp: u64, kl: u64, kp: u64, expected: u64, jl: u64, jp: u64) -> u64 {
+    let (table, key) = (string(tl, tp), string(kl, kp));
+    let current = entry(table, key).map(|json| hash(&json)).unwrap_or(0);
+    if current != expected {
+        return 0;
+    }
+
+    insert_entry(table, key, string(jl, jp));
+    1
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __patch_table_entry(_: u64, tl: u64, tp: u64, kl: u64, kp: u64, pl: u64, pp: u64) -> u64 {
+    let (table, key) = (string(tl, tp), string(kl, kp));
+    let current = entry(table, key);
+
+    match moth_abi::json_patch::apply(current.as_deref(), string(pl, pp), |json, text| *json += &json_string(text)) {
+        Ok(Some(json)) => {
+            insert_entry(table, key, &json);
+            1
+        },
+        Ok(None) => 0,
+        Err(e) => panic!("patch_table_entry: {}", e),
+    }
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __entry_history(_: u64, tl: u64, tp: u64, kl: u64, kp: u64, limit: u64) -> u64 {
+    let path = (string(tl, tp).to_string(), string(kl, kp).to_string());
+    let history = with_host(|host| {
+        let revisions = host.revisions.iter().rev().filter_map(|revision| {
+            let (_, json) = revision.changes.iter().find(|(p, _)| *p == path)?;
+            Some(format!(
+                "{{\"revision\":\"{}\",\"timestamp\":{},\"message\":{},\"erased\":{}}}",
+                revision.hash,
+                revision.timestamp,
+                json_string(&revision.message),
+                json.is_none(),
+            ))
+        });
+
+        revisions.take(limit as _).collect::<Vec<_>>().join(",")
+    });
+
+    parse(&format!("[{}]", history))
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __read_table_entry_at(_: u64, tl: u64, tp: u64, kl: u64, kp: u64, rl: u64, rp: u64) -> u64 {
+    let (path, revision) = ((string(tl, tp).to_string(), string(kl, kp).to_string()), string(rl, rp));
+    let json = with_host(|host| {
+        let i = host.revisions.iter().position(|r| r.hash == revision);
+        let i = i.unwrap_or_else(|| panic!("read_table_entry_at: unknown revision {}", revision));
+
+        let mut changes = host.revisions[..=i].iter().rev().flat_map(|revision| &revision.changes);
+        changes.find(|(p, _)| *p == path).and_then(|(_, json)| json.clone())
+    });
+
+    json.map(|json| parse(&json)).unwrap_or(0)
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __sql_query(_: u64, sl: u64, sp: u64, pl: u64, pp: u64) -> u64 {
+    let (sql, params) = (string(sl, sp), string(pl, pp));
+    let rows = with_host(|host| {
+        host.sql_statements.push((sql.into(), params.into()));
+        host.sql_results.get(sql).cloned()
+    });
+
+    parse(rows.as_deref().unwrap_or("[]"))
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __search_table(_: u64, tl: u64, tp: u64, ql: u64, qp: u64, limit: u64) -> u64 {
+    let table = string(tl, tp);
+    let words: Vec<String> = words_of(string(ql, qp)).map(|(_, word)| word).collect();
+
+    let mut ranked = with_host(|host| {
+        let properties = host.search.get(table);
+        let properties = properties.unwrap_or_else(|| panic!("search_table: {} isn't indexed", table));
+        let entries = host.entries.iter().filter(|((t, _), _)| t == table);
+
+        let ranked = entries.filter_map(|((_, key), json)| {
+            let texts = search_texts(properties, json);
+            let score = texts.iter().flat_map(|text| words_of(text)).filter(|(_, word)| words.contains(word)).count();
+            (score > 0).then(|| (key.clone(), score, snippet(&texts, &words)))
+        });
+
+        ranked.collect::<Vec<_>>()
+    });
+
+    ranked.sort_by(|(ka, a, _), (kb, b, _)| b.cmp(a).then_with(|| ka.cmp(kb)));
+    ranked.truncate(limit as _);
+
+    let results = ranked.iter().map(|(key, score, snippet)| {
+        format!("{{\"key\":{},\"score\":{},\"snippet\":{}}}", json_string(key), score, json_string(snippet))
+    });
+
+    parse(&format!("[{}]", results.collect::<Vec<_>>().join(",")))
+}
+
+/// Texts of an entry, in the order of the searched properties
+fn search_texts(properties: &[String], json: &str) -> Vec<String> {
+    let Ok(file) = JsonFile::new(Some(json)) else { return Vec::new() };
+    let mut texts = Vec::new();
+
+    for property in properties {
+        let property = JsonPath::from(parse_path(property));
+        match file.get(&property) {
+            JsonValue::String(text) => texts.push(text.to_string()),
+            JsonValue::Array(length) => for i in 0..*length {
+                if let JsonValue::String(text) = file.get(&property.clone().i_num(i)) {
+                    texts.push(text.to_string());
+                }
+            },
+            _ => (),
+        }
+    }
+
+    texts
+}
+
+/// Lowercase words of a text, with their byte offset
+fn words_of(text: &str) -> impl Iterator<Item = (usize, String)> + '_ {
+    let mut chars = text.char_indices().peekable();
+    core::iter::from_fn(move || {
+        while chars.next_if(|(_, c)| !c.is_alphanumeric()).is_some() {}
+        let (start, _) = *chars.peek()?;
+
+        let mut word = String::new();
+        while let Some((_, c)) = chars.next_if(|(_, c)| c.is_alphanumeric()) {
+            word.extend(c.to_lowercase());
+        }
+
+        Some((start, word))
+    })
+}
+
+/// The first text which has a match, starting 60 characters before it
+fn snippet(texts: &[String], words: &[String]) -> String {
+    for text in texts {
+        let Some((offset, _)) = words_of(text).find(|(_, word)| words.contains(word)) else { continue };
+        let start = text[..offset].char_indices().rev().nth(59).map(|(i, _)| i).unwrap_or(0);
+        return text[start..].trim().to_string();
+    }
+
+    String::new()
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __write_blob(_: u64, tl: u64, tp: u64, kl: u64, kp: u64, cl: u64, cp: u64, bl: u64, bp: u64) {
+    let blob = (string(cl, cp).to_string(), bytes(bl, bp).to_vec());
+    with_host(|host| host.blobs.insert((string(tl, tp).into(), string(kl, kp).into()), blob));
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __read_blob(_: u64, tl: u64, tp: u64, kl: u64, kp: u64, out_len_ptr: u64) -> u64 {
+    let blob = with_host(|host| host.blobs.get(&(string(tl, tp).into(), string(kl, kp).into())).cloned());
+    match blob {
+        Some((_, bytes)) => give(&bytes, out_len_ptr),
+        None => 0,
+    }
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __read_blob_type(_: u64, tl: u64, tp: u64, kl: u64, kp: u64, out_len_ptr: u64) -> u64 {
+    let blob = with_host(|host| host.blobs.get(&(string(tl, tp).into(), string(kl, kp).into())).cloned());
+    match blob {
+        Some((content_type, _)) => give(content_type.as_bytes(), out_len_ptr),
+        None => 0,
+    }
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __query_table(_: u64, tl: u64, tp: u64, fl: u64, fp: u64) -> u64 {
+    let table = string(tl, tp);
+    let filter = JsonFile::new(Some(string(fl, fp))).expect("query_table: invalid filter JSON");
+    let root = JsonPath::new();
+
+    let conditions: Vec<JsonPath> = match filter.get(&root) {
+        JsonValue::Array(_) => filter.iter_array(&root).map(|(_, _, path)| path).collect(),
+        _ => vec![root],
+    };
+
+    let matching = with_host(|host| {
+        let entries = host.entries.iter().filter(|((t, _), _)| t == table);
+        let entries = entries.filter(|(_, json)| {
+            let Ok(entry) = JsonFile::new(Some(json.as_str())) else { return false };
+            conditions.iter().all(|condition| matches(&filter, condition, &entry))
+        });
+
+        entries.map(|(_, json)| json.as_str()).collect::<Vec<_>>().join(",")
+    });
+
+    parse(&format!("[{}]", matching))
+}
+
+/// Tests an entry against a condition of a `query_table` filter; panics if it's invalid
+fn matches(filter: &JsonFile, condition: &JsonPath, entry: &JsonFile) -> bool {
+    let get = |prop| match filter.get(&condition.clone().i_str(prop)) {
+        JsonValue::Null | JsonValue::Array(_) | JsonValue::Object(_) => None,
+        scalar => Some(scalar),
+    };
+
+    let path = match filter.get(&condition.clone().i_str("path")) {
+        JsonValue::String(string) => JsonPath::from(parse_path(string)),
+        _ => panic!("query_table: invalid filter (path must be a string)"),
+    };
+
+    let value = entry.get(&path);
+    let compare = |bound: &JsonValue| match (value, bound) {
+        (JsonValue::Number(a), JsonValue::Number(b)) => a.partial_cmp(b),
+        (JsonValue::String(a), JsonValue::String(b)) => Some(a.as_str().cmp(b.as_str())),
+        _ => None,
+    };
+
+    match (get("equals"), get("contains"), get("min"), get("max")) {
+        (Some(expected), None, None, None) => value == expected,
+        (None, Some(needle), None, None) => match (value, needle) {
+            (JsonValue::String(string), JsonValue::String(needle)) => string.contains(needle.as_str()),
+            (JsonValue::Array(_), needle) => entry.iter_array(&path).any(|(_, file, path)| file.get(&path) == needle),
+            _ => false,
+        },
+        (None, None, min, max) if min.is_some() || max.is_some() => {
+            let min_ok = min.map(|min| compare(min).is_some_and(Ordering::is_ge)).unwrap_or(true);
+            let max_ok = max.map(|max| compare(max).is_some_and(Ordering::is_le)).unwrap_or(true);
+            min_ok && max_ok
+        },
+        _ => panic!("query_table: invalid filter (expected one of equals/contains/min+max)"),
+    }
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __read_table_page(_: u64, tl: u64, tp: u64, offset: u64, limit: u64, sl: u64, sp: u64) -> u64 {
+    assert!(string(sl, sp).is_empty(), "Sorted pages aren't supported by the mock host");
+    let table = string(tl, tp);
+
+    let page = with_host(|host| {
+        let entries = host.entries.iter().filter(|((t, _), _)| t == table);
+        let entries = entries.skip(offset as _).take(limit as _);
+        entries.map(|(_, json)| json.as_str()).collect::<Vec<_>>().join(",")
+    });
+
+    parse(&format!("[{}]", page))
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __db_sync_status(_: u64) -> u64 {
+    parse("{\"last_push\":0,\"last_pull\":0,\"failures\":0,\"pending_entries\":0}")
+}
+
+fn give_request_str(out_len_ptr: u64, select: fn(&MockRequest) -> &[u8]) -> u64 {
+    with_host(|host| unsafe { give(select(&host.request), out_len_ptr) })
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_id(_: u64, out_len_ptr: u64) -> u64 {
+    give_request_str(out_len_ptr, |r| r.id.as_bytes())
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_method(_: u64, out_len_ptr: u64) -> u64 {
+    give_request_str(out_len_ptr, |r| r.method.as_bytes())
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_url(_: u64, out_len_ptr: u64) -> u64 {
+    give_request_str(out_len_ptr, |r| r.url.as_bytes())
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_route(_: u64, out_len_ptr: u64) -> u64 {
+    give_request_str(out_len_ptr, |r| r.route.as_bytes())
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_remainder(_: u64, out_len_ptr: u64) -> u64 {
+    give_request_str(out_len_ptr, |r| r.remainder.as_bytes())
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __site_routes(_: u64, out_len_ptr: u64) -> u64 {
+    let routes = with_host(|host| host.routes.join("\n"));
+    give(routes.as_bytes(), out_len_ptr)
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_subdomain(_: u64, out_len_ptr: u64) -> u64 {
+    give_request_str(out_len_ptr, |r| r.subdomain.as_bytes())
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_body(_: u64, out_len_ptr: u64) -> u64 {
+    give_request_str(out_len_ptr, |r| &r.body)
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_client_ip(_: u64, out_len_ptr: u64) -> u64 {
+    give_request_str(out_len_ptr, |r| r.client_ip.as_bytes())
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_scheme(_: u64, out_len_ptr: u64) -> u64 {
+    give_request_str(out_len_ptr, |r| r.scheme.as_bytes())
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_csrf_token(_: u64, out_len_ptr: u64) -> u64 {
+    give_request_str(out_len_ptr, |r| r.csrf_token.as_bytes())
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_locale(_: u64, out_len_ptr: u64) -> u64 {
+    give_request_str(out_len_ptr, |r| r.locale.as_bytes())
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __request_header(_: u64, nl: u64, np: u64, out_len_ptr: u64) -> u64 {
+    let name = string(nl, np);
+    let find = |host: &mut MockHost| host.request.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).cloned();
+    match with_host(find) {
+        Some((_, value)) => give(value.as_bytes(), out_len_ptr),
+        None => 0,
+    }
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __env_var(_: u64, nl: u64, np: u64, out_len_ptr: u64) -> u64 {
+    match with_host(|host| host.env.get(string(nl, np)).cloned()) {
+        Some(value) => give(value.as_bytes(), out_len_ptr),
+        None => 0,
+    }
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __cache_get(_: u64, kl: u64, kp: u64, out_len_ptr: u64) -> u64 {
+    match with_host(|host| host.cache.get(string(kl, kp)).cloned()) {
+        Some(value) => give(value.as_bytes(), out_len_ptr),
+        None => 0,
+    }
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __cache_put(_: u64, kl: u64, kp: u64, vl: u64, vp: u64, _ttl_secs: u64) {
+    with_host(|host| host.cache.insert(string(kl, kp).into(), string(vl, vp).into()));
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __erase_subject(_: u64, pl: u64, pp: u64, kl: u64, kp: u64) -> u64 {
+    let (prefix, subject) = (string(pl, pp), string(kl, kp));
+    let is_subject = |(table, key): &(String, String)| table.starts_with(prefix) && key == subject;
+
+    with_host(|host| {
+        let before = host.entries.len() + host.blobs.len();
+        let erased: Vec<_> = host.entries.keys().filter(|k| is_subject(k)).map(|k| (k.clone(), None)).collect();
+        host.entries.retain(|k, _| !is_subject(k));
+        host.blobs.retain(|k, _| !is_subject(k));
+
+        if !erased.is_empty() {
+            host.commit(erased);
+        }
+
+        (before - host.entries.len() - host.blobs.len()) as _
+    })
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __verify_captcha(_: u64, _tl: u64, _tp: u64) -> u64 {
+    with_host(|host| host.captcha_valid as _)
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __verify_webhook(_: u64, _pl: u64, _pp: u64, _sl: u64, _sp: u64) -> u64 {
+    with_host(|host| host.webhook_valid as _)
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __translate(_: u64, kl: u64, kp: u64, out_len_ptr: u64) -> u64 {
+    let key = string(kl, kp);
+    let text = with_host(|host| host.translations.get(key).cloned());
+    give(text.as_deref().unwrap_or(key).as_bytes(), out_len_ptr)
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __send_email(_: u64, tl: u64, tp: u64, sl: u64, sp: u64, bl: u64, bp: u64) -> u64 {
+    let email = (string(tl, tp).into(), string(sl, sp).into(), string(bl, bp).into());
+    with_host(|host| host.emails.push(email));
+    1
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __call_service(_: u64, hl: u64, hp: u64, cl: u64, cp: u64, jl: u64, jp: u64) -> u64 {
+    let (hostname, callback) = (string(hl, hp), string(cl, cp));
+    let response = with_host(|host| {
+        host.service_calls.push((hostname.into(), callback.into(), string(jl, jp).into()));
+        host.services.get(&(hostname.into(), callback.into())).cloned()
+    });
+
+    let response = response.unwrap_or_else(|| panic!("call_service: no response set for {}/{}", hostname, callback));
+    let json_ptr = parse(&response);
+    assert_ne!(json_ptr, 0, "call_service: invalid response JSON");
+    json_ptr
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __publish(_: u64, tl: u64, tp: u64, jl: u64, jp: u64) {
+    let message = (string(tl, tp).into(), string(jl, jp).into());
+    with_host(|host| host.messages.push(message));
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __upload_token(_: u64, tl: u64, tp: u64, kl: u64, kp: u64, cl: u64, cp: u64, max: u64, bl: u64, bp: u64, out_len_ptr: u64) -> u64 {
+    let callback = string(bl, bp);
+    let token = with_host(|host| {
+        host.upload_tokens += 1;
+        let token = format!("{:016x}", hash(&host.upload_tokens.to_string()));
+        host.uploads.push(MockUpload {
+            token: token.clone(),
+            table: string(tl, tp).into(),
+            key: string(kl, kp).into(),
+            content_type: string(cl, cp).into(),
+            max_size: max as _,
+            callback: (!callback.is_empty()).then(|| callback.into()),
+        });
+
+        token
+    });
+
+    give(token.as_bytes(), out_len_ptr)
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __absolute_url(_: u64, pl: u64, pp: u64, out_len_ptr: u64) -> u64 {
+    let url = format!("https://localhost/{}", string(pl, pp).trim_start_matches('/'));
+    give(url.as_bytes(), out_len_ptr)
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __set_template_name(_: u64, nl: u64, np: u64) {
+    with_host(|host| host.template = Some(string(nl, np).into()));
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __set_template_param(_: u64, kl: u64, kp: u64, vl: u64, vp: u64) {
+    let value = escape_html(string(vl, vp));
+    with_host(|host| host.template_params.insert(string(kl, kp).into(), value));
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn __set_template_param_raw(_: u64, kl: u64, kp: u64, vl: u64, vp: u64) {
+    with_host(|host| host.template_params.insert(string(kl, kp).into(), string(vl, vp).into()));
+}
+
+/// Parses the JSON returned by an exported callback (0 if none)
+///
+/// # Safety
+///
+/// `json_ptr` must be the return value of an exported callback.
+pub unsafe fn response(json_ptr: u64) -> Option<Box<JsonFile>> {
+    match json_ptr {
+        0 => None,
+        ptr => Some(Box::from_raw(ptr as *mut JsonFile)),
+    }
+}
@@ -0,0 +1,76 @@
+use moth_wasm::{moth_callback, mock::{self, MockRequest}, JsonFile, JsonPath, Request};
+
+#[moth_callback]
+fn save_note(mut request: Request, key: &str) -> Option<Box<JsonFile>> {
+    let body = request.take_body();
+    let json = body.dump(&JsonPath::new()).unwrap();
+    request.write_table_entry("notes", key, json.as_str());
+    None
+}
+
+#[moth_callback]
+fn load_note(request: Request, key: &str) -> Option<Box<JsonFile>> {
+    request.read_table_entry("notes", key)
+}
+
+/// Text body mode, returns the patched entry
+#[moth_callback]
+fn patch_note(request: Request, key: &str) -> Option<Box<JsonFile>> {
+    let patched = request.patch_table_entry("notes", key, &request.body_text());
+    patched.then(|| request.read_table_entry("notes", key)).flatten()
+}
+
+/// Calls an exported callback with a JSON body & a single path parameter
+fn call(callback: extern "C" fn(u64, u64, u64, u64) -> u64, body: &str, param: &str) -> Option<String> {
+    let json_ptr = callback(mock::token(), mock::body(body), param.as_ptr() as _, param.len() as _);
+    let response = unsafe { mock::response(json_ptr) };
+    response.map(|json| json.dump(&JsonPath::new()).unwrap().as_str().to_string())
+}
+
+/// Calls `patch_note` with a text body; true if the entry was patched
+fn patch_entry(key: &str, patch: &str) -> bool {
+    mock::set_request(MockRequest { body: patch.into(), ..Default::default() });
+    call(patch_note, "null", key).is_some()
+}
+
+#[test]
+fn table_round_trip() {
+    mock::reset();
+    assert_eq!(call(load_note, "null", "first"), None);
+
+    assert_eq!(call(save_note, r#"{"title":"Hello"}"#, "first"), None);
+    assert_eq!(mock::entry("notes", "first").as_deref(), Some(r#"{ "title": "Hello" }"#));
+    assert_eq!(mock::entry("notes", "second"), None);
+
+    mock::insert_entry("notes", "second", r#"["a","b"]"#);
+    assert_eq!(call(load_note, "null", "first").as_deref(), Some(r#"{ "title": "Hello" }"#));
+    assert_eq!(call(load_note, "null", "second").as_deref(), Some(r#"[ "a", "b" ]"#));
+}
+
+#[test]
+fn merge_patch() {
+    mock::reset();
+    mock::insert_entry("notes", "first", r#"{"title":"Hello","draft":true,"tags":["a"]}"#);
+
+    assert!(patch_entry("first", r#"{"title":"Hello \"world\"","draft":null,"meta":{"views":2}}"#));
+    let expected = r#"{"title":"Hello \"world\"","tags":["a"],"meta":{"views":2}}"#;
+    assert_eq!(mock::entry("notes", "first").as_deref(), Some(expected));
+
+    // missing entries are patched as null
+    assert!(patch_entry("second", r#"{"views":1}"#));
+    assert_eq!(mock::entry("notes", "second").as_deref(), Some(r#"{"views":1}"#));
+}
+
+#[test]
+fn json_patch() {
+    mock::reset();
+    mock::insert_entry("notes", "first", r#"{"title":"Hello","tags":["a"]}"#);
+
+    assert!(patch_entry("first", r#"[{"op":"test","path":"/title","value":"Hello"},{"op":"add","path":"/tags/-","value":"b"}]"#));
+    let expected = r#"{"title":"Hello","tags":["a","b"]}"#;
+    assert_eq!(mock::entry("notes", "first").as_deref(), Some(expected));
+
+    // a failed operation leaves the entry untouched
+    assert!(!patch_entry("first", r#"[{"op":"remove","path":"/tags/0"},{"op":"test","path":"/title","value":"Bye"}]"#));
+    assert_eq!(mock::entry("notes", "first").as_deref(), Some(expected));
+}
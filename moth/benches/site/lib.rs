@@ -0,0 +1,37 @@
+//! Site of `benches/scenario.json`, with an endpoint for each stage
+//!
+//! Build it into its bundle directory, then run the scenario from `moth/`:
+//!
+//! ```text
+//! cargo build --release --target wasm32-unknown-unknown --manifest-path benches/site/Cargo.toml
+//! cp benches/site/target/wasm32-unknown-unknown/release/site.wasm benches/site/bundle/
+//! cargo run --release --bin moth-bench -- benches/site/bundle benches/scenario.json
+//! ```
+//!
+//! Counters are stored in `target/moth-bench-db`, relative to the working directory.
+
+use moth_wasm::{moth_callback, Request, JsonFile};
+
+/// Renders `index.html`, whose title comes from the route's template defaults
+#[moth_callback]
+fn index(request: Request) -> Option<Box<JsonFile>> {
+    request.set_template_param("path", &request.path());
+    None
+}
+
+#[moth_callback]
+fn echo(mut request: Request) -> Option<Box<JsonFile>> {
+    Some(request.take_body())
+}
+
+#[moth_callback]
+fn read_counter(request: Request, name: &str) -> Option<Box<JsonFile>> {
+    let counter = request.read_table_entry("counters", name);
+    counter.or_else(|| JsonFile::new(Some("0")).ok().map(Box::new))
+}
+
+#[moth_callback]
+fn increment_counter(request: Request, name: &str) -> Option<Box<JsonFile>> {
+    let value = request.increment_counter("counters", name, 1);
+    JsonFile::new(Some(&value.to_string())).ok().map(Box::new)
+}
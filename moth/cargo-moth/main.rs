@@ -1,7 +1,9 @@
 use rustgit::{create_ed25519_keypair, dump_ed25519_pk_openssh};
 use lmfu::{ArcStr, json::{JsonFile, Path as JsonPath}};
-use std::{env, io, fs, process::Command, path::Path};
+use std::{env, io::{self, Read, Write}, fs, process::Command, path::Path};
+use flate2::{write::DeflateEncoder, Compression};
 use cpio::{NewcBuilder, write_cpio};
+use sha2::{Sha256, Digest};
 use ureq::post;
 
 fn init_logger() {
@@ -11,6 +13,9 @@ fn init_logger() {
 }
 
 const CPIO_REGULAR_FILE_MODE: u32 = 0o100_000;
+/// Table of contents of v2 bundles; first file of the archive
+const BUNDLE_INDEX: &str = "bundle-index.json";
+const ADMIN_KEY: &str = "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
 
 fn keygen() {
     let keypair = create_ed25519_keypair();
@@ -20,18 +25,38 @@ fn keygen() {
     println!("Public Key:  {}", openssh);
     println!("Replace '[username]' with your username on the GIT server.");
     println!("-> For GitHub: This is your login email");
-    println!("");
+    println!();
     println!("Add this public key to `authorized_keys` on your GIT server");
     println!("-> For GitHub: https://github.com/settings/keys");
-    println!("");
+    println!();
     println!("Use the keypair_hex string in your moth configuration file.");
     println!("Run with --help for more information.");
 }
 
 fn print_usage() {
-    println!("Usage: cargo moth [OPTIONS] SITE_HOST DEPLOY_HOST");
+    println!("Usage: cargo moth [OPTIONS] [COMMAND] SITE_HOST DEPLOY_HOST");
     println!("Will build, bundle and upload a service to a running moth server");
-    println!("");
+    println!();
+    println!("COMMANDS:");
+    println!("    gdpr-erase TABLE_PREFIX SUBJECT_KEY");
+    println!("                                    Erase the entries & blobs named SUBJECT_KEY in all tables");
+    println!("                                    starting with TABLE_PREFIX, then push the removal");
+    println!("    db dump TARBALL_PATH            Save all database files of the service as a tarball");
+    println!("    db restore TARBALL_PATH         Replace all database files of the service with the ones");
+    println!("                                    in a tarball, then push the result");
+    println!("    secret set NAME VALUE           Set a secret environment variable of the service, which");
+    println!("                                    scripts read with Request::env; kept by the server");
+    println!("    secret unset NAME               Remove a secret environment variable of the service");
+    println!("    log-level LEVEL [MODULE]        Set the log level (off/error/warn/info/debug/trace) of the");
+    println!("                                    service on the server, or of one of its modules");
+    println!("    log-level reset [MODULE]        Log records of the service at the server's levels again");
+    println!("    promote ENVIRONMENT             Write the database files of ENVIRONMENT (see environments in");
+    println!("                                    the database config) over the service's ones, then push them");
+    println!("    export --out DIRECTORY          Build the service, then write its pages & assets to DIRECTORY");
+    println!("                                    for static hosting, with the 'moth' binary; no DEPLOY_HOST");
+    println!("    claim                           Print the DNS record proving that this admin key owns");
+    println!("                                    SITE_HOST, for servers requiring it (claims); no DEPLOY_HOST");
+    println!();
     println!("OPTIONS:");
     println!("    -h, --help                      Print help and exit");
     println!("    -q, --quiet                     Do not print cargo log messages");
@@ -44,7 +69,13 @@ fn print_usage() {
     println!("        --all-features              Activate all available features");
     println!("        --manifest-path <PATH>      Path to Cargo.toml");
     println!("        --dump-service BUNDLE_PATH  Dump the service bundle at BUNLDE_PATH");
-    println!("");
+    println!("        --status                    Print the database sync status of the service and exit");
+    println!("        --wasi                      Build for wasm32-wasip1 (clocks, randomness & stderr are available)");
+    println!("        --errors                    Print the last script failures of the service and exit");
+    println!("        --env ENVIRONMENT           Deploy with the database branch of ENVIRONMENT");
+    println!("        --rewrite-history           With gdpr-erase: replace the database history with a single");
+    println!("                                    commit, so that erased entries can't be recovered");
+    println!();
     println!("This utility will use the default target building directory.");
     println!("This utility makes some assumptions about the service crate:");
     println!("    - The crate's [lib] output must be named 'site'.");
@@ -53,22 +84,79 @@ fn print_usage() {
     println!("        - The bundle directory must contain a service configuration file (config.json).");
     println!("        - The bundle directory can contain any asset/subdir you want, such as templates");
     println!("          or other regular files and directories.");
-    println!("");
+    println!("        - Assets ending in .html/.htm/.xml/.txt are compiled as templates on deployment,");
+    println!("          named by their path in the bundle (ex: {{% include \"partials/nav.html\" %}}).");
+    println!();
     println!("The configuration file must be a valid JSON file with the following properties:");
     println!("    routes             The routes that this service allows");
     println!("    on_404             The routes that this service takes on HTTP error 404");
+    println!("    migrations         Optional array of rw script callbacks, run on deployment; the Nth one");
+    println!("                       brings the database to version N (stored in _meta/version.json)");
+    println!("    jobs               Optional array of scheduled script callbacks:");
+    println!("    |-- schedule       Cron-like schedule, in UTC; Example: '*/5 * * * *'");
+    println!("    |-- callback       Name of the script callback (rust function name)");
+    println!("    `-- access         'ro' or 'rw', like in routes");
+    println!("    preview            Optional; true for staging deployments (noindex), or with a login:");
+    println!("    |-- username       HTTP basic-auth username");
+    println!("    `-- password       HTTP basic-auth password");
+    println!("    hostnames          Optional other hostnames of the service; patterns like '*.example.com'");
+    println!("                       match all subdomains, which scripts get with Request::subdomain");
+    println!("    errors             Optional error documents, by status code: {{ \"404\": \"404.html\" }};");
+    println!("                       they're templates, rendered with 'status' & 'path' parameters");
+    println!("    request_id_header  Optional; if true, responses carry an X-Request-Id header, which");
+    println!("                       matches server log lines & Request::id");
+    println!("    canonical          Optional base of absolute URLs, see Request::absolute_url");
+    println!("    |-- scheme         Defaults to 'https'");
+    println!("    `-- host           Defaults to SITE_HOST; Example: 'www.example.com'");
+    println!("    services           Optional callbacks which other sites of the server can run with");
+    println!("                       Request::call_service, with their hostnames: {{ \"quote\": [\"shop.com\"] }}");
+    println!("    subscriptions      Optional rw script callbacks run for the messages of Request::publish,");
+    println!("                       by topic: {{ \"orders.placed\": \"on_order\" }}");
+    println!("    max_upload_kb      Optional size limit of the uploads of Request::upload_token (default: 1024)");
+    println!("    export             Optional values of route parameters for 'export', by route: an array,");
+    println!("                       or a ro callback returning one ({{ \"/blog/[param]\": \"list_posts\" }});");
+    println!("                       other routes with parameters aren't exported");
+    println!("    surfaces           Optional routes served on site ports of the server, by port:");
+    println!("                       {{ \"8443\": \"/admin\" }} serves /admin/users as /users on port 8443;");
+    println!("                       other ports don't serve them");
+    println!("    cache_kb           Optional size of the Request::cache_get/put cache (default: 1024)");
+    println!("    captcha            Optional config of Request::verify_captcha");
+    println!("    |-- provider       'hcaptcha', 'turnstile' or 'recaptcha'");
+    println!("    `-- secret         Secret key given by the provider");
     println!("    database           Database access config for the service");
     println!("    |-- host           Git server; For GitHub: 'github.com:22'");
     println!("    |-- username       Git username; For GitHub: 'git'");
     println!("    |-- keypair_hex    Hex-Encoded key pair to use (generate one with --keygen)");
     println!("    |-- path           Git repository; Example: 'MyAccount/my-db-repo.git'");
-    println!("    `-- branch         Git branch to use in the database GIT repository");
-    println!("");
+    println!("    |-- branch         Git branch to use in the database GIT repository");
+    println!("    |-- directory      Instead of the git settings above: a directory of the server holding");
+    println!("    |                  the tables, written to immediately (no sync, environments or history)");
+    println!("    |-- environments   Optional object of other branches, by environment name (e.g. 'staging'):");
+    println!("    |   |-- branch     Git branch of the environment");
+    println!("    |   `-- hostnames  Optional array of SITE_HOSTs which use it unless --env is given");
+    println!("    |-- sqlite         Optional SQLite database file of the server, for Request::sql_query");
+    println!("    |-- search         Optional object of tables to index for Request::search, with the");
+    println!("    |                  properties to index; Example: {{ \"posts\": [\"title\", \"body\"] }}");
+    println!("    |-- max_blob_kb    Optional size limit of blobs written by scripts (default: 1024)");
+    println!("    |-- file_cache_kb  Optional size of the cache of entries read by scripts (default: 4096)");
+    println!("    |-- retention      Optional array of retention policies, enforced daily at midnight UTC:");
+    println!("    |   |-- table      Policies apply to tables starting with this");
+    println!("    |   |-- days       Maximum age of entries");
+    println!("    |   `-- timestamp  Property of entries holding their creation time, in seconds since");
+    println!("    |                  the unix epoch; entries without it are kept");
+    println!("    `-- sync           Optional periodic commit/push or pull of the database:");
+    println!("        |-- schedule   Cron-like schedule, in UTC; Example: '*/10 * * * *'");
+    println!("        `-- on_conflict  If the branch was updated remotely: 'ours' (force push),");
+    println!("                       'theirs' (drop local changes) or 'merge' (default: local");
+    println!("                       entries overwrite remote ones)");
+    println!();
     println!("Format of routes & on_404 in the configuration file:");
     println!("    This part of the configuration file allows you to define endpoints");
     println!("    in the server. The path component of a request's URL will guide the");
     println!("    server in choosing what to respond with.");
-    println!("");
+    println!("    Script routes can also be declared in the crate with moth_wasm::moth_routes!,");
+    println!("    which fails the build if they differ from the configuration file.");
+    println!();
     println!("    In this part of the configuration file:");
     println!("    - string values are used for static assets");
     println!("        - this can be a path to a directory in the bundle");
@@ -77,32 +165,51 @@ fn print_usage() {
     println!("        - The first array item must be 'rw' or 'ro':");
     println!("            - 'ro': the script callbacks will get a read-only access to the database");
     println!("            - 'rw': the script callbacks will get a read-write access to the database");
+    println!("              (they run again, up to 3 times, if another script modified what they read)");
     println!("        - The second array item is the name of the script callback (rust function name)");
-    println!("    - objects represent directories");
+    println!("          It takes one path variable per [param] step; deployments of a bundle naming");
+    println!("          a callback which site.wasm lacks, or with another arity, are rejected");
+    println!("        - An optional third item sets template defaults, which the script can override:");
+    println!("          {{ \"template\": \"page.html\", \"params\": {{ \"title\": \"My Site\" }} }}");
+    println!("          It can also set \"priority\": \"batch\" for bulk endpoints (exports, imports),");
+    println!("          which can't starve \"interactive\" ones (the default) during spikes");
+    println!("          and \"body\": \"text\"/\"bytes\" to get raw bodies (webhooks, forms) with");
+    println!("          Request::body_text/body_bytes, or \"none\" to ignore them (default: \"json\")");
+    println!("        - \"methods\": [\"POST\"] restricts the accepted HTTP methods (others get a 405),");
+    println!("          \"max_body_kb\": 64 the size of bodies (larger ones get a 413) and \"auth\" sets");
+    println!("          an [auth] check for the route alone, or names its callback (\"auth\": \"session\")");
+    println!("    - objects with \"callback\" & \"access\" keys are script callbacks too, with the");
+    println!("      options above as other keys: {{ \"callback\": \"x\", \"access\": \"rw\", \"methods\": [\"POST\"] }}");
+    println!("    - \"[upload]\" is an upload endpoint; the token is the next path item");
+    println!("        - [\"[upload]\", READ_SECS, TOTAL_SECS] also sets the per-read and total timeouts");
+    println!("        - clients upload files into blobs with tokens of Request::upload_token");
+    println!("        - large files can be sent in chunks: TOKEN/0, TOKEN/1, ... then TOKEN/done;");
+    println!("          TOKEN/progress returns what was received, to resume after a disconnect");
+    println!("    - other objects represent directories");
     println!("        - directory objects can have special keys:");
     println!("        - [param]: can match any path item.");
     println!("        - [empty]: will match when the directory itself is accessed.");
     println!("                   Warning: when the value routed to this key is a static bundle");
     println!("                   directory, this allows free access, to all contained assets.");
     println!("        - other keys must match what is written exactly.");
-    println!("");
+    println!();
     println!("    For example:");
-    println!("");
+    println!();
     println!("    \"routes:\" {{");
     println!("        \"assets\": {{");
     println!("            \"[param]\": \"denied.png\"");
     println!("        }}");
     println!("    }}");
-    println!("");
+    println!();
     println!("    This route will allow the following URLs:");
     println!("    - https://myhost/assets/wooooooo");
     println!("    - https://myhost/assets/reiubhrzegr");
     println!("    - https://myhost/assets/reiubhr");
     println!("    And they will all lead to bundle/denied.png");
-    println!("");
+    println!();
     println!("    ...but will deny the following URL because it goes 'too far':");
     println!("    - https://myhost/assets/something/something");
-    println!("");
+    println!();
     println!("    Please ask the authors directly for more information.");
 }
 
@@ -114,6 +221,12 @@ fn main() {
     let mut cargo_args = vec!["build", "--target=wasm32-unknown-unknown"];
     let mut pos_args = Vec::new();
     let mut cpio_dump = None;
+    let mut status = false;
+    let mut errors = false;
+    let mut target = "wasm32-unknown-unknown";
+    let mut rewrite_history = false;
+    let mut environment = None;
+    let mut out_dir = None;
     let mut manifest_path = "./Cargo.toml".into();
     let cargo = env::var("CARGO");
     let cargo = cargo.as_deref().unwrap_or("cargo");
@@ -128,9 +241,24 @@ fn main() {
             return print_usage();
         } else if arg == "--keygen" {
             return keygen();
+        } else if arg == "--status" {
+            status = true;
+        } else if arg == "--wasi" {
+            target = "wasm32-wasip1";
+            cargo_args[1] = "--target=wasm32-wasip1";
+        } else if arg == "--errors" {
+            errors = true;
+        } else if arg == "--rewrite-history" {
+            rewrite_history = true;
+        } else if arg == "--env" {
+            let name = args.next().expect("Missing environment following --env");
+            environment = Some(name);
         } else if arg == "--dump-service" {
             let path = args.next().expect("Missing path following --dump-service");
             cpio_dump = Some(path);
+        } else if arg == "--out" {
+            let path = args.next().expect("Missing directory following --out");
+            out_dir = Some(path);
         } else if arg == "--manifest-path" {
             let path = args.next().expect("Missing path following --manifest-path");
             manifest_path = path;
@@ -157,16 +285,89 @@ fn main() {
         }
     }
 
+    // cargo passes the name of the subcommand first
+    if pos_args.first().map(String::as_str) == Some("moth") {
+        pos_args.remove(0);
+    }
+
+    // commands which don't need a deployment server
+    match pos_args.first().map(String::as_str) {
+        Some("export") => return match (&pos_args[1..], out_dir) {
+            ([site_host], Some(out_dir)) => export(cargo, cargo_args, &manifest_path, target, site_host, &out_dir),
+            _ => println!("Usage: cargo moth export --out DIRECTORY SITE_HOST"),
+        },
+        Some("claim") => return match &pos_args[1..] {
+            [site_host] => print_claim(site_host),
+            _ => println!("Usage: cargo moth claim SITE_HOST"),
+        },
+        _ => (),
+    }
+
     let deploy_host = pos_args.pop().expect("Missing positional argument: DEPLOY_HOST");
     let site_host = pos_args.pop().expect("Missing positional argument: SITE_HOST");
 
+    if status {
+        return print_status(&site_host, &deploy_host);
+    }
+
+    if errors {
+        return print_errors(&site_host, &deploy_host);
+    }
+
+    match pos_args.first().map(String::as_str) {
+        Some("gdpr-erase") => return match &pos_args[1..] {
+            [table_prefix, subject] => gdpr_erase(table_prefix, subject, rewrite_history, &site_host, &deploy_host),
+            _ => println!("Usage: cargo moth gdpr-erase TABLE_PREFIX SUBJECT_KEY SITE_HOST DEPLOY_HOST"),
+        },
+        Some("db") => return match &pos_args[1..] {
+            [action, path] if action == "dump" => db_dump(path, &site_host, &deploy_host),
+            [action, path] if action == "restore" => db_restore(path, &site_host, &deploy_host),
+            _ => println!("Usage: cargo moth db dump|restore TARBALL_PATH SITE_HOST DEPLOY_HOST"),
+        },
+        Some("secret") => return match &pos_args[1..] {
+            [action, name, value] if action == "set" => set_secret(name, Some(value), &site_host, &deploy_host),
+            [action, name] if action == "unset" => set_secret(name, None, &site_host, &deploy_host),
+            _ => println!("Usage: cargo moth secret set NAME VALUE | secret unset NAME SITE_HOST DEPLOY_HOST"),
+        },
+        Some("log-level") => return match &pos_args[1..] {
+            [level] => set_log_level(level, None, &site_host, &deploy_host),
+            [level, module] => set_log_level(level, Some(module), &site_host, &deploy_host),
+            _ => println!("Usage: cargo moth log-level LEVEL|reset [MODULE] SITE_HOST DEPLOY_HOST"),
+        },
+        Some("promote") => return match &pos_args[1..] {
+            [environment] => promote(environment, &site_host, &deploy_host),
+            _ => println!("Usage: cargo moth promote ENVIRONMENT SITE_HOST DEPLOY_HOST"),
+        },
+        Some(command) => return println!("Unexpected command: {}", command),
+        None => (),
+    }
+
+    let Some(bundle) = build_bundle(cargo, cargo_args, &manifest_path, target) else {
+        return;
+    };
+
+    if let Some(path) = cpio_dump {
+        fs::write(path, &bundle).expect("Failed to dump service archive");
+    }
+
+    // ------------------ STEP 3 & 4 ------------------
+    let msg = match upload("service", &bundle, environment.as_deref(), &site_host, &deploy_host) {
+        true => "> Service uploaded successfully",
+        false => "> Failed to upload service",
+    };
+
+    println!("{}", msg);
+}
+
+/// Builds the service, then creates its bundle in memory (steps 1 & 2)
+fn build_bundle<'a>(cargo: &str, mut cargo_args: Vec<&'a str>, manifest_path: &'a str, target: &str) -> Option<Vec<u8>> {
     let profile = match cargo_args.contains(&"--release") {
         true => "release",
         false => "debug",
     };
 
     cargo_args.push("--manifest-path");
-    cargo_args.push(&manifest_path);
+    cargo_args.push(manifest_path);
 
     // ------------------ STEP 1 ------------------
     println!("Building Service Callbacks");
@@ -190,13 +391,16 @@ fn main() {
 
     // todo: guess binary name from manifest
     println!("- Bundling site.wasm");
-    let site_wasm_path = path.join(format!("target/wasm32-unknown-unknown/{}/site.wasm", profile));
-    let site_wasm = match fs::File::open(&site_wasm_path) {
-        Ok(file) => file,
-        Err(e) => return println!("Failed to open {}: {}", site_wasm_path.display(), e),
+    let site_wasm_path = path.join(format!("target/{}/{}/site.wasm", target, profile));
+    let site_wasm = match fs::read(&site_wasm_path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            println!("Failed to read {}: {}", site_wasm_path.display(), e);
+            return None;
+        },
     };
 
-    let mut to_bundle = vec![(header("site.wasm"), site_wasm)];
+    let mut files = vec![("site.wasm".to_string(), site_wasm)];
     let mut seen_config_json = false;
 
     let mut process_bundle_entry = |path: &Path| {
@@ -207,40 +411,128 @@ fn main() {
             }
 
             println!("- Bundling {}", bundle_path);
-            let file = match fs::File::open(&path) {
-                Ok(file) => file,
-                Err(e) => return println!("Failed to open {}: {}", path.display(), e),
-            };
-            to_bundle.push((header(bundle_path), file));
+            match fs::read(path) {
+                Ok(bytes) => files.push((bundle_path.to_string(), bytes)),
+                Err(e) => println!("Failed to read {}: {}", path.display(), e),
+            }
         } else {
-            return println!("Failed to process {}", path.display());
+            println!("Failed to process {}", path.display());
         }
     };
 
     if let Err(e) = visit_dirs(&bundle, &mut process_bundle_entry) {
         log::error!("{:?}", e);
-        return println!("Failed to open bundle directory: {:?}", bundle);
+        println!("Failed to open bundle directory: {:?}", bundle);
+        return None;
     }
 
     if !seen_config_json {
-        return println!("> Bundle: Missing config.json");
+        println!("> Bundle: Missing config.json");
+        return None;
     }
 
+    let (index, files) = pack(files);
+    let entries = files.into_iter().map(|(name, bytes)| (header(&name), io::Cursor::new(bytes)));
+    let index = (header(BUNDLE_INDEX), io::Cursor::new(index.into_bytes()));
+
     let mut bundle = Vec::new();
-    match write_cpio(to_bundle.into_iter(), &mut bundle) {
+    match write_cpio(core::iter::once(index).chain(entries), &mut bundle) {
         Ok(_) => (),
-        Err(e) => return println!("Failed to create bundle: {}", e),
+        Err(e) => {
+            println!("Failed to create bundle: {}", e);
+            return None;
+        },
     };
 
     println!("> Created bundle successfully: {} bytes", bundle.len());
+    Some(bundle)
+}
 
-    if let Some(path) = cpio_dump {
-        fs::write(path, &bundle).expect("Failed to dump service archive");
+/// Builds & bundles the service, then writes its pages & assets to `out_dir` with the
+/// `moth` binary installed alongside this one
+fn export<'a>(cargo: &str, cargo_args: Vec<&'a str>, manifest_path: &'a str, target: &str, site_host: &str, out_dir: &str) {
+    let Some(bundle) = build_bundle(cargo, cargo_args, manifest_path, target) else {
+        return;
+    };
+
+    let path = Path::new(manifest_path).parent().expect("Invalid manifest path");
+    let bundle_path = path.join("target/moth-export.cpio");
+    if let Err(e) = fs::write(&bundle_path, &bundle) {
+        return println!("Failed to write {}: {}", bundle_path.display(), e);
+    }
+
+    println!("Exporting Pages & Assets");
+
+    let sibling = env::current_exe().ok().and_then(|exe| Some(exe.parent()?.join("moth")));
+    let moth = sibling.filter(|path| path.is_file()).unwrap_or("moth".into());
+
+    let status = Command::new(&moth)
+            .arg("--export")
+            .arg(&bundle_path)
+            .args([site_host, out_dir])
+            .status();
+
+    match status {
+        Ok(status) if status.success() => println!("> Exported service to {}", out_dir),
+        Ok(_) => println!("> Some pages couldn't be exported"),
+        Err(e) => println!("Failed to run {}: {}", moth.display(), e),
+    }
+}
+
+/// Prints the DNS record proving that the admin key owns `site_host`, for servers
+/// which require it before the first deployment
+fn print_claim(site_host: &str) {
+    let key: Vec<u8> = (0..ADMIN_KEY.len()).step_by(2).map(|i| u8::from_str_radix(&ADMIN_KEY[i..i + 2], 16).unwrap()).collect();
+    println!("Add this TXT record to the DNS zone of {}:", site_host);
+    println!("_moth-claim.{}. TXT \"moth-claim={}\"", site_host, encode_hex(&Sha256::digest(&key)));
+}
+
+/// Requests an upload token, then uploads `bytes`
+fn upload(kind: &str, bytes: &[u8], environment: Option<&str>, site_host: &str, deploy_host: &str) -> bool {
+    println!("Requesting Upload");
+
+    let size_bytes = format!("{}", bytes.len());
+    let sha256 = encode_hex(&Sha256::digest(bytes));
+    let mut params = vec![("kind", kind), ("size_bytes", &size_bytes), ("sha256", &sha256)];
+    params.extend(environment.map(|environment| ("environment", environment)));
+
+    let resp = match admin_request("request", &params, site_host, deploy_host) {
+        Some(resp) => resp.into_string().unwrap(),
+        None => return false,
+    };
+
+    let err = "Invalid server reply";
+    let resp = JsonFile::new(Some(&resp)).expect(err);
+    let token = resp.get(&JsonPath::new()).as_string().expect(err);
+
+    println!("token: {}", token);
+    println!("Uploading");
+
+    let upload_url = format!("http://{}/upload/{}", deploy_host, token);
+    match post(&upload_url).send(bytes) {
+        Ok(resp) => resp.into_string().unwrap() == "success",
+        Err(ureq::Error::Status(422, resp)) => {
+            println!("The server rejected the upload:");
+            for reason in resp.into_string().unwrap_or_default().lines() {
+                println!("- {}", reason);
+            }
+
+            false
+        },
+        Err(e) => {
+            println!("Failed to upload: {:?}", e);
+            false
+        },
     }
+}
 
-    // ------------------ STEP 3 ------------------
-    println!("Requesting Service Upload");
+/// Posts `params`, the site name & the admin key to an admin endpoint of the deployment server
+fn admin_request(endpoint: &str, params: &[(&str, &str)], site_host: &str, deploy_host: &str) -> Option<ureq::Response> {
+    admin_post(endpoint, admin_params(params, site_host), deploy_host)
+}
 
+/// `params`, the site name & the admin key, as a JSON object
+fn admin_params(params: &[(&str, &str)], site_host: &str) -> JsonFile {
     let mut file = JsonFile::new(None).unwrap();
     file.set_object(&JsonPath::new());
     let mut set = |prop, string: ArcStr| {
@@ -249,40 +541,138 @@ fn main() {
     };
 
     set("site", site_host.into());
-    set("key", "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".into());
-    set("size_bytes", format!("{}", bundle.len()).into());
+    set("key", ADMIN_KEY.into());
 
-    let payload = file.dump(&JsonPath::new()).unwrap();
+    for (prop, value) in params {
+        set(prop, (*value).into());
+    }
 
-    let request_url = format!("http://{}/request", deploy_host);
-    let resp = match post(&request_url).send(payload.as_bytes()) {
-        Ok(resp) => resp.into_string().unwrap(),
-        Err(e) => return println!("Failed to request service upload: {:?}", e),
-    };
+    file
+}
 
-    let err = "Invalid server reply";
-    let resp = JsonFile::new(Some(&resp)).expect(err);
-    let token = resp.get(&JsonPath::new()).as_string().expect(err);
+fn admin_post(endpoint: &str, params: JsonFile, deploy_host: &str) -> Option<ureq::Response> {
+    let payload = params.dump(&JsonPath::new()).unwrap();
 
-    println!("token: {}", token);
+    let url = format!("http://{}/{}", deploy_host, endpoint);
+    match post(&url).send(payload.as_bytes()) {
+        Ok(resp) => Some(resp),
+        Err(e) => {
+            println!("Failed to send {} request: {:?}", endpoint, e);
+            None
+        },
+    }
+}
 
-    // ------------------ STEP 4 ------------------
-    println!("Uploading Service");
+fn print_status(site_host: &str, deploy_host: &str) {
+    if let Some(resp) = admin_request("status", &[], site_host, deploy_host) {
+        println!("{}", resp.into_string().unwrap());
+    }
+}
 
-    let upload_url = format!("http://{}/upload/{}", deploy_host, token);
-    let resp = match post(&upload_url).send(bundle.as_slice()) {
-        Ok(resp) => resp.into_string().unwrap(),
-        Err(e) => return println!("Failed to request service upload: {:?}", e),
+fn print_errors(site_host: &str, deploy_host: &str) {
+    if let Some(resp) = admin_request("errors", &[], site_host, deploy_host) {
+        println!("{}", resp.into_string().unwrap());
+    }
+}
+
+fn gdpr_erase(table_prefix: &str, subject: &str, rewrite_history: bool, site_host: &str, deploy_host: &str) {
+    let mut params = admin_params(&[("table_prefix", table_prefix), ("subject", subject)], site_host);
+
+    // false is the default, and lmfu parses `false` as true
+    if rewrite_history {
+        let path = params.prop(JsonPath::new(), "rewrite_history");
+        params.set_boolean(&path, true);
+    }
+
+    if let Some(resp) = admin_post("erase", params, deploy_host) {
+        println!("{}", resp.into_string().unwrap());
+    }
+}
+
+fn set_secret(name: &str, value: Option<&str>, site_host: &str, deploy_host: &str) {
+    let mut params = vec![("name", name)];
+    params.extend(value.map(|value| ("value", value)));
+
+    if let Some(resp) = admin_request("secret", &params, site_host, deploy_host) {
+        println!("{}", resp.into_string().unwrap());
+    }
+}
+
+fn set_log_level(level: &str, module: Option<&str>, site_host: &str, deploy_host: &str) {
+    let mut params = Vec::new();
+    params.extend((level != "reset").then_some(("level", level)));
+    params.extend(module.map(|module| ("module", module)));
+
+    if let Some(resp) = admin_request("log_level", &params, site_host, deploy_host) {
+        println!("{}", resp.into_string().unwrap());
+    }
+}
+
+fn promote(environment: &str, site_host: &str, deploy_host: &str) {
+    if let Some(resp) = admin_request("promote", &[("environment", environment)], site_host, deploy_host) {
+        println!("{}", resp.into_string().unwrap());
+    }
+}
+
+fn db_dump(path: &str, site_host: &str, deploy_host: &str) {
+    let mut dump = Vec::new();
+    if let Some(resp) = admin_request("dump", &[], site_host, deploy_host) {
+        if let Err(e) = resp.into_reader().read_to_end(&mut dump) {
+            return println!("Failed to download database dump: {}", e);
+        }
+
+        match fs::write(path, &dump) {
+            Ok(()) => println!("> Dumped database: {} bytes", dump.len()),
+            Err(e) => println!("Failed to write {}: {}", path, e),
+        }
+    }
+}
+
+fn db_restore(path: &str, site_host: &str, deploy_host: &str) {
+    let dump = match fs::read(path) {
+        Ok(dump) => dump,
+        Err(e) => return println!("Failed to read {}: {}", path, e),
     };
 
-    let msg = match resp == "success" {
-        true => "> Service uploaded successfully",
-        false => "> Failed to upload service",
+    let msg = match upload("restore", &dump, None, site_host, deploy_host) {
+        true => "> Database dump uploaded successfully",
+        false => "> Failed to upload database dump",
     };
 
     println!("{}", msg);
 }
 
+/// Deflates files which get smaller & lists them all in a bundle index
+fn pack(files: Vec<(String, Vec<u8>)>) -> (String, Vec<(String, Vec<u8>)>) {
+    let mut index = String::from("{\"version\":2,\"files\":{");
+    let mut packed = Vec::with_capacity(files.len());
+
+    for (i, (name, bytes)) in files.into_iter().enumerate() {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        let deflated = encoder.write_all(&bytes).and_then(|()| encoder.finish()).ok();
+        let deflated = deflated.filter(|deflated| deflated.len() < bytes.len());
+
+        if i > 0 {
+            index.push(',');
+        }
+
+        let compression = if deflated.is_some() { "deflate" } else { "none" };
+        let sha256 = encode_hex(&Sha256::digest(&bytes));
+        // quotes would break the index
+        let escaped_name = name.replace('\\', "\\\\").replace('"', "\\\"");
+        index += &format!("\"{}\":{{\"size\":{},\"sha256\":\"{}\",\"compression\":\"{}\"}}", escaped_name, bytes.len(), sha256, compression);
+
+        packed.push((name, deflated.unwrap_or(bytes)));
+    }
+
+    index += "}}";
+    (index, packed)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 fn visit_dirs<F: FnMut(&Path)>(dir: &Path, cb: &mut F) -> io::Result<()> {
     for entry in fs::read_dir(dir)? {
         let entry = entry?;
@@ -0,0 +1,124 @@
+//! Messages which sites publish on topics, delivered to the subscribers of these topics
+//!
+//! Any site of the server can subscribe to any topic: the callback of each
+//! subscription is queued as a batch execution, with this request body:
+//!
+//! ```json
+//! { "topic": "orders.placed", "publisher": "shop.example.com", "message": { "id": 42 } }
+//! ```
+//!
+//! Hosts can also queue a callback of a specific site, with
+//! [`Sites::queue_callback`]. Messages aren't persisted: those which are
+//! queued when the server stops are lost.
+
+use super::{Sites, Site, Arc, PoolStr, ScriptCommand, ScriptSender, Priority, RequestInfo, request::new_request_id, push_json_str};
+use lmfu::strpool::Pool;
+use flume::{Sender, Receiver};
+
+/// A callback which runs for each message published on a topic
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscription {
+    pub topic: String,
+    pub callback: PoolStr,
+}
+
+/// JSON bodies are valid, as checked by the sender
+pub(crate) enum Message {
+    Published {
+        publisher: String,
+        topic: String,
+        json: String,
+    },
+    /// For a callback of a specific site
+    Direct {
+        hostname: String,
+        callback: String,
+        json: String,
+    },
+}
+
+#[derive(Clone)]
+pub(crate) struct Bus {
+    tx: Sender<Message>,
+    rx: Receiver<Message>,
+}
+
+impl Bus {
+    pub(crate) fn new() -> Self {
+        let (tx, rx) = flume::unbounded();
+        Self { tx, rx }
+    }
+}
+
+impl Sites {
+    /// Queues a message for the subscribers of `topic`; `json` must be valid
+    pub fn publish(&self, publisher: &str, topic: &str, json: String) {
+        let message = Message::Published { publisher: publisher.to_string(), topic: topic.to_string(), json };
+        // the receiver is kept in `self`
+        let _ = self.bus.tx.send(message);
+    }
+
+    /// Queues a rw execution of `callback`, for work which doesn't run on a script thread
+    ///
+    /// `json` is the request body and must be valid.
+    pub fn queue_callback(&self, hostname: &str, callback: &str, json: String) {
+        let message = Message::Direct { hostname: hostname.to_string(), callback: callback.to_string(), json };
+        let _ = self.bus.tx.send(message);
+    }
+}
+
+pub fn dispatcher(runs_tx: ScriptSender, sites: Sites, tid: usize) {
+    while let Ok(message) = sites.bus.rx.recv() {
+        let (topic, publisher, json) = match message {
+            Message::Published { publisher, topic, json } => (topic, publisher, json),
+            Message::Direct { hostname, callback, json } => {
+                match sites.get(&hostname) {
+                    Some(site) => dispatch(&site, &Pool::get_static_pool().intern(&callback), &json, &runs_tx, tid),
+                    None => log::error!("Site {} disappeared before its callback {} ran", hostname, callback),
+                }
+
+                continue;
+            },
+        };
+
+        let mut body = String::from("{\"topic\":");
+        push_json_str(&mut body, &topic);
+        body += ",\"publisher\":";
+        push_json_str(&mut body, &publisher);
+        body += ",\"message\":";
+        body += &json;
+        body.push('}');
+
+        for site in sites.all() {
+            for subscription in site.subscriptions() {
+                if subscription.topic == topic {
+                    dispatch(&site, &subscription.callback, &body, &runs_tx, tid);
+                }
+            }
+        }
+    }
+}
+
+fn dispatch(site: &Arc<dyn Site>, callback: &PoolStr, body: &str, runs_tx: &ScriptSender, tid: usize) {
+    let Ok(body) = site.parse_json(body, tid) else {
+        return log::error!("Couldn't create message body for {}", callback);
+    };
+
+    let command = ScriptCommand {
+        site: site.clone(),
+        script_name: callback.clone(),
+        read_only: false,
+        priority: Priority::Batch,
+        template_defaults: Default::default(),
+        path_vars: Vec::new(),
+        info: RequestInfo { id: new_request_id(), ..Default::default() },
+        body,
+        request: None,
+        guard: None,
+    };
+
+    if let Err(command) = runs_tx.send(command) {
+        log::error!("[{}] No script thread for callback {}", command.info.id, callback);
+        let _ = site.dump_json(command.body, tid);
+    }
+}
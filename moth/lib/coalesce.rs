@@ -0,0 +1,167 @@
+//! Sharing of script responses between identical concurrent requests
+//!
+//! On routes where it's enabled, a request which arrives while an identical
+//! one (same site, subdomain, scheme, method, URL, body & negotiation headers)
+//! is being processed doesn't run the script again: it waits, and gets the
+//! same response.
+//! Responses of such routes mustn't depend on cookies or other headers.
+//!
+//! If the first request doesn't get a response by its deadline (plus
+//! [`GRACE`]), for instance because the thread processing it panicked,
+//! the requests waiting for it get a 503.
+
+use super::{RequestInfo, request::{respond, respond_error, header}};
+use tiny_http::{Request, Header};
+use sha2::{Sha256, Digest};
+use std::sync::{Mutex, Once, mpsc::Sender};
+use std::time::{Duration, Instant};
+use std::thread;
+
+/// Headers which differ between the requests sharing a response
+const PER_REQUEST_HEADERS: &[&str] = &["Set-Cookie", "X-Request-Id"];
+/// Time given to a request past its deadline to respond, before the
+/// requests waiting for it give up
+const GRACE: Duration = Duration::from_secs(5);
+/// How often flights are checked for requests which didn't respond in time
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Beyond this, requests are processed without being coalesced
+const MAX_FLIGHTS: usize = 1024;
+
+/// Request waiting for the response of an identical one
+pub(crate) enum Waiter {
+    Request(Request),
+    /// Request of the test [`Harness`](super::testing::Harness), which
+    /// gets a 503 if this is dropped; (status, headers, body)
+    Harness(Sender<(u16, Vec<Header>, Vec<u8>)>),
+}
+
+struct Flight {
+    key: [u8; 32],
+    leader_id: String,
+    /// Deadline of the leader, after which waiters give up (see [`GRACE`])
+    deadline: Instant,
+    /// (waiter, request ID)
+    waiters: Vec<(Waiter, String)>,
+}
+
+static FLIGHTS: Mutex<Vec<Flight>> = Mutex::new(Vec::new());
+
+/// Headers taken into account by [`key`]
+pub(crate) const KEY_HEADERS: &[&str] = &["Accept", "If-None-Match"];
+
+/// Identifies requests which get the same response, given the headers
+/// (besides `Accept-Language`) it depends on; shared with [`response_cache`]
+///
+/// [`response_cache`]: super::response_cache
+pub(crate) fn key(hostname: &str, info: &RequestInfo, headers: &[&str], body: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in [hostname, &info.subdomain, &info.scheme, &info.method, &info.url, &info.accept_language] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+
+    for name in headers {
+        hasher.update(info.header(name).unwrap_or("").as_bytes());
+        hasher.update([0]);
+    }
+
+    hasher.update(body);
+    hasher.finalize().into()
+}
+
+/// Gives the waiter back if its request must be processed, as no identical request is
+pub(crate) fn join(key: [u8; 32], waiter: Waiter, request_id: &str, deadline: Instant) -> Option<Waiter> {
+    static SWEEPER: Once = Once::new();
+    SWEEPER.call_once(|| {
+        let builder = thread::Builder::new().name("coalesce".into());
+        let _ = builder.spawn(|| loop {
+            thread::sleep(SWEEP_INTERVAL);
+            sweep();
+        });
+    });
+
+    let mut flights = FLIGHTS.lock().unwrap();
+    let full = flights.len() >= MAX_FLIGHTS;
+    match flights.iter_mut().find(|flight| flight.key == key) {
+        Some(flight) => {
+            log::info!("[{}] Waiting for the response of {}", request_id, flight.leader_id);
+            flight.waiters.push((waiter, request_id.into()));
+            None
+        },
+        None if full => Some(waiter),
+        None => {
+            flights.push(Flight { key, leader_id: request_id.into(), deadline, waiters: Vec::new() });
+            Some(waiter)
+        },
+    }
+}
+
+/// Responds with a 503 to the requests waiting for a request which didn't respond in time
+fn sweep() {
+    let now = Instant::now();
+    let abandoned: Vec<Flight> = {
+        let mut flights = FLIGHTS.lock().unwrap();
+        let (abandoned, pending) = flights.drain(..).partition(|flight| now > flight.deadline + GRACE);
+        *flights = pending;
+        abandoned
+    };
+
+    abandoned.into_iter().for_each(fail);
+}
+
+fn fail(flight: Flight) {
+    log::error!("[{}] Got no response; giving up on {} identical requests", flight.leader_id, flight.waiters.len());
+    for (waiter, request_id) in flight.waiters {
+        if let Waiter::Request(request) = waiter {
+            respond_error(None, request, &request_id, None, 503);
+        }
+    }
+}
+
+/// If the thread panics while this is alive, the requests waiting
+/// for this one get a 503 right away, instead of at the next sweep
+pub(crate) struct PanicGuard<'a>(pub &'a str);
+
+impl Drop for PanicGuard<'_> {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+
+        let flight = FLIGHTS.lock().ok().and_then(|mut flights| {
+            let i = flights.iter().position(|flight| flight.leader_id == self.0)?;
+            Some(flights.swap_remove(i))
+        });
+
+        if let Some(flight) = flight {
+            fail(flight);
+        }
+    }
+}
+
+/// Sends the response of a request to the ones which waited for it, if any
+pub(crate) fn release(leader_id: &str, status: u16, headers: &[Header], body: &[u8]) {
+    let waiters = {
+        let mut flights = FLIGHTS.lock().unwrap();
+        let Some(i) = flights.iter().position(|flight| flight.leader_id == leader_id) else { return };
+        flights.swap_remove(i).waiters
+    };
+
+    let is_shared = |h: &&Header| !PER_REQUEST_HEADERS.iter().any(|name| h.field.equiv(name));
+    let shared: Vec<Header> = headers.iter().filter(is_shared).cloned().collect();
+    let has_request_id = headers.iter().any(|h| h.field.equiv("X-Request-Id"));
+
+    for (waiter, request_id) in waiters {
+        let mut headers = shared.clone();
+        if has_request_id {
+            headers.push(header("X-Request-Id", &request_id));
+        }
+
+        match waiter {
+            Waiter::Request(request) => respond(request, &request_id, status, headers, body),
+            Waiter::Harness(sender) => {
+                let _ = sender.send((status, headers, body.to_vec()));
+            },
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Host-managed CSRF tokens
+//!
+//! Clients of sites with a [`CsrfSecret`] get a session cookie on their first
+//! response. Tokens are derived from the session & the secret; script routes
+//! marked as CSRF-protected refuse requests without the token of their session
+//! in an `X-CSRF-Token` header.
+
+use tiny_http::Request;
+use sha2::{Sha256, Digest};
+use rand::RngCore;
+
+pub const SESSION_COOKIE: &str = "moth_session";
+pub const TOKEN_HEADER: &str = "X-CSRF-Token";
+
+/// Key from which sessions & tokens of a site are derived
+pub struct CsrfSecret([u8; 32]);
+
+impl CsrfSecret {
+    pub fn new(secret: [u8; 32]) -> Self {
+        Self(secret)
+    }
+
+    /// Tokens issued before are invalidated when the secret changes
+    pub fn random() -> Self {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self(secret)
+    }
+
+    /// Token which requests of a session must carry
+    pub fn token(&self, session: &str) -> String {
+        self.derive("token", session)
+    }
+
+    /// Session of a client without cookie, from the ID of the request
+    fn new_session(&self, request_id: &str) -> String {
+        self.derive("session", request_id)
+    }
+
+    fn derive(&self, purpose: &str, input: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.0);
+        hasher.update(purpose);
+        hasher.update(input);
+        hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Session of a request, and whether it's new (i.e. its cookie must be set)
+pub(crate) fn session(secret: &CsrfSecret, request: &Request, request_id: &str) -> (String, bool) {
+    let cookies = request.headers().iter().filter(|h| h.field.equiv("Cookie"));
+    let cookies = cookies.flat_map(|h| h.value.as_str().split(';'));
+
+    for cookie in cookies {
+        if let Some((SESSION_COOKIE, session)) = cookie.trim().split_once('=') {
+            // other values couldn't have been issued by us
+            if session.len() == 64 && session.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return (session.to_string(), false);
+            }
+        }
+    }
+
+    (secret.new_session(request_id), true)
+}
+
+/// `Set-Cookie` header value for a new session
+pub(crate) fn session_cookie(session: &str) -> String {
+    format!("{}={}; Path=/; HttpOnly; SameSite=Lax", SESSION_COOKIE, session)
+}
+
+/// Whether the `X-CSRF-Token` header of a request matches `token`
+pub(crate) fn has_token(request: &Request, token: &str) -> bool {
+    let header = request.headers().iter().find(|h| h.field.equiv(TOKEN_HEADER));
+    match header {
+        Some(header) => super::request::constant_time_eq(header.value.as_str(), token),
+        None => false,
+    }
+}
@@ -0,0 +1,84 @@
+//! Static copies of sites, for CDN hosting
+//!
+//! [`export`] requests paths of a site with a [`Harness`], and writes the
+//! successful responses to a directory where a static file server can serve
+//! them at the same URLs:
+//! - paths ending with a file name (`/feed.xml`) & static assets are
+//!   written as they are
+//! - other responses go to an index file of a directory named after the
+//!   path, with the extension of their content type: `/blog/first-post`
+//!   becomes `blog/first-post/index.html`
+//!
+//! Hosts list the paths, e.g. from the routes of the site: [`export`] runs
+//! no script which the requests wouldn't run.
+
+use super::{testing::Harness, renderer::{JSON, XML, PLAIN_TEXT}};
+use std::{fs, path::{Path, Component}};
+
+#[derive(Debug, Clone, Default)]
+pub struct Exported {
+    /// Files written in the output directory
+    pub files: Vec<String>,
+    /// (path, status) of the requests which weren't exported
+    pub skipped: Vec<(String, u16)>,
+}
+
+/// Writes the responses to GET requests of `paths` in `out`, which is created if missing
+///
+/// Fails if a file can't be written.
+pub fn export(harness: &Harness, paths: &[String], out: &Path) -> Result<Exported, ()> {
+    let mut exported = Exported::default();
+
+    for path in paths {
+        let response = harness.get(path);
+        if response.status != 200 {
+            log::warn!("Skipped {}: status {}", path, response.status);
+            exported.skipped.push((path.clone(), response.status));
+            continue;
+        }
+
+        let Some(file_name) = file_name(path, response.content_type) else {
+            log::warn!("Skipped {}: it can't be a file path", path);
+            exported.skipped.push((path.clone(), response.status));
+            continue;
+        };
+
+        if exported.files.contains(&file_name) {
+            continue;
+        }
+
+        let file_path = out.join(&file_name);
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| log::error!("Failed to create {}: {}", parent.display(), e))?;
+        }
+
+        fs::write(&file_path, &response.body).map_err(|e| log::error!("Failed to write {}: {}", file_path.display(), e))?;
+        exported.files.push(file_name);
+    }
+
+    Ok(exported)
+}
+
+/// Path of the file of a response, relative to the output directory
+fn file_name(path: &str, content_type: Option<&str>) -> Option<String> {
+    let path = path.split(['?', '#']).next().unwrap().trim_matches('/');
+    if !Path::new(path).components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+
+    let last_step = path.rsplit('/').next().unwrap();
+    let extension = match content_type {
+        _ if last_step.contains('.') => return Some(path.to_string()),
+        // static assets
+        None if !path.is_empty() => return Some(path.to_string()),
+        Some(JSON) => "json",
+        Some(XML) => "xml",
+        Some(PLAIN_TEXT) => "txt",
+        _ => "html",
+    };
+
+    match path.is_empty() {
+        true => Some(format!("index.{}", extension)),
+        false => Some(format!("{}/index.{}", path, extension)),
+    }
+}
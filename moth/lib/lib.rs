@@ -1,7 +1,9 @@
 // #![doc = include_str!("../../README.md")]
+#![allow(clippy::result_unit_err)]
 
-use std::{sync::{Arc, RwLock}, thread, net::ToSocketAddrs};
-use lmfu::{strpool::{Pool, PoolStr}, LiteMap, HashMap};
+use std::{sync::{Arc, RwLock}, thread::{self, JoinHandle}, net::{ToSocketAddrs, TcpListener}, time::Duration, collections::VecDeque, path::PathBuf};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use lmfu::{strpool::PoolStr, LiteMap, HashMap};
 use tiny_http::{Server, StatusCode};
 
 pub type OpaqueJsonPointer = usize;
@@ -9,11 +11,33 @@ pub type OpaqueJsonPointer = usize;
 pub mod request;
 pub mod script;
 pub mod renderer;
+pub mod scheduler;
+pub mod bus;
+pub mod upload;
+pub mod proxy;
+pub mod native;
+pub mod testing;
+pub mod export;
+pub mod record;
+pub mod csrf;
+pub mod coalesce;
+pub mod response_cache;
+pub mod log_context;
+pub mod systemd;
+pub mod socket;
+pub mod trace;
+pub mod schema;
 
 pub use {
-    request::{request_waiter},
-    script::{script_runner, ScriptCommand, ScriptResult},
+    request::{request_waiter, request_acceptor, RequestInfo},
+    script::{script_runner, script_queues, ScriptCommand, ScriptResult, ScriptSender, Priority},
     renderer::{renderer, RendererCommand},
+    scheduler::{scheduler, Job, Schedule},
+    bus::{dispatcher, Subscription},
+    upload::{upload_worker, Upload},
+    proxy::{IpRange, IpFilter},
+    native::{NativeSite, NativeRequest, NativeResponse, NativeHandler},
+    csrf::CsrfSecret,
 };
 
 #[derive(Debug, PartialEq)]
@@ -21,116 +45,939 @@ pub struct EndpointMap {
     pub default: Option<Box<Endpoint>>,
     pub wildcard: Option<Box<Endpoint>>,
     pub items: HashMap<str, Endpoint>,
+    /// Clients allowed in this subtree, in addition to the site-wide filter
+    pub ip_filter: Option<IpFilter>,
+    /// Security headers of this subtree, applied after the site-wide ones
+    pub security_headers: Option<HeaderOverrides>,
 }
 
 pub type ReadOnly = bool;
 
+/// If true, requests must carry the CSRF token of their session, see [`csrf`]
+pub type CsrfProtected = bool;
+
+/// If true, identical concurrent requests share a single script execution, see [`coalesce`]
+pub type Coalesced = bool;
+
+/// If true, responses are kept until the database of the site changes, see [`response_cache`]
+pub type Cached = bool;
+
+/// JSON bodies which don't match it get a 422 response, see [`schema`]
+pub type BodySchema = Option<Arc<schema::Schema>>;
+
+/// How long a request can wait for its script & its rendering; it gets a 504 response after that
+pub type Timeout = Duration;
+
+/// Timeout of script routes which don't set one
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// If true, responses carry a strong `ETag` and `GET` requests with a matching
+/// `If-None-Match` header get an empty 304 response, see [`renderer::etag`]
+pub type Etag = bool;
+
+/// Upper-case HTTP methods accepted by a script route, if restricted; `HEAD`
+/// is accepted with `GET`. Requests with other methods get a 405 response.
+pub type Methods = Option<Arc<[String]>>;
+
+/// Size limit of the bodies of a script route, in bytes; larger ones get a 413 response
+pub type MaxBody = Option<usize>;
+
+/// Whether `methods` accept a request method
+pub fn accepts_method(methods: &Methods, method: &str) -> bool {
+    let accepts = |m: &str| methods.as_ref().is_none_or(|methods| methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(m)));
+    accepts(method) || (method.eq_ignore_ascii_case("HEAD") && accepts("GET"))
+}
+
+/// Limits applied while streaming an upload body
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct UploadTimeouts {
+    /// Maximum duration of a single read from the client
+    pub read: Duration,
+    /// Maximum duration of the whole upload
+    pub total: Duration,
+}
+
+impl Default for UploadTimeouts {
+    fn default() -> Self {
+        Self {
+            read: Duration::from_secs(30),
+            total: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Settings of preview/staging deployments, which must stay private
+///
+/// Responses of such sites carry `X-Robots-Tag: noindex`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Preview {
+    /// Expected `Authorization` header value, if access is restricted
+    pub authorization: Option<String>,
+}
+
+impl Preview {
+    pub fn with_basic_auth(username: &str, password: &str) -> Self {
+        let credentials = format!("{}:{}", username, password);
+        Self {
+            authorization: Some(format!("Basic {}", request::encode_base64(credentials.as_bytes()))),
+        }
+    }
+}
+
+/// Health of the periodic database synchronization of a site
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SyncStatus {
+    /// Unix timestamp of the last successful push; 0 if there was none
+    pub last_push: u64,
+    /// Unix timestamp of the last successful pull; 0 if there was none
+    pub last_pull: u64,
+    /// Number of failed syncs since the last successful one
+    pub failures: u64,
+    /// Number of entries written since the last successful push
+    pub pending_entries: usize,
+}
+
+impl SyncStatus {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"last_push\":{},\"last_pull\":{},\"failures\":{},\"pending_entries\":{}}}",
+            self.last_push, self.last_pull, self.failures, self.pending_entries,
+        )
+    }
+}
+
+/// A failed script execution
+#[derive(Debug, PartialEq, Clone)]
+pub struct ScriptError {
+    /// Unix timestamp
+    pub time: u64,
+    pub request_id: String,
+    pub callback: String,
+    /// Trap message
+    pub message: String,
+}
+
+/// Failed script executions of a site
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ScriptErrors {
+    /// Number of failures since the site was deployed
+    pub total: u64,
+    /// Last failures, oldest first
+    pub recent: VecDeque<ScriptError>,
+}
+
+impl ScriptErrors {
+    /// Number of failures kept in `recent`
+    pub const RECENT: usize = 32;
+
+    pub fn push(&mut self, error: ScriptError) {
+        self.total += 1;
+        if self.recent.len() == Self::RECENT {
+            self.recent.pop_front();
+        }
+
+        self.recent.push_back(error);
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = format!("{{\"total\":{},\"recent\":[", self.total);
+
+        for (i, error) in self.recent.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+
+            json += &format!("{{\"time\":{},\"request_id\":", error.time);
+            push_json_str(&mut json, &error.request_id);
+            json += ",\"callback\":";
+            push_json_str(&mut json, &error.callback);
+            json += ",\"message\":";
+            push_json_str(&mut json, &error.message);
+            json.push('}');
+        }
+
+        json += "]}";
+        json
+    }
+}
+
+/// Appends `string` to `json`, quoted & escaped
+pub fn push_json_str(json: &mut String, string: &str) {
+    json.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// Headers of all responses, unless a site or route overrides them
+///
+/// `X-Content-Type-Options` is left out of responses without a `Content-Type`,
+/// such as static assets, which browsers would refuse to use otherwise.
+pub const DEFAULT_SECURITY_HEADERS: &[(&str, &str)] = &[
+    ("Strict-Transport-Security", "max-age=31536000"),
+    ("X-Content-Type-Options", "nosniff"),
+    ("Content-Security-Policy", "frame-ancestors 'self'"),
+    ("Referrer-Policy", "strict-origin-when-cross-origin"),
+];
+
+/// Changes to the security headers of a site or route subtree
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct HeaderOverrides {
+    /// (name, value); `None` removes the header
+    pub headers: Vec<(&'static str, Option<String>)>,
+}
+
+impl HeaderOverrides {
+    /// Fails if `name` isn't one of [`DEFAULT_SECURITY_HEADERS`]
+    pub fn set(&mut self, name: &str, value: Option<String>) -> Result<(), ()> {
+        let known = DEFAULT_SECURITY_HEADERS.iter().find(|(known, _)| known.eq_ignore_ascii_case(name));
+        let (name, _) = known.ok_or_else(|| log::error!("Unsupported security header: {}", name))?;
+        self.headers.retain(|(n, _)| n != name);
+        self.headers.push((name, value));
+        Ok(())
+    }
+
+    /// Applies these changes to a list of headers
+    pub fn apply(&self, headers: &mut Vec<(&'static str, String)>) {
+        for (name, value) in &self.headers {
+            headers.retain(|(n, _)| n != name);
+            if let Some(value) = value {
+                headers.push((name, value.clone()));
+            }
+        }
+    }
+}
+
+/// Template name & parameters set by a route, which scripts can override
+#[derive(Debug, PartialEq, Default)]
+pub struct TemplateDefaults {
+    pub template: Option<PoolStr>,
+    pub parameters: LiteMap<PoolStr, String>,
+}
+
+/// How script endpoints read request bodies
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum BodyMode {
+    /// Parsed as JSON; invalid bodies are refused
+    #[default]
+    Json,
+    /// Passed as is to scripts, invalid UTF-8 is refused
+    Text,
+    /// Passed as is to scripts
+    Bytes,
+    /// Ignored
+    None,
+}
+
+impl BodyMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Text => "text",
+            Self::Bytes => "bytes",
+            Self::None => "none",
+        }
+    }
+}
+
+/// How `Endpoint::Protected` checks the `Authorization` header of requests
+#[derive(Debug, PartialEq, Clone)]
+pub enum Auth {
+    /// Expected header value, see `Auth::basic`
+    Basic(String),
+    /// Expected header value, see `Auth::bearer`
+    Bearer(String),
+    /// Read-only script receiving the header value as its only path
+    /// parameter; requests are accepted if it returns `true`
+    Callback(PoolStr),
+}
+
+impl Auth {
+    pub fn basic(username: &str, password: &str) -> Self {
+        Self::Basic(Preview::with_basic_auth(username, password).authorization.unwrap())
+    }
+
+    pub fn bearer(token: &str) -> Self {
+        Self::Bearer(format!("Bearer {}", token))
+    }
+
+    /// `WWW-Authenticate` header value of rejections
+    pub fn challenge(&self) -> &'static str {
+        match self {
+            Self::Basic(_) => "Basic realm=\"protected\"",
+            Self::Bearer(_) | Self::Callback(_) => "Bearer",
+        }
+    }
+}
+
+/// Options of a route running a script
+#[derive(Debug, PartialEq)]
+pub struct ScriptEndpoint {
+    pub read_only: ReadOnly,
+    pub script_name: PoolStr,
+    pub template_defaults: Arc<TemplateDefaults>,
+    pub priority: Priority,
+    pub body_mode: BodyMode,
+    pub csrf_protected: CsrfProtected,
+    pub etag: Etag,
+    pub timeout: Timeout,
+    pub coalesced: Coalesced,
+    pub cached: Cached,
+    pub schema: BodySchema,
+    pub methods: Methods,
+    pub max_body: MaxBody,
+}
+
+impl ScriptEndpoint {
+    /// Interactive route with a JSON body, the default timeout & no other option
+    pub fn new(read_only: ReadOnly, script_name: PoolStr) -> Self {
+        Self {
+            read_only,
+            script_name,
+            template_defaults: Default::default(),
+            priority: Priority::default(),
+            body_mode: BodyMode::default(),
+            csrf_protected: false,
+            etag: false,
+            timeout: DEFAULT_TIMEOUT,
+            coalesced: false,
+            cached: false,
+            schema: None,
+            methods: None,
+            max_body: None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Endpoint {
-    ScriptExec(ReadOnly, PoolStr),
+    ScriptExec(ScriptEndpoint),
     Static(PoolStr),
+    /// Fixed response, generated when the site is loaded: (content type, body)
+    Document(&'static str, Arc<[u8]>),
     Dir(EndpointMap),
-    Upload,
+    Upload(UploadTimeouts),
     Error(StatusCode),
+    /// Requests failing `auth` get a 401 response
+    Protected { auth: Auth, inner: Box<Endpoint> },
 }
 
-pub trait Site: Sync + Send + 'static {
-    fn pool(&self) -> &Pool;
-    fn name(&self) -> &str;
+/// Which requests a site serves, and how
+pub trait Routing {
     fn hostname(&self) -> &str;
-    fn prepare_tls(&self, script_threads: usize);
+    fn routes(&self) -> &Endpoint;
 
-    fn parse_json(&self, json: &str, script_thread_id: usize) -> Result<OpaqueJsonPointer, ()>;
-    fn dump_json(&self, json: OpaqueJsonPointer, script_thread_id: usize) -> Result<String, ()>;
+    /// Name of the site in logs
+    fn name(&self) -> &str { self.hostname() }
 
-    fn on_404(&self) -> &Endpoint;
-    fn routes(&self) -> &Endpoint;
+    /// Other hostnames of the site; `*.example.com` matches all subdomains of example.com
+    fn hostnames(&self) -> &[String] { &[] }
+
+    /// Where requests matching no route end up
+    fn on_404(&self) -> &Endpoint { &NOT_FOUND }
+
+    /// Set for preview/staging deployments
+    fn preview(&self) -> Option<&Preview> { None }
 
-    fn open_static(&self, path: &str) -> Option<&[u8]>;
+    /// If true, responses carry the ID of their request in an `X-Request-Id` header
+    fn request_id_header(&self) -> bool { false }
+
+    /// Clients allowed to access the site; others get a 403 response
+    fn ip_filter(&self) -> Option<&IpFilter> { None }
+
+    /// Enables sessions & CSRF tokens, see [`csrf`]
+    fn csrf_secret(&self) -> Option<&CsrfSecret> { None }
+
+    /// Changes to [`DEFAULT_SECURITY_HEADERS`] for the whole site
+    fn security_headers(&self) -> Option<&HeaderOverrides> { None }
+
+    /// Parts of the site served on site ports of the server, see [`Sites::with_site_ports`]
+    fn surfaces(&self) -> &[Surface] { &[] }
+}
+
+/// Routes of a site under `prefix`, served at the root of a site port:
+/// `GET /users` on port 8443 leads to `/admin/users` with `{ 8443, "/admin" }`
+///
+/// Unless it's `/`, the prefix isn't served on other ports.
+#[derive(Debug, Clone)]
+pub struct Surface {
+    pub port: u16,
+    pub prefix: String,
+}
 
-    fn check_upload_token(&self, token: &str) -> Option<usize>;
-    fn upload_progress(&self, token: &str, to_append: &[u8]);
-    fn end_of_upload(&self, token: &str, success: bool);
+static NOT_FOUND: Endpoint = Endpoint::Error(StatusCode(404));
+
+/// Body of an `Endpoint::Static` response, sent without being copied
+pub enum StaticBody {
+    /// Shared by the responses which use it
+    Memory(Arc<[u8]>),
+    /// Streamed from disk: (file, length)
+    File(std::fs::File, u64),
+}
+
+/// Files of `Endpoint::Static` routes
+pub trait StaticAssets {
+    fn open_static(&self, _path: &str) -> Option<StaticBody> { None }
+}
+
+/// Executions of `Endpoint::ScriptExec` routes & scheduled jobs
+///
+/// Request bodies & script results are JSON values owned by the
+/// script thread which parsed them, identified by opaque pointers.
+pub trait ScriptHost {
+    /// Called when the site is inserted, with the number of threads which
+    /// might call other methods; per-thread state can be created lazily
+    fn prepare_tls(&self, _script_threads: usize) {}
+
+    fn parse_json(&self, _json: &str, _script_thread_id: usize) -> Result<OpaqueJsonPointer, ()> { Err(()) }
+    fn dump_json(&self, _json: OpaqueJsonPointer, _script_thread_id: usize) -> Result<String, ()> { Err(()) }
 
     fn process_script(
         &self,
-        script: PoolStr,
-        read_only: bool,
-        path_vars: &[String],
-        body: OpaqueJsonPointer,
-        script_thread_id: usize,
-    ) -> Result<ScriptResult, ()>;
+        _script: PoolStr,
+        _read_only: bool,
+        _path_vars: &[String],
+        _info: &RequestInfo,
+        _body: OpaqueJsonPointer,
+        _script_thread_id: usize,
+    ) -> Result<ScriptResult, ()> { Err(()) }
+
+    /// Whether the site `caller` may run `callback` with `process_script`
+    fn accepts_service_call(&self, _callback: &str, _caller: &str) -> bool { false }
+
+    /// Periodic script executions, checked every minute
+    fn jobs(&self) -> &[Job] { &[] }
+
+    /// Callbacks which run for the messages of other sites, see [`bus`]
+    fn subscriptions(&self) -> &[Subscription] { &[] }
+
+    /// Last failed executions of `process_script`
+    fn script_errors(&self) -> ScriptErrors { ScriptErrors::default() }
+}
+
+/// Bodies of `Endpoint::Upload` routes
+pub trait UploadSink {
+    /// Maximum body length of the upload, if the token is valid
+    fn check_upload_token(&self, _token: &str) -> Option<usize> { None }
+    fn upload_progress(&self, _token: &str, _to_append: &[u8]) {}
+    /// Rejected uploads give their reasons, sent to the client with a 422 status
+    fn end_of_upload(&self, _token: &str, _success: bool) -> Result<(), String> { Ok(()) }
+
+    /// Expected SHA-256 digest of an upload, verified before `end_of_upload(token, true)`
+    fn upload_digest(&self, _token: &str) -> Option<[u8; 32]> { None }
 
-    fn render_template(&self, name: PoolStr, parameters: LiteMap<PoolStr, String>) -> Result<String, ()>;
+    /// (chunks, bytes) received for a chunked upload; None if the token can't be used for one
+    fn chunked_progress(&self, _token: &str) -> Option<(usize, usize)> { None }
+    /// Appends the next chunk of an upload, which was fully received
+    fn append_chunk(&self, _token: &str, _chunk: &[u8]) {}
+}
+
+/// Templates of `ScriptResult::Template` results & error documents
+pub trait TemplateRenderer {
+    /// Parameters are inserted as is: values which come from clients must
+    /// already be HTML-escaped, see [`renderer::escape_html`]
+    fn render_template(&self, _name: PoolStr, _parameters: LiteMap<PoolStr, String>) -> Result<String, ()> { Err(()) }
+
+    /// Custom document of error responses: (content type, body)
+    ///
+    /// `path` is the path of the failed request, without the query string;
+    /// it comes from the client, so it must be escaped before rendering.
+    fn error_document(&self, _code: u16, _path: &str) -> Option<(&'static str, Vec<u8>)> { None }
+}
+
+/// Maintenance of the database of a site
+pub trait SiteDatabase {
+    /// When to call `sync_database`, checked every minute
+    fn sync_schedule(&self) -> Option<&Schedule> { None }
+
+    /// Pushes local database changes & pulls remote ones; runs in its own thread
+    fn sync_database(&self) {}
+
+    fn sync_status(&self) -> Option<SyncStatus> { None }
+
+    /// Changes whenever database entries change; responses of routes with
+    /// caching enabled are only kept by sites which have one
+    fn revision(&self) -> Option<u64> { None }
+
+    /// Erases expired database entries; runs daily, in its own thread
+    fn enforce_retention(&self) {}
+
+    /// Erases the database entries of a data subject & pushes the removal
+    ///
+    /// Returns the number of erased files.
+    fn erase_subject(&self, _table_prefix: &str, _subject_key: &str, _rewrite_history: bool) -> Result<usize, ()> { Err(()) }
+
+    /// All database files, as a tarball
+    fn dump_database(&self) -> Result<Vec<u8>, ()> { Err(()) }
+
+    /// Replaces all database files with the ones in a tarball & pushes the result
+    fn restore_database(&self, _dump: &[u8]) -> Result<(), ()> { Err(()) }
+
+    /// Writes the database files of another environment over the site's ones & pushes the result
+    ///
+    /// Returns the number of promoted files.
+    fn promote_database(&self, _environment: &str) -> Result<usize, ()> { Err(()) }
+}
+
+/// A website served by moth
+///
+/// It's implemented for all types which implement the capability traits;
+/// their methods have defaults, except for `Routing::hostname/routes`.
+pub trait Site: Routing + StaticAssets + ScriptHost + UploadSink + TemplateRenderer + SiteDatabase + Sync + Send + 'static {}
+
+impl<T> Site for T where T: Routing + StaticAssets + ScriptHost + UploadSink + TemplateRenderer + SiteDatabase + Sync + Send + 'static {}
+
+/// How requests with a missing or unknown `Host` header are handled
+#[derive(Debug, PartialEq, Clone)]
+pub enum Fallback {
+    /// Served by the site with this (main) hostname
+    Site(String),
+    /// Redirected to this base URL, keeping the path & query string
+    Redirect(String),
+}
+
+/// (hostname or pattern, site), from `Site::hostnames`
+type Hostname = (String, Arc<dyn Site>);
+
+/// Path prefix of ACME HTTP-01 challenges, see `Sites::set_acme_challenge`
+pub const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// (main hostname of the site, token, key authorization)
+type AcmeChallenge = (String, String, String);
+
+/// Number of threads of a kind
+///
+/// `min` threads start with the server; more are started while work waits
+/// in their queue, up to `max`. Started threads are kept.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ThreadCount {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl ThreadCount {
+    pub fn fixed(count: usize) -> Self {
+        Self { min: count, max: count }
+    }
+
+    /// One thread per CPU, growing up to four per CPU
+    pub fn auto() -> Self {
+        let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        Self { min: cpus, max: cpus * 4 }
+    }
+
+    /// Fails unless `1 <= min <= max`
+    pub fn new(min: usize, max: usize) -> Result<Self, ()> {
+        match 1 <= min && min <= max {
+            true => Ok(Self { min, max }),
+            false => Err(()),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Sites {
     sites: Arc<RwLock<HashMap<str, Arc<dyn Site>>>>,
-    request_threads: usize,
-    script_threads: usize,
-    render_threads: usize,
+    hostnames: Arc<RwLock<Vec<Hostname>>>,
+    acme_challenges: Arc<RwLock<Vec<AcmeChallenge>>>,
+    fallback: Option<Fallback>,
+    trusted_proxies: Vec<IpRange>,
+    recording: Option<PathBuf>,
+    request_threads: ThreadCount,
+    script_threads: ThreadCount,
+    render_threads: ThreadCount,
+    upload_threads: ThreadCount,
+    site_ports: Vec<u16>,
+    bus: bus::Bus,
 }
 
 impl Sites {
-    pub fn new(request_threads: usize, script_threads: usize, render_threads: usize) -> Self {
+    /// Uploads wait for one of the `upload_threads`; they're refused once
+    /// as many uploads as the maximum number of upload threads are waiting
+    pub fn new(request_threads: ThreadCount, script_threads: ThreadCount, render_threads: ThreadCount, upload_threads: ThreadCount) -> Self {
         Self {
             sites: Arc::new(RwLock::new(HashMap::new())),
+            hostnames: Arc::new(RwLock::new(Vec::new())),
+            acme_challenges: Arc::new(RwLock::new(Vec::new())),
+            fallback: None,
+            trusted_proxies: Vec::new(),
+            recording: None,
             request_threads,
             script_threads,
             render_threads,
+            upload_threads,
+            site_ports: Vec::new(),
+            bus: bus::Bus::new(),
         }
     }
 
+    /// Listening ports reserved to the [`Surface`]s of sites
+    ///
+    /// Requests received on these ports only reach a site if it has a
+    /// surface there; others get a 404 response.
+    pub fn with_site_ports(mut self, site_ports: Vec<u16>) -> Self {
+        self.site_ports = site_ports;
+        self
+    }
+
+    pub(crate) fn site_ports(&self) -> &[u16] {
+        &self.site_ports
+    }
+
+    /// Without a fallback, requests for unknown hosts get a 502 response
+    pub fn with_fallback(mut self, fallback: Fallback) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    pub(crate) fn fallback(&self) -> Option<&Fallback> {
+        self.fallback.as_ref()
+    }
+
+    /// Requests from these addresses can set the client IP & scheme
+    /// with `X-Forwarded-For` & `X-Forwarded-Proto` headers
+    pub fn with_trusted_proxies(mut self, trusted_proxies: Vec<IpRange>) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    pub(crate) fn trusted_proxies(&self) -> &[IpRange] {
+        &self.trusted_proxies
+    }
+
+    /// Saves all requests & responses in a directory, for debugging; see [`record`]
+    ///
+    /// Recordings contain request headers & bodies, including credentials.
+    pub fn with_recording(mut self, directory: PathBuf) -> Self {
+        self.recording = Some(directory);
+        self
+    }
+
+    /// Thread indexes go up to this number (excluded)
     pub(crate) fn total_threads(&self) -> usize {
-        self.request_threads + self.script_threads + self.render_threads
+        // + 2 for the scheduler & bus threads
+        self.request_threads.max + self.script_threads.max + self.render_threads.max + 2
     }
 
     pub fn insert(&self, site: Box<dyn Site>) {
         let mut map = self.sites.write().unwrap();
+        let mut hostnames = self.hostnames.write().unwrap();
         site.prepare_tls(self.total_threads());
         let arc: Arc<dyn Site> = site.into();
         let clone = arc.clone();
         println!("Inserting site: {}", clone.hostname());
+
+        // hostnames of the previous version of the site
+        hostnames.retain(|(_, s)| s.hostname() != clone.hostname());
+        for hostname in clone.hostnames() {
+            hostnames.push((hostname.to_ascii_lowercase(), clone.clone()));
+        }
+
         map.insert_ref(clone.hostname(), arc);
     }
 
-    pub(crate) fn get(&self, host: &str) -> Option<Arc<dyn Site>> {
+    /// Unregisters a site and its aliases
+    ///
+    /// Its resources are freed once its pending requests are processed.
+    pub fn remove(&self, hostname: &str) -> Option<Arc<dyn Site>> {
+        let mut map = self.sites.write().unwrap();
+        let mut hostnames = self.hostnames.write().unwrap();
+        hostnames.retain(|(_, s)| s.hostname() != hostname);
+
+        // lmfu's HashMap only stores hashes
+        let sites = &mut map.hash_to_value;
+        let (hash, _) = sites.iter().find(|(_, site)| site.hostname() == hostname)?;
+        let hash = *hash;
+        sites.remove(&hash)
+    }
+
+    /// Site by its main hostname
+    pub fn get(&self, host: &str) -> Option<Arc<dyn Site>> {
         let map = self.sites.read().unwrap();
         map.get(host).cloned()
     }
+
+    /// Site serving `host`, and the subdomain matched by a wildcard hostname
+    ///
+    /// Exact hostnames take precedence over wildcards, then the
+    /// most specific wildcard wins.
+    pub fn resolve(&self, host: &str) -> Option<(Arc<dyn Site>, String)> {
+        let host = host.to_ascii_lowercase();
+        if let Some(site) = self.get(&host) {
+            return Some((site, String::new()));
+        }
+
+        let hostnames = self.hostnames.read().unwrap();
+        if let Some((_, site)) = hostnames.iter().find(|(hostname, _)| *hostname == host) {
+            return Some((site.clone(), String::new()));
+        }
+
+        let mut best: Option<(&str, &Arc<dyn Site>)> = None;
+        for (pattern, site) in hostnames.iter() {
+            let Some(parent) = pattern.strip_prefix("*.") else { continue };
+            let subdomain = match host.strip_suffix(parent).and_then(|s| s.strip_suffix('.')) {
+                Some(subdomain) if !subdomain.is_empty() => subdomain,
+                _ => continue,
+            };
+
+            let more_specific = match best {
+                Some((best_subdomain, _)) => subdomain.len() < best_subdomain.len(),
+                None => true,
+            };
+
+            if more_specific {
+                best = Some((subdomain, site));
+            }
+        }
+
+        best.map(|(subdomain, site)| (site.clone(), subdomain.to_string()))
+    }
+
+    /// Serves an ACME HTTP-01 challenge on all hostnames of a site, before routing
+    ///
+    /// moth has no TLS listener, so it doesn't request certificates itself; this
+    /// lets an ACME client prove control of the hostnames, for the certificates
+    /// of a TLS-terminating proxy.
+    pub fn set_acme_challenge(&self, hostname: &str, token: &str, key_authorization: &str) {
+        let mut challenges = self.acme_challenges.write().unwrap();
+        challenges.retain(|(h, t, _)| h != hostname || t != token);
+        challenges.push((hostname.into(), token.into(), key_authorization.into()));
+    }
+
+    pub fn remove_acme_challenge(&self, hostname: &str, token: &str) {
+        self.acme_challenges.write().unwrap().retain(|(h, t, _)| h != hostname || t != token);
+    }
+
+    /// Key authorization of a pending challenge of a site
+    pub(crate) fn acme_challenge(&self, hostname: &str, token: &str) -> Option<String> {
+        let challenges = self.acme_challenges.read().unwrap();
+        let challenge = challenges.iter().find(|(h, t, _)| h == hostname && t == token);
+        challenge.map(|(_, _, key_authorization)| key_authorization.clone())
+    }
+
+    pub(crate) fn all(&self) -> Vec<Arc<dyn Site>> {
+        let map = self.sites.read().unwrap();
+        map.hash_to_value.iter_values().cloned().collect()
+    }
 }
 
 pub fn serve<A: ToSocketAddrs>(addr: A, sites: Sites) {
-    let server = Server::http(addr).unwrap();
-    let server = Arc::new(server);
+    serve_all(&[addr], sites)
+}
+
+/// Listens on several addresses (IPv4 & IPv6, multiple ports...),
+/// with the same sites & worker threads for all of them
+pub fn serve_all<A: ToSocketAddrs>(addrs: &[A], sites: Sites) {
+    let servers = addrs.iter().map(|addr| Server::http(addr).unwrap()).collect();
+    serve_servers(servers, sites)
+}
+
+/// Like [`serve_all`], on sockets which are already listening, such as
+/// those passed by systemd (see [`systemd::listeners`])
+pub fn serve_listeners(listeners: Vec<TcpListener>, sites: Sites) {
+    let servers = listeners.into_iter().map(|listener| Server::from_listener(listener, None).unwrap()).collect();
+    serve_servers(servers, sites)
+}
+
+fn serve_servers(servers: Vec<Server>, sites: Sites) {
 
-    let (runs_tx, runs_rx) = flume::unbounded();
+    if let Some(directory) = &sites.recording {
+        record::enable(directory.clone());
+    }
+
+    let (runs_tx, runs_rx) = script_queues();
     let (renders_tx, renders_rx) = flume::unbounded();
+    // sending fails once as many uploads as upload threads can be started are waiting
+    let (uploads_tx, uploads_rx) = flume::bounded(sites.upload_threads.max);
+
+    let (requests_tx, requests_rx) = flume::unbounded();
 
-    let mut guards = Vec::with_capacity(sites.total_threads());
+    let mut guards = Vec::with_capacity(sites.total_threads() + servers.len());
 
-    for tid in 0..sites.request_threads {
-        let (runs_tx, server, sites) = (runs_tx.clone(), server.clone(), sites.clone());
-        let thread = thread::spawn(move || request_waiter(server, runs_tx, sites, tid));
-        guards.push(thread);
+    for (i, server) in servers.into_iter().map(Arc::new).enumerate() {
+        let requests_tx = requests_tx.clone();
+        let worker = move || request_acceptor(server.clone(), requests_tx.clone());
+        guards.push(supervise(format!("accept-{}", i), worker));
     }
 
-    for tid in 0..sites.script_threads {
-        let tid = sites.request_threads + tid;
+    let (request_max, script_max) = (sites.request_threads.max, sites.script_threads.max);
+
+    let request_pool = {
+        let (runs_tx, uploads_tx) = (runs_tx.clone(), uploads_tx.clone());
+        let (requests_rx, sites) = (requests_rx.clone(), sites.clone());
+        WorkerPool {
+            count: sites.request_threads,
+            started: 0,
+            backlogged: false,
+            backlog: Box::new({
+                let requests_rx = requests_rx.clone();
+                move || requests_rx.len()
+            }),
+            spawn: Box::new(move |tid| {
+                let (runs_tx, uploads_tx) = (runs_tx.clone(), uploads_tx.clone());
+                let (requests_rx, requests_tx, sites) = (requests_rx.clone(), requests_tx.clone(), sites.clone());
+                let worker = move || request_waiter(requests_rx.clone(), requests_tx.clone(), runs_tx.clone(), uploads_tx.clone(), sites.clone(), tid);
+                supervise(format!("request-{}", tid), worker)
+            }),
+        }
+    };
+
+    let script_pool = {
         let (runs_rx, renders_tx) = (runs_rx.clone(), renders_tx.clone());
-        let thread = thread::spawn(move || script_runner(runs_rx, renders_tx, tid));
-        guards.push(thread);
-    }
+        WorkerPool {
+            count: sites.script_threads,
+            started: 0,
+            backlogged: false,
+            backlog: Box::new({
+                let runs_rx = runs_rx.clone();
+                move || runs_rx.len()
+            }),
+            spawn: Box::new(move |i| {
+                let (runs_rx, renders_tx) = (runs_rx.clone(), renders_tx.clone());
+                // the first script thread never runs batch executions, so that they can't starve interactive ones
+                let serve_batch = i > 0 || script_max == 1;
+                let tid = request_max + i;
+                let worker = move || script_runner(runs_rx.clone(), renders_tx.clone(), serve_batch, tid);
+                supervise(format!("script-{}", tid), worker)
+            }),
+        }
+    };
 
-    for tid in 0..sites.render_threads {
-        let tid = sites.request_threads + sites.script_threads + tid;
+    let render_pool = {
         let renders_rx = renders_rx.clone();
-        let thread = thread::spawn(move || renderer(renders_rx, tid));
-        guards.push(thread);
+        WorkerPool {
+            count: sites.render_threads,
+            started: 0,
+            backlogged: false,
+            backlog: Box::new({
+                let renders_rx = renders_rx.clone();
+                move || renders_rx.len()
+            }),
+            spawn: Box::new(move |i| {
+                let tid = request_max + script_max + i;
+                let renders_rx = renders_rx.clone();
+                let worker = move || renderer(renders_rx.clone(), tid);
+                supervise(format!("render-{}", tid), worker)
+            }),
+        }
+    };
+
+    let upload_pool = {
+        let uploads_rx = uploads_rx.clone();
+        WorkerPool {
+            count: sites.upload_threads,
+            started: 0,
+            backlogged: false,
+            backlog: Box::new({
+                let uploads_rx = uploads_rx.clone();
+                move || uploads_rx.len()
+            }),
+            spawn: Box::new(move |i| {
+                let uploads_rx = uploads_rx.clone();
+                let worker = move || upload_worker(uploads_rx.clone());
+                supervise(format!("upload-{}", i), worker)
+            }),
+        }
+    };
+
+    let mut pools = [request_pool, script_pool, render_pool, upload_pool];
+    for pool in &mut pools {
+        while pool.started < pool.count.min {
+            guards.push(pool.grow());
+        }
+    }
+
+    if pools.iter().any(|pool| pool.count.max > pool.count.min) {
+        let builder = thread::Builder::new().name("thread-growth".into());
+        guards.push(builder.spawn(move || loop {
+            thread::sleep(GROWTH_INTERVAL);
+            for pool in &mut pools {
+                // work must have waited at two checks in a row
+                let backlogged = (pool.backlog)() > 0;
+                if backlogged && pool.backlogged && pool.started < pool.count.max {
+                    pool.grow();
+                }
+
+                pool.backlogged = backlogged;
+            }
+        }).unwrap());
+    }
+
+    {
+        let tid = request_max + script_max + sites.render_threads.max;
+        let (runs_tx, sites) = (runs_tx.clone(), sites.clone());
+        let worker = move || scheduler(runs_tx.clone(), sites.clone(), tid);
+        guards.push(supervise("scheduler".into(), worker));
+    }
+
+    {
+        let tid = request_max + script_max + sites.render_threads.max + 1;
+        let (runs_tx, sites) = (runs_tx.clone(), sites.clone());
+        let worker = move || dispatcher(runs_tx.clone(), sites.clone(), tid);
+        guards.push(supervise("bus".into(), worker));
+    }
+
+    systemd::notify("READY=1");
+    if let Some(interval) = systemd::watchdog_interval() {
+        let builder = thread::Builder::new().name("watchdog".into());
+        guards.push(builder.spawn(move || loop {
+            thread::sleep(interval / 2);
+            systemd::notify("WATCHDOG=1");
+        }).unwrap());
     }
 
     for guard in guards {
         let _ = guard.join();
     }
 }
+
+/// How often queues are checked, to start more threads if work waits in them
+const GROWTH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Threads of a kind, started on demand
+struct WorkerPool {
+    count: ThreadCount,
+    started: usize,
+    /// Whether work waited in the queue at the last check
+    backlogged: bool,
+    /// Number of waiting tasks
+    backlog: Box<dyn Fn() -> usize + Send>,
+    /// Starts the thread of this index
+    spawn: Box<dyn Fn(usize) -> JoinHandle<()> + Send>,
+}
+
+impl WorkerPool {
+    fn grow(&mut self) -> JoinHandle<()> {
+        self.started += 1;
+        (self.spawn)(self.started - 1)
+    }
+}
+
+/// Spawns a worker thread which restarts its loop if it panics,
+/// so that a single faulty request doesn't cost a thread forever
+fn supervise<F: Fn() + Send + 'static>(name: String, worker: F) -> JoinHandle<()> {
+    let builder = thread::Builder::new().name(name.clone());
+    let thread = builder.spawn(move || loop {
+        let panic = match catch_unwind(AssertUnwindSafe(&worker)) {
+            Ok(()) => break,
+            Err(panic) => panic,
+        };
+
+        let message = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+            (Some(message), _) => message,
+            (_, Some(message)) => message.as_str(),
+            _ => "<unknown>",
+        };
+
+        log::error!("Thread {} panicked ({}); restarting it", name, message);
+    });
+
+    thread.unwrap()
+}
@@ -0,0 +1,44 @@
+//! Site & request handled by the current thread, for log records
+//!
+//! Worker threads enter a context for each request they handle; loggers read
+//! it with [`current`] to tag records with the site & request ID.
+
+use std::cell::RefCell;
+
+#[derive(Clone, Default)]
+struct Context {
+    site: Option<String>,
+    request_id: String,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Restores the previous context of the thread when dropped
+pub struct Guard(Option<Context>);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CONTEXT.with(|context| *context.borrow_mut() = previous);
+    }
+}
+
+/// Records logged by this thread are tagged with `site` & `request_id`, until the guard is dropped
+pub fn enter(site: Option<&str>, request_id: &str) -> Guard {
+    let new = Context {
+        site: site.map(str::to_string),
+        request_id: request_id.to_string(),
+    };
+
+    Guard(CONTEXT.with(|context| context.borrow_mut().replace(new)))
+}
+
+/// Calls `f` with the (site, request ID) of the current thread
+pub fn current<R>(f: impl FnOnce(Option<&str>, Option<&str>) -> R) -> R {
+    CONTEXT.with(|context| match &*context.borrow() {
+        Some(Context { site, request_id }) => f(site.as_deref(), Some(request_id)),
+        None => f(None, None),
+    })
+}
@@ -0,0 +1,147 @@
+use super::{
+    Endpoint, EndpointMap, ScriptEndpoint, BodyMode, RequestInfo, ScriptResult, OpaqueJsonPointer,
+    Routing, StaticAssets, ScriptHost, UploadSink, TemplateRenderer, SiteDatabase,
+};
+use lmfu::{strpool::{Pool, PoolStr}, HashMap};
+
+/// What native handlers know about a request
+pub struct NativeRequest {
+    /// Path items matched by `[param]` route items
+    pub path_vars: Vec<String>,
+    /// Method, URL, raw body...
+    pub info: RequestInfo,
+}
+
+/// Body of a successful (200) response
+pub struct NativeResponse {
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl NativeResponse {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::bytes(super::renderer::PLAIN_TEXT, text.into().into_bytes())
+    }
+
+    pub fn json(json: impl Into<String>) -> Self {
+        Self::bytes(super::renderer::JSON, json.into().into_bytes())
+    }
+
+    pub fn bytes(content_type: &'static str, body: Vec<u8>) -> Self {
+        Self { content_type, body }
+    }
+}
+
+pub type NativeHandler = Box<dyn Fn(NativeRequest) -> NativeResponse + Send + Sync>;
+
+/// A site whose routes are Rust closures, for embedders which
+/// don't need a wasm bundle (health checks, internal tools...)
+///
+/// Handlers run on script threads, with the raw request body.
+pub struct NativeSite {
+    pool: Pool,
+    hostname: String,
+    routes: Endpoint,
+    /// route path => handler
+    handlers: HashMap<str, NativeHandler>,
+}
+
+impl NativeSite {
+    pub fn new(hostname: &str) -> Self {
+        Self {
+            pool: Pool::new(),
+            hostname: hostname.into(),
+            routes: empty_dir(),
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Adds a route; `[param]` path items match any item, like in wasm sites
+    ///
+    /// Example: `site.route("/users/[param]", |request| ...)`
+    pub fn route<F>(mut self, path: &str, handler: F) -> Self
+    where
+        F: Fn(NativeRequest) -> NativeResponse + Send + Sync + 'static,
+    {
+        let mut map = match &mut self.routes {
+            Endpoint::Dir(map) => map,
+            _ => unreachable!(/* see new() */),
+        };
+
+        for step in path.split('/').filter(|s| !s.is_empty()) {
+            let next = match step {
+                "[param]" => &mut **map.wildcard.get_or_insert_with(|| Box::new(empty_dir())),
+                step => {
+                    if !map.items.contains_key(step) {
+                        map.items.insert_ref(step, empty_dir());
+                    }
+
+                    map.items.get_mut(step).unwrap()
+                },
+            };
+
+            map = match next {
+                Endpoint::Dir(map) => map,
+                _ => unreachable!(/* only directories are inserted */),
+            };
+        }
+
+        let name = self.pool.intern(path);
+        let endpoint = Endpoint::ScriptExec(ScriptEndpoint { body_mode: BodyMode::Bytes, ..ScriptEndpoint::new(true, name) });
+        map.default = Some(Box::new(endpoint));
+
+        self.handlers.insert_ref(path, Box::new(handler));
+        self
+    }
+}
+
+fn empty_dir() -> Endpoint {
+    Endpoint::Dir(EndpointMap {
+        default: None,
+        wildcard: None,
+        items: HashMap::new(),
+        ip_filter: None,
+        security_headers: None,
+    })
+}
+
+impl Routing for NativeSite {
+    fn hostname(&self) -> &str { &self.hostname }
+    fn routes(&self) -> &Endpoint { &self.routes }
+}
+
+impl ScriptHost for NativeSite {
+    // bodies are passed raw (see BodyMode::Bytes), this is just "null"
+    fn parse_json(&self, _json: &str, _script_thread_id: usize) -> Result<OpaqueJsonPointer, ()> {
+        Ok(0)
+    }
+
+    fn process_script(
+        &self,
+        script: PoolStr,
+        _read_only: bool,
+        path_vars: &[String],
+        info: &RequestInfo,
+        _body: OpaqueJsonPointer,
+        _script_thread_id: usize,
+    ) -> Result<ScriptResult, ()> {
+        let fail = || log::error!("Missing native handler: {}", script);
+        let handler = self.handlers.get(&script).ok_or_else(fail)?;
+
+        let request = NativeRequest {
+            path_vars: path_vars.to_vec(),
+            info: info.clone(),
+        };
+
+        let response = handler(request);
+        Ok(ScriptResult::Bytes {
+            content_type: response.content_type,
+            bytes: response.body,
+        })
+    }
+}
+
+impl StaticAssets for NativeSite {}
+impl UploadSink for NativeSite {}
+impl TemplateRenderer for NativeSite {}
+impl SiteDatabase for NativeSite {}
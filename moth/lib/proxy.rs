@@ -0,0 +1,111 @@
+use std::net::IpAddr;
+use tiny_http::Request;
+
+/// An IP address or a CIDR range; examples: `127.0.0.1`, `10.0.0.0/8`, `fd00::/8`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn parse(range: &str) -> Result<Self, ()> {
+        let (addr, prefix_len) = match range.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (range, None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|_| ())?;
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_len = match prefix_len.map(str::parse) {
+            Some(Ok(len)) if len <= max_len => len,
+            Some(_) => return Err(()),
+            None => max_len,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (range, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => (u32::from(range) as u128, u32::from(ip) as u128, 32),
+            (IpAddr::V6(range), IpAddr::V6(ip)) => (u128::from(range), u128::from(ip), 128),
+            _ => return false,
+        };
+
+        let shift = bits - self.prefix_len as u32;
+        range.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+/// Which clients may access a site or a route subtree
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct IpFilter {
+    /// If not empty, other clients are refused
+    pub allow: Vec<IpRange>,
+    /// Refused even if they're in `allow`
+    pub deny: Vec<IpRange>,
+}
+
+impl IpFilter {
+    pub fn accepts(&self, ip: IpAddr) -> bool {
+        let matches = |ranges: &[IpRange]| ranges.iter().any(|range| range.contains(ip));
+        (self.allow.is_empty() || matches(&self.allow)) && !matches(&self.deny)
+    }
+}
+
+/// Where a request really comes from
+pub(crate) struct Client {
+    pub ip: String,
+    /// `http` or `https`
+    pub scheme: String,
+}
+
+/// Uses `X-Forwarded-For` & `X-Forwarded-Proto` if the peer is a trusted proxy
+///
+/// The client is the last address of `X-Forwarded-For` which isn't a trusted
+/// proxy, as earlier ones can be forged by the client itself.
+pub(crate) fn client(request: &Request, trusted_proxies: &[IpRange]) -> Client {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|range| range.contains(ip));
+    let peer = request.remote_addr().map(|addr| addr.ip());
+
+    let mut client = Client {
+        ip: peer.map(|ip| ip.to_canonical().to_string()).unwrap_or_default(),
+        scheme: "http".into(),
+    };
+
+    if peer.map(is_trusted) != Some(true) {
+        return client;
+    }
+
+    let mut forwarded_for = Vec::new();
+    for header in request.headers() {
+        if header.field.equiv("X-Forwarded-For") {
+            forwarded_for.extend(header.value.as_str().split(',').map(str::trim));
+        } else if header.field.equiv("X-Forwarded-Proto") {
+            let scheme = header.value.as_str().split(',').next().map(str::trim);
+            if let Some(scheme @ ("http" | "https")) = scheme {
+                client.scheme = scheme.into();
+            }
+        }
+    }
+
+    for address in forwarded_for.into_iter().rev() {
+        let ip = match address.parse::<IpAddr>() {
+            Ok(ip) => ip.to_canonical(),
+            // garbage: nothing before it can be trusted
+            Err(_) => break,
+        };
+
+        client.ip = ip.to_string();
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+
+    client
+}
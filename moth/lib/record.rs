@@ -0,0 +1,160 @@
+//! Recording of requests & responses, to reproduce bugs with `moth --replay`
+//!
+//! Each request produces two files in the recording directory:
+//! `<request id>.request.json` and `<request id>.response.json`.
+//! Bodies are base64-encoded; upload bodies aren't recorded.
+
+use super::request::{encode_base64, decode_base64};
+use super::push_json_str;
+use lmfu::json::{JsonFile, Path as JsonPath};
+use tiny_http::{Request, Header};
+use std::{path::{Path, PathBuf}, sync::OnceLock, fs};
+
+static DIRECTORY: OnceLock<PathBuf> = OnceLock::new();
+
+pub(crate) fn enable(directory: PathBuf) {
+    if let Err(error) = fs::create_dir_all(&directory) {
+        return log::error!("Couldn't create recording directory {}: {}", directory.display(), error);
+    }
+
+    log::warn!("Recording all requests & responses in {}", directory.display());
+    let _ = DIRECTORY.set(directory);
+}
+
+pub(crate) fn enabled() -> bool {
+    DIRECTORY.get().is_some()
+}
+
+/// A recorded request
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedRequest {
+    pub id: String,
+    /// Main hostname of the site which served the request; empty for unknown hosts
+    pub site: String,
+    pub method: String,
+    pub url: String,
+    pub body: Vec<u8>,
+}
+
+/// A recorded response
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// Saves a request; called again for the same request once its body is read
+pub(crate) fn request(request: &Request, request_id: &str, site: &str, body: &[u8]) {
+    let mut json = String::from("{\"id\":");
+    push_json_str(&mut json, request_id);
+    json += ",\"site\":";
+    push_json_str(&mut json, site);
+    json += ",\"method\":";
+    push_json_str(&mut json, request.method().as_str());
+    json += ",\"url\":";
+    push_json_str(&mut json, request.url());
+    json += ",\"headers\":";
+    push_headers(&mut json, request.headers());
+    json += ",\"body\":";
+    push_json_str(&mut json, &encode_base64(body));
+    json.push('}');
+
+    save(request_id, "request", json);
+}
+
+pub(crate) fn response(request_id: &str, status: u16, headers: &[Header], body: &[u8]) {
+    let mut json = format!("{{\"status\":{},\"headers\":", status);
+    push_headers(&mut json, headers);
+    json += ",\"body\":";
+    push_json_str(&mut json, &encode_base64(body));
+    json.push('}');
+
+    save(request_id, "response", json);
+}
+
+fn push_headers(json: &mut String, headers: &[Header]) {
+    json.push('{');
+    for (i, header) in headers.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+
+        push_json_str(json, header.field.as_str().as_str());
+        json.push(':');
+        push_json_str(json, header.value.as_str());
+    }
+    json.push('}');
+}
+
+fn save(request_id: &str, kind: &str, json: String) {
+    let Some(directory) = DIRECTORY.get() else { return };
+    // IDs are made of hexadecimal digits & a dash
+    let path = directory.join(format!("{}.{}.json", request_id, kind));
+    if let Err(error) = fs::write(&path, json) {
+        log::error!("[{}] Couldn't record the {}: {}", request_id, kind, error);
+    }
+}
+
+/// Reads the recordings of a directory, in the order of their requests
+///
+/// Responses are missing for requests which were still being processed.
+pub fn load(directory: &Path) -> Result<Vec<(RecordedRequest, Option<RecordedResponse>)>, ()> {
+    let fail = |error| log::error!("Couldn't read {}: {}", directory.display(), error);
+    let mut records = Vec::new();
+
+    for entry in fs::read_dir(directory).map_err(fail)? {
+        let path = entry.map_err(fail)?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let Some(id) = name.strip_suffix(".request.json") else { continue };
+
+        let request = read_json(&path)?;
+        let get_str = |prop| request.get(&JsonPath::new().i_str(prop)).as_string().map(|s| s.to_string());
+        let get_body = |file: &JsonFile| file.get(&JsonPath::new().i_str("body")).as_string().and_then(|b| decode_base64(b));
+        let invalid = || log::error!("Invalid recording: {}", path.display());
+
+        let request = RecordedRequest {
+            id: id.to_string(),
+            site: get_str("site").ok_or_else(invalid)?,
+            method: get_str("method").ok_or_else(invalid)?,
+            url: get_str("url").ok_or_else(invalid)?,
+            body: get_body(&request).ok_or_else(invalid)?,
+        };
+
+        let path = directory.join(format!("{}.response.json", id));
+        let response = match path.exists() {
+            true => Some(read_json(&path)?),
+            false => None,
+        };
+
+        let response = match response {
+            Some(response) => {
+                let invalid = || log::error!("Invalid recording: {}", path.display());
+                let content_type = JsonPath::new().i_str("headers").i_str("Content-Type");
+                Some(RecordedResponse {
+                    status: response.get(&JsonPath::new().i_str("status")).as_num().ok_or_else(invalid)? as u16,
+                    content_type: response.get(&content_type).as_string().map(|s| s.to_string()),
+                    body: get_body(&response).ok_or_else(invalid)?,
+                })
+            },
+            None => None,
+        };
+
+        records.push((request, response));
+    }
+
+    records.sort_by_key(|(request, _)| sort_key(&request.id));
+    Ok(records)
+}
+
+fn read_json(path: &Path) -> Result<JsonFile, ()> {
+    let text = fs::read_to_string(path).map_err(|e| log::error!("Couldn't read {}: {}", path.display(), e))?;
+    JsonFile::new(Some(&text)).map_err(|e| log::error!("Couldn't parse {}: {}", path.display(), e))
+}
+
+/// (process start time, counter), see `new_request_id`
+fn sort_key(request_id: &str) -> (u64, u64) {
+    let (start, counter) = request_id.split_once('-').unwrap_or(("", request_id));
+    let parse = |hex| u64::from_str_radix(hex, 16).unwrap_or(0);
+    (parse(start), parse(counter))
+}
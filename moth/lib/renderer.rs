@@ -1,8 +1,14 @@
-use super::{PoolStr, OpaqueJsonPointer, Arc, Site};
-use tiny_http::{Request, Response};
+use super::{PoolStr, OpaqueJsonPointer, Arc, Site, Endpoint, ScriptEndpoint, RequestInfo, request::{response_headers, respond_error, respond, header, resolve_route}, log_context, coalesce, trace};
+use tiny_http::Request;
+use sha2::{Sha256, Digest};
 use flume::Receiver;
 use lmfu::LiteMap;
 
+pub const JSON: &str = "application/json";
+pub const HTML: &str = "text/html; charset=utf-8";
+pub const PLAIN_TEXT: &str = "text/plain; charset=utf-8";
+pub const XML: &str = "application/xml; charset=utf-8";
+
 pub enum RendererCommand {
     Template {
         site: Arc<dyn Site>,
@@ -13,35 +19,214 @@ pub enum RendererCommand {
         site: Arc<dyn Site>,
         json_body: OpaqueJsonPointer,
     },
+    /// Either of the above, depending on the `Accept` header of the request
+    Negotiated {
+        site: Arc<dyn Site>,
+        template: PoolStr,
+        parameters: LiteMap<PoolStr, String>,
+        json_body: OpaqueJsonPointer,
+    },
+    Text {
+        site: Arc<dyn Site>,
+        content_type: &'static str,
+        text: String,
+    },
+    Bytes {
+        site: Arc<dyn Site>,
+        content_type: &'static str,
+        bytes: Vec<u8>,
+    },
 }
 
-pub fn renderer(
-    renders_rx: Receiver<(Request, RendererCommand)>,
-    tid: usize,
-) {
-    for (request, command) in renders_rx.into_iter() {
-        let result = match command {
+/// Content type of a rendered template, based on its extension
+pub fn template_content_type(template: &str) -> &'static str {
+    match template.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("html" | "htm") => HTML,
+        Some("xml") => XML,
+        _ => PLAIN_TEXT,
+    }
+}
+
+/// Makes text safe to insert in HTML elements & quoted attributes
+///
+/// Template parameters are inserted as is, so values which come from
+/// clients must go through this first.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Strong validator of a response body, for routes with `"etag": true`
+pub fn etag(body: &[u8]) -> String {
+    let digest = Sha256::digest(body);
+    let hex: String = digest[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("\"{}\"", hex)
+}
+
+/// Whether an `If-None-Match` header value matches `etag`
+///
+/// The comparison is weak, as required for this header: `W/"x"` matches `"x"`.
+pub fn none_match(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Whether an `Accept` header asks for JSON rather than HTML
+///
+/// Each type gets the quality of the most specific range matching it
+/// (`text/html`, then `text/*`, then `*/*`). Ties go to HTML, so browsers &
+/// requests without an `Accept` header get the template.
+pub fn prefers_json(accept: &str) -> bool {
+    let quality = |media_type: &str| {
+        let wildcard = format!("{}/*", media_type.split('/').next().unwrap());
+        let mut best = (0, 0.0);
+
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let media_range = params.next().unwrap_or("");
+            let specificity = match media_range {
+                r if r.eq_ignore_ascii_case(media_type) => 3,
+                r if r.eq_ignore_ascii_case(&wildcard) => 2,
+                "*/*" => 1,
+                _ => continue,
+            };
+
+            let q = params.find_map(|param| param.strip_prefix("q=")).and_then(|q| q.parse().ok());
+            if specificity > best.0 {
+                best = (specificity, q.unwrap_or(1.0));
+            }
+        }
+
+        best.1
+    };
+
+    quality("application/json") > quality("text/html")
+}
+
+/// ETag of a response, if its route has `"etag": true` & the request can be answered with a 304
+fn conditional_etag(site: &Arc<dyn Site>, info: &RequestInfo, body: &[u8]) -> Option<String> {
+    let cacheable = matches!(info.method.as_str(), "GET" | "HEAD");
+    let route = resolve_route(site, &info.url);
+    let enabled = matches!(route.endpoint, Endpoint::ScriptExec(ScriptEndpoint { etag: true, .. }));
+    (cacheable && enabled).then(|| etag(body))
+}
+
+impl RendererCommand {
+    /// Returns (content type, body); `accept` is the `Accept` header of the request
+    pub(crate) fn render(self, accept: &str, tid: usize) -> Result<(&'static str, Vec<u8>), ()> {
+        match self {
             RendererCommand::Template {
                 site,
                 template,
                 parameters,
-            } => site.render_template(template, parameters),
+            } => {
+                let content_type = template_content_type(&template);
+                site.render_template(template, parameters).map(|text| (content_type, text.into_bytes()))
+            },
             RendererCommand::Json {
                 site,
                 json_body,
-            } => site.dump_json(json_body, tid),
+            } => site.dump_json(json_body, tid).map(|json| (JSON, json.into_bytes())),
+            RendererCommand::Negotiated {
+                site,
+                template,
+                parameters,
+                json_body,
+            } => match prefers_json(accept) {
+                true => site.dump_json(json_body, tid).map(|json| (JSON, json.into_bytes())),
+                false => {
+                    // the JSON must still be freed
+                    let _ = site.dump_json(json_body, tid);
+                    let content_type = template_content_type(&template);
+                    site.render_template(template, parameters).map(|text| (content_type, text.into_bytes()))
+                },
+            },
+            RendererCommand::Text {
+                site: _,
+                content_type,
+                text,
+            } => Ok((content_type, text.into_bytes())),
+            RendererCommand::Bytes {
+                site: _,
+                content_type,
+                bytes,
+            } => Ok((content_type, bytes)),
+        }
+    }
+}
+
+pub fn renderer(
+    renders_rx: Receiver<(Request, RequestInfo, RendererCommand)>,
+    tid: usize,
+) {
+    for (request, info, command) in renders_rx.into_iter() {
+        let request_id = &info.id;
+        let site = match &command {
+            RendererCommand::Template { site, .. } => site.clone(),
+            RendererCommand::Json { site, .. } => site.clone(),
+            RendererCommand::Negotiated { site, .. } => site.clone(),
+            RendererCommand::Text { site, .. } => site.clone(),
+            RendererCommand::Bytes { site, .. } => site.clone(),
         };
 
-        let respond = |reader, code: u32| {
-            let response = Response::new(code.into(), vec![], reader, None, None);
-            if let Err(error) = request.respond(response) {
-                log::error!("Couldn't respond: {:?}", error);
+        let _log_context = log_context::enter(Some(site.hostname()), request_id);
+        let _flight = coalesce::PanicGuard(request_id);
+
+        if info.expired() {
+            log::error!("[{}] Timed out in the render queue", request_id);
+            if let RendererCommand::Json { json_body, .. } | RendererCommand::Negotiated { json_body, .. } = command {
+                let _ = site.dump_json(json_body, tid);
             }
+
+            respond_error(Some(&site), request, request_id, Some(&info.url), 504);
+            continue;
+        }
+
+        let mut span = trace::stage(request_id, "render");
+        let negotiated = matches!(command, RendererCommand::Negotiated { .. });
+        let accept = request.headers().iter().find(|h| h.field.equiv("Accept"));
+        let result = command.render(accept.map(|h| h.value.as_str()).unwrap_or(""), tid);
+        if result.is_err() {
+            span.fail();
+        }
+        core::mem::drop(span);
+
+        let (content_type, body) = match result {
+            Ok(result) => result,
+            Err(()) => {
+                log::error!("[{}] Failed to render the response", request_id);
+                respond_error(Some(&site), request, request_id, Some(&info.url), 500);
+                continue;
+            },
         };
 
-        match result {
-            Ok(body) => respond(body.as_bytes(), 200),
-            Err(()) => respond(b"Renderer error".as_slice(), 500),
+        let mut headers = response_headers(Some(&site), &request, request_id, Some(&info.url));
+        if negotiated {
+            headers.push(header("Vary", "Accept"));
         }
+
+        if let Some(etag) = conditional_etag(&site, &info, &body) {
+            headers.push(header("ETag", &etag));
+
+            let if_none_match = request.headers().iter().find(|h| h.field.equiv("If-None-Match"));
+            if if_none_match.is_some_and(|h| none_match(h.value.as_str(), &etag)) {
+                respond(request, request_id, 304, headers, &[]);
+                continue;
+            }
+        }
+
+        headers.push(header("Content-Type", content_type));
+        respond(request, request_id, 200, headers, &body);
     }
 }
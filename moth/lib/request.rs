@@ -1,184 +1,844 @@
-use super::{Sites, Arc, Endpoint, Site, ScriptCommand};
-use flume::Sender;
-use tiny_http::{Server, Request, Response};
+use super::{Sites, Arc, PoolStr, OpaqueJsonPointer, Endpoint, ScriptEndpoint, Site, Surface, Auth, Methods, ScriptResult, accepts_method, HeaderOverrides, DEFAULT_SECURITY_HEADERS, DEFAULT_TIMEOUT, ACME_CHALLENGE_PREFIX, ScriptCommand, ScriptSender, Priority, script::GuardCheck, Preview, Fallback, BodyMode, StaticBody, upload::Upload, proxy::{client, IpFilter}, record, csrf, coalesce::{self, Waiter}, response_cache, renderer, schema, log_context, trace};
+use flume::{Sender, Receiver};
+use tiny_http::{Server, Request, Response, Header, StatusCode};
+use core::str::from_utf8;
+use std::{sync::{OnceLock, atomic::{AtomicU64, Ordering}}, time::{SystemTime, UNIX_EPOCH, Instant, Duration}};
+use std::{fs::File, io::{Read, Seek}};
+
+/// What scripts can know about the request which triggered them
+#[derive(Debug, Clone, Default)]
+pub struct RequestInfo {
+    /// Unique ID of the request (or scheduled job execution), found in log lines
+    pub id: String,
+    /// HTTP method, in uppercase; empty for scheduled jobs
+    pub method: String,
+    /// Full original URL, including the query string
+    pub url: String,
+    /// Route which matched the URL; example: `/users/[param]/posts`
+    pub route: String,
+    /// Part of the URL which wasn't matched by the route, including the query string
+    pub remainder: String,
+    /// Part of the host matched by a wildcard hostname; example: `blog` for
+    /// `blog.example.com` with `*.example.com`. Empty for other hostnames.
+    pub subdomain: String,
+    /// IP address of the client, as seen by the first trusted proxy (if any)
+    pub client_ip: String,
+    /// `http` or `https`, as seen by the first trusted proxy (if any)
+    pub scheme: String,
+    /// Raw body, for routes with a `text` or `bytes` body mode
+    pub body: Arc<[u8]>,
+    /// CSRF token of the client's session; empty if the site has no CSRF secret
+    pub csrf_token: String,
+    /// Raw `Accept-Language` header; empty if absent
+    pub accept_language: String,
+    /// (name, value) of all headers; empty for scheduled jobs
+    pub headers: Vec<(String, String)>,
+    /// When the client is assumed to have given up, from the route's timeout;
+    /// None for scheduled jobs
+    pub deadline: Option<Instant>,
+}
+
+impl RequestInfo {
+    pub fn expired(&self) -> bool {
+        expired(self.deadline)
+    }
+
+    /// Value of the first header named `name`, case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let header = self.headers.iter().find(|(field, _)| field.eq_ignore_ascii_case(name));
+        header.map(|(_, value)| value.as_str())
+    }
+}
+
+pub(crate) fn expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() > deadline)
+}
+
+/// Process start time & a counter, both in hexadecimal
+pub(crate) fn new_request_id() -> String {
+    static START: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let start = START.get_or_init(|| match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_secs(),
+        Err(_) => 0,
+    });
+
+    format!("{:x}-{:x}", start, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A request waiting for a request thread
+pub struct Incoming {
+    pub request: Request,
+    /// Port of the listener which received it
+    pub port: Option<u16>,
+    /// ID of the request, once the `Auth::Callback` guards of its route
+    /// accepted it on a script thread (see [`GuardCheck`])
+    pub authorized: Option<String>,
+}
+
+/// Forwards the requests of a listener to request threads, with its port
+pub fn request_acceptor(server: Arc<Server>, requests_tx: Sender<Incoming>) {
+    let port = server.server_addr().to_ip().map(|addr| addr.port());
+    loop {
+        match server.recv() {
+            Ok(request) => if requests_tx.send(Incoming { request, port, authorized: None }).is_err() {
+                return log::error!("No request thread for {}", server.server_addr());
+            },
+            Err(error) => log::error!("Error while parsing http request: {}", error),
+        }
+    }
+}
 
 pub fn request_waiter(
-    server: Arc<Server>,
-    runs_tx: Sender<ScriptCommand>,
+    requests_rx: Receiver<Incoming>,
+    requests_tx: Sender<Incoming>,
+    runs_tx: ScriptSender,
+    uploads_tx: Sender<Upload>,
     sites: Sites,
     tid: usize,
 ) {
-    loop {
-        let request = server.recv();
-        if let Ok(request) = request {
-            let mut site = None;
-
-            for header in request.headers() {
-                if header.field.equiv("Host") {
-                    let host = header.value.as_str().split(":").next().unwrap();
-                    site = sites.get(host);
-                    break;
-                }
+    for Incoming { request, port, authorized } in requests_rx.into_iter() {
+        let id = authorized.clone().unwrap_or_else(new_request_id);
+        let client = client(&request, sites.trusted_proxies());
+        let mut site = None;
+
+        for header in request.headers() {
+            if header.field.equiv("Host") {
+                let host = header.value.as_str().split(":").next().unwrap();
+                site = sites.resolve(host);
+                break;
             }
+        }
 
-            if let Some(site) = site {
-                let mut path_vars = Vec::new();
-                let mut path_override = None;
+        let token = request.url().strip_prefix(ACME_CHALLENGE_PREFIX);
+        let challenge = site.as_ref().zip(token).and_then(|((s, _), token)| sites.acme_challenge(s.hostname(), token));
+        if let Some(key_authorization) = challenge {
+            let headers = vec![header("Content-Type", "application/octet-stream")];
+            respond(request, &id, 200, headers, key_authorization.as_bytes());
+            continue;
+        }
 
-                let mut endpoint = site.routes();
-                let path_iter = request.url().split('/').filter(|s| !s.is_empty());
-                for step in path_iter {
-                    if let Endpoint::Upload = endpoint {
-                        path_vars.push(step.into());
-                        continue;
-                    }
+        if site.is_none() {
+            match sites.fallback() {
+                Some(Fallback::Site(hostname)) => site = sites.get(hostname).map(|s| (s, String::new())),
+                Some(Fallback::Redirect(base)) => {
+                    redirect(request, base, &id);
+                    continue;
+                },
+                None => (),
+            }
+        }
 
-                    if let Endpoint::Dir(map) = endpoint {
-                        if let Some(next) = map.items.get(step) {
-                            endpoint = next;
-                            continue;
-                        }
-
-                        if let Some(Endpoint::Static(_)) = map.default.as_deref() {
-                            // fallback to empty
-                            endpoint = map.default.as_ref().unwrap();
-                            // must not continue/break so that the step path land in path_override
-                        } else if let Some(next) = map.wildcard.as_deref() {
-                            path_vars.push(step.into());
-                            endpoint = &next;
-                            continue;
-                        }
-                    }
+        let _log_context = log_context::enter(site.as_ref().map(|(s, _)| s.hostname()), &id);
 
-                    if let Endpoint::Static(path) = endpoint {
-                        let path = path_override.get_or_insert_with(|| path.to_string());
-                        path.push_str("/");
-                        path.push_str(step);
+        if trace::enabled() && authorized.is_none() {
+            let traceparent = request.headers().iter().find(|h| h.field.equiv("traceparent"));
+            let attributes = vec![
+                ("http.request.method", request.method().as_str().into()),
+                ("url.full", request.url().into()),
+                ("server.address", site.as_ref().map(|(s, _)| s.hostname()).unwrap_or("").into()),
+                ("client.address", client.ip.as_str().into()),
+            ];
 
-                        continue;
-                    }
+            trace::begin(&id, traceparent.map(|h| h.value.as_str()), attributes);
+        }
 
-                    endpoint = site.on_404();
-                    break;
-                }
+        if record::enabled() && authorized.is_none() {
+            // bodies are recorded once read, by process_endpoint
+            let hostname = site.as_ref().map(|(s, _)| s.hostname()).unwrap_or("");
+            record::request(&request, &id, hostname, b"");
+        }
 
-                while let Endpoint::Dir(map) = endpoint {
-                    if let Some(next) = map.default.as_deref() {
-                        endpoint = &next;
-                        continue;
-                    }
+        if let Some(preview) = site.as_ref().and_then(|(s, _)| s.preview()) {
+            if !is_authorized(preview, &request) {
+                log::info!("[{}] Unauthorized preview request from {}", id, client.ip);
+                deny_preview(site.as_ref().map(|(s, _)| s), request, &id);
+                continue;
+            }
+        }
+
+        if let Some((site, subdomain)) = site {
+            let site_port = port.filter(|port| sites.site_ports().contains(port));
+            let Some(url) = surface_url(&site, site_port, request.url()) else {
+                log::info!("[{}] {} isn't served on port {}", id, request.url(), port.unwrap_or(0));
+                respond_error(Some(&site), request, &id, None, 404);
+                continue;
+            };
+
+            let Route { mut endpoint, path_vars, path_override, route, remainder, ip_filters, guards, .. } = resolve_route(&site, &url);
+
+            if !ip_allowed(&ip_filters, &client.ip) {
+                log::info!("[{}] Refused request from {} to {}", id, client.ip, route);
+                endpoint = &FORBIDDEN;
+            }
+
+            let csrf_secret = site.csrf_secret();
+            let session = csrf_secret.map(|secret| csrf::session(secret, &request, &id).0);
+            let csrf_token = csrf_secret.zip(session).map(|(s, session)| s.token(&session));
 
-                    endpoint = site.on_404();
-                    break;
+            let info = RequestInfo {
+                id,
+                method: request.method().to_string(),
+                url,
+                route,
+                remainder,
+                subdomain,
+                client_ip: client.ip,
+                scheme: client.scheme,
+                // read in process_endpoint, if needed
+                body: Default::default(),
+                csrf_token: csrf_token.unwrap_or_default(),
+                accept_language: accept_language(&request).into(),
+                headers: request.headers().iter().map(|h| (h.field.to_string(), h.value.to_string())).collect(),
+                // set in process_endpoint, for script routes
+                deadline: None,
+            };
+
+            // callbacks run on script threads, see GuardCheck
+            let (callbacks, guards): (Vec<_>, Vec<_>) = match authorized {
+                Some(_) => Default::default(),
+                None => guards.into_iter().partition(|auth| matches!(auth, Auth::Callback(_))),
+            };
+
+            if let Some(auth) = failed_guard(&site, &guards, authorization(&request), &info, tid) {
+                log::info!("[{}] Unauthorized request from {} to {}", info.id, info.client_ip, info.route);
+                unauthorized(Some(&site), request, &info.id, Some(&info.url), auth.challenge());
+                continue;
+            }
+
+            if !callbacks.is_empty() {
+                let timeout = match endpoint {
+                    Endpoint::ScriptExec(script) => script.timeout,
+                    _ => DEFAULT_TIMEOUT,
+                };
+
+                let callbacks = callbacks.iter().filter_map(|auth| match auth {
+                    Auth::Callback(callback) => Some(callback.clone()),
+                    _ => None,
+                });
+
+                check_guards(&site, callbacks.collect(), info, request, port, timeout, &requests_tx, &runs_tx, tid);
+                continue;
+            }
+
+            if let Endpoint::ScriptExec(ScriptEndpoint { csrf_protected: true, .. }) = endpoint {
+                if info.csrf_token.is_empty() || !csrf::has_token(&request, &info.csrf_token) {
+                    log::info!("[{}] Missing or invalid CSRF token from {}", info.id, info.client_ip);
+                    endpoint = &FORBIDDEN;
                 }
+            }
+            process_endpoint(Some(&site), path_vars, path_override, info, request, endpoint, &runs_tx, &uploads_tx, tid);
+        } else {
+            log::error!("[{}] Unknown host in request header from {}", id, client.ip);
+            let info = RequestInfo { id, client_ip: client.ip, scheme: client.scheme, ..Default::default() };
+            process_endpoint(None, Vec::new(), None, info, request, &Endpoint::Error(502.into()), &runs_tx, &uploads_tx, tid);
+        }
+    }
+}
 
-                process_endpoint(Some(&site), path_vars, path_override, request, endpoint, &runs_tx, tid);
-            } else {
-                log::error!("Unknown host in request header");
-                process_endpoint(None, Vec::new(), None, request, &Endpoint::Error(502.into()), &runs_tx, tid);
+pub(crate) static FORBIDDEN: Endpoint = Endpoint::Error(StatusCode(403));
+
+/// URL to route for a request received on `site_port` (if it's one); `None`
+/// if the site doesn't serve it there (see [`Surface`])
+pub(crate) fn surface_url(site: &Arc<dyn Site>, site_port: Option<u16>, url: &str) -> Option<String> {
+    let prefix = |surface: &Surface| surface.prefix.trim_end_matches('/').to_string();
+    if let Some(port) = site_port {
+        let surface = site.surfaces().iter().find(|surface| surface.port == port)?;
+        return Some(prefix(surface) + url);
+    }
+
+    let path = url.split(['?', '#']).next().unwrap();
+    let hidden = site.surfaces().iter().map(prefix).any(|prefix| {
+        !prefix.is_empty() && path.strip_prefix(&prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    });
+
+    (!hidden).then(|| url.to_string())
+}
+
+/// Where a URL leads in the routes of a site
+pub(crate) struct Route<'a> {
+    pub endpoint: &'a Endpoint,
+    pub path_vars: Vec<String>,
+    /// Asset path, for static directories
+    pub path_override: Option<String>,
+    pub route: String,
+    pub remainder: String,
+    /// Filters of the site & of the traversed subtrees
+    pub ip_filters: Vec<&'a IpFilter>,
+    /// Checks of the traversed `Endpoint::Protected` subtrees
+    pub guards: Vec<&'a Auth>,
+    /// Security headers of the traversed subtrees, outermost first
+    pub security_headers: Vec<&'a HeaderOverrides>,
+}
+
+/// Skips `Endpoint::Protected` wrappers, collecting their checks
+fn unwrap_protected<'a>(mut endpoint: &'a Endpoint, guards: &mut Vec<&'a Auth>) -> &'a Endpoint {
+    while let Endpoint::Protected { auth, inner } = endpoint {
+        guards.push(auth);
+        endpoint = inner;
+    }
+
+    endpoint
+}
+
+pub(crate) fn resolve_route<'a>(site: &'a Arc<dyn Site>, url: &str) -> Route<'a> {
+    let mut path_vars = Vec::new();
+    let mut path_override = None;
+    let mut ip_filters: Vec<_> = site.ip_filter().into_iter().collect();
+    let mut guards = Vec::new();
+    let mut security_headers = Vec::new();
+
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url, None),
+    };
+
+    let mut route = String::new();
+    let mut remainder = String::new();
+
+    let mut endpoint = site.routes();
+    let path_iter = path.split('/').filter(|s| !s.is_empty());
+    for step in path_iter {
+        endpoint = unwrap_protected(endpoint, &mut guards);
+
+        if let Endpoint::Upload(_) = endpoint {
+            path_vars.push(step.into());
+            remainder.push('/');
+            remainder.push_str(step);
+            continue;
+        }
+
+        if let Endpoint::Dir(map) = endpoint {
+            ip_filters.extend(map.ip_filter.as_ref());
+            security_headers.extend(map.security_headers.as_ref());
+
+            if let Some(next) = map.items.get(step) {
+                route.push('/');
+                route.push_str(step);
+                endpoint = next;
+                continue;
             }
-        } else if let Err(error) = request {
-            log::error!("Error while parsing http request: {}", error);
+
+            if let Some(Endpoint::Static(_)) = map.default.as_deref() {
+                // fallback to empty
+                endpoint = map.default.as_ref().unwrap();
+                // must not continue/break so that the step path land in path_override
+            } else if let Some(next) = map.wildcard.as_deref() {
+                path_vars.push(step.into());
+                route.push_str("/[param]");
+                endpoint = next;
+                continue;
+            }
+        }
+
+        if let Endpoint::Static(path) = endpoint {
+            let path = path_override.get_or_insert_with(|| path.to_string());
+            path.push('/');
+            path.push_str(step);
+
+            remainder.push('/');
+            remainder.push_str(step);
+            continue;
+        }
+
+        endpoint = site.on_404();
+        break;
+    }
+
+    endpoint = unwrap_protected(endpoint, &mut guards);
+    while let Endpoint::Dir(map) = endpoint {
+        ip_filters.extend(map.ip_filter.as_ref());
+        security_headers.extend(map.security_headers.as_ref());
+
+        if let Some(next) = map.default.as_deref() {
+            endpoint = unwrap_protected(next, &mut guards);
+            continue;
         }
+
+        endpoint = site.on_404();
+        break;
+    }
+
+    if route.is_empty() {
+        route.push('/');
     }
+
+    if let Some(query) = query {
+        remainder.push('?');
+        remainder.push_str(query);
+    }
+
+    Route { endpoint, path_vars, path_override, route, remainder, ip_filters, guards, security_headers }
 }
 
+/// Whether all filters of a route accept a client; unknown addresses are refused
+pub(crate) fn ip_allowed(ip_filters: &[&IpFilter], client_ip: &str) -> bool {
+    match client_ip.parse() {
+        Ok(ip) => ip_filters.iter().all(|filter| filter.accepts(ip)),
+        Err(_) => ip_filters.is_empty(),
+    }
+}
+
+/// First guard of a route which rejects the `Authorization` header, if any
+///
+/// `Auth::Callback` guards run on the calling thread; request threads queue
+/// them with [`check_guards`] instead.
+pub(crate) fn failed_guard<'a>(
+    site: &Arc<dyn Site>,
+    guards: &[&'a Auth],
+    authorization: Option<&str>,
+    info: &RequestInfo,
+    tid: usize,
+) -> Option<&'a Auth> {
+    let accepts = |auth: &Auth| match (auth, authorization) {
+        (_, None) => false,
+        (Auth::Basic(expected) | Auth::Bearer(expected), Some(value)) => constant_time_eq(value, expected),
+        (Auth::Callback(callback), Some(value)) => {
+            let Ok(body) = site.parse_json("null", tid) else { return false };
+            callback_accepts(site, callback, &[value.into()], info, body, tid)
+        },
+    };
+
+    guards.iter().find(|auth| !accepts(auth)).copied()
+}
+
+/// Whether an `Auth::Callback` script returns `true`; its only path
+/// parameter is the `Authorization` header
+pub(crate) fn callback_accepts(
+    site: &Arc<dyn Site>,
+    callback: &PoolStr,
+    path_vars: &[String],
+    info: &RequestInfo,
+    body: OpaqueJsonPointer,
+    tid: usize,
+) -> bool {
+    match site.process_script(callback.clone(), true, path_vars, info, body, tid) {
+        Ok(ScriptResult::Json(json)) => site.dump_json(json, tid).is_ok_and(|json| json.trim() == "true"),
+        _ => false,
+    }
+}
+
+/// Queues the `Auth::Callback` guards of a request like other scripts; the
+/// request comes back to `requests_tx` once they accept it
+#[allow(clippy::too_many_arguments)]
+fn check_guards(
+    site: &Arc<dyn Site>,
+    mut callbacks: Vec<PoolStr>,
+    mut info: RequestInfo,
+    request: Request,
+    port: Option<u16>,
+    timeout: Duration,
+    requests_tx: &Sender<Incoming>,
+    runs_tx: &ScriptSender,
+    tid: usize,
+) {
+    let path_vars = match authorization(&request) {
+        Some(value) => vec![value.into()],
+        None => {
+            log::info!("[{}] Unauthorized request from {} to {}", info.id, info.client_ip, info.route);
+            return unauthorized(Some(site), request, &info.id, Some(&info.url), "Bearer");
+        },
+    };
+
+    let Ok(body) = site.parse_json("null", tid) else {
+        log::error!("[{}] Couldn't create the body of guard {}", info.id, callbacks[0]);
+        return respond_error(Some(site), request, &info.id, Some(&info.url), 500);
+    };
+
+    info.deadline = Some(Instant::now() + timeout);
+    let command = ScriptCommand {
+        site: site.clone(),
+        script_name: callbacks.remove(0),
+        read_only: true,
+        priority: Priority::Interactive,
+        template_defaults: Default::default(),
+        path_vars,
+        info,
+        body,
+        request: Some(request),
+        guard: Some(GuardCheck { then: callbacks, port, requests_tx: requests_tx.clone() }),
+    };
+
+    if let Err(command) = runs_tx.send(command) {
+        log::error!("[{}] No script thread for guard {}", command.info.id, command.script_name);
+        let _ = site.dump_json(command.body, tid);
+        let request = command.request.unwrap(/* set above */);
+        respond_error(Some(site), request, &command.info.id, Some(&command.info.url), 503);
+    }
+}
+
+/// JSON text to parse for a request body; `None` if the body is invalid
+pub(crate) fn json_body(body_mode: BodyMode, content: &[u8]) -> Option<&str> {
+    // other modes give the raw body to scripts
+    match body_mode {
+        // GET requests usually have no body
+        BodyMode::Json if content.trim_ascii().is_empty() => Some("null"),
+        BodyMode::Json => from_utf8(content).ok(),
+        BodyMode::Text => from_utf8(content).ok().map(|_| "null"),
+        BodyMode::Bytes | BodyMode::None => Some("null"),
+    }
+}
+
+fn is_authorized(preview: &Preview, request: &Request) -> bool {
+    match (&preview.authorization, authorization(request)) {
+        (Some(expected), Some(value)) => constant_time_eq(value, expected),
+        (Some(_), None) => false,
+        (None, _) => true,
+    }
+}
+
+fn authorization(request: &Request) -> Option<&str> {
+    let header = request.headers().iter().find(|header| header.field.equiv("Authorization"));
+    header.map(|header| header.value.as_str())
+}
+
+fn accept_language(request: &Request) -> &str {
+    let header = request.headers().iter().find(|header| header.field.equiv("Accept-Language"));
+    header.map(|header| header.value.as_str()).unwrap_or("")
+}
+
+pub(crate) fn constant_time_eq(value: &str, expected: &str) -> bool {
+    let (value, expected) = (value.as_bytes(), expected.as_bytes());
+    let diff = value.iter().zip(expected).fold(0, |acc, (a, b)| acc | (a ^ b));
+    value.len() == expected.len() && diff == 0
+}
+
+fn redirect(request: Request, base: &str, request_id: &str) {
+    let location = format!("{}{}", base.trim_end_matches('/'), request.url());
+
+    // the URL comes from a parsed request line, it can't contain line breaks
+    let headers = vec![header("Location", &location)];
+    respond(request, request_id, 301, headers, b"");
+}
+
+fn deny_preview(site: Option<&Arc<dyn Site>>, request: Request, request_id: &str) {
+    unauthorized(site, request, request_id, None, "Basic realm=\"preview\"");
+}
+
+/// Responds with a 401 status and a `WWW-Authenticate` challenge
+pub(crate) fn unauthorized(site: Option<&Arc<dyn Site>>, request: Request, request_id: &str, url: Option<&str>, challenge: &str) {
+    let mut headers = response_headers(site, &request, request_id, url);
+    headers.push(header("WWW-Authenticate", challenge));
+
+    let body = include_str!("proc-failure.html").as_bytes();
+    respond(request, request_id, 401, headers, body);
+}
+
+/// Lists the accepted methods in an `Allow` header
+fn method_not_allowed(site: Option<&Arc<dyn Site>>, request: Request, request_id: &str, url: &str, methods: &Methods) {
+    let mut headers = response_headers(site, &request, request_id, Some(url));
+    headers.push(header("Allow", &methods.as_deref().unwrap_or_default().join(", ")));
+
+    let body = include_str!("proc-failure.html").as_bytes();
+    respond(request, request_id, 405, headers, body);
+}
+
+/// Responds with the site's error document for `code`, or with the default one;
+/// `url` is the one the request was routed with, if it was (see [`response_headers`])
+pub(crate) fn respond_error(site: Option<&Arc<dyn Site>>, request: Request, request_id: &str, url: Option<&str>, code: u16) {
+    let mut headers = response_headers(site, &request, request_id, url);
+    let path = url.unwrap_or(request.url()).split('?').next().unwrap();
+
+    let body = match site.and_then(|s| s.error_document(code, path)) {
+        Some((content_type, body)) => {
+            headers.push(header("Content-Type", content_type));
+            body
+        },
+        None => include_str!("proc-failure.html").as_bytes().to_vec(),
+    };
+
+    respond(request, request_id, code, headers, &body);
+}
+
+/// Sends a response, which is recorded if recording is enabled
+pub(crate) fn respond(request: Request, request_id: &str, status: u16, mut headers: Vec<Header>, body: &[u8]) {
+    if !headers.iter().any(|h| h.field.equiv("Content-Type")) {
+        headers.retain(|h| !h.field.equiv("X-Content-Type-Options"));
+    }
+
+    if record::enabled() {
+        record::response(request_id, status, &headers, body);
+    }
+
+    coalesce::release(request_id, status, &headers, body);
+    response_cache::store(request_id, status, &headers, body);
+    trace::end(request_id, status);
+
+    let response = Response::new(status.into(), headers, body, None, None);
+    if let Err(error) = request.respond(response) {
+        log::error!("[{}] Couldn't respond: {:?}", request_id, error);
+    }
+}
+
+/// Streams a file as a 200 response, without loading it in memory
+fn respond_file(request: Request, request_id: &str, headers: Vec<Header>, mut file: File, length: u64) {
+    if record::enabled() {
+        let mut body = Vec::new();
+        let _ = file.read_to_end(&mut body).and_then(|_| file.rewind());
+        record::response(request_id, 200, &headers, &body);
+    }
+
+    trace::end(request_id, 200);
+
+    let response = Response::new(200.into(), headers, file, Some(length as usize), None);
+    if let Err(error) = request.respond(response) {
+        log::error!("[{}] Couldn't respond: {:?}", request_id, error);
+    }
+}
+
+pub(crate) fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap(/* static strings */)
+}
+
+/// Headers which every response of a site must carry; `url` is the one the
+/// request was routed with (see [`surface_url`]), if it was
+pub(crate) fn response_headers(site: Option<&Arc<dyn Site>>, request: &Request, request_id: &str, url: Option<&str>) -> Vec<Header> {
+    let mut headers = Vec::new();
+
+    if let Some(secret) = site.and_then(|s| s.csrf_secret()) {
+        // the session is derived from the request ID, like in request_waiter
+        if let (session, true) = csrf::session(secret, request, request_id) {
+            headers.push(header("Set-Cookie", &csrf::session_cookie(&session)));
+        }
+    }
+
+    if site.and_then(|s| s.preview()).is_some() {
+        headers.push(header("X-Robots-Tag", "noindex"));
+    }
+
+    let mut security_headers: Vec<_> = DEFAULT_SECURITY_HEADERS.iter().map(|(n, v)| (*n, v.to_string())).collect();
+    if let Some(site) = site {
+        // the route isn't known on all paths leading here
+        let route = url.map(|url| resolve_route(site, url));
+        let route_overrides = route.into_iter().flat_map(|route| route.security_headers);
+        let overrides = site.security_headers().into_iter().chain(route_overrides);
+        overrides.for_each(|overrides| overrides.apply(&mut security_headers));
+    }
+
+    for (name, value) in security_headers {
+        // values come from config files, which might contain line breaks
+        match Header::from_bytes(name.as_bytes(), value.as_bytes()) {
+            Ok(header) => headers.push(header),
+            Err(()) => log::error!("[{}] Invalid {} header value", request_id, name),
+        }
+    }
+
+    if site.map(|s| s.request_id_header()) == Some(true) && !request_id.is_empty() {
+        headers.push(header("X-Request-Id", request_id));
+    }
+
+    headers
+}
+
+pub fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | (b[2] as usize);
+
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(n >> (18 - 6 * i)) & 63] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+
+    encoded
+}
+
+pub(crate) fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() / 4 * 3);
+    let mut n = 0;
+    let mut bits = 0;
+
+    for c in encoded.bytes().filter(|c| *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+
+        n = (n << 6) | value as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((n >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn process_endpoint(
     site: Option<&Arc<dyn Site>>,
     path_vars: Vec<String>,
     path_override: Option<String>,
+    mut info: RequestInfo,
     mut request: Request,
     endpoint: &Endpoint,
-    runs_tx: &Sender<ScriptCommand>,
+    runs_tx: &ScriptSender,
+    uploads_tx: &Sender<Upload>,
     tid: usize,
 ) {
-    if let Endpoint::ScriptExec(read_only, script_name) = endpoint {
+    if let Endpoint::ScriptExec(ScriptEndpoint { read_only, script_name, template_defaults, priority, body_mode, timeout, coalesced, cached, schema, methods, max_body, .. }) = endpoint {
         let site = site.unwrap();
-        let mut content = String::new();
-        if let Ok(_) = request.as_reader().read_to_string(&mut content) {
-            if let Ok(body) = site.parse_json(&content, tid) {
-                let _ = runs_tx.send(ScriptCommand {
-                    site: site.clone(),
-                    script_name: script_name.clone(),
-                    read_only: *read_only,
-                    path_vars,
-                    body,
-                    request,
-                });
-            } else {
-                log::error!("Couldn't parse request body as JSON");
-                process_endpoint(Some(site), Vec::new(), None, request, &Endpoint::Error(400.into()), runs_tx, tid);
+        if !accepts_method(methods, &info.method) {
+            log::info!("[{}] {} requests aren't accepted by {}", info.id, info.method, info.route);
+            return method_not_allowed(Some(site), request, &info.id, &info.url, methods);
+        }
+
+        info.deadline = Some(Instant::now() + *timeout);
+        let max_body = max_body.unwrap_or(usize::MAX);
+        let mut content = Vec::new();
+        if *body_mode != BodyMode::None {
+            // a body without Content-Length is cut one byte past the limit
+            let too_large = request.body_length().is_some_and(|len| len > max_body);
+            let read = (!too_large).then(|| request.as_reader().take((max_body as u64).saturating_add(1)).read_to_end(&mut content));
+            if too_large || content.len() > max_body {
+                log::info!("[{}] Request body is too large for {}", info.id, info.route);
+                return process_endpoint(Some(site), Vec::new(), None, info, request, &Endpoint::Error(413.into()), runs_tx, uploads_tx, tid);
             }
-        } else {
-            log::error!("Couldn't read request body");
-            process_endpoint(Some(site), Vec::new(), None, request, &Endpoint::Error(400.into()), runs_tx, tid);
+
+            if let Some(Err(_)) = read {
+                log::error!("[{}] Couldn't read request body", info.id);
+                return process_endpoint(Some(site), Vec::new(), None, info, request, &Endpoint::Error(400.into()), runs_tx, uploads_tx, tid);
+            }
+        }
+
+        let json = json_body(*body_mode, &content);
+        if let Some(Err(mismatches)) = schema.as_ref().zip(json).map(|(schema, json)| schema.check(json)) {
+            log::info!("[{}] Request body doesn't match the schema of {}", info.id, info.route);
+            let mut headers = response_headers(Some(site), &request, &info.id, Some(&info.url));
+            headers.push(header("Content-Type", renderer::JSON));
+            return respond(request, &info.id, 422, headers, schema::mismatches_json(&mismatches).as_bytes());
+        }
+
+        let Some(Ok(body)) = json.map(|json| site.parse_json(json, tid)) else {
+            log::error!("[{}] Couldn't parse request body as {}", info.id, body_mode.name());
+            return process_endpoint(Some(site), Vec::new(), None, info, request, &Endpoint::Error(400.into()), runs_tx, uploads_tx, tid);
+        };
+
+        if record::enabled() {
+            record::request(&request, &info.id, site.hostname(), &content);
+        }
+
+        let revision = site.revision().filter(|_| *cached && response_cache::cacheable(&info));
+        if let Some(revision) = revision {
+            let key = coalesce::key(site.hostname(), &info, response_cache::KEY_HEADERS, &content);
+            match response_cache::lookup(key, revision) {
+                Some((mut headers, cached_body)) => {
+                    let _ = site.dump_json(body, tid);
+                    if site.request_id_header() {
+                        headers.push(header("X-Request-Id", &info.id));
+                    }
+
+                    let etag = headers.iter().find(|h| h.field.equiv("ETag")).map(|h| h.value.to_string());
+                    let if_none_match = info.headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("If-None-Match"));
+                    return match (etag, if_none_match) {
+                        (Some(tag), Some((_, value))) if renderer::none_match(value, &tag) => {
+                            respond(request, &info.id, 304, headers, &[])
+                        },
+                        _ => respond(request, &info.id, 200, headers, &cached_body),
+                    };
+                },
+                None => response_cache::expect(&info.id, key, revision),
+            }
+        }
+
+        let request = match *coalesced {
+            true => match coalesce::join(coalesce::key(site.hostname(), &info, coalesce::KEY_HEADERS, &content), Waiter::Request(request), &info.id, info.deadline.unwrap(/* set above */)) {
+                Some(Waiter::Request(request)) => request,
+                _ => {
+                    // the response of an identical request will be shared
+                    let _ = site.dump_json(body, tid);
+                    return;
+                },
+            },
+            false => request,
+        };
+
+        if let BodyMode::Text | BodyMode::Bytes = body_mode {
+            info.body = content.into();
+        }
+
+        let command = ScriptCommand {
+            site: site.clone(),
+            script_name: script_name.clone(),
+            read_only: *read_only,
+            priority: *priority,
+            template_defaults: template_defaults.clone(),
+            path_vars,
+            info,
+            body,
+            request: Some(request),
+            guard: None,
+        };
+
+        if let Err(command) = runs_tx.send(command) {
+            log::error!("[{}] No script thread for {}", command.info.id, command.script_name);
+            let _ = site.dump_json(command.body, tid);
+            let request = command.request.unwrap(/* set above */);
+            respond_error(Some(site), request, &command.info.id, Some(&command.info.url), 503);
         }
     } else if let Endpoint::Static(path) = endpoint {
         let site = site.unwrap();
         let path = path_override.as_deref().unwrap_or(path);
 
-        if let Some(reader) = site.open_static(path) {
-            let response = Response::new(200.into(), vec![], reader, None, None);
-            if let Err(error) = request.respond(response) {
-                log::error!("Couldn't respond: {:?}", error);
+        if let Some(body) = site.open_static(path) {
+            let headers = response_headers(Some(site), &request, &info.id, Some(&info.url));
+            match body {
+                StaticBody::Memory(bytes) => respond(request, &info.id, 200, headers, &bytes),
+                StaticBody::File(file, length) => respond_file(request, &info.id, headers, file, length),
             }
         } else {
-            log::error!("Missing static resource: {}", path);
+            log::error!("[{}] Missing static resource: {}", info.id, path);
             if site.on_404() != endpoint {
-                process_endpoint(Some(site), Vec::new(), None, request, site.on_404(), runs_tx, tid);
+                process_endpoint(Some(site), Vec::new(), None, info, request, site.on_404(), runs_tx, uploads_tx, tid);
             } else {
-                log::error!("Invalid 404 handler");
-                process_endpoint(Some(site), Vec::new(), None, request, &Endpoint::Error(500.into()), runs_tx, tid);
+                log::error!("[{}] Invalid 404 handler", info.id);
+                process_endpoint(Some(site), Vec::new(), None, info, request, &Endpoint::Error(500.into()), runs_tx, uploads_tx, tid);
             }
         }
-    } else if let Endpoint::Upload = endpoint {
+    } else if let Endpoint::Document(content_type, body) = endpoint {
+        let mut headers = response_headers(site, &request, &info.id, Some(&info.url));
+        headers.push(header("Content-Type", content_type));
+        respond(request, &info.id, 200, headers, body);
+    } else if let Endpoint::Upload(timeouts) = endpoint {
         let site = site.unwrap();
-        if path_vars.len() == 1 {
-            let token = &path_vars[0];
-            if let Some(mut body_len) = site.check_upload_token(token) {
-                let chunk_size = 4096 * 4;
-
-                let reader = request.as_reader();
-                let mut buf = vec![0; chunk_size];
-
-                while body_len > 0 {
-                    if let Ok(len) = reader.read(&mut buf) {
-                        site.upload_progress(token, &buf[..len]);
-                        if let Some(len) = body_len.checked_sub(len) {
-                            body_len = len;
-                        } else {
-                            site.end_of_upload(token, false);
-                            log::error!("Client tried to upload more than allowed");
-                            return process_endpoint(Some(site), Vec::new(), None, request, &Endpoint::Error(400.into()), runs_tx, tid);
-                        }
-                    } else {
-                        site.end_of_upload(token, false);
-                        log::error!("Failed to process upload request");
-                        return process_endpoint(Some(site), Vec::new(), None, request, &Endpoint::Error(400.into()), runs_tx, tid);
-                    }
-                }
+        if let 1..=2 = path_vars.len() {
+            let mut path_vars = path_vars.into_iter();
+            let upload = Upload {
+                site: site.clone(),
+                token: path_vars.next().unwrap(),
+                operation: path_vars.next(),
+                request_id: info.id,
+                url: info.url,
+                timeouts: *timeouts,
+                request,
+            };
 
-                site.end_of_upload(token, true);
-
-                let response = "success".as_bytes();
-                if let Err(error) = request.respond(Response::new(200.into(), vec![], response, None, None)) {
-                    log::error!("Couldn't respond: {:?}", error);
-                }
-
-                return;
+            if let Err(error) = uploads_tx.try_send(upload) {
+                let upload = error.into_inner();
+                log::error!("[{}] Too many uploads waiting for a thread", upload.request_id);
+                respond_error(Some(&upload.site), upload.request, &upload.request_id, Some(&upload.url), 503);
             }
+        } else {
+            log::error!("[{}] Invalid upload token/request", info.id);
+            respond_error(Some(site), request, &info.id, Some(&info.url), 400);
         }
-
-        log::error!("Invalid upload token/request");
-        process_endpoint(Some(site), Vec::new(), None, request, &Endpoint::Error(400.into()), runs_tx, tid);
     } else if let Endpoint::Error(code) = endpoint {
-        let body = include_str!("proc-failure.html").as_bytes();
-        let response = Response::new(*code, vec![], body, None, None);
-        if let Err(error) = request.respond(response) {
-            log::error!("Couldn't respond: {:?}", error);
-        }
+        respond_error(site, request, &info.id, Some(&info.url), code.0);
     } else {
-        log::error!("Landed at an Endpoint::Directory(_) without any wildcard route");
-        process_endpoint(site, Vec::new(), None, request, &Endpoint::Error(500.into()), runs_tx, tid);
+        log::error!("[{}] Landed at an Endpoint::Directory(_) without any wildcard route", info.id);
+        process_endpoint(site, Vec::new(), None, info, request, &Endpoint::Error(500.into()), runs_tx, uploads_tx, tid);
     }
 }
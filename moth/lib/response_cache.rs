@@ -0,0 +1,107 @@
+//! Responses of script routes, kept until the database of their site changes
+//!
+//! On routes where it's enabled, successful (200) responses to `GET` & `HEAD`
+//! requests are stored with the database revision of their site (see
+//! `SiteDatabase::revision`). An identical request (same site, subdomain,
+//! scheme, method, URL, body, `Accept` & `Accept-Language` headers, see
+//! [`coalesce::key`]) gets the stored response without running the script,
+//! until the revision changes. Like with [`coalesce`], responses of such
+//! routes mustn't depend on cookies or other headers.
+//!
+//! [`coalesce`]: super::coalesce
+//! [`coalesce::key`]: super::coalesce::key
+
+use super::RequestInfo;
+use tiny_http::Header;
+use std::{sync::{Arc, Mutex}, collections::VecDeque};
+
+const MAX_ENTRIES: usize = 4096;
+const MAX_BYTES: usize = 64 * 1024 * 1024;
+/// Larger responses aren't stored
+const MAX_ENTRY_BYTES: usize = MAX_BYTES / 16;
+/// Requests whose response is expected, but which never got one (panics)
+const MAX_PENDING: usize = 1024;
+
+/// Headers which differ between the requests sharing a response
+const PER_REQUEST_HEADERS: &[&str] = &["Set-Cookie", "X-Request-Id"];
+
+struct Entry {
+    key: [u8; 32],
+    revision: u64,
+    headers: Vec<Header>,
+    body: Arc<[u8]>,
+}
+
+struct Store {
+    /// Least recently used first
+    entries: VecDeque<Entry>,
+    bytes: usize,
+    /// (request ID, key, revision)
+    pending: VecDeque<(String, [u8; 32], u64)>,
+}
+
+static STORE: Mutex<Store> = Mutex::new(Store {
+    entries: VecDeque::new(),
+    bytes: 0,
+    pending: VecDeque::new(),
+});
+
+/// Headers taken into account by [`coalesce::key`]
+pub(crate) const KEY_HEADERS: &[&str] = &["Accept"];
+
+/// Only `GET` & `HEAD` responses are cached
+pub(crate) fn cacheable(info: &RequestInfo) -> bool {
+    matches!(info.method.as_str(), "GET" | "HEAD")
+}
+
+/// Headers & body of the stored response, if it's still up to date
+pub(crate) fn lookup(key: [u8; 32], revision: u64) -> Option<(Vec<Header>, Arc<[u8]>)> {
+    let mut store = STORE.lock().unwrap();
+    let i = store.entries.iter().position(|entry| entry.key == key)?;
+    let entry = store.entries.remove(i).unwrap();
+
+    if entry.revision != revision {
+        store.bytes -= entry.body.len();
+        return None;
+    }
+
+    let response = (entry.headers.clone(), entry.body.clone());
+    store.entries.push_back(entry);
+    Some(response)
+}
+
+/// The response of this request will be stored by [`store`]
+pub(crate) fn expect(request_id: &str, key: [u8; 32], revision: u64) {
+    let mut store = STORE.lock().unwrap();
+    if store.pending.len() >= MAX_PENDING {
+        store.pending.pop_front();
+    }
+
+    store.pending.push_back((request_id.into(), key, revision));
+}
+
+/// Stores the response of a request passed to [`expect`], if it's a success
+pub(crate) fn store(request_id: &str, status: u16, headers: &[Header], body: &[u8]) {
+    let mut store = STORE.lock().unwrap();
+    let Some(i) = store.pending.iter().position(|(id, _, _)| id == request_id) else { return };
+    let (_, key, revision) = store.pending.remove(i).unwrap();
+
+    if status != 200 || body.len() > MAX_ENTRY_BYTES {
+        return;
+    }
+
+    if let Some(i) = store.entries.iter().position(|entry| entry.key == key) {
+        let replaced = store.entries.remove(i).unwrap();
+        store.bytes -= replaced.body.len();
+    }
+
+    let is_shared = |h: &&Header| !PER_REQUEST_HEADERS.iter().any(|name| h.field.equiv(name));
+    let headers = headers.iter().filter(is_shared).cloned().collect();
+    store.entries.push_back(Entry { key, revision, headers, body: body.into() });
+    store.bytes += body.len();
+
+    while store.entries.len() > MAX_ENTRIES || store.bytes > MAX_BYTES {
+        let evicted = store.entries.pop_front().unwrap(/* at least the new entry */);
+        store.bytes -= evicted.body.len();
+    }
+}
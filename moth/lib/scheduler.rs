@@ -0,0 +1,171 @@
+use super::{Sites, Site, Arc, PoolStr, ReadOnly, ScriptCommand, ScriptSender, Priority, RequestInfo, request::new_request_id};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::thread::{sleep, spawn};
+
+/// A periodic script execution, declared by a site
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub schedule: Schedule,
+    pub callback: PoolStr,
+    pub read_only: ReadOnly,
+}
+
+/// A cron-like schedule (`minute hour day-of-month month day-of-week`), evaluated in UTC
+///
+/// Each field accepts `*`, `N`, `N-M`, `*/S` or `N-M/S`, and comma-separated lists of these.
+/// Unlike cron, day-of-month and day-of-week must both match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+}
+
+impl Schedule {
+    pub fn parse(schedule: &str) -> Result<Self, ()> {
+        let mut fields = schedule.split_whitespace();
+        let mut next = |min, max| parse_field(fields.next().ok_or(())?, min, max);
+
+        let this = Self {
+            minutes: next(0, 59)?,
+            hours: next(0, 23)?,
+            days: next(1, 31)?,
+            months: next(1, 12)?,
+            // 7 is an alias of 0 (sunday)
+            weekdays: next(0, 7).map(|w| (w | (w >> 7)) & 0x7f)?,
+        };
+
+        match fields.next() {
+            Some(_) => Err(()),
+            None => Ok(this),
+        }
+    }
+
+    /// `time` is the number of seconds since the unix epoch
+    pub fn matches(&self, time: u64) -> bool {
+        let minutes = time / 60;
+        let days = minutes / (60 * 24);
+
+        // 1970-01-01 was a thursday
+        let weekday = (days + 4) % 7;
+        let (_year, month, day) = civil_from_days(days);
+
+        let bit = |mask: u64, value: u64| (mask >> value) & 1 == 1;
+
+        bit(self.minutes, minutes % 60)
+            && bit(self.hours, (minutes / 60) % 24)
+            && bit(self.days, day)
+            && bit(self.months, month)
+            && bit(self.weekdays, weekday)
+    }
+}
+
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, ()> {
+    let mut mask = 0;
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse().map_err(|_| ())?),
+            None => (item, 1),
+        };
+
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (start.parse().map_err(|_| ())?, end.parse().map_err(|_| ())?),
+                None => {
+                    let value = range.parse().map_err(|_| ())?;
+                    (value, value)
+                },
+            },
+        };
+
+        if step == 0 || start < min || end > max || start > end {
+            return Err(());
+        }
+
+        for value in (start..=end).step_by(step) {
+            mask |= 1 << value;
+        }
+    }
+
+    Ok(mask)
+}
+
+/// Converts days since the unix epoch to (year, month, day)
+///
+/// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+pub fn scheduler(
+    runs_tx: ScriptSender,
+    sites: Sites,
+    tid: usize,
+) {
+    loop {
+        // wake up at the start of the next minute
+        let time = now();
+        sleep(Duration::from_secs(60 - (time % 60)));
+        let time = now();
+
+        for site in sites.all() {
+            if site.sync_schedule().map(|s| s.matches(time)) == Some(true) {
+                let site = site.clone();
+                // network operations: don't delay jobs
+                spawn(move || site.sync_database());
+            }
+
+            if time % 86400 < 60 {
+                let site = site.clone();
+                spawn(move || site.enforce_retention());
+            }
+
+            for job in site.jobs() {
+                if job.schedule.matches(time) {
+                    dispatch(&site, job, &runs_tx, tid);
+                }
+            }
+        }
+    }
+}
+
+fn dispatch(site: &Arc<dyn Site>, job: &Job, runs_tx: &ScriptSender, tid: usize) {
+    if let Ok(body) = site.parse_json("null", tid) {
+        let command = ScriptCommand {
+            site: site.clone(),
+            script_name: job.callback.clone(),
+            read_only: job.read_only,
+            priority: Priority::Batch,
+            template_defaults: Default::default(),
+            path_vars: Vec::new(),
+            info: RequestInfo { id: new_request_id(), ..Default::default() },
+            body,
+            request: None,
+            guard: None,
+        };
+
+        if let Err(command) = runs_tx.send(command) {
+            log::error!("[{}] No script thread for job {}", command.info.id, job.callback);
+            let _ = site.dump_json(command.body, tid);
+        }
+    } else {
+        log::error!("Couldn't create job body for {}", job.callback);
+    }
+}
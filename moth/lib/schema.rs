@@ -0,0 +1,277 @@
+//! Validation of JSON request bodies, before scripts run
+//!
+//! Script routes can have a `schema`: bodies which don't match it get a 422
+//! response listing every mismatch, as `{ "errors": [{ "path", "message" }] }`
+//! where paths are JSON pointers (`/items/0/price`). Schemas are a subset of
+//! JSON Schema:
+//!
+//! ```json
+//! {
+//!     "type": "object",
+//!     "required": ["title"],
+//!     "additionalProperties": false,
+//!     "properties": {
+//!         "title": { "type": "string", "minLength": 1, "maxLength": 200 },
+//!         "tags": { "type": "array", "maxItems": 8, "items": { "type": "string" } },
+//!         "visibility": { "enum": ["public", "private"] },
+//!         "rating": { "type": ["integer", "null"], "minimum": 0, "maximum": 5 }
+//!     }
+//! }
+//! ```
+//!
+//! Keywords are checked when they apply to the value's type; other JSON
+//! Schema keywords are refused, except annotations (`title`, `description`).
+
+use super::push_json_str;
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+
+const TYPES: &[&str] = &["object", "array", "string", "number", "integer", "boolean", "null"];
+const ANNOTATIONS: &[&str] = &["$schema", "$id", "title", "description", "examples", "default"];
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Schema {
+    /// Accepted types, among [`TYPES`]; any type if empty
+    types: Vec<&'static str>,
+    properties: Vec<(String, Schema)>,
+    required: Vec<String>,
+    additional_properties: bool,
+    items: Option<Box<Schema>>,
+    /// Accepted values (strings, numbers, booleans or null)
+    enumeration: Option<Vec<JsonValue>>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+}
+
+/// (JSON pointer, message)
+pub type Mismatch = (String, String);
+
+fn count(file: &JsonFile, path: &JsonPath, keyword: &str) -> Result<Option<usize>, String> {
+    match file.get(path) {
+        JsonValue::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(Some(*n as usize)),
+        JsonValue::Null => Ok(None),
+        _ => Err(format!("{} must be a non-negative integer", keyword)),
+    }
+}
+
+fn number(file: &JsonFile, path: &JsonPath, keyword: &str) -> Result<Option<f64>, String> {
+    match file.get(path) {
+        JsonValue::Number(n) => Ok(Some(*n)),
+        JsonValue::Null => Ok(None),
+        _ => Err(format!("{} must be a number", keyword)),
+    }
+}
+
+fn type_name(value: &JsonValue) -> &'static str {
+    match value {
+        JsonValue::Object(_) => "object",
+        JsonValue::Array(_) => "array",
+        JsonValue::String(_) => "string",
+        JsonValue::Number(_) => "number",
+        JsonValue::Boolean(_) => "boolean",
+        JsonValue::Null => "null",
+    }
+}
+
+impl Schema {
+    /// Reads the schema at `path`; errors name the faulty keyword
+    pub fn parse(file: &JsonFile, path: &JsonPath) -> Result<Self, String> {
+        let JsonValue::Object(keys) = file.get(path) else {
+            return Err("must be an object".into());
+        };
+
+        let mut schema = Self { additional_properties: true, ..Default::default() };
+        let at = |keyword: &str| path.clone().i_str(keyword);
+
+        for keyword in keys.iter() {
+            match &**keyword {
+                "type" | "properties" | "required" | "additionalProperties" | "items" | "enum" => (),
+                "minLength" | "maxLength" | "minimum" | "maximum" | "minItems" | "maxItems" => (),
+                k if ANNOTATIONS.contains(&k) => (),
+                k => return Err(format!("{} isn't a supported keyword", k)),
+            }
+        }
+
+        let type_of = |path: &JsonPath| match file.get(path) {
+            JsonValue::String(name) => TYPES.iter().find(|t| **t == &**name).copied(),
+            _ => None,
+        };
+
+        schema.types = match file.get(&at("type")) {
+            JsonValue::Null => Vec::new(),
+            JsonValue::String(_) => vec![type_of(&at("type")).ok_or("type must be one of object/array/string/number/integer/boolean/null")?],
+            JsonValue::Array(length) => {
+                let types = (0..*length).map(|i| type_of(&at("type").i_num(i)));
+                types.collect::<Option<_>>().ok_or("type must only have object/array/string/number/integer/boolean/null")?
+            },
+            _ => return Err("type must be a string or an array of strings".into()),
+        };
+
+        match file.get(&at("properties")) {
+            JsonValue::Object(names) => for name in names.iter() {
+                let property = Self::parse(file, &at("properties").i_str(name));
+                let property = property.map_err(|e| format!("properties.{}: {}", name, e))?;
+                schema.properties.push((name.to_string(), property));
+            },
+            JsonValue::Null => (),
+            _ => return Err("properties must be an object".into()),
+        }
+
+        match file.get(&at("required")) {
+            JsonValue::Array(length) => for i in 0..*length {
+                let name = file.get(&at("required").i_num(i)).as_string().ok_or("required must be an array of strings")?;
+                schema.required.push(name.to_string());
+            },
+            JsonValue::Null => (),
+            _ => return Err("required must be an array of strings".into()),
+        }
+
+        schema.additional_properties = match file.get(&at("additionalProperties")) {
+            JsonValue::Boolean(allowed) => *allowed,
+            JsonValue::Null => true,
+            _ => return Err("additionalProperties must be a boolean".into()),
+        };
+
+        if !matches!(file.get(&at("items")), JsonValue::Null) {
+            let items = Self::parse(file, &at("items")).map_err(|e| format!("items: {}", e))?;
+            schema.items = Some(Box::new(items));
+        }
+
+        schema.enumeration = match file.get(&at("enum")) {
+            JsonValue::Array(length) => {
+                let values = (0..*length).map(|i| match file.get(&at("enum").i_num(i)) {
+                    JsonValue::Object(_) | JsonValue::Array(_) => None,
+                    value => Some(value.clone()),
+                });
+
+                Some(values.collect::<Option<_>>().ok_or("enum must only have strings, numbers, booleans or null")?)
+            },
+            JsonValue::Null => None,
+            _ => return Err("enum must be an array".into()),
+        };
+
+        schema.min_length = count(file, &at("minLength"), "minLength")?;
+        schema.max_length = count(file, &at("maxLength"), "maxLength")?;
+        schema.minimum = number(file, &at("minimum"), "minimum")?;
+        schema.maximum = number(file, &at("maximum"), "maximum")?;
+        schema.min_items = count(file, &at("minItems"), "minItems")?;
+        schema.max_items = count(file, &at("maxItems"), "maxItems")?;
+
+        Ok(schema)
+    }
+
+    /// Checks a JSON body; bodies which aren't valid JSON are left to the script route
+    pub fn check(&self, json: &str) -> Result<(), Vec<Mismatch>> {
+        let Ok(file) = JsonFile::new(Some(json)) else { return Ok(()) };
+        let mut mismatches = Vec::new();
+        self.check_value(&file, &JsonPath::new(), &mut String::new(), &mut mismatches);
+
+        match mismatches.is_empty() {
+            true => Ok(()),
+            false => Err(mismatches),
+        }
+    }
+
+    fn check_value(&self, file: &JsonFile, path: &JsonPath, pointer: &mut String, mismatches: &mut Vec<Mismatch>) {
+        let value = file.get(path);
+        let mut mismatch = |message: String| mismatches.push((pointer.clone(), message));
+
+        let is_integer = matches!(value, JsonValue::Number(n) if n.fract() == 0.0);
+        let type_matches = |t: &&str| *t == type_name(value) || (*t == "integer" && is_integer);
+        if !self.types.is_empty() && !self.types.iter().any(type_matches) {
+            return mismatch(format!("must be of type {}", self.types.join("/")));
+        }
+
+        if let Some(values) = &self.enumeration {
+            if !values.contains(value) {
+                return mismatch("must be one of the values of its enum".into());
+            }
+        }
+
+        match value {
+            JsonValue::String(string) => {
+                let length = string.chars().count();
+                if self.min_length.is_some_and(|min| length < min) {
+                    mismatch(format!("must have at least {} characters", self.min_length.unwrap()));
+                }
+
+                if self.max_length.is_some_and(|max| length > max) {
+                    mismatch(format!("must have at most {} characters", self.max_length.unwrap()));
+                }
+            },
+            JsonValue::Number(n) => {
+                if self.minimum.is_some_and(|min| *n < min) {
+                    mismatch(format!("must be at least {}", self.minimum.unwrap()));
+                }
+
+                if self.maximum.is_some_and(|max| *n > max) {
+                    mismatch(format!("must be at most {}", self.maximum.unwrap()));
+                }
+            },
+            JsonValue::Array(length) => {
+                if self.min_items.is_some_and(|min| *length < min) {
+                    mismatch(format!("must have at least {} items", self.min_items.unwrap()));
+                }
+
+                if self.max_items.is_some_and(|max| *length > max) {
+                    mismatch(format!("must have at most {} items", self.max_items.unwrap()));
+                }
+
+                if let Some(items) = &self.items {
+                    for i in 0..*length {
+                        let parent_length = pointer.len();
+                        *pointer += &format!("/{}", i);
+                        items.check_value(file, &path.clone().i_num(i), pointer, mismatches);
+                        pointer.truncate(parent_length);
+                    }
+                }
+            },
+            JsonValue::Object(keys) => {
+                for name in self.required.iter().filter(|name| !keys.iter().any(|key| &**key == name.as_str())) {
+                    mismatches.push((format!("{}/{}", pointer, escape(name)), "is missing".into()));
+                }
+
+                for key in keys.iter() {
+                    let parent_length = pointer.len();
+                    *pointer += &format!("/{}", escape(key));
+
+                    match self.properties.iter().find(|(name, _)| name.as_str() == &**key) {
+                        Some((_, property)) => property.check_value(file, &path.clone().i_str(key), pointer, mismatches),
+                        None if !self.additional_properties => mismatches.push((pointer.clone(), "isn't an allowed property".into())),
+                        None => (),
+                    }
+
+                    pointer.truncate(parent_length);
+                }
+            },
+            JsonValue::Boolean(_) | JsonValue::Null => (),
+        }
+    }
+}
+
+/// Escapes a property name for a JSON pointer
+fn escape(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+/// Body of 422 responses
+pub(crate) fn mismatches_json(mismatches: &[Mismatch]) -> String {
+    let mut json = String::from("{\"errors\":[");
+    for (i, (pointer, message)) in mismatches.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+
+        json += "{\"path\":";
+        push_json_str(&mut json, pointer);
+        json += ",\"message\":";
+        push_json_str(&mut json, message);
+        json.push('}');
+    }
+
+    json += "]}";
+    json
+}
@@ -1,39 +1,275 @@
-use super::{PoolStr, OpaqueJsonPointer, Site, Arc, RendererCommand};
-use flume::{Receiver, Sender};
+use super::{PoolStr, OpaqueJsonPointer, Site, Arc, RendererCommand, TemplateDefaults, RequestInfo, log_context, coalesce, trace};
+use super::request::{respond_error, unauthorized, callback_accepts, Incoming};
+use flume::{Receiver, Sender, Selector};
 use tiny_http::Request;
 use lmfu::LiteMap;
 
+/// How urgent a script execution is
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Priority {
+    /// Page loads, API calls
+    #[default]
+    Interactive,
+    /// Exports, imports, scheduled jobs
+    Batch,
+}
+
+/// Queues script executions according to their priority
+#[derive(Clone)]
+pub struct ScriptSender {
+    interactive: Sender<ScriptCommand>,
+    batch: Sender<ScriptCommand>,
+}
+
+impl ScriptSender {
+    /// Gives the command back if no script thread is running
+    pub fn send(&self, command: ScriptCommand) -> Result<(), Box<ScriptCommand>> {
+        let queue = match command.priority {
+            Priority::Interactive => &self.interactive,
+            Priority::Batch => &self.batch,
+        };
+
+        queue.send(command).map_err(|e| Box::new(e.into_inner()))
+    }
+}
+
+#[derive(Clone)]
+pub struct ScriptReceiver {
+    interactive: Receiver<ScriptCommand>,
+    batch: Receiver<ScriptCommand>,
+}
+
+impl ScriptReceiver {
+    /// Number of waiting executions
+    pub(crate) fn len(&self) -> usize {
+        self.interactive.len() + self.batch.len()
+    }
+
+    /// Interactive executions are always picked first
+    ///
+    /// If `serve_batch` is false, batch executions are ignored.
+    fn recv(&self, serve_batch: bool) -> Option<ScriptCommand> {
+        if let Ok(command) = self.interactive.try_recv() {
+            return Some(command);
+        }
+
+        let result = match serve_batch {
+            true => Selector::new()
+                .recv(&self.interactive, |r| r)
+                .recv(&self.batch, |r| r)
+                .wait(),
+            false => self.interactive.recv(),
+        };
+
+        result.ok()
+    }
+}
+
+pub fn script_queues() -> (ScriptSender, ScriptReceiver) {
+    let (interactive_tx, interactive_rx) = flume::unbounded();
+    let (batch_tx, batch_rx) = flume::unbounded();
+
+    let sender = ScriptSender {
+        interactive: interactive_tx,
+        batch: batch_tx,
+    };
+
+    let receiver = ScriptReceiver {
+        interactive: interactive_rx,
+        batch: batch_rx,
+    };
+
+    (sender, receiver)
+}
+
 pub struct ScriptCommand {
     pub site: Arc<dyn Site>,
     pub script_name: PoolStr,
     pub read_only: bool,
+    pub priority: Priority,
+    pub template_defaults: Arc<TemplateDefaults>,
     pub path_vars: Vec<String>,
+    pub info: RequestInfo,
     pub body: OpaqueJsonPointer,
-    pub request: Request,
+    /// None for scheduled jobs
+    pub request: Option<Request>,
+    /// Set for the `Auth::Callback` guards of a request
+    pub guard: Option<GuardCheck>,
+}
+
+/// Runs the `Auth::Callback` guards of a request on a script thread, starting
+/// with `ScriptCommand::script_name`; if all of them return `true`, the
+/// request goes back to the request threads, which serve its route.
+pub struct GuardCheck {
+    /// Guards to run after the first one
+    pub then: Vec<PoolStr>,
+    /// Port of the listener which received the request
+    pub port: Option<u16>,
+    pub requests_tx: Sender<Incoming>,
 }
 
 pub enum ScriptResult {
     Template {
-        template: PoolStr,
+        /// None if the script didn't set one; the route's template is used then
+        template: Option<PoolStr>,
         parameters: LiteMap<PoolStr, String>,
     },
     Json(OpaqueJsonPointer),
+    /// Both: the renderer picks one based on the `Accept` header of the
+    /// request, see [`prefers_json`](super::renderer::prefers_json)
+    Negotiated {
+        /// None if the script didn't set one; the route's template is used then
+        template: Option<PoolStr>,
+        parameters: LiteMap<PoolStr, String>,
+        json_body: OpaqueJsonPointer,
+    },
+    /// HTML or plain text
+    Text {
+        content_type: &'static str,
+        text: String,
+    },
+    Bytes {
+        content_type: &'static str,
+        bytes: Vec<u8>,
+    },
+}
+
+/// Adds the route's template parameters to the ones set by a script
+fn with_defaults(mut parameters: LiteMap<PoolStr, String>, defaults: &TemplateDefaults) -> LiteMap<PoolStr, String> {
+    for (key, value) in defaults.parameters.iter() {
+        // script values win
+        let _ = parameters.try_insert(key.clone(), value.clone());
+    }
+
+    parameters
+}
+
+/// What to render for a script result; `None` if there's no template to render
+pub(crate) fn render_command(
+    site: &Arc<dyn Site>,
+    result: ScriptResult,
+    defaults: &TemplateDefaults,
+) -> Option<RendererCommand> {
+    let site = site.clone();
+    Some(match result {
+        ScriptResult::Template { template, parameters } => {
+            let template = template.or_else(|| defaults.template.clone())?;
+            RendererCommand::Template { site, template, parameters: with_defaults(parameters, defaults) }
+        },
+        ScriptResult::Json(json_body) => RendererCommand::Json { site, json_body },
+        ScriptResult::Negotiated { template, parameters, json_body } => match template.or_else(|| defaults.template.clone()) {
+            Some(template) => RendererCommand::Negotiated { site, template, parameters: with_defaults(parameters, defaults), json_body },
+            None => RendererCommand::Json { site, json_body },
+        },
+        ScriptResult::Text { content_type, text } => RendererCommand::Text { site, content_type, text },
+        ScriptResult::Bytes { content_type, bytes } => RendererCommand::Bytes { site, content_type, bytes },
+    })
 }
 
+/// If `serve_batch` is false, this thread is reserved to interactive executions
 pub fn script_runner(
-    runs_rx: Receiver<ScriptCommand>,
-    renders_tx: Sender<(Request, RendererCommand)>,
+    runs_rx: ScriptReceiver,
+    renders_tx: Sender<(Request, RequestInfo, RendererCommand)>,
+    serve_batch: bool,
     tid: usize,
 ) {
-    for cmd in runs_rx.into_iter() {
+    while let Some(cmd) = runs_rx.recv(serve_batch) {
+        if cmd.guard.is_some() {
+            check_guards(cmd, tid);
+            continue;
+        }
+
         let site = cmd.site;
-        let result = site.process_script(cmd.script_name, cmd.read_only, &cmd.path_vars, cmd.body, tid);
-        if let Ok(script_result) = result {
-            let render = match script_result {
-                ScriptResult::Template { template, parameters } => RendererCommand::Template { site, template, parameters },
-                ScriptResult::Json(json_body) => RendererCommand::Json { site, json_body },
-            };
-            let _ = renders_tx.send((cmd.request, render));
+        let script_name = cmd.script_name.clone();
+        let _log_context = log_context::enter(Some(site.hostname()), &cmd.info.id);
+
+        // the client has probably given up: don't waste a script thread on it
+        if cmd.info.expired() {
+            log::error!("[{}] Timed out in the script queue ({})", cmd.info.id, script_name);
+            let _ = site.dump_json(cmd.body, tid);
+            if let Some(request) = cmd.request {
+                respond_error(Some(&site), request, &cmd.info.id, Some(&cmd.info.url), 504);
+            }
+
+            continue;
         }
+
+        let mut span = trace::stage(&cmd.info.id, "script");
+        span.attribute("moth.script", &*script_name);
+        let flight = coalesce::PanicGuard(&cmd.info.id);
+        let result = site.process_script(cmd.script_name, cmd.read_only, &cmd.path_vars, &cmd.info, cmd.body, tid);
+        if result.is_err() {
+            span.fail();
+        }
+        core::mem::drop(flight);
+        core::mem::drop(span);
+
+        match (result, cmd.request) {
+            (Ok(script_result), Some(request)) if cmd.info.expired() => {
+                log::error!("[{}] Script {} exceeded its timeout", cmd.info.id, script_name);
+                if let ScriptResult::Json(json_body) | ScriptResult::Negotiated { json_body, .. } = script_result {
+                    let _ = site.dump_json(json_body, tid);
+                }
+
+                respond_error(Some(&site), request, &cmd.info.id, Some(&cmd.info.url), 504);
+            },
+            (Ok(script_result), Some(request)) => {
+                let Some(render) = render_command(&site, script_result, &cmd.template_defaults) else {
+                    log::error!("[{}] Script {} returned no JSON and set no template", cmd.info.id, script_name);
+                    respond_error(Some(&site), request, &cmd.info.id, Some(&cmd.info.url), 500);
+                    continue;
+                };
+
+                if let Err(e) = renders_tx.send((request, cmd.info, render)) {
+                    let (request, info, _render) = e.into_inner();
+                    log::error!("[{}] No renderer thread for script {}", info.id, script_name);
+                    respond_error(Some(&site), request, &info.id, Some(&info.url), 500);
+                }
+            },
+            (Ok(ScriptResult::Json(json_body) | ScriptResult::Negotiated { json_body, .. }), None) => {
+                // nobody to respond to, but the json must still be freed
+                let _ = site.dump_json(json_body, tid);
+            },
+            (Ok(ScriptResult::Template { .. } | ScriptResult::Text { .. } | ScriptResult::Bytes { .. }), None) => (),
+            (Err(()), Some(request)) => {
+                log::error!("[{}] Script {} failed", cmd.info.id, script_name);
+                let status = if cmd.info.expired() { 504 } else { 500 };
+                respond_error(Some(&site), request, &cmd.info.id, Some(&cmd.info.url), status);
+            },
+            (Err(()), None) => log::error!("[{}] Script {} failed", cmd.info.id, script_name),
+        }
+    }
+}
+
+fn check_guards(cmd: ScriptCommand, tid: usize) {
+    let ScriptCommand { site, script_name, path_vars, info, body, request, guard, .. } = cmd;
+    let (Some(request), Some(guard)) = (request, guard) else { return };
+    let _log_context = log_context::enter(Some(site.hostname()), &info.id);
+
+    if info.expired() {
+        log::error!("[{}] Timed out in the script queue ({})", info.id, script_name);
+        let _ = site.dump_json(body, tid);
+        return respond_error(Some(&site), request, &info.id, Some(&info.url), 504);
+    }
+
+    let mut body = Some(body);
+    for callback in core::iter::once(script_name).chain(guard.then) {
+        let body = match body.take() {
+            Some(body) => Ok(body),
+            None => site.parse_json("null", tid),
+        };
+
+        if !body.is_ok_and(|body| callback_accepts(&site, &callback, &path_vars, &info, body, tid)) {
+            log::info!("[{}] Unauthorized request from {} to {}", info.id, info.client_ip, info.route);
+            return unauthorized(Some(&site), request, &info.id, Some(&info.url), "Bearer");
+        }
+    }
+
+    let incoming = Incoming { request, port: guard.port, authorized: Some(info.id) };
+    if let Err(e) = guard.requests_tx.send(incoming) {
+        let Incoming { request, authorized, .. } = e.into_inner();
+        let request_id = authorized.unwrap(/* set above */);
+        log::error!("[{}] No request thread", request_id);
+        respond_error(Some(&site), request, &request_id, None, 503);
     }
 }
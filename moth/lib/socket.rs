@@ -0,0 +1,52 @@
+//! Read timeouts on the connections of requests
+//!
+//! tiny_http doesn't give access to the socket of a request, so it's found
+//! among the file descriptors of the process by its peer address. This is
+//! only done on Linux (`/proc/self/fd`); elsewhere, [`RequestSocket::find`]
+//! gives `None` and timeouts can only be checked once reads complete.
+//!
+//! Timeouts can't be set on listening sockets instead: connections would
+//! inherit them, but `accept` would time out too, stopping tiny_http.
+
+use tiny_http::Request;
+use std::{net::TcpStream, mem::ManuallyDrop, time::Duration};
+
+/// The TCP socket of a request, which tiny_http keeps owning; it must not
+/// outlive the request
+pub struct RequestSocket(ManuallyDrop<TcpStream>);
+
+impl RequestSocket {
+    /// Finds the socket of `request`, if it's a TCP connection
+    #[cfg(target_os = "linux")]
+    pub fn find(request: &Request) -> Option<Self> {
+        use std::os::fd::{FromRawFd, RawFd};
+
+        let peer = *request.remote_addr()?;
+        for entry in std::fs::read_dir("/proc/self/fd").ok()?.flatten() {
+            let Some(fd) = entry.file_name().to_str().and_then(|fd| fd.parse::<RawFd>().ok()) else { continue };
+
+            // SAFETY: the stream is never dropped, so it doesn't close the file
+            // descriptor; getpeername fails if it isn't a connected socket
+            let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+            if stream.peer_addr().ok() == Some(peer) {
+                return Some(Self(stream));
+            }
+        }
+
+        None
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn find(_request: &Request) -> Option<Self> {
+        None
+    }
+
+    /// Reads time out with `WouldBlock` or `TimedOut` errors; `None` disables it
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        // a zero duration would be rejected
+        let timeout = timeout.map(|timeout| timeout.max(Duration::from_millis(1)));
+        if let Err(e) = self.0.set_read_timeout(timeout) {
+            log::warn!("Failed to set the read timeout of a connection: {}", e);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Integration with systemd: socket activation & service notifications
+//!
+//! With socket activation (`LISTEN_FDS`), the server accepts connections on
+//! the TCP sockets passed by systemd instead of binding its own. Services of
+//! `Type=notify` are told `READY=1` once requests are served and, with
+//! `WatchdogSec=`, get `WATCHDOG=1` at half the watchdog interval.
+//!
+//! Outside of systemd, none of this has any effect.
+
+use std::{env, net::TcpListener, time::Duration};
+
+/// File descriptor of the first socket passed by systemd
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Listening sockets passed by systemd, if it started this process
+///
+/// The `LISTEN_*` variables are removed, so that child processes don't take
+/// the sockets too. Call this before starting other threads.
+#[cfg(unix)]
+pub fn listeners() -> Result<Vec<TcpListener>, ()> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    let for_us = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok()) == Some(std::process::id());
+    let count = env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<i32>().ok());
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+
+    let (true, Some(count)) = (for_us, count) else {
+        return Ok(Vec::new());
+    };
+
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count) {
+        // SAFETY: systemd passed this file descriptor to this process, which doesn't use it otherwise
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        let Ok(addr) = listener.local_addr() else {
+            // it's not a socket this function can take ownership of
+            let _ = listener.into_raw_fd();
+            log::error!("File descriptor {} passed by systemd isn't a TCP socket", fd);
+            return Err(());
+        };
+
+        log::info!("Listening on {} (socket activation)", addr);
+        listeners.push(listener);
+    }
+
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> Result<Vec<TcpListener>, ()> {
+    Ok(Vec::new())
+}
+
+/// Sends a state (`READY=1`, `WATCHDOG=1`...) to systemd, if it expects notifications
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::net::UnixDatagram;
+
+        let Some(socket) = env::var_os("NOTIFY_SOCKET") else { return };
+        let socket = socket.to_string_lossy();
+        let Ok(sender) = UnixDatagram::unbound() else { return };
+
+        let sent = match socket.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::{unix::net::SocketAddr, linux::net::SocketAddrExt};
+                SocketAddr::from_abstract_name(name).and_then(|addr| sender.send_to_addr(state.as_bytes(), &addr))
+            },
+            _ => sender.send_to(state.as_bytes(), &*socket),
+        };
+
+        if let Err(e) = sent {
+            log::warn!("Failed to notify systemd ({}): {}", state, e);
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+/// Interval within which systemd expects `WATCHDOG=1`, if it's enabled for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    let micros = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    match env::var("WATCHDOG_PID").ok().map(|pid| pid.parse::<u32>()) {
+        Some(Ok(pid)) if pid != std::process::id() => None,
+        _ => Some(Duration::from_micros(micros)),
+    }
+}
@@ -0,0 +1,332 @@
+use super::{Site, Arc, Endpoint, ScriptEndpoint, BodyMode, RequestInfo, StaticBody, accepts_method};
+use super::request::{resolve_route, surface_url, json_body, new_request_id, ip_allowed, failed_guard, header, Route, FORBIDDEN};
+use super::script::render_command;
+use super::{renderer::{self, JSON, HTML, XML, PLAIN_TEXT}, schema, coalesce::{self, Waiter}, response_cache};
+use tiny_http::Header;
+use std::{io::Read, sync::{mpsc, Mutex, Condvar}, time::Instant};
+
+/// Content types of script responses, to get them back from shared headers
+const CONTENT_TYPES: &[&str] = &[JSON, HTML, XML, PLAIN_TEXT];
+
+/// Runs requests through a site in-process, for end-to-end tests
+///
+/// Routing, body modes, scripts, templates, error documents, coalescing &
+/// response caching behave like in `serve`, except that uploads aren't
+/// supported, that CSRF tokens aren't checked and that scripts run on the
+/// calling thread, with the instance of an idle script thread (see
+/// [`Harness::with_script_threads`]).
+///
+/// Example: `assert_eq!(harness.post("/api/users", "{}").status, 200)`
+pub struct Harness {
+    site: Arc<dyn Site>,
+    authorization: Option<String>,
+    accept_language: String,
+    headers: Vec<(String, String)>,
+    site_port: Option<u16>,
+    /// Script threads which no request is using
+    idle_threads: Mutex<Vec<usize>>,
+    thread_freed: Condvar,
+}
+
+/// Script thread of a request, idle again once this is dropped
+struct ScriptThread<'a> {
+    harness: &'a Harness,
+    tid: usize,
+}
+
+impl Drop for ScriptThread<'_> {
+    fn drop(&mut self) {
+        self.harness.idle_threads.lock().unwrap().push(self.tid);
+        self.harness.thread_freed.notify_one();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResponse {
+    pub status: u16,
+    /// None for static assets & default error pages
+    pub content_type: Option<&'static str>,
+    pub body: Vec<u8>,
+    /// `ETag` header, for routes with `"etag": true`
+    pub etag: Option<String>,
+}
+
+impl TestResponse {
+    /// Body as text; invalid UTF-8 sequences are replaced with U+FFFD
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.content_type == Some(JSON)
+    }
+}
+
+impl Harness {
+    pub fn new(site: Box<dyn Site>) -> Self {
+        site.prepare_tls(1);
+        Self {
+            site: site.into(),
+            authorization: None,
+            accept_language: String::new(),
+            headers: Vec::new(),
+            site_port: None,
+            idle_threads: Mutex::new(vec![0]),
+            thread_freed: Condvar::new(),
+        }
+    }
+
+    /// Lets `threads` requests run scripts at the same time (default: 1),
+    /// for tests sending requests from several threads
+    pub fn with_script_threads(self, threads: usize) -> Self {
+        self.site.prepare_tls(threads);
+
+        // the instance of a script thread may be created from the one of the first
+        // thread, which must then be idle: they're all created before any request
+        for tid in 1..threads {
+            if let Ok(json) = self.site.parse_json("null", tid) {
+                let _ = self.site.dump_json(json, tid);
+            }
+        }
+
+        *self.idle_threads.lock().unwrap() = (0..threads).rev().collect();
+        self
+    }
+
+    /// Sets the `Authorization` header of requests, for `Endpoint::Protected` routes
+    pub fn with_authorization(mut self, authorization: &str) -> Self {
+        self.authorization = Some(authorization.into());
+        self
+    }
+
+    /// Sets the `Accept-Language` header of requests, to test translations
+    pub fn with_accept_language(mut self, accept_language: &str) -> Self {
+        self.accept_language = accept_language.into();
+        self
+    }
+
+    /// Adds a header to requests, for scripts reading it with `Request::header`
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sends requests as if they were received on a site port, to test [`Surface`](super::Surface)s
+    pub fn with_site_port(mut self, port: u16) -> Self {
+        self.site_port = Some(port);
+        self
+    }
+
+    pub fn get(&self, url: &str) -> TestResponse {
+        self.request("GET", url, b"")
+    }
+
+    pub fn post(&self, url: &str, json: &str) -> TestResponse {
+        self.request("POST", url, json.as_bytes())
+    }
+
+    pub fn request(&self, method: &str, url: &str, body: &[u8]) -> TestResponse {
+        let Some(url) = surface_url(&self.site, self.site_port, url) else {
+            return self.error(&RequestInfo { url: url.into(), ..Default::default() }, 404);
+        };
+
+        let url = url.as_str();
+        let Route { mut endpoint, path_vars, path_override, route, remainder, ip_filters, guards, .. } = resolve_route(&self.site, url);
+        if !ip_allowed(&ip_filters, "127.0.0.1") {
+            endpoint = &FORBIDDEN;
+        }
+
+        let mut info = RequestInfo {
+            id: new_request_id(),
+            method: method.to_ascii_uppercase(),
+            url: url.into(),
+            route,
+            remainder,
+            client_ip: "127.0.0.1".into(),
+            scheme: "http".into(),
+            accept_language: self.accept_language.clone(),
+            headers: self.headers.clone(),
+            ..Default::default()
+        };
+
+        let authorization = self.authorization.as_deref();
+        if failed_guard(&self.site, &guards, authorization, &info, 0).is_some() {
+            return self.error(&info, 401);
+        }
+
+        let path = path_override.as_deref();
+        let response = self.process(endpoint, path, path_vars, &mut info, body);
+        share(&info.id, &response);
+        response
+    }
+
+    /// Waits for an idle script thread
+    fn script_thread(&self) -> ScriptThread<'_> {
+        let mut idle = self.idle_threads.lock().unwrap();
+        loop {
+            match idle.pop() {
+                Some(tid) => return ScriptThread { harness: self, tid },
+                None => idle = self.thread_freed.wait(idle).unwrap(),
+            }
+        }
+    }
+
+    fn process(
+        &self,
+        endpoint: &Endpoint,
+        path_override: Option<&str>,
+        path_vars: Vec<String>,
+        info: &mut RequestInfo,
+        body: &[u8],
+    ) -> TestResponse {
+        match endpoint {
+            Endpoint::ScriptExec(ScriptEndpoint { read_only, script_name, template_defaults, body_mode, etag, timeout, coalesced, cached, schema, methods, max_body, .. }) => {
+                if !accepts_method(methods, &info.method) {
+                    return self.error(info, 405);
+                }
+
+                if max_body.is_some_and(|max| body.len() > max) {
+                    return self.error(info, 413);
+                }
+
+                let json = json_body(*body_mode, body);
+                if let Some(Err(mismatches)) = schema.as_ref().zip(json).map(|(schema, json)| schema.check(json)) {
+                    let body = schema::mismatches_json(&mismatches).into_bytes();
+                    return TestResponse { status: 422, content_type: Some(JSON), body, etag: None };
+                }
+
+                let site = &self.site;
+                let thread = self.script_thread();
+                let tid = thread.tid;
+                let Some(Ok(json_body)) = json.map(|json| site.parse_json(json, tid)) else {
+                    log::error!("[{}] Couldn't parse request body as {}", info.id, body_mode.name());
+                    return self.error(info, 400);
+                };
+
+                let revision = site.revision().filter(|_| *cached && response_cache::cacheable(info));
+                if let Some(revision) = revision {
+                    let key = coalesce::key(site.hostname(), info, response_cache::KEY_HEADERS, body);
+                    match response_cache::lookup(key, revision) {
+                        Some((headers, cached_body)) => {
+                            let _ = site.dump_json(json_body, tid);
+                            return not_modified(info, shared_response(200, &headers, cached_body.to_vec()));
+                        },
+                        None => response_cache::expect(&info.id, key, revision),
+                    }
+                }
+
+                if *coalesced {
+                    let (sender, receiver) = mpsc::channel();
+                    let key = coalesce::key(site.hostname(), info, coalesce::KEY_HEADERS, body);
+                    if coalesce::join(key, Waiter::Harness(sender), &info.id, Instant::now() + *timeout).is_none() {
+                        // the response of an identical request will be shared
+                        let _ = site.dump_json(json_body, tid);
+                        drop(thread);
+                        return match receiver.recv() {
+                            Ok((status, headers, body)) => shared_response(status, &headers, body),
+                            Err(_) => self.error(info, 503),
+                        };
+                    }
+                }
+
+                if let BodyMode::Text | BodyMode::Bytes = body_mode {
+                    info.body = body.into();
+                }
+
+                let request_id = info.id.clone();
+                let _flight = coalesce::PanicGuard(&request_id);
+                let result = site.process_script(script_name.clone(), *read_only, &path_vars, info, json_body, tid);
+                let Ok(result) = result else {
+                    log::error!("[{}] Script {} failed", info.id, script_name);
+                    return self.error(info, 500);
+                };
+
+                let Some(command) = render_command(site, result, template_defaults) else {
+                    log::error!("[{}] Script {} returned no JSON and set no template", info.id, script_name);
+                    return self.error(info, 500);
+                };
+
+                let Ok((content_type, body)) = command.render(info.header("Accept").unwrap_or(""), tid) else {
+                    return self.error(info, 500);
+                };
+
+                let etag = (*etag && matches!(info.method.as_str(), "GET" | "HEAD")).then(|| renderer::etag(&body));
+                not_modified(info, TestResponse { status: 200, content_type: Some(content_type), body, etag })
+            },
+            Endpoint::Static(path) => {
+                let path = path_override.unwrap_or(path);
+                match self.site.open_static(path) {
+                    Some(body) => {
+                        let body = match body {
+                            StaticBody::Memory(bytes) => bytes.to_vec(),
+                            StaticBody::File(mut file, _) => {
+                                let mut bytes = Vec::new();
+                                match file.read_to_end(&mut bytes) {
+                                    Ok(_) => bytes,
+                                    Err(_) => return self.error(info, 500),
+                                }
+                            },
+                        };
+
+                        TestResponse { status: 200, content_type: None, body, etag: None }
+                    },
+                    None if self.site.on_404() != endpoint => {
+                        self.process(self.site.on_404(), None, Vec::new(), info, body)
+                    },
+                    None => self.error(info, 500),
+                }
+            },
+            Endpoint::Document(content_type, body) => {
+                TestResponse { status: 200, content_type: Some(content_type), body: body.to_vec(), etag: None }
+            },
+            Endpoint::Upload(_) => {
+                log::error!("[{}] Uploads aren't supported by the test harness", info.id);
+                self.error(info, 501)
+            },
+            Endpoint::Error(code) => self.error(info, code.0),
+            Endpoint::Dir(_) | Endpoint::Protected { .. } => self.error(info, 500),
+        }
+    }
+
+    fn error(&self, info: &RequestInfo, status: u16) -> TestResponse {
+        let path = info.url.split('?').next().unwrap();
+        match self.site.error_document(status, path) {
+            Some((content_type, body)) => TestResponse { status, content_type: Some(content_type), body, etag: None },
+            None => TestResponse { status, content_type: None, body: include_str!("proc-failure.html").into(), etag: None },
+        }
+    }
+}
+
+/// A 304 instead of `response` if the request has a matching `If-None-Match` header
+fn not_modified(info: &RequestInfo, response: TestResponse) -> TestResponse {
+    let if_none_match = info.headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("If-None-Match"));
+    match (&response.etag, if_none_match) {
+        (Some(tag), Some((_, value))) if renderer::none_match(value, tag) => {
+            TestResponse { status: 304, content_type: None, body: Vec::new(), etag: response.etag }
+        },
+        _ => response,
+    }
+}
+
+/// Gives a response to the requests which waited for it & to the
+/// response cache, like `serve` does when responding
+fn share(request_id: &str, response: &TestResponse) {
+    let mut headers = Vec::new();
+    if let Some(content_type) = response.content_type {
+        headers.push(header("Content-Type", content_type));
+    }
+
+    if let Some(etag) = &response.etag {
+        headers.push(header("ETag", etag));
+    }
+
+    coalesce::release(request_id, response.status, &headers, &response.body);
+    response_cache::store(request_id, response.status, &headers, &response.body);
+}
+
+/// Response of an identical request, from the headers it was shared with
+fn shared_response(status: u16, headers: &[Header], body: Vec<u8>) -> TestResponse {
+    let value = |name| headers.iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str());
+    let content_type = value("Content-Type").and_then(|value| CONTENT_TYPES.iter().copied().find(|t| *t == value));
+    TestResponse { status, content_type, body, etag: value("ETag").map(String::from) }
+}
@@ -0,0 +1,237 @@
+//! Spans of requests through the request, script & render threads
+//!
+//! Once an [`Exporter`] is set, each request gets a trace whose root span
+//! lasts until its response is sent. The threads which handle the request
+//! then open a child span for their [`stage`] (`script`, `render`), and code
+//! running in these stages can open nested spans with [`span`]. Clients can
+//! make requests part of their own traces with a `traceparent` header.
+//!
+//! Without an exporter, none of this does anything. Scheduled jobs aren't traced.
+
+use std::{sync::{Mutex, OnceLock}, collections::VecDeque, cell::RefCell, time::SystemTime};
+
+/// Requests whose response is expected, but which never got one (panics)
+const MAX_PENDING: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    Str(String),
+    Int(i64),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        Self::Str(value.into())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        Self::Str(value)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+/// A finished span
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_id: Option<[u8; 8]>,
+    /// True for the span of a whole request
+    pub root: bool,
+    pub name: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, AttributeValue)>,
+    /// The operation failed (or the response has a 5xx status)
+    pub error: bool,
+}
+
+/// Sends finished spans somewhere; this must not block
+pub trait Exporter: Send + Sync {
+    fn export(&self, span: SpanData);
+}
+
+static EXPORTER: OnceLock<Box<dyn Exporter>> = OnceLock::new();
+
+/// Root spans of requests which have no response yet, by request ID
+static ROOTS: Mutex<VecDeque<(String, SpanData)>> = Mutex::new(VecDeque::new());
+
+thread_local! {
+    /// (trace ID, span ID) of the innermost open span of this thread
+    static CURRENT: RefCell<Option<([u8; 16], [u8; 8])>> = const { RefCell::new(None) };
+}
+
+/// Enables tracing; only the first exporter is kept
+pub fn set_exporter(exporter: Box<dyn Exporter>) {
+    if EXPORTER.set(exporter).is_err() {
+        log::warn!("A trace exporter was already set");
+    }
+}
+
+pub fn enabled() -> bool {
+    EXPORTER.get().is_some()
+}
+
+/// (trace ID, parent span ID) of a `traceparent` header: `00-<trace ID>-<span ID>-<flags>`
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, span_id) = (parts.next()?, parts.next()?, parts.next()?);
+    if version != "00" || parts.next().is_none() {
+        return None;
+    }
+
+    let trace_id: [u8; 16] = decode_hex(trace_id)?;
+    let span_id: [u8; 8] = decode_hex(span_id)?;
+    let valid = trace_id != [0; 16] && span_id != [0; 8];
+    valid.then_some((trace_id, span_id))
+}
+
+fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let mut bytes = [0; N];
+    if hex.len() != N * 2 {
+        return None;
+    }
+
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(bytes)
+}
+
+/// Opens the root span of a request, which [`end`] closes
+pub(crate) fn begin(request_id: &str, traceparent: Option<&str>, attributes: Vec<(&'static str, AttributeValue)>) {
+    if !enabled() {
+        return;
+    }
+
+    let (trace_id, parent_id) = match traceparent.and_then(parse_traceparent) {
+        Some((trace_id, parent_id)) => (trace_id, Some(parent_id)),
+        None => (rand::random(), None),
+    };
+
+    let root = SpanData {
+        trace_id,
+        span_id: rand::random(),
+        parent_id,
+        root: true,
+        name: "request".into(),
+        start: SystemTime::now(),
+        end: SystemTime::now(),
+        attributes,
+        error: false,
+    };
+
+    let mut roots = ROOTS.lock().unwrap();
+    if roots.len() == MAX_PENDING {
+        roots.pop_front();
+    }
+
+    roots.push_back((request_id.into(), root));
+}
+
+/// Closes the root span of a request, once its response is sent
+pub(crate) fn end(request_id: &str, status: u16) {
+    if !enabled() {
+        return;
+    }
+
+    let mut roots = ROOTS.lock().unwrap();
+    let Some(i) = roots.iter().position(|(id, _)| id == request_id) else { return };
+    let (_, mut root) = roots.remove(i).unwrap();
+    core::mem::drop(roots);
+
+    root.end = SystemTime::now();
+    root.error = status >= 500;
+    root.attributes.push(("http.response.status_code", (status as i64).into()));
+    export(root);
+}
+
+fn export(span: SpanData) {
+    if let Some(exporter) = EXPORTER.get() {
+        exporter.export(span);
+    }
+}
+
+/// An open span, which is exported when dropped
+///
+/// While it's open, spans opened by the same thread are its children.
+pub struct Span {
+    data: Option<SpanData>,
+    previous: Option<([u8; 16], [u8; 8])>,
+}
+
+impl Span {
+    const NONE: Self = Self { data: None, previous: None };
+
+    fn open(name: &str, trace_id: [u8; 16], parent_id: [u8; 8]) -> Self {
+        let data = SpanData {
+            trace_id,
+            span_id: rand::random(),
+            parent_id: Some(parent_id),
+            root: false,
+            name: name.into(),
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: Vec::new(),
+            error: false,
+        };
+
+        let previous = CURRENT.with(|current| current.borrow_mut().replace((trace_id, data.span_id)));
+        Self { data: Some(data), previous }
+    }
+
+    pub fn attribute(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    pub fn fail(&mut self) {
+        if let Some(data) = &mut self.data {
+            data.error = true;
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(mut data) = self.data.take() else { return };
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+
+        data.end = SystemTime::now();
+        export(data);
+    }
+}
+
+/// Opens the span of a thread's work for a request, as a child of its root span
+///
+/// Does nothing if the request isn't traced.
+pub fn stage(request_id: &str, name: &str) -> Span {
+    if !enabled() {
+        return Span::NONE;
+    }
+
+    let roots = ROOTS.lock().unwrap();
+    let root = roots.iter().find(|(id, _)| id == request_id);
+    let Some((trace_id, root_id)) = root.map(|(_, root)| (root.trace_id, root.span_id)) else { return Span::NONE };
+    core::mem::drop(roots);
+
+    Span::open(name, trace_id, root_id)
+}
+
+/// Opens a child of the current span of this thread, if there's one
+pub fn span(name: &str) -> Span {
+    match CURRENT.with(|current| *current.borrow()) {
+        Some((trace_id, parent_id)) => Span::open(name, trace_id, parent_id),
+        None => Span::NONE,
+    }
+}
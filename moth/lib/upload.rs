@@ -0,0 +1,180 @@
+//! Bodies of `Endpoint::Upload` routes
+//!
+//! The path items following the route select the operation:
+//! - `<token>`: the whole file, as the request body
+//! - `<token>/<index>`: the chunk `index` (from 0) of a chunked upload; chunks
+//!   must be sent in order. Resending a chunk which was received is accepted,
+//!   so that clients can retry when a response is lost; a later one gets a 409.
+//! - `<token>/progress`: `{ "chunks", "bytes", "max_bytes" }` received so far,
+//!   for clients to resume a chunked upload after a disconnect
+//! - `<token>/done`: ends a chunked upload
+//!
+//! A chunk which isn't fully received is dropped, and can be sent again.
+//! Sites can reject complete uploads: the client gets a 422 response with
+//! the reasons, as text.
+
+use super::{Arc, Site, UploadTimeouts, request::{respond_error, respond, response_headers, header}, log_context, socket::RequestSocket};
+use tiny_http::Request;
+use std::{time::Instant, io::ErrorKind};
+use sha2::{Sha256, Digest};
+use flume::Receiver;
+
+/// An upload request, waiting for an upload worker
+pub struct Upload {
+    pub site: Arc<dyn Site>,
+    pub token: String,
+    /// Path items after the token
+    pub operation: Option<String>,
+    pub request_id: String,
+    /// URL the request was routed with
+    pub url: String,
+    pub timeouts: UploadTimeouts,
+    pub request: Request,
+}
+
+/// Streams upload bodies, so that request threads don't wait for them
+pub fn upload_worker(uploads_rx: Receiver<Upload>) {
+    for upload in uploads_rx.into_iter() {
+        process_upload(upload);
+    }
+}
+
+/// (reason, status code)
+type Failure = (&'static str, u16);
+
+/// Reads a body of at most `max_len` bytes, passing it to `on_read` as it comes
+///
+/// The body ends with the connection if there's no `Content-Length`. Reads
+/// time out on the socket (see [`RequestSocket`]); if it can't be found, the
+/// timeouts are checked once reads complete.
+fn read_body(request: &mut Request, max_len: usize, timeouts: &UploadTimeouts, mut on_read: impl FnMut(&[u8])) -> Result<(), Failure> {
+    let expected = request.body_length();
+    if expected.is_some_and(|len| len > max_len) {
+        return Err(("Client tried to upload more than allowed", 413));
+    }
+
+    let chunk_size = 4096 * 4;
+
+    let socket = RequestSocket::find(request);
+    let reader = request.as_reader();
+    let mut buf = vec![0; chunk_size];
+    let mut received = 0;
+    let start = Instant::now();
+
+    loop {
+        let remaining = timeouts.total.saturating_sub(start.elapsed());
+        if let Some(socket) = &socket {
+            socket.set_read_timeout(Some(timeouts.read.min(remaining)));
+        }
+
+        let read_start = Instant::now();
+        let len = match reader.read(&mut buf) {
+            Ok(0) if expected.is_some_and(|len| received < len) => {
+                return Err(("Client closed the connection during upload", 400));
+            },
+            Ok(0) => break,
+            Ok(len) => len,
+            // the timeout stays set, so that tiny_http gives up on the rest of the body
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Err(("Upload timed out", 408)),
+            Err(_) => return Err(("Failed to process upload request", 400)),
+        };
+
+        if read_start.elapsed() > timeouts.read || start.elapsed() > timeouts.total {
+            return Err(("Upload timed out", 408));
+        }
+
+        received += len;
+        if received > max_len {
+            return Err(("Client tried to upload more than allowed", 413));
+        }
+
+        on_read(&buf[..len]);
+    }
+
+    // the connection can be kept alive for more requests
+    if let Some(socket) = &socket {
+        socket.set_read_timeout(None);
+    }
+
+    Ok(())
+}
+
+fn process_upload(upload: Upload) {
+    let Upload { site, token, operation, request_id, url, timeouts, mut request } = upload;
+    let _log_context = log_context::enter(Some(site.hostname()), &request_id);
+
+    let Some(max_len) = site.check_upload_token(&token) else {
+        log::error!("[{}] Invalid upload token/request", request_id);
+        return respond_error(Some(&site), request, &request_id, Some(&url), 400);
+    };
+
+    let result = match operation.as_deref() {
+        None => whole_upload(&site, &token, max_len, &timeouts, &mut request),
+        Some(operation) => match (site.chunked_progress(&token), operation) {
+            (None, _) => Err(("Not a chunked upload", 400)),
+            (Some((chunks, bytes)), "progress") => {
+                let json = format!("{{\"chunks\":{},\"bytes\":{},\"max_bytes\":{}}}", chunks, bytes, max_len);
+                let mut headers = response_headers(Some(&site), &request, &request_id, Some(&url));
+                headers.push(header("Content-Type", "application/json"));
+                return respond(request, &request_id, 200, headers, json.as_bytes());
+            },
+            (Some(_), "done") => Ok(()),
+            (Some((chunks, bytes)), index) => match index.parse::<usize>() {
+                // already received: the client didn't get the response
+                Ok(index) if index < chunks => Ok(()),
+                Ok(index) if index == chunks => chunk_upload(&site, &token, max_len.saturating_sub(bytes), &timeouts, &mut request),
+                Ok(_) => Err(("Upload chunks must be sent in order", 409)),
+                Err(_) => Err(("Invalid upload operation", 400)),
+            },
+        },
+    };
+
+    // whole uploads & the end of chunked ones
+    let ended = match (operation.as_deref(), &result) {
+        (None, _) | (Some("done"), Ok(())) => site.end_of_upload(&token, result.is_ok()),
+        _ => Ok(()),
+    };
+
+    if let Err((reason, code)) = result {
+        log::error!("[{}] {}", request_id, reason);
+        return respond_error(Some(&site), request, &request_id, Some(&url), code);
+    }
+
+    if let Err(reasons) = ended {
+        log::error!("[{}] Upload rejected", request_id);
+        let mut headers = response_headers(Some(&site), &request, &request_id, Some(&url));
+        headers.push(header("Content-Type", "text/plain; charset=utf-8"));
+        return respond(request, &request_id, 422, headers, reasons.as_bytes());
+    }
+
+    let headers = response_headers(Some(&site), &request, &request_id, Some(&url));
+    respond(request, &request_id, 200, headers, b"success");
+}
+
+fn whole_upload(site: &Arc<dyn Site>, token: &str, max_len: usize, timeouts: &UploadTimeouts, request: &mut Request) -> Result<(), Failure> {
+    let digest = site.upload_digest(token);
+    let mut hasher = Sha256::new();
+
+    let mut result = read_body(request, max_len, timeouts, |bytes| {
+        site.upload_progress(token, bytes);
+        if digest.is_some() {
+            hasher.update(bytes);
+        }
+    });
+
+    if let (Ok(()), Some(expected)) = (result, digest) {
+        if hasher.finalize()[..] != expected {
+            result = Err(("Upload digest mismatch", 400));
+        }
+    }
+
+    result
+}
+
+/// Chunks are kept until they're complete, then appended at once
+fn chunk_upload(site: &Arc<dyn Site>, token: &str, max_len: usize, timeouts: &UploadTimeouts, request: &mut Request) -> Result<(), Failure> {
+    let mut chunk = Vec::new();
+    read_body(request, max_len, timeouts, |bytes| chunk.extend_from_slice(bytes))?;
+    site.append_chunk(token, &chunk);
+    Ok(())
+}
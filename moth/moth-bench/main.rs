@@ -0,0 +1,249 @@
+//! Load tests of site bundles, served by an in-process server
+//!
+//! `moth-bench BUNDLE SCENARIO` runs each stage of the scenario in turn:
+//! concurrent clients send its request for `duration_secs`, then the
+//! throughput & latency percentiles of the stage are reported.
+//!
+//! ```json
+//! {
+//!     "connections": 8,
+//!     "duration_secs": 10,
+//!     "stages": [
+//!         { "name": "static", "method": "GET", "url": "/style.css" },
+//!         { "name": "json echo", "method": "POST", "url": "/api/echo", "body": "{\"text\":\"hi\"}" },
+//!         { "name": "db write", "method": "POST", "url": "/api/counters/bench/increment", "body": "{}" },
+//!         { "name": "db read", "method": "GET", "url": "/api/counters/bench" },
+//!         { "name": "template", "method": "GET", "url": "/" }
+//!     ]
+//! }
+//! ```
+//!
+//! Each stage is warmed up for a second first. The server uses `"auto"`
+//! thread counts; only warnings & errors are logged. `benches/` holds a
+//! site with these endpoints and this scenario.
+
+#![allow(clippy::unit_arg, clippy::result_unit_err, clippy::too_many_arguments)]
+
+/// The server, for its bundle loading; its `main` isn't used
+#[allow(dead_code)]
+#[path = "../moth-wasm/main.rs"]
+mod server;
+
+use server::{load_bundle, logging};
+use moth::{serve_all, Sites, ThreadCount};
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+use std::{net::{TcpListener, TcpStream}, thread, io::{copy, sink}, time::{Duration, Instant}};
+
+const HOSTNAME: &str = "bench.localhost";
+const WARMUP: Duration = Duration::from_secs(1);
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Stage {
+    name: String,
+    method: String,
+    url: String,
+    /// Sent as JSON if not empty
+    body: String,
+}
+
+struct Scenario {
+    connections: usize,
+    duration: Duration,
+    stages: Vec<Stage>,
+}
+
+impl Scenario {
+    fn parse(text: &str) -> Result<Self, ()> {
+        let file = JsonFile::new(Some(text)).map_err(|e| log::error!("Invalid scenario: {}", e))?;
+        let get = |path: &JsonPath| file.get(path);
+
+        let connections = match get(&JsonPath::new().i_str("connections")) {
+            JsonValue::Number(n) if *n >= 1.0 => *n as usize,
+            JsonValue::Null => 8,
+            _ => return Err(log::error!("Invalid scenario (connections must be a positive number)")),
+        };
+
+        let duration = match get(&JsonPath::new().i_str("duration_secs")) {
+            JsonValue::Number(secs) if *secs > 0.0 => Duration::from_secs_f64(*secs),
+            JsonValue::Null => Duration::from_secs(10),
+            _ => return Err(log::error!("Invalid scenario (duration_secs must be a positive number)")),
+        };
+
+        let stages_path = JsonPath::new().i_str("stages");
+        let JsonValue::Array(_) = get(&stages_path) else {
+            return Err(log::error!("Invalid scenario (stages must be an array)"));
+        };
+
+        let mut stages = Vec::new();
+        for (i, _, path) in file.iter_array(&stages_path) {
+            let get_str = |prop, default: Option<&str>| match (get(&path.clone().i_str(prop)), default) {
+                (JsonValue::String(s), _) => Ok(s.to_string()),
+                (JsonValue::Null, Some(default)) => Ok(default.to_string()),
+                _ => Err(log::error!("Invalid scenario (stage {}: {} must be a string)", i, prop)),
+            };
+
+            let url = get_str("url", None)?;
+            stages.push(Stage {
+                name: get_str("name", Some(&url))?,
+                method: get_str("method", Some("GET"))?,
+                body: get_str("body", Some(""))?,
+                url,
+            });
+        }
+
+        Ok(Self { connections, duration, stages })
+    }
+}
+
+/// Outcome of a stage
+#[derive(Default)]
+struct Report {
+    latencies: Vec<Duration>,
+    /// (status, responses)
+    statuses: Vec<(u16, usize)>,
+    /// Requests which got no response
+    failures: usize,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.latencies.extend(other.latencies);
+        self.failures += other.failures;
+        for (status, count) in other.statuses {
+            self.count(status, count);
+        }
+    }
+
+    fn count(&mut self, status: u16, count: usize) {
+        match self.statuses.iter_mut().find(|(s, _)| *s == status) {
+            Some((_, total)) => *total += count,
+            None => self.statuses.push((status, count)),
+        }
+    }
+
+    /// `percent`th percentile of the latencies; they must be sorted
+    fn percentile(&self, percent: usize) -> Duration {
+        let index = (self.latencies.len().saturating_sub(1) * percent) / 100;
+        self.latencies.get(index).copied().unwrap_or_default()
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [bundle, scenario] = args.as_slice() else {
+        return println!("Usage: moth-bench BUNDLE SCENARIO");
+    };
+
+    logging::init(log::LevelFilter::Warn);
+
+    let text = match std::fs::read_to_string(scenario) {
+        Ok(text) => text,
+        Err(e) => panic!("Failed to read scenario {}: {}", scenario, e),
+    };
+
+    let Ok(scenario) = Scenario::parse(&text) else {
+        std::process::exit(1);
+    };
+
+    let threads = ThreadCount::auto();
+    let sites = Sites::new(threads, threads, threads, ThreadCount::fixed(1));
+    sites.insert(Box::new(load_bundle(bundle, HOSTNAME)));
+
+    // the port of this listener is free once it's dropped
+    let address = TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr());
+    let address = address.expect("No free port for the bench server");
+    thread::spawn(move || serve_all(&[address], sites));
+
+    let started = Instant::now();
+    while TcpStream::connect(address).is_err() {
+        if started.elapsed() > STARTUP_TIMEOUT {
+            panic!("The bench server didn't start");
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let agent = ureq::AgentBuilder::new()
+        .max_idle_connections_per_host(scenario.connections)
+        .timeout(REQUEST_TIMEOUT)
+        .build();
+
+    let base = format!("http://{}", address);
+    println!("{} connections, {:?} per stage, {}-{} server threads of each kind", scenario.connections, scenario.duration, threads.min, threads.max);
+    println!("{:<20} {:>10} {:>10} {:>10} {:>10} {:>10}  statuses", "stage", "req/s", "p50", "p90", "p99", "max");
+
+    for stage in &scenario.stages {
+        run(&agent, &base, stage, scenario.connections, WARMUP);
+        let mut report = run(&agent, &base, stage, scenario.connections, scenario.duration);
+        report.latencies.sort();
+
+        let throughput = report.latencies.len() as f64 / scenario.duration.as_secs_f64();
+        let mut statuses: Vec<_> = report.statuses.iter().map(|(s, n)| format!("{}: {}", s, n)).collect();
+        if report.failures > 0 {
+            statuses.push(format!("failed: {}", report.failures));
+        }
+
+        println!(
+            "{:<20} {:>10.1} {:>10} {:>10} {:>10} {:>10}  {}",
+            stage.name,
+            throughput,
+            millis(report.percentile(50)),
+            millis(report.percentile(90)),
+            millis(report.percentile(99)),
+            millis(report.percentile(100)),
+            statuses.join(", "),
+        );
+    }
+}
+
+/// Sends the request of a stage from `connections` clients for `duration`
+fn run(agent: &ureq::Agent, base: &str, stage: &Stage, connections: usize, duration: Duration) -> Report {
+    let url = format!("{}{}", base, stage.url);
+    let start = Instant::now();
+
+    let client = || {
+        let mut report = Report::default();
+        while start.elapsed() < duration {
+            let sent = Instant::now();
+            let request = agent.request(&stage.method, &url).set("Host", HOSTNAME);
+            let result = match stage.body.is_empty() {
+                true => request.call(),
+                false => request.set("Content-Type", "application/json").send_string(&stage.body),
+            };
+
+            let response = match result {
+                Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+                Err(ureq::Error::Transport(_)) => {
+                    report.failures += 1;
+                    continue;
+                },
+            };
+
+            let status = response.status();
+            match copy(&mut response.into_reader(), &mut sink()) {
+                Ok(_) => {
+                    report.latencies.push(sent.elapsed());
+                    report.count(status, 1);
+                },
+                Err(_) => report.failures += 1,
+            }
+        }
+
+        report
+    };
+
+    thread::scope(|scope| {
+        let clients: Vec<_> = (0..connections).map(|_| scope.spawn(client)).collect();
+        let mut report = Report::default();
+        for client in clients {
+            report.merge(client.join().unwrap());
+        }
+
+        report
+    })
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,33 @@
+//! Content-addressed store of static assets, shared by all sites
+//!
+//! Sites built with the same frameworks tend to ship identical files;
+//! each distinct content is kept in memory once, as long as a site uses it.
+
+use sha2::{Sha256, Digest};
+use std::{collections::HashMap, sync::{Arc, Weak, Mutex, OnceLock}};
+
+type Store = HashMap<[u8; 32], Weak<[u8]>>;
+
+fn store() -> &'static Mutex<Store> {
+    static STORE: OnceLock<Mutex<Store>> = OnceLock::new();
+    STORE.get_or_init(Default::default)
+}
+
+/// Returns the stored copy of `content`, if another site has one
+pub fn intern(content: Box<[u8]>) -> Arc<[u8]> {
+    let digest: [u8; 32] = Sha256::digest(&content).into();
+    let mut store = store().lock().unwrap();
+
+    if let Some(stored) = store.get(&digest).and_then(Weak::upgrade) {
+        return stored;
+    }
+
+    // contents of removed sites
+    if store.len() >= 64 && store.len().is_power_of_two() {
+        store.retain(|_, weak| weak.strong_count() > 0);
+    }
+
+    let content: Arc<[u8]> = content.into();
+    store.insert(digest, Arc::downgrade(&content));
+    content
+}
@@ -0,0 +1,114 @@
+//! Bundle format v2: CPIO archives starting with a table of contents
+//!
+//! `bundle-index.json` gives the size & SHA-256 digest of each file, and
+//! whether it's stored deflated in the archive:
+//!
+//! ```json
+//! { "version": 2, "files": { "app.js": { "size": 52110, "sha256": "...", "compression": "deflate" } } }
+//! ```
+//!
+//! Files missing from the index (& all files of v1 bundles) are stored as is.
+//! Static assets stay compressed in memory until they're first requested.
+
+use super::{deploy::decode_hex, assets};
+use lmfu::{HashMap, json::{JsonFile, Path as JsonPath, Value as JsonValue}};
+use flate2::read::DeflateDecoder;
+use sha2::{Sha256, Digest};
+use std::{sync::{Arc, OnceLock}, io::Read};
+use core::str::from_utf8;
+
+pub const INDEX: &str = "bundle-index.json";
+
+#[derive(Copy, Clone)]
+pub struct IndexEntry {
+    size: usize,
+    sha256: [u8; 32],
+    deflated: bool,
+}
+
+impl IndexEntry {
+    /// Unpacked size
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+pub type Index = HashMap<str, IndexEntry>;
+
+pub fn parse_index(bytes: &[u8]) -> Result<Index, ()> {
+    let fail = || log::error!("Invalid {}", INDEX);
+    let json = from_utf8(bytes).map_err(|_| fail())?;
+    let json = JsonFile::new(Some(json)).map_err(|e| log::error!("Invalid {}: {}", INDEX, e))?;
+
+    if json.get(&JsonPath::new().i_str("version")).as_num() != Some(2.0) {
+        return Err(log::error!("Unsupported bundle version"));
+    }
+
+    let files_path = JsonPath::new().i_str("files");
+    let JsonValue::Object(names) = json.get(&files_path) else { return Err(fail()) };
+
+    let mut index = HashMap::new();
+    for name in names {
+        let path = files_path.clone().i_str(name);
+        let get_str = |prop| json.get(&path.clone().i_str(prop)).as_string().ok_or_else(fail);
+
+        let size = json.get(&path.clone().i_str("size")).as_num().ok_or_else(fail)? as usize;
+        let sha256 = decode_hex(get_str("sha256")?).ok_or_else(fail)?;
+        let deflated = match &**get_str("compression")? {
+            "deflate" => true,
+            "none" => false,
+            other => return Err(log::error!("Unsupported compression in {}: {}", INDEX, other)),
+        };
+
+        index.insert_ref(&**name, IndexEntry { size, sha256, deflated });
+    }
+
+    Ok(index)
+}
+
+/// Original content of a file, checked against its index entry
+pub fn unpack(name: &str, stored: &[u8], entry: Option<&IndexEntry>) -> Result<Box<[u8]>, ()> {
+    let Some(entry) = entry else { return Ok(stored.into()) };
+
+    let content = match entry.deflated {
+        true => {
+            let mut content = Vec::with_capacity(entry.size);
+            let result = DeflateDecoder::new(stored).take(entry.size as u64 + 1).read_to_end(&mut content);
+            result.map_err(|e| log::error!("Couldn't decompress {}: {}", name, e))?;
+            content.into_boxed_slice()
+        },
+        false => stored.into(),
+    };
+
+    if content.len() != entry.size || Sha256::digest(&content)[..] != entry.sha256 {
+        return Err(log::error!("Corrupted bundle file: {}", name));
+    }
+
+    Ok(content)
+}
+
+/// Static asset, possibly still compressed
+pub struct Asset {
+    stored: Arc<[u8]>,
+    entry: Option<IndexEntry>,
+    content: OnceLock<Option<Arc<[u8]>>>,
+}
+
+impl Asset {
+    pub fn new(stored: Box<[u8]>, entry: Option<IndexEntry>) -> Self {
+        Self { stored: assets::intern(stored), entry, content: OnceLock::new() }
+    }
+
+    /// Unpacks the asset on first use; None if it's corrupted
+    pub fn content(&self, name: &str) -> Option<Arc<[u8]>> {
+        if self.entry.is_none() {
+            return Some(self.stored.clone());
+        }
+
+        let content = self.content.get_or_init(|| {
+            unpack(name, &self.stored, self.entry.as_ref()).ok().map(assets::intern)
+        });
+
+        content.clone()
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::{HashMap, BTreeMap};
+use std::time::{Duration, Instant};
+use std::sync::Mutex;
+
+struct Entry {
+    value: Vec<u8>,
+    expires: Option<Instant>,
+    last_use: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, Entry>,
+    /// last_use => key, least recently used first
+    lru: BTreeMap<u64, String>,
+    size: usize,
+    clock: u64,
+}
+
+/// Key-value cache of a site, shared by its script threads
+///
+/// Least recently used entries are evicted when the total
+/// size of keys & values would exceed `capacity` bytes.
+pub struct Cache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(Entries::default()),
+            capacity,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        entries.clock += 1;
+
+        let entry = entries.map.get_mut(key)?;
+        if entry.expires.map(|e| e <= Instant::now()) == Some(true) {
+            entries.remove(key);
+            return None;
+        }
+
+        let key = entries.lru.remove(&entry.last_use).unwrap(/* kept in sync with map */);
+        entry.last_use = entries.clock;
+        entries.lru.insert(entry.last_use, key);
+
+        Some(entry.value.clone())
+    }
+
+    /// A `ttl` of zero keeps the entry until it's evicted
+    pub fn put(&self, key: &str, value: Vec<u8>, ttl: Duration) {
+        let size = key.len() + value.len();
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(key);
+
+        if size > self.capacity {
+            return;
+        }
+
+        while entries.size + size > self.capacity {
+            let (_, oldest) = entries.lru.pop_first().unwrap(/* size > 0 */);
+            let entry = entries.map.remove(&oldest).unwrap(/* kept in sync with lru */);
+            entries.size -= oldest.len() + entry.value.len();
+        }
+
+        entries.clock += 1;
+        let entry = Entry {
+            value,
+            expires: (!ttl.is_zero()).then(|| Instant::now() + ttl),
+            last_use: entries.clock,
+        };
+
+        entries.size += size;
+        entries.lru.insert(entry.last_use, key.to_string());
+        entries.map.insert(key.to_string(), entry);
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    pub fn clear(&self) {
+        *self.entries.lock().unwrap() = Entries::default();
+    }
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.map.remove(key) {
+            self.lru.remove(&entry.last_use);
+            self.size -= key.len() + entry.value.len();
+        }
+    }
+}
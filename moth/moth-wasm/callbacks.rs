@@ -0,0 +1,167 @@
+//! Deploy-time check of the callbacks named in config.json
+//!
+//! `#[moth_callback]` records the name & arity (path variables) of each
+//! callback in the `moth_callbacks` custom section of site.wasm, as
+//! `name:arity\n` entries. Before instantiating a site, its routes, jobs,
+//! subscriptions, migrations, services & export callbacks are checked
+//! against them, so that a bundle with a typo in a callback name is rejected
+//! instead of failing with "Missing callback" at request time.
+//!
+//! Modules built without the section (older `moth-wasm`, or a stripped
+//! binary) aren't checked.
+
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+use super::crawling::{SPECIAL_KEYS, script_access, is_script_object};
+
+pub const SECTION: &str = "moth_callbacks";
+
+/// Callbacks of a module, with their arity
+pub struct Callbacks(Vec<(String, usize)>);
+
+/// A callback named in config.json
+struct Use {
+    callback: String,
+    arity: usize,
+    /// Where it's named, for errors
+    usage: String,
+}
+
+impl Callbacks {
+    /// Reads the `moth_callbacks` section of a module; `None` if it has none
+    pub fn parse(wasm: &[u8]) -> Result<Option<Self>, ()> {
+        let fail = || log::error!("Invalid site.wasm (malformed module)");
+        let mut data = wasm.strip_prefix(b"\0asm").ok_or_else(fail)?.get(4..).ok_or_else(fail)?;
+        let mut entries = Vec::new();
+        let mut found = false;
+
+        while let Some((&id, rest)) = data.split_first() {
+            let (size, rest) = leb128(rest).ok_or_else(fail)?;
+            let content = rest.get(..size).ok_or_else(fail)?;
+            data = &rest[size..];
+
+            // custom sections have id 0 and start with their name
+            if id != 0 {
+                continue;
+            }
+
+            let (name_len, content) = leb128(content).ok_or_else(fail)?;
+            if content.get(..name_len) == Some(SECTION.as_bytes()) {
+                entries.extend_from_slice(&content[name_len..]);
+                found = true;
+            }
+        }
+
+        if !found {
+            return Ok(None);
+        }
+
+        let fail = || log::error!("Invalid {} section in site.wasm", SECTION);
+        let entries = std::str::from_utf8(&entries).map_err(|_| fail())?;
+        let mut callbacks = Vec::new();
+        for entry in entries.lines() {
+            let (name, arity) = entry.split_once(':').ok_or_else(fail)?;
+            callbacks.push((name.to_string(), arity.parse().map_err(|_| fail())?));
+        }
+
+        Ok(Some(Self(callbacks)))
+    }
+
+    /// Logs each callback of config.json which is missing or has a different arity
+    pub fn check(&self, file: &JsonFile) -> Result<(), ()> {
+        let mut uses = Vec::new();
+        let root = JsonPath::new();
+        walk(file, &root.clone().i_str("routes"), "routes", 0, &mut uses);
+        walk(file, &root.clone().i_str("on_404"), "on_404", 0, &mut uses);
+
+        for (_, _, job_path) in file.iter_array(&root.clone().i_str("jobs")) {
+            add_use(file, &job_path.i_str("callback"), 0, "jobs", &mut uses);
+        }
+
+        for (_, _, item_path) in file.iter_array(&root.clone().i_str("migrations")) {
+            add_use(file, &item_path, 0, "migrations", &mut uses);
+        }
+
+        for (key, section) in [("subscriptions", "subscriptions"), ("export", "export")] {
+            let path = root.clone().i_str(key);
+            let JsonValue::Object(keys) = file.get(&path) else { continue };
+            for key in keys.iter() {
+                // export callbacks get the values of the previous steps
+                let arity = match section {
+                    "export" => key.matches("[param]").count().saturating_sub(1),
+                    _ => 0,
+                };
+
+                add_use(file, &path.clone().i_str(key), arity, &format!("{} of {}", section, key), &mut uses);
+            }
+        }
+
+        // services are named by their keys
+        if let JsonValue::Object(keys) = file.get(&root.i_str("services")) {
+            for key in keys.iter() {
+                uses.push(Use { callback: key.to_string(), arity: 0, usage: "services".into() });
+            }
+        }
+
+        let mut valid = true;
+        for Use { callback, arity, usage } in uses {
+            match self.0.iter().find(|(name, _)| *name == callback) {
+                None => log::error!("Invalid bundle: callback {} ({}) is not in site.wasm", callback, usage),
+                Some((_, found)) if *found != arity => log::error!(
+                    "Invalid bundle: callback {} ({}) must take {} path variable(s), but it takes {}",
+                    callback, usage, arity, found,
+                ),
+                Some(_) => continue,
+            }
+
+            valid = false;
+        }
+
+        valid.then_some(()).ok_or(())
+    }
+}
+
+/// Reads an unsigned LEB128 number
+fn leb128(data: &[u8]) -> Option<(usize, &[u8])> {
+    let mut value = 0;
+    for (i, byte) in data.iter().enumerate().take(5) {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &data[i + 1..]));
+        }
+    }
+
+    None
+}
+
+fn add_use(file: &JsonFile, path: &JsonPath, arity: usize, usage: &str, uses: &mut Vec<Use>) {
+    if let JsonValue::String(callback) = file.get(path) {
+        uses.push(Use { callback: callback.to_string(), arity, usage: usage.to_string() });
+    }
+}
+
+/// Script routes get the values of their `[param]` steps; auth callbacks get the header value
+fn walk(file: &JsonFile, path: &JsonPath, route: &str, params: usize, uses: &mut Vec<Use>) {
+    match script_access(file, path) {
+        Some("[upload]") => return,
+        Some(_) => {
+            let (callback_path, options) = match is_script_object(file, path) {
+                true => (path.clone().i_str("callback"), path.clone()),
+                false => (path.clone().i_num(1), path.clone().i_num(2)),
+            };
+
+            add_use(file, &callback_path, params, route, uses);
+            add_use(file, &options.clone().i_str("auth"), 1, &format!("auth of {}", route), uses);
+            add_use(file, &options.i_str("auth").i_str("callback"), 1, &format!("auth of {}", route), uses);
+            return;
+        },
+        None => (),
+    }
+
+    let JsonValue::Object(keys) = file.get(path) else { return };
+    add_use(file, &path.clone().i_str("[auth]").i_str("callback"), 1, &format!("[auth] of {}", route), uses);
+
+    for key in keys.iter().filter(|k| !SPECIAL_KEYS.contains(&&***k)) {
+        let params = params + (&**key == "[param]") as usize;
+        walk(file, &path.clone().i_str(key), &format!("{}/{}", route, key), params, uses);
+    }
+}
@@ -0,0 +1,90 @@
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+use std::time::Duration;
+
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Provider {
+    HCaptcha,
+    Turnstile,
+    ReCaptcha,
+}
+
+impl Provider {
+    pub fn parse(provider: &str) -> Result<Self, ()> {
+        match provider {
+            "hcaptcha" => Ok(Self::HCaptcha),
+            "turnstile" => Ok(Self::Turnstile),
+            "recaptcha" => Ok(Self::ReCaptcha),
+            _ => Err(()),
+        }
+    }
+
+    fn verify_url(self) -> &'static str {
+        match self {
+            Self::HCaptcha => "https://api.hcaptcha.com/siteverify",
+            Self::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            Self::ReCaptcha => "https://www.google.com/recaptcha/api/siteverify",
+        }
+    }
+}
+
+/// Server-side verification of CAPTCHA responses
+pub struct Captcha {
+    provider: Provider,
+    secret: String,
+}
+
+impl Captcha {
+    pub fn parse(file: &JsonFile, path: &JsonPath) -> Result<Option<Self>, ()> {
+        match file.get(path) {
+            JsonValue::Object(_) => (),
+            JsonValue::Null => return Ok(None),
+            _ => return Err(log::error!("Invalid captcha config (must be an object)")),
+        }
+
+        let get_str = |prop| match file.get(&path.clone().i_str(prop)) {
+            JsonValue::String(s) => Ok(s),
+            _ => Err(log::error!("Invalid captcha config ({} must be a string)", prop)),
+        };
+
+        let provider = match Provider::parse(get_str("provider")?) {
+            Ok(provider) => Ok(provider),
+            Err(()) => Err(log::error!("Invalid captcha provider (must be hcaptcha/turnstile/recaptcha)")),
+        }?;
+
+        Ok(Some(Self {
+            provider,
+            secret: get_str("secret")?.to_string(),
+        }))
+    }
+
+    /// Asks the provider if `token` (sent by the browser widget) is valid
+    ///
+    /// Any failure to reach the provider counts as a rejection.
+    pub fn verify(&self, token: &str) -> bool {
+        if token.is_empty() {
+            return false;
+        }
+
+        let form = [("secret", self.secret.as_str()), ("response", token)];
+        let request = ureq::post(self.provider.verify_url()).timeout(VERIFY_TIMEOUT);
+
+        let reply = match request.send_form(&form).map(|r| r.into_string()) {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(e)) => {
+                log::error!("Invalid captcha verification reply: {:?}", e);
+                return false;
+            },
+            Err(e) => {
+                log::error!("Captcha verification failed: {:?}", e);
+                return false;
+            },
+        };
+
+        match JsonFile::new(Some(&reply)) {
+            Ok(reply) => matches!(reply.get(&JsonPath::new().i_str("success")), JsonValue::Boolean(true)),
+            Err(_) => false,
+        }
+    }
+}
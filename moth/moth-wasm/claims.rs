@@ -0,0 +1,97 @@
+//! Proof of ownership of the hostnames claimed on the deployment server
+//!
+//! The first upload for a hostname binds it to the admin key of the upload.
+//! With the `claims` server config, a hostname can only be claimed if the
+//! server admin allowed it, or if its DNS zone has a TXT record binding it
+//! to the key, which `cargo moth claim` prints:
+//!
+//! ```text
+//! _moth-claim.blog.example.com. TXT "moth-claim=<sha256 of the admin key>"
+//! ```
+//!
+//! Records are looked up with a DNS-over-HTTPS resolver (JSON API).
+
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+use sha2::{Sha256, Digest};
+use std::time::Duration;
+
+pub const DEFAULT_RESOLVER: &str = "https://cloudflare-dns.com/dns-query";
+const RECORD_PREFIX: &str = "_moth-claim";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// DNS type of TXT records
+const TXT: f64 = 16.0;
+
+/// The `claims` object of the server config
+pub struct ClaimPolicy {
+    /// Hostnames which can be claimed without a DNS record; `*.example.com` matches subdomains
+    pub allow: Vec<String>,
+    /// DNS-over-HTTPS resolver for TXT records; `None` if they aren't accepted
+    pub resolver: Option<String>,
+}
+
+impl ClaimPolicy {
+    /// Checks that the owner of `key` can claim `hostname`
+    pub fn check(&self, hostname: &str, key: &[u8; 32]) -> Result<(), ()> {
+        let hostname = hostname.to_ascii_lowercase();
+        if self.allow.iter().any(|pattern| hostname_matches(pattern, &hostname)) {
+            return Ok(());
+        }
+
+        let Some(resolver) = &self.resolver else {
+            return Err(log::error!("Hostname {} isn't allowed on this server", hostname));
+        };
+
+        let name = format!("{}.{}", RECORD_PREFIX, hostname);
+        let expected = txt_value(key);
+        match lookup_txt(resolver, &name)?.contains(&expected) {
+            true => Ok(()),
+            false => Err(log::error!("Hostname {} isn't claimed: {} must have a TXT record \"{}\"", hostname, name, expected)),
+        }
+    }
+}
+
+/// Whether `hostname` is `pattern`, or one of its subdomains if it looks like `*.example.com`
+pub fn hostname_matches(pattern: &str, hostname: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => hostname.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.') && sub.len() > 1),
+        None => pattern == hostname,
+    }
+}
+
+/// Content of the TXT record binding a hostname to an admin key
+pub fn txt_value(key: &[u8; 32]) -> String {
+    let digest = Sha256::digest(key);
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("moth-claim={}", hex)
+}
+
+/// TXT records of `name`, without their quotes
+fn lookup_txt(resolver: &str, name: &str) -> Result<Vec<String>, ()> {
+    let request = ureq::get(resolver)
+        .timeout(LOOKUP_TIMEOUT)
+        .set("Accept", "application/dns-json")
+        .query("name", name)
+        .query("type", "TXT");
+
+    let reply = match request.call().map(|r| r.into_string()) {
+        Ok(Ok(reply)) => reply,
+        Ok(Err(e)) => return Err(log::error!("Invalid DNS reply for {}: {:?}", name, e)),
+        Err(e) => return Err(log::error!("DNS lookup of {} failed: {:?}", name, e)),
+    };
+
+    let reply = JsonFile::new(Some(&reply)).map_err(|_| log::error!("Invalid DNS reply for {}", name))?;
+    let answers = JsonPath::new().i_str("Answer");
+    let mut records = Vec::new();
+    for (_, _, answer) in reply.iter_array(&answers) {
+        let is_txt = matches!(reply.get(&answer.clone().i_str("type")), JsonValue::Number(t) if *t == TXT);
+        if let (true, JsonValue::String(data)) = (is_txt, reply.get(&answer.i_str("data"))) {
+            // long records are split in quoted strings
+            match data.contains('"') {
+                true => records.push(data.split('"').skip(1).step_by(2).collect()),
+                false => records.push(data.to_string()),
+            }
+        }
+    }
+
+    Ok(records)
+}
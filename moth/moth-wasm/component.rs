@@ -0,0 +1,754 @@
+//! Component-model guests, implementing the `site` world of moth-abi/wit/moth.wit
+//!
+//! Engines only run core modules here: the main module of a component (the one
+//! exporting `callback`) is instantiated on its own, and the imports of the
+//! world are lowered onto the host functions of [`super::handle`], following
+//! the canonical ABI:
+//! - strings & lists are `(ptr, len)` pairs of `i32`s;
+//! - results which don't fit in a value are written at a return pointer,
+//!   given as the last parameter;
+//! - buffers given to the guest are allocated with its `cabi_realloc`;
+//! - `document` resources are the handles of [`super::host_json`].
+//!
+//! The other modules of a component aren't instantiated: the shim & fixup
+//! modules only route the imports of the main module, and WASI preview 1 is
+//! provided directly, as for core modules, in place of the adapter which
+//! wit-component adds for `wasm32-wasip1` guests. Components whose main
+//! module imports anything else (guests built for `wasm32-wasip2`, other
+//! adapters) are rejected.
+
+use lmfu::json::JsonFile;
+use moth::OpaqueJsonPointer;
+use super::wasm::{Engine, Runtime, Module, Instance, Func, TypedFunc, Memory, WasmParams, WasmResults, IntoFunc, F64};
+use super::wasm::{Caller, Linker, Store, Trap, trap, export};
+use super::handle::{self, Handle};
+
+/// Magic number & version of component binaries (core modules have version 1)
+const MAGIC: &[u8] = b"\0asm\x0d\0\x01\0";
+
+const CORE_MODULE_SECTION: u8 = 1;
+
+pub const REQUEST: &str = "moth:site/request@1.0.0";
+pub const TABLES: &str = "moth:site/tables@1.0.0";
+pub const CACHE: &str = "moth:site/cache@1.0.0";
+pub const TEMPLATE: &str = "moth:site/template@1.0.0";
+
+pub const REALLOC: &str = "cabi_realloc";
+pub const INIT: &str = "init";
+pub const CALLBACK: &str = "callback";
+pub const POST_CALLBACK: &str = "cabi_post_callback";
+
+/// `fn(old_ptr, old_size, align, new_size) -> ptr`
+pub type Realloc = TypedFunc<(i32, i32, i32, i32), (i32,)>;
+
+/// `fn(name_ptr, name_len, body_ptr, body_len, params_ptr, params_len) -> retptr`
+type Callback = TypedFunc<(i32, i32, i32, i32, i32, i32), (i32,)>;
+
+/// Functions of the world which the host provides, by interface
+const IMPORTS: &[(&str, &[&str])] = &[
+    (REQUEST, &[
+        "id", "method", "url", "route", "remainder", "routes", "subdomain", "client-ip", "scheme",
+        "header", "body", "csrf-token", "locale", "translate", "absolute-url", "verify-captcha",
+        "verify-webhook", "send-email", "env", "call-service", "publish", "upload-token",
+    ]),
+    (TABLES, &[
+        "read-entry", "read-entries", "write-entry", "increment-counter", "entry-hash", "cas-entry",
+        "patch-table-entry", "entry-history", "read-table-entry-at", "sql-query", "search-table",
+        "query", "read-page", "write-blob", "read-blob", "read-blob-type", "erase-subject", "sync-status",
+        "[constructor]document", "[static]document.open", "[method]document.get-string",
+        "[method]document.get-number", "[method]document.set-string", "[method]document.set-number",
+        "[method]document.save", "[resource-drop]document",
+    ]),
+    (CACHE, &["get", "put"]),
+    (TEMPLATE, &["set-name", "set-param", "set-param-raw"]),
+];
+
+pub fn is_component(bytes: &[u8]) -> bool {
+    bytes.get(..MAGIC.len()) == Some(MAGIC)
+}
+
+pub fn provides(interface: &str, name: &str) -> bool {
+    IMPORTS.iter().any(|(i, names)| *i == interface && names.contains(&name))
+}
+
+fn read_leb128(bytes: &[u8], offset: &mut usize) -> Option<usize> {
+    let mut value = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes.get(*offset)?;
+        *offset += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Core modules at the top level of a component, in order
+fn core_modules(bytes: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut modules = Vec::new();
+    let mut offset = MAGIC.len();
+
+    while offset < bytes.len() {
+        let id = bytes[offset];
+        offset += 1;
+        let size = read_leb128(bytes, &mut offset)?;
+        let content = bytes.get(offset..offset.checked_add(size)?)?;
+        offset += size;
+
+        if id == CORE_MODULE_SECTION {
+            modules.push(content);
+        }
+    }
+
+    Some(modules)
+}
+
+/// Compiles the module of a component which exports `callback`
+pub fn main_module(bytes: &[u8], metered: bool) -> Result<Module, String> {
+    let modules = core_modules(bytes).ok_or("Invalid component")?;
+    for module in modules {
+        let module = Engine::compile(module, metered)?;
+        if module.exports().any(|export| export.name() == CALLBACK) {
+            return match adapted_import(&module) {
+                Some(import) => Err(format!("The component's main module imports {}, which an adapter module provides: \
+                    only WASI preview 1 is supported, build for wasm32-wasip1", import)),
+                None => Ok(module),
+            };
+        }
+    }
+
+    Err(format!("The component has no module exporting {}", CALLBACK))
+}
+
+/// An import which neither the world nor WASI preview 1 provides, as `module::name`
+fn adapted_import(module: &Module) -> Option<String> {
+    let provided = |module: &str| module == super::wasi::MODULE || IMPORTS.iter().any(|(i, _)| *i == module);
+    let mut imports = module.imports().filter(|import| !provided(import.module()));
+    imports.next().map(|import| format!("{}::{}", import.module(), import.name()))
+}
+
+fn addr(value: i32) -> u64 {
+    value as u32 as u64
+}
+
+fn memory(caller: &Caller) -> Memory {
+    caller.data().mem.unwrap()
+}
+
+fn read(caller: &Caller, ptr: u64, len: usize) -> Result<Vec<u8>, Trap> {
+    let range = (ptr as usize)..(ptr as usize + len);
+    let bytes = memory(caller).data(caller).get(range).map(<[u8]>::to_vec);
+    bytes.ok_or_else(|| trap("Invalid Pointer"))
+}
+
+fn write(caller: &mut Caller, ptr: u64, bytes: &[u8]) -> Result<(), Trap> {
+    memory(caller).write(&mut *caller, ptr as _, bytes).map_err(|e| trap(format!("{:?}", e)))
+}
+
+fn alloc(caller: &mut Caller, align: i32, bytes: &[u8]) -> Result<u64, Trap> {
+    let ptr = addr(handle::guest_realloc(caller, align, bytes.len() as i32)?);
+    write(caller, ptr, bytes)?;
+    Ok(ptr)
+}
+
+fn write_pair(caller: &mut Caller, at: u64, ptr: u64, len: u64) -> Result<(), Trap> {
+    let mut pair = [0; 8];
+    pair[..4].copy_from_slice(&(ptr as u32).to_le_bytes());
+    pair[4..].copy_from_slice(&(len as u32).to_le_bytes());
+    write(caller, at, &pair)
+}
+
+/// Lowers the output of a host function which wrote its length at `retptr`
+///
+/// The `u64` length & the `(ptr, len)` pair replacing it both fill the
+/// 8 bytes of the return area of a string or list.
+fn store_buffer(caller: &mut Caller, retptr: u64, ptr: u64) -> Result<(), Trap> {
+    let len = u64::from_le_bytes(read(caller, retptr, 8)?.try_into().unwrap());
+
+    let mem_len = memory(caller).data(&*caller).len() as u64;
+    if ptr.checked_add(len).is_none_or(|end| end > mem_len) {
+        return Err(trap(format!("Invalid buffer: {} bytes at {}", len, ptr)));
+    }
+
+    write_pair(caller, retptr, ptr, len)
+}
+
+/// Like [`store_buffer`], for options: a zero pointer is `none`, the
+/// host function wrote the length at `retptr + 4`
+fn store_option_buffer(caller: &mut Caller, retptr: u64, ptr: u64) -> Result<(), Trap> {
+    if ptr != 0 {
+        store_buffer(caller, retptr + 4, ptr)?;
+    }
+
+    write(caller, retptr, &[(ptr != 0) as u8])
+}
+
+/// Lowers JSON written by [`Handle::write_guest_json`]; a zero pointer is `null`
+fn store_json(caller: &mut Caller, retptr: u64, ptr: u64) -> Result<(), Trap> {
+    match ptr {
+        0 => {
+            let ptr = alloc(caller, 1, b"null")?;
+            write_pair(caller, retptr, ptr, 4)
+        },
+        ptr => {
+            let len = caller.data().json_len.get();
+            write_pair(caller, retptr, ptr, len)
+        },
+    }
+}
+
+fn store_option_json(caller: &mut Caller, retptr: u64, ptr: u64) -> Result<(), Trap> {
+    if ptr != 0 {
+        store_json(caller, retptr + 4, ptr)?;
+    }
+
+    write(caller, retptr, &[(ptr != 0) as u8])
+}
+
+/// Strings of a lowered `list<string>`
+fn read_strings(caller: &Caller, ptr: i32, len: i32) -> Result<Vec<String>, Trap> {
+    let pairs = read(caller, addr(ptr), len as u32 as usize * 8)?;
+    let fail = |_| trap("Invalid Bytes");
+
+    let mut strings = Vec::new();
+    for pair in pairs.chunks(8) {
+        let ptr = u32::from_le_bytes(pair[..4].try_into().unwrap());
+        let len = u32::from_le_bytes(pair[4..].try_into().unwrap());
+        let bytes = read(caller, ptr as u64, len as usize)?;
+        strings.push(String::from_utf8(bytes).map_err(fail)?);
+    }
+
+    Ok(strings)
+}
+
+/// A host function, called by the wrappers of the component imports
+fn host<P, R, Params, Results>(store: &mut Store, func: impl IntoFunc<Handle, Params, Results>) -> TypedFunc<P, R>
+where
+    P: WasmParams,
+    R: WasmResults,
+{
+    let func = Func::wrap(&mut *store, func);
+    func.typed(&*store).unwrap(/* the types of P & R are those of the host function */)
+}
+
+fn token(caller: &Caller) -> u64 {
+    caller.data().token
+}
+
+pub fn define(linker: &mut Linker, store: &mut Store) -> Option<()> {
+    define_request(linker, store)?;
+    define_tables(linker, store)?;
+    define_document(linker, store)?;
+    define_cache_and_template(linker, store)
+}
+
+fn define_request(linker: &mut Linker, store: &mut Store) -> Option<()> {
+    type Getter = fn(Caller, u64, u64) -> Result<u64, Trap>;
+
+    let getters: [(&str, Getter); 11] = [
+        ("id", handle::request_id),
+        ("method", handle::request_method),
+        ("url", handle::request_url),
+        ("route", handle::request_route),
+        ("remainder", handle::request_remainder),
+        ("subdomain", handle::request_subdomain),
+        ("client-ip", handle::request_client_ip),
+        ("scheme", handle::request_scheme),
+        ("body", handle::request_body),
+        ("csrf-token", handle::request_csrf_token),
+        ("locale", handle::request_locale),
+    ];
+
+    for (name, getter) in getters {
+        let getter: TypedFunc<_, (u64,)> = host(store, getter);
+        let wrapper = move |mut caller: Caller, retptr: i32| {
+            let token = token(&caller);
+            let ptr = getter.call(&mut caller, (token, addr(retptr)))?.0;
+            store_buffer(&mut caller, addr(retptr), ptr)
+        };
+
+        linker.func_wrap(REQUEST, name, wrapper).ok()?;
+    }
+
+    let routes = |mut caller: Caller, retptr: i32| {
+        let routes = caller.data().site().routes.clone();
+        let mut pairs = Vec::with_capacity(routes.len() * 8);
+        for route in routes.iter() {
+            let ptr = alloc(&mut caller, 1, route.as_bytes())?;
+            pairs.extend_from_slice(&(ptr as u32).to_le_bytes());
+            pairs.extend_from_slice(&(route.len() as u32).to_le_bytes());
+        }
+
+        let ptr = alloc(&mut caller, 4, &pairs)?;
+        write_pair(&mut caller, addr(retptr), ptr, routes.len() as u64)
+    };
+    linker.func_wrap(REQUEST, "routes", routes).ok()?;
+
+    let header: TypedFunc<_, (u64,)> = host(store, handle::request_header);
+    let wrapper = move |mut caller: Caller, np: i32, nl: i32, retptr: i32| {
+        let token = token(&caller);
+        let ptr = header.call(&mut caller, (token, addr(nl), addr(np), addr(retptr) + 4))?.0;
+        store_option_buffer(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(REQUEST, "header", wrapper).ok()?;
+
+    let env: TypedFunc<_, (u64,)> = host(store, handle::env_var);
+    let wrapper = move |mut caller: Caller, np: i32, nl: i32, retptr: i32| {
+        let token = token(&caller);
+        let ptr = env.call(&mut caller, (token, addr(nl), addr(np), addr(retptr) + 4))?.0;
+        store_option_buffer(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(REQUEST, "env", wrapper).ok()?;
+
+    let translate: TypedFunc<_, (u64,)> = host(store, handle::translate);
+    let wrapper = move |mut caller: Caller, kp: i32, kl: i32, retptr: i32| {
+        let token = token(&caller);
+        let ptr = translate.call(&mut caller, (token, addr(kl), addr(kp), addr(retptr)))?.0;
+        store_buffer(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(REQUEST, "translate", wrapper).ok()?;
+
+    let absolute_url: TypedFunc<_, (u64,)> = host(store, handle::absolute_url);
+    let wrapper = move |mut caller: Caller, pp: i32, pl: i32, retptr: i32| {
+        let token = token(&caller);
+        let ptr = absolute_url.call(&mut caller, (token, addr(pl), addr(pp), addr(retptr)))?.0;
+        store_buffer(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(REQUEST, "absolute-url", wrapper).ok()?;
+
+    let verify_captcha: TypedFunc<_, (u64,)> = host(store, handle::verify_captcha);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32| {
+        let token = token(&caller);
+        Ok(verify_captcha.call(&mut caller, (token, addr(tl), addr(tp)))?.0 as i32)
+    };
+    linker.func_wrap(REQUEST, "verify-captcha", wrapper).ok()?;
+
+    let verify_webhook: TypedFunc<_, (u64,)> = host(store, handle::verify_webhook);
+    let wrapper = move |mut caller: Caller, pp: i32, pl: i32, sp: i32, sl: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(pl), addr(pp), addr(sl), addr(sp));
+        Ok(verify_webhook.call(&mut caller, inputs)?.0 as i32)
+    };
+    linker.func_wrap(REQUEST, "verify-webhook", wrapper).ok()?;
+
+    let send_email: TypedFunc<_, (u64,)> = host(store, handle::send_email);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, sp: i32, sl: i32, bp: i32, bl: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(sl), addr(sp), addr(bl), addr(bp));
+        Ok(send_email.call(&mut caller, inputs)?.0 as i32)
+    };
+    linker.func_wrap(REQUEST, "send-email", wrapper).ok()?;
+
+    let call_service: TypedFunc<_, (u64,)> = host(store, handle::call_service);
+    let wrapper = move |mut caller: Caller, hp: i32, hl: i32, cp: i32, cl: i32, jp: i32, jl: i32, retptr: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(hl), addr(hp), addr(cl), addr(cp), addr(jl), addr(jp));
+        let ptr = call_service.call(&mut caller, inputs)?.0;
+        store_json(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(REQUEST, "call-service", wrapper).ok()?;
+
+    let publish: TypedFunc<_, ()> = host(store, handle::publish);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, jp: i32, jl: i32| {
+        let token = token(&caller);
+        publish.call(&mut caller, (token, addr(tl), addr(tp), addr(jl), addr(jp)))
+    };
+    linker.func_wrap(REQUEST, "publish", wrapper).ok()?;
+
+    let upload_token: TypedFunc<_, (u64,)> = host(store, handle::upload_token);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, cp: i32, cl: i32, max_size: i64, bp: i32, bl: i32, retptr: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(kl), addr(kp), addr(cl), addr(cp), max_size as u64, addr(bl), addr(bp), addr(retptr));
+        let ptr = upload_token.call(&mut caller, inputs)?.0;
+        store_buffer(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(REQUEST, "upload-token", wrapper).ok()?;
+
+    Some(())
+}
+
+fn define_tables(linker: &mut Linker, store: &mut Store) -> Option<()> {
+    let read_entry: TypedFunc<_, (u64,)> = host(store, handle::read_table_entry);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, retptr: i32| {
+        let token = token(&caller);
+        let ptr = read_entry.call(&mut caller, (token, addr(tl), addr(tp), addr(kl), addr(kp)))?.0;
+        store_json(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "read-entry", wrapper).ok()?;
+
+    let wrapper = |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, retptr: i32| {
+        let fail = |_| trap("Invalid Bytes");
+        let table = String::from_utf8(read(&caller, addr(tp), addr(tl) as _)?).map_err(fail)?;
+        let keys = read_strings(&caller, kp, kl)?;
+        let ptr = handle::write_entries(&mut caller, &table, keys.iter().map(String::as_str))?;
+        store_json(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "read-entries", wrapper).ok()?;
+
+    let write_entry: TypedFunc<_, ()> = host(store, handle::write_table_entry);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, jp: i32, jl: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(kl), addr(kp), addr(jl), addr(jp));
+        write_entry.call(&mut caller, inputs)
+    };
+    linker.func_wrap(TABLES, "write-entry", wrapper).ok()?;
+
+    let increment_counter: TypedFunc<_, (i64,)> = host(store, handle::increment_counter);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, delta: i64| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(kl), addr(kp), delta);
+        Ok(increment_counter.call(&mut caller, inputs)?.0)
+    };
+    linker.func_wrap(TABLES, "increment-counter", wrapper).ok()?;
+
+    let entry_hash: TypedFunc<_, (u64,)> = host(store, handle::entry_hash);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(kl), addr(kp));
+        Ok(entry_hash.call(&mut caller, inputs)?.0 as i64)
+    };
+    linker.func_wrap(TABLES, "entry-hash", wrapper).ok()?;
+
+    let cas_entry: TypedFunc<_, (u64,)> = host(store, handle::cas_entry);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, expected_hash: i64, jp: i32, jl: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(kl), addr(kp), expected_hash as u64, addr(jl), addr(jp));
+        Ok(cas_entry.call(&mut caller, inputs)?.0 as i32)
+    };
+    linker.func_wrap(TABLES, "cas-entry", wrapper).ok()?;
+
+    let patch_entry: TypedFunc<_, (u64,)> = host(store, handle::patch_table_entry);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, pp: i32, pl: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(kl), addr(kp), addr(pl), addr(pp));
+        Ok(patch_entry.call(&mut caller, inputs)?.0 as i32)
+    };
+    linker.func_wrap(TABLES, "patch-table-entry", wrapper).ok()?;
+
+    let entry_history: TypedFunc<_, (u64,)> = host(store, handle::entry_history);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, limit: i64, retptr: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(kl), addr(kp), limit as u64);
+        let ptr = entry_history.call(&mut caller, inputs)?.0;
+        store_json(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "entry-history", wrapper).ok()?;
+
+    let read_entry_at: TypedFunc<_, (u64,)> = host(store, handle::read_table_entry_at);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, rp: i32, rl: i32, retptr: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(kl), addr(kp), addr(rl), addr(rp));
+        let ptr = read_entry_at.call(&mut caller, inputs)?.0;
+        store_option_json(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "read-table-entry-at", wrapper).ok()?;
+
+    let sql_query: TypedFunc<_, (u64,)> = host(store, handle::sql_query);
+    let wrapper = move |mut caller: Caller, sp: i32, sl: i32, pp: i32, pl: i32, retptr: i32| {
+        let token = token(&caller);
+        let ptr = sql_query.call(&mut caller, (token, addr(sl), addr(sp), addr(pl), addr(pp)))?.0;
+        store_json(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "sql-query", wrapper).ok()?;
+
+    let search_table: TypedFunc<_, (u64,)> = host(store, handle::search_table);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, qp: i32, ql: i32, limit: i64, retptr: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(ql), addr(qp), limit as u64);
+        let ptr = search_table.call(&mut caller, inputs)?.0;
+        store_json(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "search-table", wrapper).ok()?;
+
+    let query: TypedFunc<_, (u64,)> = host(store, handle::query_table);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, fp: i32, fl: i32, retptr: i32| {
+        let token = token(&caller);
+        let ptr = query.call(&mut caller, (token, addr(tl), addr(tp), addr(fl), addr(fp)))?.0;
+        store_json(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "query", wrapper).ok()?;
+
+    let read_page: TypedFunc<_, (u64,)> = host(store, handle::read_table_page);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, offset: i64, limit: i64, sp: i32, sl: i32, retptr: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), offset as u64, limit as u64, addr(sl), addr(sp));
+        let ptr = read_page.call(&mut caller, inputs)?.0;
+        store_json(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "read-page", wrapper).ok()?;
+
+    let write_blob: TypedFunc<_, ()> = host(store, handle::write_blob);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, cp: i32, cl: i32, bp: i32, bl: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(kl), addr(kp), addr(cl), addr(cp), addr(bl), addr(bp));
+        write_blob.call(&mut caller, inputs)
+    };
+    linker.func_wrap(TABLES, "write-blob", wrapper).ok()?;
+
+    let read_blob: TypedFunc<_, (u64,)> = host(store, handle::read_blob);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, retptr: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(kl), addr(kp), addr(retptr) + 4);
+        let ptr = read_blob.call(&mut caller, inputs)?.0;
+        store_option_buffer(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "read-blob", wrapper).ok()?;
+
+    let read_blob_type: TypedFunc<_, (u64,)> = host(store, handle::read_blob_type);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, retptr: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(tl), addr(tp), addr(kl), addr(kp), addr(retptr) + 4);
+        let ptr = read_blob_type.call(&mut caller, inputs)?.0;
+        store_option_buffer(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "read-blob-type", wrapper).ok()?;
+
+    let erase_subject: TypedFunc<_, (u64,)> = host(store, handle::erase_subject);
+    let wrapper = move |mut caller: Caller, pp: i32, pl: i32, kp: i32, kl: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(pl), addr(pp), addr(kl), addr(kp));
+        Ok(erase_subject.call(&mut caller, inputs)?.0 as i64)
+    };
+    linker.func_wrap(TABLES, "erase-subject", wrapper).ok()?;
+
+    let sync_status: TypedFunc<_, (u64,)> = host(store, handle::db_sync_status);
+    let wrapper = move |mut caller: Caller, retptr: i32| {
+        let token = token(&caller);
+        let ptr = sync_status.call(&mut caller, (token,))?.0;
+        store_json(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "sync-status", wrapper).ok()?;
+
+    Some(())
+}
+
+// `F64` is `f64` itself with wasmtime
+#[cfg_attr(feature = "wasmtime", allow(clippy::useless_conversion))]
+fn define_document(linker: &mut Linker, store: &mut Store) -> Option<()> {
+    let json_new: TypedFunc<_, (u64,)> = host(store, handle::json_new);
+    let wrapper = move |mut caller: Caller| {
+        let token = token(&caller);
+        Ok(json_new.call(&mut caller, (token,))?.0 as i32)
+    };
+    linker.func_wrap(TABLES, "[constructor]document", wrapper).ok()?;
+
+    let json_open: TypedFunc<_, (u64,)> = host(store, handle::json_open);
+    let wrapper = move |mut caller: Caller, tp: i32, tl: i32, kp: i32, kl: i32, retptr: i32| {
+        let token = token(&caller);
+        let json = json_open.call(&mut caller, (token, addr(tl), addr(tp), addr(kl), addr(kp)))?.0;
+
+        // option<document>: discriminant, then the handle at +4
+        let mut option = [0; 8];
+        option[0] = (json != 0) as u8;
+        option[4..].copy_from_slice(&(json as u32).to_le_bytes());
+        write(&mut caller, addr(retptr), &option)
+    };
+    linker.func_wrap(TABLES, "[static]document.open", wrapper).ok()?;
+
+    let get_str: TypedFunc<_, (u64,)> = host(store, handle::json_get_str);
+    let wrapper = move |mut caller: Caller, json: i32, pp: i32, pl: i32, retptr: i32| {
+        let token = token(&caller);
+        let inputs = (token, addr(json), addr(pl), addr(pp), addr(retptr) + 4);
+        let ptr = get_str.call(&mut caller, inputs)?.0;
+        store_option_buffer(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(TABLES, "[method]document.get-string", wrapper).ok()?;
+
+    let get_num: TypedFunc<_, (F64,)> = host(store, handle::json_get_num);
+    let wrapper = move |mut caller: Caller, json: i32, pp: i32, pl: i32, retptr: i32| {
+        let token = token(&caller);
+        let value = f64::from(get_num.call(&mut caller, (token, addr(json), addr(pl), addr(pp)))?.0);
+
+        // option<f64>: discriminant, then the value at +8
+        let mut option = [0; 16];
+        option[0] = !value.is_nan() as u8;
+        option[8..].copy_from_slice(&value.to_le_bytes());
+        write(&mut caller, addr(retptr), &option)
+    };
+    linker.func_wrap(TABLES, "[method]document.get-number", wrapper).ok()?;
+
+    let set_str: TypedFunc<_, ()> = host(store, handle::json_set_str);
+    let wrapper = move |mut caller: Caller, json: i32, pp: i32, pl: i32, vp: i32, vl: i32| {
+        let token = token(&caller);
+        set_str.call(&mut caller, (token, addr(json), addr(pl), addr(pp), addr(vl), addr(vp)))
+    };
+    linker.func_wrap(TABLES, "[method]document.set-string", wrapper).ok()?;
+
+    let set_num: TypedFunc<_, ()> = host(store, handle::json_set_num);
+    let wrapper = move |mut caller: Caller, json: i32, pp: i32, pl: i32, value: F64| {
+        let token = token(&caller);
+        set_num.call(&mut caller, (token, addr(json), addr(pl), addr(pp), value))
+    };
+    linker.func_wrap(TABLES, "[method]document.set-number", wrapper).ok()?;
+
+    let save: TypedFunc<_, ()> = host(store, handle::json_save);
+    let wrapper = move |mut caller: Caller, json: i32, tp: i32, tl: i32, kp: i32, kl: i32| {
+        let token = token(&caller);
+        save.call(&mut caller, (token, addr(json), addr(tl), addr(tp), addr(kl), addr(kp)))
+    };
+    linker.func_wrap(TABLES, "[method]document.save", wrapper).ok()?;
+
+    let close: TypedFunc<_, ()> = host(store, handle::json_close);
+    let wrapper = move |mut caller: Caller, json: i32| {
+        let token = token(&caller);
+        close.call(&mut caller, (token, addr(json)))
+    };
+    linker.func_wrap(TABLES, "[resource-drop]document", wrapper).ok()?;
+
+    Some(())
+}
+
+fn define_cache_and_template(linker: &mut Linker, store: &mut Store) -> Option<()> {
+    let cache_get: TypedFunc<_, (u64,)> = host(store, handle::cache_get);
+    let wrapper = move |mut caller: Caller, kp: i32, kl: i32, retptr: i32| {
+        let token = token(&caller);
+        let ptr = cache_get.call(&mut caller, (token, addr(kl), addr(kp), addr(retptr) + 4))?.0;
+        store_option_buffer(&mut caller, addr(retptr), ptr)
+    };
+    linker.func_wrap(CACHE, "get", wrapper).ok()?;
+
+    let cache_put: TypedFunc<_, ()> = host(store, handle::cache_put);
+    let wrapper = move |mut caller: Caller, kp: i32, kl: i32, vp: i32, vl: i32, ttl_secs: i64| {
+        let token = token(&caller);
+        cache_put.call(&mut caller, (token, addr(kl), addr(kp), addr(vl), addr(vp), ttl_secs as u64))
+    };
+    linker.func_wrap(CACHE, "put", wrapper).ok()?;
+
+    let set_name: TypedFunc<_, ()> = host(store, handle::set_template_name);
+    let wrapper = move |mut caller: Caller, np: i32, nl: i32| {
+        let token = token(&caller);
+        set_name.call(&mut caller, (token, addr(nl), addr(np)))
+    };
+    linker.func_wrap(TEMPLATE, "set-name", wrapper).ok()?;
+
+    type SetParam = fn(Caller, u64, u64, u64, u64, u64) -> Result<(), Trap>;
+    let setters: [(&str, SetParam); 2] = [
+        ("set-param", handle::set_template_param),
+        ("set-param-raw", handle::set_template_param_raw),
+    ];
+
+    for (name, setter) in setters {
+        let setter: TypedFunc<_, ()> = host(store, setter);
+        let wrapper = move |mut caller: Caller, kp: i32, kl: i32, vp: i32, vl: i32| {
+            let token = token(&caller);
+            setter.call(&mut caller, (token, addr(kl), addr(kp), addr(vl), addr(vp)))
+        };
+
+        linker.func_wrap(TEMPLATE, name, wrapper).ok()?;
+    }
+
+    Some(())
+}
+
+/// Exports of the world, & the JSON texts which the host exchanges with them
+pub struct Exports {
+    realloc: Realloc,
+    init: Option<TypedFunc<(), ()>>,
+    callback: Callback,
+    post_callback: Option<TypedFunc<(i32,), ()>>,
+    /// Bodies & responses, by [`OpaqueJsonPointer`] (index + 1)
+    texts: Vec<Option<String>>,
+}
+
+impl Exports {
+    pub fn new(instance: &Instance, store: &mut Store) -> Option<Self> {
+        Some(Self {
+            realloc: export(instance, store, REALLOC)?,
+            init: instance.get_typed_func(&mut *store, INIT).ok(),
+            callback: export(instance, store, CALLBACK)?,
+            post_callback: instance.get_typed_func(&mut *store, POST_CALLBACK).ok(),
+            texts: Vec::new(),
+        })
+    }
+
+    pub fn has_init(&self) -> bool {
+        self.init.is_some()
+    }
+
+    pub fn call_init(&self, store: &mut Store) -> Result<(), Trap> {
+        match &self.init {
+            Some(init) => init.call(store, ()),
+            None => Err(trap(format!("Missing export: {}", INIT))),
+        }
+    }
+
+    pub fn parse_json(&mut self, json: &str) -> OpaqueJsonPointer {
+        let free = self.texts.iter().position(Option::is_none);
+        let index = free.unwrap_or_else(|| {
+            self.texts.push(None);
+            self.texts.len() - 1
+        });
+
+        self.texts[index] = Some(json.to_string());
+        index + 1
+    }
+
+    pub fn dump_json(&mut self, json: OpaqueJsonPointer) -> Result<String, Trap> {
+        let text = self.texts.get_mut(json.wrapping_sub(1)).and_then(Option::take);
+        text.ok_or_else(|| trap("Invalid JSON pointer"))
+    }
+
+    fn alloc(&self, store: &mut Store, mem: Memory, align: i32, bytes: &[u8]) -> Result<i32, Trap> {
+        let ptr = self.realloc.call(&mut *store, (0, 0, align, bytes.len() as i32))?.0;
+        mem.write(store, addr(ptr) as _, bytes).map_err(|e| trap(format!("{:?}", e)))?;
+        Ok(ptr)
+    }
+
+    /// Runs the route callback `name`, which takes ownership of `body`
+    pub fn call_callback(
+        &mut self,
+        store: &mut Store,
+        mem: Memory,
+        name: &str,
+        body: OpaqueJsonPointer,
+        params: &[String],
+    ) -> Result<OpaqueJsonPointer, Trap> {
+        let body = match body {
+            0 => "null".to_string(),
+            body => self.dump_json(body)?,
+        };
+
+        let name_ptr = self.alloc(store, mem, 1, name.as_bytes())?;
+        let body_ptr = self.alloc(store, mem, 1, body.as_bytes())?;
+
+        let mut pairs = Vec::with_capacity(params.len() * 8);
+        for param in params {
+            let ptr = self.alloc(store, mem, 1, param.as_bytes())?;
+            pairs.extend_from_slice(&ptr.to_le_bytes());
+            pairs.extend_from_slice(&(param.len() as u32).to_le_bytes());
+        }
+        let params_ptr = self.alloc(store, mem, 4, &pairs)?;
+
+        let inputs = (name_ptr, name.len() as i32, body_ptr, body.len() as i32, params_ptr, params.len() as i32);
+        let retptr = addr(self.callback.call(&mut *store, inputs)?.0);
+
+        // option<string>: discriminant, then (ptr, len) at +4
+        let fail = || trap("Invalid Pointer");
+        let option = mem.data(&*store).get(retptr as usize..retptr as usize + 12).ok_or_else(fail)?;
+        let response = match option[0] {
+            0 => None,
+            _ => {
+                let ptr = u32::from_le_bytes(option[4..8].try_into().unwrap()) as usize;
+                let len = u32::from_le_bytes(option[8..].try_into().unwrap()) as usize;
+                let bytes = mem.data(&*store).get(ptr..ptr + len).ok_or_else(fail)?;
+                let text = core::str::from_utf8(bytes).map_err(|_| trap("Invalid Bytes"))?;
+                Some(text.to_string())
+            },
+        };
+
+        if let Some(post_callback) = &self.post_callback {
+            post_callback.call(&mut *store, (retptr as i32,))?;
+        }
+
+        match response {
+            Some(json) => match JsonFile::new(Some(&json)) {
+                Ok(_) => Ok(self.parse_json(&json)),
+                Err(e) => Err(trap(format!("{} returned invalid JSON: {:?}", name, e))),
+            },
+            None => Ok(0),
+        }
+    }
+}
+
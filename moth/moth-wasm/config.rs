@@ -0,0 +1,1002 @@
+//! Typed & validated configuration files
+//!
+//! [`Checker`] reads a JSON file and collects every error it finds, each with
+//! the path of the faulty value (`database.sync.schedule`, `jobs[0].access`),
+//! instead of stopping at the first one. Unknown keys are errors too, with a
+//! suggestion when they look like a typo of a known key.
+//!
+//! The server config file is read into a [`ServerConfig`], and the settings
+//! of site bundles (config.json) into a [`SiteConfig`] once the whole file is
+//! checked. Sections with their own types (routes, email, webhooks...) are
+//! then built by their parsers, from a file known to be well-formed.
+
+use super::{deploy::decode_hex, crawling, logging::{LogConfig, Format}, otlp::TracingConfig, claims::{ClaimPolicy, DEFAULT_RESOLVER}, policy::BundlePolicy};
+use moth::{ThreadCount, IpRange, Fallback, Schedule, schema::Schema};
+use lmfu::{ArcStr, json::{JsonFile, Path as JsonPath, Value as JsonValue}};
+use std::{cell::RefCell, fmt::Display, path::PathBuf};
+
+const DEFAULT_MAX_BLOB_KB: usize = 1024;
+const DEFAULT_CACHE_KB: usize = 1024;
+const DEFAULT_MAX_UPLOAD_KB: usize = 1024;
+const DEFAULT_FILE_CACHE_KB: usize = 4096;
+/// Uploads are mostly spent waiting for clients
+const DEFAULT_UPLOAD_THREADS: ThreadCount = ThreadCount { min: 1, max: 32 };
+
+const SERVER_KEYS: &[&str] = &[
+    "request_threads", "script_threads", "render_threads", "threads", "upload_threads",
+    "max_service_cpio_mb", "hostname", "listen_addr", "listen_addrs", "trusted_proxies",
+    "fallback", "dev_bundles", "secrets", "secrets_store", "record_dir", "logging",
+    "tracing", "claims", "bundle_policy", "site_ports",
+];
+
+const SITE_KEYS: &[&str] = &[
+    "canonical", "preview", "routes", "on_404", "crawling", "jobs", "security_headers",
+    "hostnames", "allow_ips", "deny_ips", "errors", "request_id_header", "database",
+    "i18n", "captcha", "cache_kb", "env", "email", "webhooks", "migrations",
+    "services", "subscriptions", "max_upload_kb", "export", "surfaces",
+];
+
+const DATABASE_KEYS: &[&str] = &[
+    "host", "username", "path", "keypair_hex", "branch", "directory", "sqlite", "search",
+    "max_blob_kb", "file_cache_kb", "retention", "sync", "environments",
+];
+
+const ROUTE_OPTIONS: &[&str] = &[
+    "template", "params", "priority", "body", "timeout_secs", "csrf", "etag", "coalesce", "cache",
+    "schema", "methods", "auth", "max_body_kb",
+];
+
+/// Keys of script routes in the object format, with [`ROUTE_OPTIONS`]
+const SCRIPT_ROUTE_KEYS: &[&str] = &["callback", "access"];
+
+const EMAIL_KEYS: &[&str] = &[
+    "server", "security", "username", "password", "password_env", "from", "max_per_hour",
+];
+
+/// Location of a value: its path in the file & its name in error messages
+#[derive(Clone)]
+pub struct At {
+    path: JsonPath,
+    name: String,
+}
+
+impl At {
+    pub fn root() -> Self {
+        Self { path: JsonPath::new(), name: String::new() }
+    }
+
+    pub fn key(&self, key: &str) -> Self {
+        let name = match (self.name.is_empty(), key.contains('.') || key.is_empty()) {
+            (_, true) => format!("{}[{:?}]", self.name, key),
+            (true, false) => key.to_string(),
+            (false, false) => format!("{}.{}", self.name, key),
+        };
+
+        Self { path: self.path.clone().i_str(key), name }
+    }
+
+    pub fn index(&self, index: usize) -> Self {
+        Self { path: self.path.clone().i_num(index), name: format!("{}[{}]", self.name, index) }
+    }
+}
+
+/// Reads values of a JSON file, collecting errors until [`Checker::finish`]
+///
+/// Getters return `None` for missing values & invalid ones, which they
+/// report; values are only required when read with [`Checker::required`].
+pub struct Checker<'a> {
+    file: &'a JsonFile,
+    /// (location, message)
+    errors: RefCell<Vec<(String, String)>>,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(file: &'a JsonFile) -> Self {
+        Self { file, errors: RefCell::new(Vec::new()) }
+    }
+
+    pub fn get(&self, at: &At) -> &'a JsonValue {
+        self.file.get(&at.path)
+    }
+
+    pub fn error(&self, at: &At, message: impl Display) {
+        self.errors.borrow_mut().push((at.name.clone(), message.to_string()));
+    }
+
+    fn invalid<T>(&self, at: &At, expected: &str) -> Option<T> {
+        self.error(at, format_args!("must be {}", expected));
+        None
+    }
+
+    /// Reports a missing value, or reads it with `read`
+    pub fn required<T>(&self, at: &At, read: impl FnOnce(&At) -> Option<T>) -> Option<T> {
+        match self.get(at) {
+            JsonValue::Null => {
+                self.error(at, "is missing");
+                None
+            },
+            _ => read(at),
+        }
+    }
+
+    pub fn string(&self, at: &At) -> Option<&'a ArcStr> {
+        match self.get(at) {
+            JsonValue::String(string) => Some(string),
+            JsonValue::Null => None,
+            _ => self.invalid(at, "a string"),
+        }
+    }
+
+    /// A string among `choices`
+    pub fn choice(&self, at: &At, choices: &[&'static str]) -> Option<&'static str> {
+        let string = self.string(at)?;
+        match choices.iter().find(|choice| ***choice == **string) {
+            Some(choice) => Some(choice),
+            None => self.invalid(at, &format!("one of {}", choices.join("/"))),
+        }
+    }
+
+    pub fn boolean(&self, at: &At, default: bool) -> bool {
+        match self.get(at) {
+            JsonValue::Boolean(boolean) => *boolean,
+            JsonValue::Null => default,
+            _ => self.invalid(at, "a boolean").unwrap_or(default),
+        }
+    }
+
+    /// An integer of at least `min`
+    pub fn count(&self, at: &At, min: usize) -> Option<usize> {
+        match self.get(at) {
+            JsonValue::Number(n) if *n >= min as f64 && n.fract() == 0.0 => Some(*n as usize),
+            JsonValue::Null => None,
+            _ => self.invalid(at, &format!("an integer of at least {}", min)),
+        }
+    }
+
+    /// A number greater than zero
+    pub fn positive(&self, at: &At) -> Option<f64> {
+        match self.get(at) {
+            JsonValue::Number(n) if *n > 0.0 => Some(*n),
+            JsonValue::Null => None,
+            _ => self.invalid(at, "a positive number"),
+        }
+    }
+
+    /// Keys of an object
+    pub fn keys(&self, at: &At) -> Option<Vec<&'a str>> {
+        match self.get(at) {
+            JsonValue::Object(keys) => Some(keys.iter().map(|key| &**key).collect()),
+            JsonValue::Null => None,
+            _ => self.invalid(at, "an object"),
+        }
+    }
+
+    /// Checks that an object only has `known` keys; false if it's missing or invalid
+    pub fn fields(&self, at: &At, known: &[&str]) -> bool {
+        let Some(keys) = self.keys(at) else { return false };
+        for key in keys.into_iter().filter(|key| !known.contains(key)) {
+            let closest = known.iter().min_by_key(|known| distance(key, known));
+            match closest.filter(|known| distance(key, known) <= 2) {
+                Some(known) => self.error(&at.key(key), format_args!("is an unknown key (did you mean {:?}?)", known)),
+                None => self.error(&at.key(key), "is an unknown key"),
+            }
+        }
+
+        true
+    }
+
+    /// Items of an array of strings, with their location
+    pub fn strings(&self, at: &At) -> Option<Vec<(At, &'a ArcStr)>> {
+        let length = match self.get(at) {
+            JsonValue::Array(length) => *length,
+            JsonValue::Null => return None,
+            _ => return self.invalid(at, "an array of strings"),
+        };
+
+        let items = (0..length).map(|i| at.index(i));
+        let items = items.filter_map(|item| self.required(&item, |item| self.string(item)).map(|s| (item, s)));
+        Some(items.collect())
+    }
+
+    /// Logs all errors
+    pub fn finish(self, what: &str) -> Result<(), ()> {
+        let errors = self.errors.into_inner();
+        for (at, message) in &errors {
+            match at.is_empty() {
+                true => log::error!("Invalid {}: {}", what, message),
+                false => log::error!("Invalid {}: {} {}", what, at, message),
+            }
+        }
+
+        match errors.len() {
+            0 => Ok(()),
+            n => Err(log::error!("{} has {} error(s)", what, n)),
+        }
+    }
+}
+
+/// Number of single-character edits turning `a` into `b`
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1).min(row[j] + 1).min(diagonal + (a != *b) as usize);
+            diagonal = above;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Number of threads of a kind: a number, `"auto"` or `{ "min": 2, "max": 8 }`
+fn thread_count(checker: &Checker, at: &At) -> Option<ThreadCount> {
+    match checker.get(at) {
+        JsonValue::Null => None,
+        JsonValue::String(s) if s == "auto" => Some(ThreadCount::auto()),
+        JsonValue::Number(_) => checker.count(at, 1).map(ThreadCount::fixed),
+        JsonValue::Object(_) => {
+            checker.fields(at, &["min", "max"]);
+            let min = checker.required(&at.key("min"), |at| checker.count(at, 1))?;
+            let max = checker.required(&at.key("max"), |at| checker.count(at, 1))?;
+            match ThreadCount::new(min, max) {
+                Ok(count) => Some(count),
+                Err(()) => checker.invalid(at, "a range whose min doesn't exceed its max"),
+            }
+        },
+        _ => checker.invalid(at, "a positive integer, \"auto\" or an object with min & max"),
+    }
+}
+
+/// Addresses or CIDR ranges
+fn ip_ranges(checker: &Checker, at: &At) -> Vec<IpRange> {
+    let mut ranges = Vec::new();
+    for (at, range) in checker.strings(at).unwrap_or_default() {
+        match IpRange::parse(range) {
+            Ok(range) => ranges.push(range),
+            Err(()) => checker.error(&at, "must be an address or a CIDR range"),
+        }
+    }
+
+    ranges
+}
+
+/// `"off"`, `"error"`... `"trace"`
+fn log_level(checker: &Checker, at: &At) -> Option<log::LevelFilter> {
+    let level = checker.choice(at, &["off", "error", "warn", "info", "debug", "trace"])?;
+    level.parse().ok()
+}
+
+fn schedule(checker: &Checker, at: &At) {
+    let Some(schedule) = checker.required(at, |at| checker.string(at)) else { return };
+    if Schedule::parse(schedule).is_err() {
+        checker.error(at, "must be a cron schedule (minute hour day month weekday)");
+    }
+}
+
+/// The server config file
+pub struct ServerConfig {
+    pub request_threads: ThreadCount,
+    pub script_threads: ThreadCount,
+    pub render_threads: ThreadCount,
+    pub upload_threads: ThreadCount,
+    /// In bytes
+    pub max_service_cpio_size: usize,
+    pub hostname: ArcStr,
+    /// Empty if missing
+    pub listen_addrs: Vec<String>,
+    pub trusted_proxies: Option<Vec<IpRange>>,
+    pub fallback: Option<Fallback>,
+    pub record_dir: Option<PathBuf>,
+    /// (hostname, directory)
+    pub dev_bundles: Vec<(String, PathBuf)>,
+    /// (hostname, (name, value) pairs)
+    pub secrets: Vec<(String, Vec<(String, String)>)>,
+    /// (file, key)
+    pub secrets_store: Option<(PathBuf, [u8; 32])>,
+    pub logging: LogConfig,
+    pub tracing: Option<TracingConfig>,
+    /// `None` if anyone can claim any hostname
+    pub claims: Option<ClaimPolicy>,
+    pub bundle_policy: BundlePolicy,
+    /// Ports of listen_addrs reserved to the surfaces of sites
+    pub site_ports: Vec<u16>,
+}
+
+impl ServerConfig {
+    /// Listening addresses are optional if `socket_activated`
+    pub fn parse(file: &JsonFile, socket_activated: bool) -> Result<Self, ()> {
+        const MB: usize = 1024 * 1024;
+        let checker = Checker::new(file);
+        let root = At::root();
+        checker.fields(&root, SERVER_KEYS);
+
+        // "threads" applies to the kinds of threads which aren't configured on their own
+        let default_threads = thread_count(&checker, &root.key("threads"));
+        let threads = |prop| {
+            let at = root.key(prop);
+            match (thread_count(&checker, &at), checker.get(&at), default_threads) {
+                (Some(count), _, _) | (None, JsonValue::Null, Some(count)) => Some(count),
+                (None, JsonValue::Null, None) => {
+                    checker.error(&at, "is missing (and so is threads)");
+                    None
+                },
+                _ => None,
+            }
+        };
+
+        let request_threads = threads("request_threads");
+        let script_threads = threads("script_threads");
+        let render_threads = threads("render_threads");
+        let upload_threads = thread_count(&checker, &root.key("upload_threads")).unwrap_or(DEFAULT_UPLOAD_THREADS);
+        let max_service_cpio_mb = checker.required(&root.key("max_service_cpio_mb"), |at| checker.count(at, 1));
+        let hostname = checker.required(&root.key("hostname"), |at| checker.string(at));
+
+        let (addr, addrs) = (root.key("listen_addr"), root.key("listen_addrs"));
+        let listen_addrs = match (checker.get(&addr), checker.get(&addrs)) {
+            (JsonValue::Null, JsonValue::Null) if socket_activated => Some(Vec::new()),
+            (JsonValue::Null, JsonValue::Null) => {
+                checker.error(&root, "must have either listen_addr or listen_addrs");
+                None
+            },
+            (_, JsonValue::Null) => checker.string(&addr).map(|addr| vec![addr.to_string()]),
+            (JsonValue::Null, _) => match checker.strings(&addrs) {
+                Some(addrs) if addrs.is_empty() => checker.invalid(&root.key("listen_addrs"), "a non-empty array"),
+                addrs => addrs.map(|addrs| addrs.iter().map(|(_, addr)| addr.to_string()).collect()),
+            },
+            _ => {
+                checker.error(&root, "must have either listen_addr or listen_addrs, not both");
+                None
+            },
+        };
+
+        let proxies = root.key("trusted_proxies");
+        let trusted_proxies = match checker.get(&proxies) {
+            JsonValue::Null => None,
+            _ => Some(ip_ranges(&checker, &proxies)),
+        };
+
+        let at = root.key("fallback");
+        let fallback = match checker.fields(&at, &["site", "redirect"]) {
+            true => match (checker.string(&at.key("site")), checker.string(&at.key("redirect"))) {
+                (Some(site), None) => Some(Fallback::Site(site.to_string())),
+                (None, Some(base)) => Some(Fallback::Redirect(base.to_string())),
+                _ => checker.invalid(&at, "an object with either site or redirect"),
+            },
+            false => None,
+        };
+
+        let record_dir = checker.string(&root.key("record_dir")).map(|dir| PathBuf::from(&**dir));
+
+        let mut dev_bundles = Vec::new();
+        let at = root.key("dev_bundles");
+        for hostname in checker.keys(&at).unwrap_or_default() {
+            if let Some(directory) = checker.required(&at.key(hostname), |at| checker.string(at)) {
+                dev_bundles.push((hostname.to_string(), PathBuf::from(&**directory)));
+            }
+        }
+
+        let mut secrets = Vec::new();
+        let at = root.key("secrets");
+        for hostname in checker.keys(&at).unwrap_or_default() {
+            let site = at.key(hostname);
+            let names = checker.required(&site, |at| checker.keys(at)).unwrap_or_default();
+            let values = names.into_iter().filter_map(|name| {
+                let value = checker.required(&site.key(name), |at| checker.string(at))?;
+                Some((name.to_string(), value.to_string()))
+            });
+
+            secrets.push((hostname.to_string(), values.collect()));
+        }
+
+        let at = root.key("secrets_store");
+        let secrets_store = match checker.fields(&at, &["file", "key"]) {
+            true => {
+                let file = checker.required(&at.key("file"), |at| checker.string(at));
+                let key = checker.required(&at.key("key"), |at| checker.string(at));
+                let key = key.and_then(|key| match decode_hex(key) {
+                    Some(key) => Some(key),
+                    None => checker.invalid(&at.key("key"), "64 hex digits"),
+                });
+
+                file.zip(key).map(|(file, key)| (PathBuf::from(&**file), key))
+            },
+            false => None,
+        };
+
+        let at = root.key("logging");
+        let mut logging = LogConfig::default();
+        if checker.fields(&at, &["format", "level", "modules", "file"]) {
+            if let Some("json") = checker.choice(&at.key("format"), &["text", "json"]) {
+                logging.format = Format::Json;
+            }
+
+            logging.level = log_level(&checker, &at.key("level")).unwrap_or(logging.level);
+            let modules = at.key("modules");
+            for module in checker.keys(&modules).unwrap_or_default() {
+                if let Some(level) = checker.required(&modules.key(module), |at| log_level(&checker, at)) {
+                    logging.modules.push((module.to_string(), level));
+                }
+            }
+
+            logging.file = checker.string(&at.key("file")).map(|file| PathBuf::from(&**file));
+        }
+
+        let at = root.key("tracing");
+        let tracing = match checker.fields(&at, &["endpoint", "service_name", "headers"]) {
+            true => {
+                let endpoint = checker.required(&at.key("endpoint"), |at| checker.string(at));
+                let service_name = checker.string(&at.key("service_name")).map(|name| name.to_string());
+
+                let mut headers = Vec::new();
+                let at = at.key("headers");
+                for name in checker.keys(&at).unwrap_or_default() {
+                    if let Some(value) = checker.required(&at.key(name), |at| checker.string(at)) {
+                        headers.push((name.to_string(), value.to_string()));
+                    }
+                }
+
+                endpoint.map(|endpoint| TracingConfig { endpoint: endpoint.to_string(), service_name, headers })
+            },
+            false => None,
+        };
+
+        let at = root.key("claims");
+        let claims = match checker.fields(&at, &["allow", "dns", "resolver"]) {
+            true => {
+                let allow = checker.strings(&at.key("allow")).unwrap_or_default();
+                let allow = allow.into_iter().map(|(_, hostname)| hostname.to_ascii_lowercase()).collect();
+                let resolver = checker.string(&at.key("resolver")).map(|url| url.to_string());
+                let resolver = match checker.boolean(&at.key("dns"), resolver.is_some()) {
+                    true => Some(resolver.unwrap_or(DEFAULT_RESOLVER.into())),
+                    false => None,
+                };
+
+                Some(ClaimPolicy { allow, resolver })
+            },
+            false => None,
+        };
+
+        let mut site_ports = Vec::new();
+        let at = root.key("site_ports");
+        let length = match checker.get(&at) {
+            JsonValue::Array(length) => *length,
+            JsonValue::Null => 0,
+            _ => checker.invalid(&at, "an array of port numbers").unwrap_or(0),
+        };
+
+        for at in (0..length).map(|i| at.index(i)) {
+            match checker.required(&at, |at| checker.count(at, 1)).map(u16::try_from) {
+                Some(Ok(port)) => site_ports.push(port),
+                Some(Err(_)) => checker.error(&at, "isn't a port number"),
+                None => (),
+            }
+        }
+
+        let at = root.key("bundle_policy");
+        let mut bundle_policy = BundlePolicy::default();
+        if checker.fields(&at, &["max_assets", "max_asset_kb", "reserved_hostnames", "reserved_routes", "start_fuel"]) {
+            bundle_policy.max_assets = checker.count(&at.key("max_assets"), 0).unwrap_or(bundle_policy.max_assets);
+            bundle_policy.max_asset_size = checker.count(&at.key("max_asset_kb"), 1).map(|kb| kb * 1024);
+            bundle_policy.start_fuel = checker.count(&at.key("start_fuel"), 1).map_or(bundle_policy.start_fuel, |fuel| fuel as u64);
+
+            let hostnames = checker.strings(&at.key("reserved_hostnames")).unwrap_or_default();
+            bundle_policy.reserved_hostnames = hostnames.into_iter().map(|(_, hostname)| hostname.to_ascii_lowercase()).collect();
+            let routes = checker.strings(&at.key("reserved_routes")).unwrap_or_default();
+            bundle_policy.reserved_routes = routes.into_iter().map(|(_, route)| route.to_string()).collect();
+        }
+
+        checker.finish("config file")?;
+
+        // all missing or invalid values were reported
+        Ok(Self {
+            request_threads: request_threads.unwrap(),
+            script_threads: script_threads.unwrap(),
+            render_threads: render_threads.unwrap(),
+            upload_threads,
+            max_service_cpio_size: max_service_cpio_mb.unwrap() * MB,
+            hostname: hostname.unwrap().clone(),
+            listen_addrs: listen_addrs.unwrap(),
+            trusted_proxies,
+            fallback,
+            record_dir,
+            dev_bundles,
+            secrets,
+            secrets_store,
+            logging,
+            tracing,
+            claims,
+            bundle_policy,
+            site_ports,
+        })
+    }
+}
+
+/// Server config property set with a `--flag-name`, if it's one
+pub fn server_flag(flag: &str) -> Option<&'static str> {
+    let key = flag.strip_prefix("--")?.replace('-', "_");
+    SERVER_KEYS.iter().find(|k| **k == key).copied()
+}
+
+/// Environment variable overriding a server config property: `MOTH_LISTEN_ADDR`
+fn env_var(key: &str) -> String {
+    format!("MOTH_{}", key.to_uppercase())
+}
+
+/// True if some server config property is set in the environment
+pub fn has_env_overrides() -> bool {
+    SERVER_KEYS.iter().any(|key| std::env::var_os(env_var(key)).is_some())
+}
+
+/// Sets server config properties from `MOTH_*` environment variables, then
+/// from command line `flags` (property, value), which take precedence
+///
+/// Values are parsed as JSON; those which aren't valid JSON are strings, so
+/// `--listen-addr 0.0.0.0:80` and `--threads auto` need no quotes. `null`
+/// unsets the property.
+pub fn apply_overrides(file: &mut JsonFile, flags: &[(&str, String)]) -> Result<(), ()> {
+    let vars = SERVER_KEYS.iter().filter_map(|key| Some((*key, std::env::var(env_var(key)).ok()?)));
+    let overrides: Vec<_> = vars.collect();
+    let overrides = overrides.iter().map(|(key, value)| (*key, value.as_str()));
+    let overrides: Vec<_> = overrides.chain(flags.iter().map(|(key, value)| (*key, value.as_str()))).collect();
+
+    if overrides.is_empty() {
+        return Ok(());
+    }
+
+    if file.get(&JsonPath::new()).as_object().is_none() {
+        return Err(log::error!("Invalid config file: must be an object"));
+    }
+
+    for (key, value) in overrides {
+        let source = match JsonFile::new(Some(value)) {
+            Ok(source) => source,
+            Err(_) => JsonFile::new(Some(&format!("{:?}", value))).unwrap(/* valid JSON string */),
+        };
+
+        let path = file.prop(JsonPath::new(), key);
+        copy_value(file, &path, &source, &JsonPath::new());
+    }
+
+    Ok(())
+}
+
+fn copy_value(file: &mut JsonFile, path: &JsonPath, source: &JsonFile, source_path: &JsonPath) {
+    match source.get(source_path) {
+        JsonValue::Null => file.remove(path),
+        JsonValue::Boolean(boolean) => file.set_boolean(path, *boolean),
+        JsonValue::Number(number) => file.set_number(path, *number),
+        JsonValue::String(string) => file.set_string(path, string.clone()),
+        JsonValue::Array(_) => {
+            file.set_array(path);
+            for (_, _, item) in source.iter_array(source_path) {
+                let item_path = file.push(path.clone());
+                copy_value(file, &item_path, source, &item);
+            }
+        },
+        JsonValue::Object(keys) => {
+            file.set_object(path);
+            for key in keys.iter() {
+                let key_path = file.prop(path.clone(), key);
+                copy_value(file, &key_path, source, &source_path.clone().i_str(key));
+            }
+        },
+    }
+}
+
+/// An entry of `database.environments`
+pub struct Environment<'a> {
+    pub name: &'a str,
+    pub branch: &'a ArcStr,
+    /// Deployments to these hostnames use this environment by default
+    pub hostnames: Vec<&'a str>,
+}
+
+/// Keys which only apply to a git repository
+const GIT_DATABASE_KEYS: &[&str] = &["host", "username", "path", "keypair_hex", "branch", "environments", "sync"];
+
+/// Settings of a site bundle, from its config.json
+pub struct SiteConfig<'a> {
+    pub canonical_scheme: &'a str,
+    pub canonical_host: Option<&'a str>,
+    pub request_id_header: bool,
+    /// None if the database is a directory
+    pub branch: Option<&'a ArcStr>,
+    /// Keeps the tables there instead of a git repository, see [`crate::storage`]
+    pub directory: Option<&'a str>,
+    /// File of the site's SQLite database, see [`crate::sql`]
+    pub sqlite: Option<&'a str>,
+    /// Other database branches, for staging deployments
+    pub environments: Vec<Environment<'a>>,
+    pub default_locale: Option<&'a str>,
+    /// Sizes in bytes
+    pub cache_size: usize,
+    pub max_blob_size: usize,
+    pub file_cache_size: usize,
+    pub max_upload_size: usize,
+}
+
+impl<'a> SiteConfig<'a> {
+    /// Checks the whole file
+    pub fn parse(file: &'a JsonFile) -> Result<Self, ()> {
+        let checker = Checker::new(file);
+        let root = At::root();
+        let size = |at: &At, default_kb| checker.count(at, 0).unwrap_or(default_kb) * 1024;
+        checker.fields(&root, SITE_KEYS);
+
+        let canonical = root.key("canonical");
+        checker.fields(&canonical, &["scheme", "host"]);
+        let canonical_scheme = checker.string(&canonical.key("scheme")).map_or("https", |s| &**s);
+        let canonical_host = checker.string(&canonical.key("host")).map(|s| &**s);
+
+        let preview = root.key("preview");
+        match checker.get(&preview) {
+            JsonValue::Boolean(_) | JsonValue::Null => (),
+            JsonValue::Object(_) => {
+                checker.fields(&preview, &["username", "password"]);
+                checker.required(&preview.key("username"), |at| checker.string(at));
+                checker.required(&preview.key("password"), |at| checker.string(at));
+            },
+            _ => checker.error(&preview, "must be a boolean or an object"),
+        }
+
+        checker.required(&root.key("routes"), |at| Some(check_routes(&checker, at)));
+        checker.required(&root.key("on_404"), |at| Some(check_routes(&checker, at)));
+
+        let crawling = root.key("crawling");
+        if checker.fields(&crawling, &["pages", "disallow"]) {
+            for prop in ["pages", "disallow"] {
+                let paths = checker.strings(&crawling.key(prop)).unwrap_or_default();
+                for (at, _) in paths.iter().filter(|(_, path)| !path.starts_with('/')) {
+                    checker.error(at, "must be an absolute path");
+                }
+            }
+        }
+
+        let jobs = root.key("jobs");
+        let length = match checker.get(&jobs) {
+            JsonValue::Array(length) => *length,
+            JsonValue::Null => 0,
+            _ => checker.invalid(&jobs, "an array").unwrap_or(0),
+        };
+
+        for job in (0..length).map(|i| jobs.index(i)) {
+            if checker.required(&job, |at| Some(checker.fields(at, &["schedule", "access", "callback"]))) == Some(true) {
+                schedule(&checker, &job.key("schedule"));
+                checker.required(&job.key("access"), |at| checker.choice(at, &["ro", "rw"]));
+                checker.required(&job.key("callback"), |at| checker.string(at));
+            }
+        }
+
+        security_headers(&checker, &root.key("security_headers"));
+        ip_ranges(&checker, &root.key("allow_ips"));
+        ip_ranges(&checker, &root.key("deny_ips"));
+
+        for (at, hostname) in checker.strings(&root.key("hostnames")).unwrap_or_default() {
+            let domain = hostname.strip_prefix("*.").unwrap_or(hostname);
+            if domain.is_empty() || domain.contains(['*', ':', '/']) {
+                checker.error(&at, "must be a hostname (wildcards look like *.example.com)");
+            }
+        }
+
+        let errors = root.key("errors");
+        for code in checker.keys(&errors).unwrap_or_default() {
+            checker.required(&errors.key(code), |at| checker.string(at));
+            if !matches!(code.parse(), Ok(400..=599u16)) {
+                checker.error(&errors.key(code), "isn't an error status code");
+            }
+        }
+
+        let request_id_header = checker.boolean(&root.key("request_id_header"), false);
+
+        let database = root.key("database");
+        let directory = checker.string(&database.key("directory")).map(|s| &**s);
+        let sqlite = checker.string(&database.key("sqlite")).map(|s| &**s);
+        let branch = checker.required(&database, |at| {
+            checker.fields(at, DATABASE_KEYS);
+            if directory.is_some() {
+                for key in GIT_DATABASE_KEYS.iter().filter(|key| !matches!(checker.get(&at.key(key)), JsonValue::Null)) {
+                    checker.error(&at.key(key), "can't be set with a directory");
+                }
+
+                return Some(None);
+            }
+
+            for key in ["host", "username", "path", "keypair_hex"] {
+                checker.required(&at.key(key), |at| checker.string(at));
+            }
+
+            checker.required(&at.key("branch"), |at| checker.string(at)).map(Some)
+        });
+
+        let mut environments = Vec::new();
+        let env_list = database.key("environments");
+        for name in checker.keys(&env_list).unwrap_or_default() {
+            let at = env_list.key(name);
+            if checker.required(&at, |at| Some(checker.fields(at, &["branch", "hostnames"]))) == Some(true) {
+                let branch = checker.required(&at.key("branch"), |at| checker.string(at));
+                let hostnames = checker.strings(&at.key("hostnames")).unwrap_or_default();
+                let hostnames = hostnames.into_iter().map(|(_, hostname)| &**hostname).collect();
+
+                if let Some(branch) = branch {
+                    environments.push(Environment { name, branch, hostnames });
+                }
+            }
+        }
+
+        let max_blob_size = size(&database.key("max_blob_kb"), DEFAULT_MAX_BLOB_KB);
+        let file_cache_size = size(&database.key("file_cache_kb"), DEFAULT_FILE_CACHE_KB);
+
+        let retention = database.key("retention");
+        let length = match checker.get(&retention) {
+            JsonValue::Array(length) => *length,
+            JsonValue::Null => 0,
+            _ => checker.invalid(&retention, "an array").unwrap_or(0),
+        };
+
+        for policy in (0..length).map(|i| retention.index(i)) {
+            if checker.required(&policy, |at| Some(checker.fields(at, &["table", "days", "timestamp"]))) == Some(true) {
+                checker.required(&policy.key("table"), |at| checker.string(at));
+                checker.required(&policy.key("days"), |at| checker.positive(at));
+                checker.required(&policy.key("timestamp"), |at| checker.string(at));
+            }
+        }
+
+        let search = database.key("search");
+        for table in checker.keys(&search).unwrap_or_default() {
+            checker.required(&search.key(table), |at| checker.strings(at));
+        }
+
+        let sync = database.key("sync");
+        if checker.fields(&sync, &["schedule", "on_conflict"]) {
+            schedule(&checker, &sync.key("schedule"));
+            checker.choice(&sync.key("on_conflict"), &["ours", "theirs", "merge"]);
+        }
+
+        let i18n = root.key("i18n");
+        checker.fields(&i18n, &["default"]);
+        let default_locale = checker.string(&i18n.key("default")).map(|s| &**s);
+
+        let captcha = root.key("captcha");
+        if checker.fields(&captcha, &["provider", "secret"]) {
+            checker.required(&captcha.key("provider"), |at| checker.choice(at, &["hcaptcha", "turnstile", "recaptcha"]));
+            checker.required(&captcha.key("secret"), |at| checker.string(at));
+        }
+
+        let cache_size = size(&root.key("cache_kb"), DEFAULT_CACHE_KB);
+        let max_upload_size = size(&root.key("max_upload_kb"), DEFAULT_MAX_UPLOAD_KB);
+
+        let env = root.key("env");
+        for name in checker.keys(&env).unwrap_or_default() {
+            checker.required(&env.key(name), |at| checker.string(at));
+        }
+
+        let email = root.key("email");
+        if checker.fields(&email, EMAIL_KEYS) {
+            checker.required(&email.key("server"), |at| checker.string(at));
+            checker.required(&email.key("from"), |at| checker.string(at));
+            checker.choice(&email.key("security"), &["tls", "starttls", "none"]);
+            env_value(&checker, &email, "password");
+            checker.string(&email.key("username"));
+            checker.count(&email.key("max_per_hour"), 0);
+        }
+
+        let webhooks = root.key("webhooks");
+        for provider in checker.keys(&webhooks).unwrap_or_default() {
+            let at = webhooks.key(provider);
+            if !["github", "stripe"].contains(&provider) {
+                checker.error(&at, "isn't a webhook provider (must be github/stripe)");
+            }
+
+            if checker.required(&at, |at| Some(checker.fields(at, &["secret", "secret_env", "tolerance_secs"]))) == Some(true) {
+                if !env_value(&checker, &at, "secret") {
+                    checker.error(&at, "must have secret or secret_env");
+                }
+
+                checker.count(&at.key("tolerance_secs"), 0);
+            }
+        }
+
+        checker.strings(&root.key("migrations"));
+
+        let subscriptions = root.key("subscriptions");
+        for topic in checker.keys(&subscriptions).unwrap_or_default() {
+            checker.required(&subscriptions.key(topic), |at| checker.string(at));
+        }
+
+        let services = root.key("services");
+        for callback in checker.keys(&services).unwrap_or_default() {
+            checker.required(&services.key(callback), |at| checker.strings(at));
+        }
+
+        let export = root.key("export");
+        for route in checker.keys(&export).unwrap_or_default() {
+            let at = export.key(route);
+            if !route.starts_with('/') || !route.ends_with("/[param]") {
+                checker.error(&at, "must be a route ending with a [param] step");
+            }
+
+            match checker.get(&at) {
+                JsonValue::String(_) => (),
+                JsonValue::Array(_) => { checker.strings(&at); },
+                _ => checker.error(&at, "must be a callback or an array of values"),
+            }
+        }
+
+        let surfaces = root.key("surfaces");
+        for port in checker.keys(&surfaces).unwrap_or_default() {
+            let at = surfaces.key(port);
+            if !matches!(port.parse(), Ok(1..=u16::MAX)) {
+                checker.error(&at, "isn't a port number");
+            }
+
+            if checker.required(&at, |at| checker.string(at)).is_some_and(|prefix| !prefix.starts_with('/')) {
+                checker.error(&at, "must be a path prefix, starting with /");
+            }
+        }
+
+        checker.finish("config.json")?;
+
+        Ok(Self {
+            canonical_scheme,
+            canonical_host,
+            request_id_header,
+            branch: branch.unwrap(/* reported if missing */),
+            directory,
+            sqlite,
+            environments,
+            default_locale,
+            cache_size,
+            max_blob_size,
+            file_cache_size,
+            max_upload_size,
+        })
+    }
+
+    /// Database branch of a deployment: the one of `environment` if it's
+    /// set, else the one of the environment listing `hostname`, else `branch`
+    pub fn branch(&self, hostname: &str, environment: Option<&str>) -> Result<&'a ArcStr, ()> {
+        let found = match environment {
+            Some(name) => match self.environments.iter().find(|env| env.name == name) {
+                Some(env) => Some(env),
+                None => return Err(log::error!("No such database environment: {}", name)),
+            },
+            None => self.environments.iter().find(|env| env.hostnames.contains(&hostname)),
+        };
+
+        let branch = found.map(|env| env.branch).or(self.branch);
+        branch.ok_or_else(|| log::error!("The database is a directory: it has no branch"))
+    }
+}
+
+/// `key` or `<key>_env`; true if one of them is set
+fn env_value(checker: &Checker, at: &At, key: &str) -> bool {
+    let var_key = format!("{}_env", key);
+    match (checker.string(&at.key(key)), checker.string(&at.key(&var_key))) {
+        (Some(_), Some(_)) => checker.invalid(at, &format!("an object with either {} or {}", key, var_key)).unwrap_or(true),
+        (value, var) => value.is_some() || var.is_some(),
+    }
+}
+
+/// Values are strings, or null to remove a default header
+fn security_headers(checker: &Checker, at: &At) {
+    for name in checker.keys(at).unwrap_or_default() {
+        if let JsonValue::Number(_) | JsonValue::Boolean(_) | JsonValue::Array(_) | JsonValue::Object(_) = checker.get(&at.key(name)) {
+            checker.error(&at.key(name), "must be a string or null");
+        }
+    }
+}
+
+/// Routes are arrays (script routes & uploads), objects (directories) or strings
+fn check_routes(checker: &Checker, at: &At) {
+    match checker.get(at) {
+        JsonValue::Array(length) => {
+            if checker.get(&at.index(0)).as_string().map(|s| &**s) == Some("[upload]") {
+                if *length != 3 {
+                    return checker.error(at, "must be [\"[upload]\", read_timeout_secs, total_timeout_secs]");
+                }
+
+                checker.required(&at.index(1), |at| checker.positive(at));
+                checker.required(&at.index(2), |at| checker.positive(at));
+                return;
+            }
+
+            if *length != 2 && *length != 3 {
+                return checker.error(at, "must have 2 or 3 items");
+            }
+
+            let access = checker.required(&at.index(0), |at| checker.choice(at, &["ro", "rw"]));
+            checker.required(&at.index(1), |at| checker.string(at));
+
+            let options = at.index(2);
+            if *length == 3 && checker.required(&options, |at| Some(checker.fields(at, ROUTE_OPTIONS))) == Some(true) {
+                check_route_options(checker, &options, access);
+            }
+        },
+        JsonValue::Object(_) if crawling::is_script_object(checker.file, &at.path) => {
+            let known = [SCRIPT_ROUTE_KEYS, ROUTE_OPTIONS].concat();
+            checker.fields(at, &known);
+            let access = checker.choice(&at.key("access"), &["ro", "rw"]);
+            check_route_options(checker, at, access);
+        },
+        JsonValue::Object(keys) => for key in keys.iter() {
+            let sub_at = at.key(key);
+            match &**key {
+                "[allow_ips]" | "[deny_ips]" => { ip_ranges(checker, &sub_at); },
+                "[headers]" => security_headers(checker, &sub_at),
+                "[auth]" => check_auth(checker, &sub_at),
+                _ => check_routes(checker, &sub_at),
+            }
+        },
+        JsonValue::String(_) => (),
+        _ => checker.error(at, "must be a route (array, object or string)"),
+    }
+}
+
+/// Options of script routes; `access` is None if invalid
+fn check_route_options(checker: &Checker, options: &At, access: Option<&str>) {
+    checker.string(&options.key("template"));
+    let params = options.key("params");
+    for key in checker.keys(&params).unwrap_or_default() {
+        checker.required(&params.key(key), |at| checker.string(at));
+    }
+
+    checker.choice(&options.key("priority"), &["interactive", "batch"]);
+    let body = checker.choice(&options.key("body"), &["json", "text", "bytes", "none"]);
+    let schema = options.key("schema");
+    match (checker.get(&schema), body) {
+        (JsonValue::Null, _) => (),
+        (_, None | Some("json")) => if let Err(e) = Schema::parse(checker.file, &schema.path) {
+            checker.error(&schema, e);
+        },
+        _ => checker.error(&schema, "requires a json body"),
+    }
+
+    checker.positive(&options.key("timeout_secs"));
+    checker.boolean(&options.key("csrf"), false);
+    checker.boolean(&options.key("etag"), false);
+
+    for (option, feature) in [("coalesce", "coalesce requests"), ("cache", "cache responses")] {
+        if checker.boolean(&options.key(option), false) && access == Some("rw") {
+            checker.error(&options.key(option), format_args!("can't be set: only ro routes can {}", feature));
+        }
+    }
+
+    let methods = checker.strings(&options.key("methods")).unwrap_or_default();
+    for (at, _) in methods.iter().filter(|(_, m)| m.is_empty() || !m.bytes().all(|b| b.is_ascii_alphabetic())) {
+        checker.error(at, "must be an HTTP method");
+    }
+
+    match checker.get(&options.key("auth")) {
+        JsonValue::String(_) | JsonValue::Null => (),
+        _ => check_auth(checker, &options.key("auth")),
+    }
+
+    checker.count(&options.key("max_body_kb"), 0);
+}
+
+/// One of `{ "basic": { "username": "admin", "password": "..." } }`,
+/// `{ "bearer": "token" }` or `{ "callback": "check_token" }`
+fn check_auth(checker: &Checker, at: &At) {
+    if !checker.fields(at, &["basic", "bearer", "callback"]) {
+        return;
+    }
+
+    let basic = at.key("basic");
+    if checker.fields(&basic, &["username", "password"]) {
+        checker.required(&basic.key("username"), |at| checker.string(at));
+        checker.required(&basic.key("password"), |at| checker.string(at));
+    }
+
+    let schemes = ["basic", "bearer", "callback"].map(|scheme| checker.get(&at.key(scheme)));
+    if schemes.iter().filter(|value| !matches!(value, JsonValue::Null)).count() != 1 {
+        checker.error(at, "must have one of basic/bearer/callback");
+    }
+
+    checker.string(&at.key("bearer"));
+    checker.string(&at.key("callback"));
+}
@@ -0,0 +1,170 @@
+//! `sitemap.xml` & `robots.txt`, generated from the routes of config.json
+//!
+//! Routes set to `"[sitemap]"` or `"[robots]"` serve them:
+//!
+//! ```json
+//! "routes": { "sitemap.xml": "[sitemap]", "robots.txt": "[robots]" },
+//! "crawling": { "pages": ["/blog/first-post"], "disallow": ["/admin", "/api"] }
+//! ```
+//!
+//! The sitemap lists pages: routes without parameters nor `[auth]`, to
+//! `.html` assets or `ro` scripts, and the `pages` of the `crawling` config.
+//! Paths starting with one of its `disallow` prefixes are left out.
+//!
+//! Sites can also generate them with a callback, which renders a
+//! `sitemap.xml` or `robots.txt` template; `Request::routes` lists routes.
+
+use moth::renderer::{escape_html, PLAIN_TEXT, XML};
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+use std::sync::Arc;
+
+pub const SITEMAP: &str = "[sitemap]";
+pub const ROBOTS: &str = "[robots]";
+
+/// Keys of route objects which aren't route steps
+pub const SPECIAL_KEYS: &[&str] = &["[allow_ips]", "[deny_ips]", "[auth]", "[headers]"];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Kind {
+    Page,
+    Sitemap,
+    Other,
+}
+
+/// Whether a route object is a script route (`{ "callback": "x", "access": "ro", ... }`),
+/// rather than a directory
+pub fn is_script_object(file: &JsonFile, path: &JsonPath) -> bool {
+    let is_string = |key| matches!(file.get(&path.clone().i_str(key)), JsonValue::String(_));
+    is_string("callback") && is_string("access")
+}
+
+/// Access (`ro`/`rw`) of a script route, in the array or object format
+pub fn script_access<'a>(file: &'a JsonFile, path: &JsonPath) -> Option<&'a str> {
+    let access = match file.get(path) {
+        JsonValue::Array(_) => file.get(&path.clone().i_num(0)),
+        JsonValue::Object(_) if is_script_object(file, path) => file.get(&path.clone().i_str("access")),
+        _ => return None,
+    };
+
+    access.as_string().map(|s| &**s)
+}
+
+/// Routes of a site, in the format of `Request::route`
+pub fn list_routes(file: &JsonFile, path: &JsonPath) -> Vec<String> {
+    let mut routes = Vec::new();
+    walk(file, path, "", false, &mut routes);
+    routes.into_iter().map(|(route, _)| route).collect()
+}
+
+fn walk(file: &JsonFile, path: &JsonPath, route: &str, protected: bool, routes: &mut Vec<(String, Kind)>) {
+    let listed = !protected && !route.contains("[param]");
+    let kind = match file.get(path) {
+        JsonValue::Array(_) | JsonValue::Object(_) if script_access(file, path).is_some() => {
+            let public = matches!(file.get(&path.clone().i_str("auth")), JsonValue::Null);
+            match script_access(file, path) {
+                Some("[upload]") => return,
+                Some("ro") if listed && public => Kind::Page,
+                _ => Kind::Other,
+            }
+        },
+        JsonValue::String(target) => match target.as_str() {
+            "[upload]" => return,
+            SITEMAP => Kind::Sitemap,
+            page if listed && (page.ends_with(".html") || page.ends_with(".htm")) => Kind::Page,
+            _ => Kind::Other,
+        },
+        JsonValue::Object(keys) => {
+            let protected = protected || !matches!(file.get(&path.clone().i_str("[auth]")), JsonValue::Null);
+            for key in keys.iter().filter(|k| !SPECIAL_KEYS.contains(&&***k)) {
+                let sub_route = match &**key {
+                    "[empty]" => route.to_string(),
+                    key => format!("{}/{}", route, key),
+                };
+
+                walk(file, &path.clone().i_str(key), &sub_route, protected, routes);
+            }
+
+            return;
+        },
+        _ => return,
+    };
+
+    let route = match route.is_empty() {
+        true => "/".to_string(),
+        false => route.to_string(),
+    };
+
+    if !routes.iter().any(|(r, _)| *r == route) {
+        routes.push((route, kind));
+    }
+}
+
+/// Bodies of `[sitemap]` & `[robots]` routes: (content type, body)
+pub struct Documents {
+    pub sitemap: (&'static str, Arc<[u8]>),
+    pub robots: (&'static str, Arc<[u8]>),
+}
+
+impl Documents {
+    /// Preview sites disallow all crawling
+    pub fn new(file: &JsonFile, routes_path: &JsonPath, path: &JsonPath, base: &str, preview: bool) -> Result<Self, ()> {
+        let get_paths = |prop| {
+            let prop_path = path.clone().i_str(prop);
+            let fail = || log::error!("Invalid crawling config ({} must be an array of absolute paths)", prop);
+            let length = match file.get(&prop_path) {
+                JsonValue::Array(length) => *length,
+                JsonValue::Null => 0,
+                _ => return Err(fail()),
+            };
+
+            let mut paths = Vec::with_capacity(length);
+            for i in 0..length {
+                match file.get(&prop_path.clone().i_num(i)).as_string() {
+                    Some(p) if p.starts_with('/') => paths.push(p.to_string()),
+                    _ => return Err(fail()),
+                }
+            }
+
+            Ok(paths)
+        };
+
+        match file.get(path) {
+            JsonValue::Object(_) | JsonValue::Null => (),
+            _ => return Err(log::error!("Invalid crawling config (must be an object)")),
+        }
+
+        let extra_pages = get_paths("pages")?;
+        let disallow = get_paths("disallow")?;
+
+        let mut routes = Vec::new();
+        walk(file, routes_path, "", false, &mut routes);
+
+        let route_pages = routes.iter().filter(|(_, kind)| *kind == Kind::Page).map(|(route, _)| route);
+        let allowed = |page: &&String| !disallow.iter().any(|prefix| page.starts_with(prefix.as_str()));
+
+        let mut sitemap = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        sitemap += "<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n";
+        for page in route_pages.chain(extra_pages.iter()).filter(allowed) {
+            sitemap += &format!("  <url><loc>{}{}</loc></url>\n", escape_html(base), escape_html(page));
+        }
+        sitemap += "</urlset>\n";
+
+        let mut robots = String::from("User-agent: *\n");
+        match preview {
+            true => robots += "Disallow: /\n",
+            false if disallow.is_empty() => robots += "Disallow:\n",
+            false => for prefix in &disallow {
+                robots += &format!("Disallow: {}\n", prefix);
+            },
+        }
+
+        if let (Some((route, _)), false) = (routes.iter().find(|(_, kind)| *kind == Kind::Sitemap), preview) {
+            robots += &format!("\nSitemap: {}{}\n", base, route);
+        }
+
+        Ok(Self {
+            sitemap: (XML, sitemap.into_bytes().into()),
+            robots: (PLAIN_TEXT, robots.into_bytes().into()),
+        })
+    }
+}
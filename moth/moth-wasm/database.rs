@@ -0,0 +1,598 @@
+use rustgit::{Remote, Repository, Reference, Hash, FileType, Error as GitError};
+use std::sync::{Arc, RwLock, Mutex, MutexGuard, atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed}};
+use std::time::{SystemTime, UNIX_EPOCH};
+use moth::{Schedule, SyncStatus};
+use lmfu::{ArcStr, HashSet, json::{JsonFile, Path as JsonPath, Value as JsonValue}};
+use super::retention::{Retention, subject_files, all_files};
+use super::{tarball, cache::Cache, history::{self, History}, replica::WriteLog};
+use super::storage::{self, Storage, SharedStorage};
+use super::{sql::SqlStore, search::Search};
+use std::time::Duration;
+
+/// What to do when the remote branch changed since the last sync
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConflictPolicy {
+    /// Force-push the local state, dropping remote changes
+    Ours,
+    /// Drop local changes, replacing them with the remote state
+    Theirs,
+    /// Apply locally written entries over the remote state
+    Merge,
+}
+
+impl ConflictPolicy {
+    pub fn parse(policy: &str) -> Result<Self, ()> {
+        match policy {
+            "ours" => Ok(Self::Ours),
+            "theirs" => Ok(Self::Theirs),
+            "merge" => Ok(Self::Merge),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncConfig {
+    pub schedule: Schedule,
+    pub on_conflict: ConflictPolicy,
+}
+
+/// Entries written since the last successful push
+#[derive(Default)]
+struct Changes {
+    paths: Vec<String>,
+    /// Some of these weren't committed yet
+    staged: bool,
+    /// Last commit which wasn't pushed yet
+    head: Option<Hash>,
+}
+
+/// Updated by syncs, readable without locking the database
+#[derive(Default)]
+pub struct SyncHealth {
+    last_push: AtomicU64,
+    last_pull: AtomicU64,
+    failures: AtomicU64,
+    pending_entries: AtomicUsize,
+}
+
+impl SyncHealth {
+    pub fn status(&self) -> SyncStatus {
+        SyncStatus {
+            last_push: self.last_push.load(Relaxed),
+            last_pull: self.last_pull.load(Relaxed),
+            failures: self.failures.load(Relaxed),
+            pending_entries: self.pending_entries.load(Relaxed),
+        }
+    }
+}
+
+/// Holds the number of migrations applied to the database
+const VERSION_PATH: &str = "_meta/version.json";
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Where the git repository of a database is pushed
+pub struct Upstream {
+    pub remote: Remote,
+    pub branch: ArcStr,
+}
+
+/// The tables of a site, see [`Storage`]
+pub struct Database {
+    /// The outer lock allows replacing the repository after a pull
+    pub repo: RwLock<SharedStorage>,
+    changes: Mutex<Changes>,
+    syncing: Mutex<()>,
+    /// Files recently read by scripts; invalidated by writes
+    file_cache: Cache,
+    /// None if the tables aren't in a git repository
+    upstream: Option<Upstream>,
+    author: String,
+    /// Maximum size of blobs written by scripts
+    pub max_blob_size: usize,
+    /// See [`SqlStore`]
+    pub sql: Option<SqlStore>,
+    search: Search,
+    pub retention: Vec<Retention>,
+    pub sync: Option<SyncConfig>,
+    pub health: SyncHealth,
+    /// Changes with the entries; random at first, so that it also changes when the site is redeployed
+    revision: AtomicU64,
+    /// Files written by the latest revisions, for replicas to catch up
+    write_log: Mutex<WriteLog>,
+}
+
+impl Database {
+    pub fn new(
+        storage: Box<dyn Storage>,
+        upstream: Option<Upstream>,
+        hostname: &str,
+        max_blob_size: usize,
+        sql: Option<SqlStore>,
+        search: Search,
+        file_cache_size: usize,
+        retention: Vec<Retention>,
+        sync: Option<SyncConfig>,
+    ) -> Self {
+        Self {
+            repo: RwLock::new(Arc::new(RwLock::new(storage))),
+            changes: Mutex::new(Changes::default()),
+            syncing: Mutex::new(()),
+            file_cache: Cache::new(file_cache_size),
+            upstream,
+            author: format!("moth@{}", hostname),
+            max_blob_size,
+            sql,
+            search,
+            retention,
+            sync,
+            health: SyncHealth::default(),
+            revision: AtomicU64::new(rand::random()),
+            write_log: Mutex::new(WriteLog::default()),
+        }
+    }
+
+    /// Must be called with the repository read-locked, so that a concurrent write can't be missed
+    pub fn cached_file(&self, path: &str) -> Option<Vec<u8>> {
+        self.file_cache.get(path)
+    }
+
+    /// Must be called with the repository read-locked, with the file as it is in the repository
+    pub fn cache_file(&self, path: &str, content: &[u8]) {
+        self.file_cache.put(path, content.to_vec(), Duration::ZERO);
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision.load(Relaxed)
+    }
+
+    /// Must be called with the repository read-locked, for the log to match the revision
+    pub fn write_log(&self) -> MutexGuard<'_, WriteLog> {
+        self.write_log.lock().unwrap()
+    }
+
+    /// Must be called with the repository locked, after staging these paths
+    pub fn record_writes<'a, I: Iterator<Item = &'a str>>(&self, paths: I) {
+        let mut changes = self.changes.lock().unwrap();
+        changes.staged = true;
+        let revision = self.revision.fetch_add(1, Relaxed).wrapping_add(1);
+
+        let mut written = Vec::new();
+        for path in paths {
+            self.file_cache.remove(path);
+            written.push(path.to_string());
+
+            // without upstream, there's nothing to push
+            if self.upstream.is_some() && !changes.paths.iter().any(|p| p == path) {
+                changes.paths.push(path.to_string());
+            }
+        }
+
+        self.write_log().push(revision, written);
+
+        self.health.pending_entries.store(changes.paths.len(), Relaxed);
+    }
+
+    /// Stages the removal of these files
+    ///
+    /// Must be called with the repository locked.
+    pub fn erase_files(&self, repo: &mut dyn Storage, paths: &[String]) -> Result<(), ()> {
+        for path in paths {
+            if let Err(e) = repo.stage(path, None) {
+                return Err(log::error!("Failed to erase {}: {:?}", path, e));
+            }
+        }
+
+        self.record_writes(paths.iter().map(|path| path.as_str()));
+        Ok(())
+    }
+
+    /// Entries of an indexed table matching `query`, see [`Search`]; errors are for the script
+    pub fn search(&self, table: &str, query: &str, limit: usize) -> Result<String, String> {
+        let storage = self.repo.read().unwrap().clone();
+        let storage = storage.read().unwrap();
+        self.search.query(&**storage, self.revision(), &self.write_log(), table, query, limit)
+    }
+
+    /// Erases the entries & blobs of a data subject; returns the number of erased files
+    pub fn erase_subject(&self, table_prefix: &str, subject_key: &str) -> Result<usize, ()> {
+        let repo = self.repo.read().unwrap().clone();
+        let mut repo = repo.write().unwrap();
+
+        let paths = subject_files(&**repo, table_prefix, subject_key)?;
+        self.erase_files(&mut **repo, &paths)?;
+
+        Ok(paths.len())
+    }
+
+    /// Erases expired entries, see [`Retention`]
+    pub fn enforce_retention(&self) -> Result<usize, ()> {
+        if self.retention.is_empty() {
+            return Ok(0);
+        }
+
+        let repo = self.repo.read().unwrap().clone();
+        let mut repo = repo.write().unwrap();
+
+        let mut paths = Vec::new();
+        for policy in &self.retention {
+            paths.append(&mut policy.expired(&**repo, now())?);
+        }
+
+        paths.sort();
+        paths.dedup();
+        self.erase_files(&mut **repo, &paths)?;
+
+        Ok(paths.len())
+    }
+
+    /// Number of migrations which were applied to the database
+    pub fn schema_version(&self) -> Result<usize, ()> {
+        let repo = self.repo.read().unwrap().clone();
+        let repo = repo.read().unwrap();
+
+        let json = match repo.read_text(VERSION_PATH) {
+            Ok(json) => json,
+            Err(GitError::PathError) => return Ok(0),
+            Err(e) => return Err(log::error!("Failed to read {}: {:?}", VERSION_PATH, e)),
+        };
+
+        let file = JsonFile::new(Some(json)).map_err(|_| log::error!("Invalid {}", VERSION_PATH))?;
+        match file.get(&JsonPath::new().i_str("version")) {
+            JsonValue::Number(version) if *version >= 0.0 => Ok(*version as usize),
+            _ => Err(log::error!("Invalid {} (version must be a positive number)", VERSION_PATH)),
+        }
+    }
+
+    pub fn set_schema_version(&self, version: usize) -> Result<(), ()> {
+        let repo = self.repo.read().unwrap().clone();
+        let mut repo = repo.write().unwrap();
+
+        let json = format!("{{\"version\":{}}}", version).into_bytes();
+        if let Err(e) = repo.stage(VERSION_PATH, Some((json, FileType::RegularFile))) {
+            return Err(log::error!("Failed to write {}: {:?}", VERSION_PATH, e));
+        }
+
+        self.record_writes(core::iter::once(VERSION_PATH));
+        Ok(())
+    }
+
+    /// Stages files written outside of scripts
+    pub fn write_files(&self, files: Vec<(String, Vec<u8>)>) -> Result<(), ()> {
+        let repo = self.repo.read().unwrap().clone();
+        let mut repo = repo.write().unwrap();
+
+        let paths: Vec<String> = files.iter().map(|(path, _)| path.clone()).collect();
+        for (path, bytes) in files {
+            if let Err(e) = repo.stage(&path, Some((bytes, FileType::RegularFile))) {
+                return Err(log::error!("Failed to write {}: {:?}", path, e));
+            }
+        }
+
+        self.record_writes(paths.iter().map(String::as_str));
+        Ok(())
+    }
+
+    /// All files of the database, as a tarball
+    pub fn dump(&self) -> Result<Vec<u8>, ()> {
+        let repo = self.repo.read().unwrap().clone();
+        let repo = repo.read().unwrap();
+        let mut dump = Vec::new();
+
+        for path in all_files(&**repo)? {
+            match repo.read_file(&path) {
+                Ok(bytes) => tarball::append_file(&mut dump, &path, bytes)?,
+                Err(e) => return Err(log::error!("Failed to read {}: {:?}", path, e)),
+            }
+        }
+
+        tarball::finish(&mut dump);
+        Ok(dump)
+    }
+
+    /// Replaces all files of the database with the ones in a tarball
+    ///
+    /// Returns the number of restored files.
+    pub fn restore(&self, dump: &[u8]) -> Result<usize, ()> {
+        let files = tarball::read_files(dump)?;
+
+        let repo = self.repo.read().unwrap().clone();
+        let mut repo = repo.write().unwrap();
+
+        let mut removed = all_files(&**repo)?;
+        removed.retain(|path| !files.iter().any(|(p, _)| p == path));
+        self.erase_files(&mut **repo, &removed)?;
+
+        for (path, content) in &files {
+            if let Err(e) = repo.stage(path, Some((content.to_vec(), FileType::RegularFile))) {
+                return Err(log::error!("Failed to restore {}: {:?}", path, e));
+            }
+        }
+
+        self.record_writes(files.iter().map(|(path, _)| path.as_str()));
+        Ok(files.len())
+    }
+
+    /// Stages the files of another branch over the local ones; they're pushed on next sync
+    ///
+    /// Files which only exist locally are kept, and so is the migration version.
+    pub fn promote(&self, branch: &str) -> Result<usize, ()> {
+        if branch == &*self.upstream()?.branch {
+            return Err(log::error!("Can't promote branch {} into itself", branch));
+        }
+
+        let source = self.clone_remote(branch, 1)?;
+        let mut files = all_files(&source)?;
+        files.retain(|path| path != VERSION_PATH);
+
+        let repo = self.repo.read().unwrap().clone();
+        let mut repo = repo.write().unwrap();
+
+        for path in &files {
+            let data = match source.read_file(path) {
+                Ok(bytes) => Some((bytes.to_vec(), FileType::RegularFile)),
+                Err(e) => return Err(log::error!("Failed to read {} in {}: {:?}", path, branch, e)),
+            };
+
+            if let Err(e) = repo.stage(path, data) {
+                return Err(log::error!("Failed to promote {}: {:?}", path, e));
+            }
+        }
+
+        self.record_writes(files.iter().map(String::as_str));
+        Ok(files.len())
+    }
+
+    /// Commits & pushes local changes, or pulls remote ones if there are none
+    ///
+    /// Does nothing if a sync is already running.
+    pub fn sync(&self) -> Result<(), ()> {
+        let _syncing = match self.syncing.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return Ok(()),
+        };
+
+        self.sync_locked()
+    }
+
+    /// Like [`Self::sync`], but waits for a running sync instead
+    pub fn sync_now(&self) -> Result<(), ()> {
+        let _syncing = self.syncing.lock().unwrap();
+        self.sync_locked()
+    }
+
+    fn sync_locked(&self) -> Result<(), ()> {
+        let result = self.try_sync();
+        match result {
+            Ok(()) => self.health.failures.store(0, Relaxed),
+            Err(()) => { self.health.failures.fetch_add(1, Relaxed); },
+        }
+
+        result
+    }
+
+    fn try_sync(&self) -> Result<(), ()> {
+        let on_conflict = match &self.sync {
+            Some(config) => config.on_conflict,
+            None => ConflictPolicy::Merge,
+        };
+
+        let Some(upstream) = &self.upstream else { return Ok(()) };
+
+        // scripts only wait for the commit & the snapshot, not for the network
+        let (mut snapshot, head, pushed) = {
+            let storage = self.repo.read().unwrap().clone();
+            let mut storage = storage.write().unwrap();
+            let mut changes = self.changes.lock().unwrap();
+
+            if changes.paths.is_empty() {
+                core::mem::drop((changes, storage));
+                return self.pull();
+            }
+
+            let repo = storage.repository().ok_or_else(|| log::error!("The database isn't a git repository"))?;
+
+            if changes.staged {
+                let message = format!("moth: {} updated entries", changes.paths.len());
+                let signature = ("moth", self.author.as_str());
+                match repo.commit(&message, signature, signature, None) {
+                    Ok(hash) => changes.head = Some(hash),
+                    Err(e) => return Err(log::error!("Failed to commit database: {:?}", e)),
+                }
+
+                changes.staged = false;
+            }
+
+            let head = changes.head.unwrap(/* set when staged was set */);
+            let snapshot = snapshot(repo, head)?;
+
+            // entries written during the push are recorded apart
+            (snapshot, head, core::mem::take(&mut changes.paths))
+        };
+
+        let heads = [(&*upstream.branch, head)];
+        let result = match snapshot.push(&upstream.remote, &heads, false) {
+            Err(GitError::MustForcePush) => match on_conflict {
+                ConflictPolicy::Ours => snapshot.push(&upstream.remote, &heads, true),
+                ConflictPolicy::Theirs => {
+                    let mut changes = self.changes.lock().unwrap();
+                    let dropped = pushed.len() + changes.paths.len();
+                    log::warn!("Database conflict: dropping {} local entries", dropped);
+                    *changes = Changes::default();
+                    self.health.pending_entries.store(0, Relaxed);
+                    core::mem::drop(changes);
+                    return self.pull();
+                },
+                ConflictPolicy::Merge => {
+                    self.unpushed(pushed);
+                    return self.merge();
+                },
+            },
+            result => result,
+        };
+
+        match result {
+            Ok(()) => {
+                let mut changes = self.changes.lock().unwrap();
+                if !changes.staged {
+                    changes.head = None;
+                }
+
+                self.health.pending_entries.store(changes.paths.len(), Relaxed);
+                self.health.last_push.store(now(), Relaxed);
+                Ok(())
+            },
+            Err(e) => {
+                self.unpushed(pushed);
+                Err(log::error!("Failed to push database: {:?}", e))
+            },
+        }
+    }
+
+    /// Entries of a failed push remain to be pushed
+    fn unpushed(&self, paths: Vec<String>) {
+        let mut changes = self.changes.lock().unwrap();
+        for path in paths {
+            if !changes.paths.contains(&path) {
+                changes.paths.push(path);
+            }
+        }
+
+        self.health.pending_entries.store(changes.paths.len(), Relaxed);
+    }
+
+    /// Replaces the history of the branch with a single commit of the current state
+    ///
+    /// Erased entries remain readable in past commits until this is done.
+    /// Scripts are blocked until the force-push completes.
+    pub fn rewrite_history(&self) -> Result<(), ()> {
+        let upstream = self.upstream()?;
+        let _syncing = self.syncing.lock().unwrap();
+
+        // waits for running scripts
+        let mut current = self.repo.write().unwrap();
+        let local = current.read().unwrap();
+        let mut fresh = Repository::new();
+
+        for path in all_files(&**local)? {
+            let data = match local.read_file(&path) {
+                Ok(bytes) => Some((bytes.to_vec(), FileType::RegularFile)),
+                Err(e) => return Err(log::error!("Failed to read {}: {:?}", path, e)),
+            };
+
+            if let Err(e) = fresh.stage(&path, data) {
+                return Err(log::error!("Failed to stage {}: {:?}", path, e));
+            }
+        }
+
+        let signature = ("moth", self.author.as_str());
+        let head = match fresh.commit("moth: history rewritten", signature, signature, None) {
+            Ok(hash) => hash,
+            Err(e) => return Err(log::error!("Failed to commit database: {:?}", e)),
+        };
+
+        if let Err(e) = fresh.push(&upstream.remote, &[(&*upstream.branch, head)], true) {
+            return Err(log::error!("Failed to push database: {:?}", e));
+        }
+
+        core::mem::drop(local);
+        *current = storage::shared(fresh);
+        self.replaced();
+        *self.changes.lock().unwrap() = Changes::default();
+        self.health.pending_entries.store(0, Relaxed);
+        self.health.last_push.store(now(), Relaxed);
+
+        Ok(())
+    }
+
+    /// Fetches the history of the remote branch, see [`History`]
+    pub fn history(&self) -> Result<History, ()> {
+        History::new(self.clone_remote(&self.upstream()?.branch, history::MAX_DEPTH)?)
+    }
+
+    /// Fails, logging an error, if the database has no git remote
+    pub fn upstream(&self) -> Result<&Upstream, ()> {
+        self.upstream.as_ref().ok_or_else(|| log::error!("The database of this site has no git remote"))
+    }
+
+    /// Must be called after replacing the repository, with the outer lock held
+    fn replaced(&self) {
+        self.file_cache.clear();
+        self.write_log().clear();
+        self.revision.fetch_add(1, Relaxed);
+    }
+
+    fn clone_remote(&self, branch: &str, depth: usize) -> Result<Repository, ()> {
+        let mut fresh = Repository::new();
+        match fresh.clone(&self.upstream()?.remote, Reference::Branch(branch), Some(depth)) {
+            Ok(()) | Err(GitError::NoSuchReference) => Ok(fresh),
+            Err(e) => Err(log::error!("Failed to pull database: {:?}", e)),
+        }
+    }
+
+    fn pull(&self) -> Result<(), ()> {
+        let fresh = self.clone_remote(&self.upstream()?.branch, 1)?;
+
+        // waits for running scripts
+        let mut current = self.repo.write().unwrap();
+
+        // a script wrote something in the meantime: retry on next sync
+        if self.changes.lock().unwrap().paths.is_empty() {
+            *current = storage::shared(fresh);
+            self.replaced();
+            self.health.last_pull.store(now(), Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Stages local entries over the remote state; they're pushed on next sync
+    fn merge(&self) -> Result<(), ()> {
+        let mut fresh = self.clone_remote(&self.upstream()?.branch, 1)?;
+
+        // waits for running scripts
+        let mut current = self.repo.write().unwrap();
+        let local = current.read().unwrap();
+        let mut changes = self.changes.lock().unwrap();
+
+        for path in &changes.paths {
+            let data = match local.read_file(path) {
+                Ok(bytes) => Some((bytes.to_vec(), FileType::RegularFile)),
+                Err(GitError::PathError) => None,
+                Err(e) => return Err(log::error!("Failed to merge {}: {:?}", path, e)),
+            };
+
+            if let Err(e) = fresh.stage(path, data) {
+                return Err(log::error!("Failed to merge {}: {:?}", path, e));
+            }
+        }
+
+        log::warn!("Database conflict: merged {} local entries", changes.paths.len());
+        changes.staged = true;
+        changes.head = None;
+
+        core::mem::drop(local);
+        *current = storage::shared(fresh);
+        self.replaced();
+        self.health.last_pull.store(now(), Relaxed);
+
+        Ok(())
+    }
+}
+
+/// Copy of the commit `head` & of its history, pushed without locking the database
+fn snapshot(repo: &Repository, head: Hash) -> Result<Repository, ()> {
+    let mut packfile = Vec::new();
+    if let Err(e) = repo.pack(HashSet::new(), &[("", head)], &mut packfile, |_, _| ()) {
+        return Err(log::error!("Failed to pack database: {:?}", e));
+    }
+
+    let mut snapshot = Repository::new();
+    match snapshot.import_packfile(packfile, Some(head)) {
+        Ok(()) => Ok(snapshot),
+        Err(e) => Err(log::error!("Failed to copy database: {:?}", e)),
+    }
+}
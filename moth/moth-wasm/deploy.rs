@@ -1,36 +1,110 @@
-use moth::{OpaqueJsonPointer, ScriptResult, Endpoint, EndpointMap, Site, Sites};
-use lmfu::{ArcStr, LiteMap, HashMap, json::{JsonFile, Path as JsonPath}};
-use super::{WasmApp, PoolStr, Pool};
-use std::sync::{Mutex, RwLock};
+use moth::{OpaqueJsonPointer, ScriptResult, RequestInfo, Endpoint, EndpointMap, Site, Sites, Routing, StaticAssets, StaticBody, ScriptHost, UploadSink, TemplateRenderer, SiteDatabase, UploadTimeouts, Priority, ScriptEndpoint};
+use lmfu::{ArcStr, LiteMap, HashMap, json::{JsonFile, Path as JsonPath, Value as JsonValue}};
+use super::{WasmApp, PoolStr, Pool, env::Secrets, secrets::{SecretStore, SiteSecrets}, claims::ClaimPolicy, policy::BundlePolicy, logging::capture_errors};
+use std::{sync::{Arc, Mutex, RwLock}, path::PathBuf, time::Duration};
 
 type Key = [u8; 32];
 
-type PendingUpload = Mutex<Vec<u8>>;
+#[derive(Clone, PartialEq)]
+enum UploadKind {
+    /// A service bundle, deployed once uploaded, with the database environment to use
+    Service(Option<ArcStr>),
+    /// A database dump of an existing site
+    Restore,
+}
+
+/// (bytes, site hostname, expected digest, kind)
+type PendingUpload = (Mutex<Vec<u8>>, ArcStr, Option<Key>, UploadKind);
 
 pub struct Deployer {
     pool: Pool,
     hostname: ArcStr,
-    pending_uploads: RwLock<LiteMap<String, (PendingUpload, ArcStr)>>,
+    pending_uploads: RwLock<LiteMap<String, PendingUpload>>,
     admins: Mutex<HashMap<str, Key>>,
     sites: Sites,
     on_404: Endpoint,
     routes: Endpoint,
     max_size_bytes: usize,
+    /// (hostname, local bundle directory) of sites in development
+    dev_bundles: Vec<(String, PathBuf)>,
+    /// Secret environment variables of each site (hostname => secrets)
+    secrets: Mutex<LiteMap<String, Secrets>>,
+    /// Where secrets set through the admin API are persisted
+    secret_store: Option<SecretStore>,
+    /// Proof required to claim a new hostname, if any
+    claims: Option<ClaimPolicy>,
+    /// Checks of uploaded bundles
+    policy: BundlePolicy,
+    /// Ports on which sites can serve surfaces
+    site_ports: Vec<u16>,
 }
 
 impl Deployer {
-    pub fn new(hostname: ArcStr, max_size_bytes: usize, sites: Sites) -> Self {
+    fn dev_bundle(&self, hostname: &str) -> Option<PathBuf> {
+        let dev_bundle = self.dev_bundles.iter().find(|(h, _)| h == hostname);
+        dev_bundle.map(|(_, directory)| directory.clone())
+    }
+
+    /// Surfaces of a site must be on site ports of this server
+    fn check_surfaces(&self, site: &WasmApp) -> Result<(), ()> {
+        let mut valid = true;
+        for surface in site.surfaces() {
+            if !self.site_ports.contains(&surface.port) {
+                log::error!("Invalid bundle: port {} of surfaces isn't a site port of this server", surface.port);
+                valid = false;
+            }
+        }
+
+        valid.then_some(()).ok_or(())
+    }
+
+    /// Secrets of a site, shared with its future deployments
+    fn secrets(&self, hostname: &str) -> Secrets {
+        let mut secrets = self.secrets.lock().unwrap();
+        if let Some(site_secrets) = secrets.get(hostname) {
+            return site_secrets.clone();
+        }
+
+        let site_secrets = Secrets::default();
+        secrets.insert(hostname.to_string(), site_secrets.clone());
+        site_secrets
+    }
+
+    pub fn new(
+        hostname: ArcStr,
+        max_size_bytes: usize,
+        sites: Sites,
+        dev_bundles: Vec<(String, PathBuf)>,
+        secrets: Vec<SiteSecrets>,
+        secret_store: Option<SecretStore>,
+        claims: Option<ClaimPolicy>,
+        policy: BundlePolicy,
+        site_ports: Vec<u16>,
+    ) -> Self {
+        // deployments clone the database of the site
+        const BATCH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
         let pool = Pool::new();
         let osef = pool.intern("_");
         let mut items = HashMap::new();
 
-        items.insert_ref("upload", Endpoint::Upload);
-        items.insert_ref("request", Endpoint::ScriptExec(false, osef.clone()));
+        items.insert_ref("upload", Endpoint::Upload(UploadTimeouts::default()));
+        items.insert_ref("request", Endpoint::ScriptExec(ScriptEndpoint { priority: Priority::Batch, timeout: BATCH_TIMEOUT, ..ScriptEndpoint::new(false, osef.clone()) }));
+        items.insert_ref("status", Endpoint::ScriptExec(ScriptEndpoint::new(true, pool.intern("status"))));
+        items.insert_ref("errors", Endpoint::ScriptExec(ScriptEndpoint::new(true, pool.intern("errors"))));
+        items.insert_ref("erase", Endpoint::ScriptExec(ScriptEndpoint { priority: Priority::Batch, timeout: BATCH_TIMEOUT, ..ScriptEndpoint::new(false, pool.intern("erase")) }));
+        items.insert_ref("dump", Endpoint::ScriptExec(ScriptEndpoint { priority: Priority::Batch, timeout: BATCH_TIMEOUT, ..ScriptEndpoint::new(true, pool.intern("dump")) }));
+        items.insert_ref("acme", Endpoint::ScriptExec(ScriptEndpoint::new(false, pool.intern("acme"))));
+        items.insert_ref("secret", Endpoint::ScriptExec(ScriptEndpoint::new(false, pool.intern("secret"))));
+        items.insert_ref("log_level", Endpoint::ScriptExec(ScriptEndpoint::new(false, pool.intern("log_level"))));
+        items.insert_ref("promote", Endpoint::ScriptExec(ScriptEndpoint { priority: Priority::Batch, timeout: BATCH_TIMEOUT, ..ScriptEndpoint::new(false, pool.intern("promote")) }));
 
         let routes = Endpoint::Dir(EndpointMap {
             default: None,
             wildcard: None,
             items,
+            ip_filter: None,
+            security_headers: None,
         });
 
         Self {
@@ -42,20 +116,31 @@ impl Deployer {
             on_404: Endpoint::Static(osef),
             routes,
             max_size_bytes,
+            dev_bundles,
+            secrets: Mutex::new(secrets.into_iter().map(|(h, s)| (h, Arc::new(RwLock::new(s)))).collect()),
+            secret_store,
+            claims,
+            policy,
+            site_ports,
         }
     }
 }
 
-impl Site for Deployer {
-    fn pool(&self) -> &Pool { &self.pool }
+impl Routing for Deployer {
     fn name(&self) -> &str { "[deployment server]" }
     fn hostname(&self) -> &str { &self.hostname }
     fn on_404(&self) -> &Endpoint { &self.on_404 }
     fn routes(&self) -> &Endpoint { &self.routes }
-    fn render_template(&self, _name: PoolStr, _parameters: LiteMap<PoolStr, String>) -> Result<String, ()> { Err(()) }
-    fn open_static(&self, _path: &str) -> Option<&[u8]> { Some(b"".as_slice()) }
-    fn prepare_tls(&self, _script_threads: usize) {}
+}
+
+impl StaticAssets for Deployer {
+    fn open_static(&self, _path: &str) -> Option<StaticBody> { Some(StaticBody::Memory(Arc::new([]))) }
+}
+
+impl TemplateRenderer for Deployer {}
+impl SiteDatabase for Deployer {}
 
+impl ScriptHost for Deployer {
     fn parse_json(&self, json: &str, _script_thread_id: usize) -> Result<OpaqueJsonPointer, ()> {
         Ok(leak(Box::new(match JsonFile::new(Some(json)) {
             Ok(json) => json,
@@ -67,9 +152,28 @@ impl Site for Deployer {
         Ok(get_back(json).dump(&JsonPath::new()).unwrap().as_str().into())
     }
 
+    fn process_script(
+        &self, script: PoolStr, _read_only: bool, _path_vars: &[String], _info: &RequestInfo,
+        body: OpaqueJsonPointer, _script_thread_id: usize,
+    ) -> Result<ScriptResult, ()> {
+        match &*script {
+            "status" => self.site_status(body),
+            "errors" => self.script_errors(body),
+            "erase" => self.erase_subject(body),
+            "dump" => self.dump_database(body),
+            "acme" => self.acme_challenge(body),
+            "secret" => self.set_secret(body),
+            "log_level" => self.set_log_level(body),
+            "promote" => self.promote_database(body),
+            _ => self.request_upload(body),
+        }
+    }
+}
+
+impl UploadSink for Deployer {
     fn check_upload_token(&self, token: &str) -> Option<usize> {
         let pending_uploads = self.pending_uploads.read().unwrap();
-        if let Some((upload, _site)) = pending_uploads.get(token) {
+        if let Some((upload, _site, _digest, _kind)) = pending_uploads.get(token) {
             let bytes = upload.lock().unwrap();
             Some(bytes.capacity())
         } else {
@@ -79,34 +183,251 @@ impl Site for Deployer {
 
     fn upload_progress(&self, token: &str, to_append: &[u8]) {
         let pending_uploads = self.pending_uploads.read().unwrap();
-        let (upload, _site) = pending_uploads.get(token).unwrap();
+        let (upload, _site, _digest, _kind) = pending_uploads.get(token).unwrap();
         let mut bytes = upload.lock().unwrap();
         bytes.extend_from_slice(to_append);
     }
 
-    fn end_of_upload(&self, token: &str, success: bool) {
+    fn upload_digest(&self, token: &str) -> Option<[u8; 32]> {
+        let pending_uploads = self.pending_uploads.read().unwrap();
+        pending_uploads.get(token).and_then(|(_upload, _site, digest, _kind)| *digest)
+    }
+
+    /// Bundles which violate the policy aren't deployed; the client gets the
+    /// violations, or the errors of a failed deployment
+    fn end_of_upload(&self, token: &str, success: bool) -> Result<(), String> {
         let mut pending_uploads = self.pending_uploads.write().unwrap();
         if success {
-            let (mut upload, hostname) = pending_uploads.remove(token).unwrap();
+            let (mut upload, hostname, _digest, kind) = pending_uploads.remove(token).unwrap();
             core::mem::drop(pending_uploads);
 
             let bytes = upload.get_mut().unwrap();
-            if let Ok(site) = WasmApp::new(&bytes, &hostname) {
-                self.sites.insert(Box::new(site));
-            } else {
-                // constructor will have logged the error already
-            }
+            let (result, errors) = match kind {
+                UploadKind::Service(environment) => {
+                    let violations = self.policy.check(bytes, &hostname, &self.hostname);
+                    if !violations.is_empty() {
+                        log::error!("Bundle of {} rejected: {}", hostname, violations.join("; "));
+                        return Err(violations.join("\n"));
+                    }
+
+                    capture_errors(|| {
+                        let site = WasmApp::new(bytes, &hostname, environment.as_deref(), self.dev_bundle(&hostname), self.secrets(&hostname))?;
+                        self.check_surfaces(&site)?;
+                        self.sites.insert(Box::new(site));
+                        Ok(())
+                    })
+                },
+                UploadKind::Restore => capture_errors(|| match self.sites.get(&hostname) {
+                    Some(site) => site.restore_database(bytes),
+                    None => Err(log::error!("Site {} disappeared before its database was restored", hostname)),
+                }),
+            };
+
+            result.map_err(|()| match errors.is_empty() {
+                true => "Deployment failed, see the server logs".into(),
+                false => errors.join("\n"),
+            })
         } else {
-            let (upload, _site) = pending_uploads.get(token).unwrap();
+            let (upload, _site, _digest, _kind) = pending_uploads.get(token).unwrap();
             let mut bytes = upload.lock().unwrap();
             bytes.clear();
+            Ok(())
         }
     }
+}
 
-    fn process_script(
-        &self, _script: PoolStr, _read_only: bool, _path_vars: &[String],
-        body: OpaqueJsonPointer, _script_thread_id: usize,
-    ) -> Result<ScriptResult, ()> {
+impl Deployer {
+    /// Checks the admin key in the request & returns the site it targets
+    fn admin_site(&self, params: &JsonFile, request: &str) -> Result<Arc<dyn Site>, ()> {
+        let get_str = |prop| params.get(&JsonPath::new().i_str(prop)).as_string()
+            .ok_or_else(|| log::error!("Invalid {} in {} request", prop, request));
+
+        let site = get_str("site")?;
+        let submitted_key = decode_hex(get_str("key")?).ok_or_else(|| log::error!("Invalid key in {} request", request))?;
+
+        let admins = self.admins.lock().unwrap();
+        if admins.get(site) != Some(&submitted_key) {
+            return Err(log::error!("Invalid signature"));
+        }
+        core::mem::drop(admins);
+
+        self.sites.get(site).ok_or_else(|| log::error!("No such site: {}", site))
+    }
+
+    /// Database sync health of a site, for monitoring; requires the site's admin key
+    fn site_status(&self, body: OpaqueJsonPointer) -> Result<ScriptResult, ()> {
+        let params = get_back(body);
+        let site = self.admin_site(&params, "status")?;
+
+        let status = match site.sync_status() {
+            Some(status) => Ok(status),
+            None => Err(log::error!("No database sync status for {}", site.name())),
+        }?;
+
+        let pool = self.pool.clone();
+        let response = JsonFile::with_key_pool(Some(&status.to_json()), pool).unwrap();
+        Ok(ScriptResult::Json(leak(Box::new(response))))
+    }
+
+    /// Last script failures of a site (wasm traps); requires the site's admin key
+    fn script_errors(&self, body: OpaqueJsonPointer) -> Result<ScriptResult, ()> {
+        let params = get_back(body);
+        let site = self.admin_site(&params, "errors")?;
+        let errors = site.script_errors();
+
+        let pool = self.pool.clone();
+        let response = JsonFile::with_key_pool(Some(&errors.to_json()), pool).unwrap();
+        Ok(ScriptResult::Json(leak(Box::new(response))))
+    }
+
+    /// Erases the entries of a data subject; requires the site's admin key
+    fn erase_subject(&self, body: OpaqueJsonPointer) -> Result<ScriptResult, ()> {
+        let params = get_back(body);
+        let site = self.admin_site(&params, "erase")?;
+
+        let get_str = |prop| params.get(&JsonPath::new().i_str(prop)).as_string()
+            .ok_or_else(|| log::error!("Invalid {} in erase request", prop));
+
+        let table_prefix = get_str("table_prefix")?;
+        let subject = get_str("subject")?;
+        // older versions of cargo moth send it as a string
+        let rewrite_history = match params.get(&JsonPath::new().i_str("rewrite_history")) {
+            JsonValue::Boolean(rewrite_history) => *rewrite_history,
+            JsonValue::String(rewrite_history) if &**rewrite_history == "true" => true,
+            JsonValue::String(rewrite_history) if &**rewrite_history == "false" => false,
+            JsonValue::Null => false,
+            _ => return Err(log::error!("Invalid rewrite_history in erase request")),
+        };
+
+        let erased = site.erase_subject(table_prefix, subject, rewrite_history)?;
+        log::info!("{}: erased {} files of subject {:?}", site.name(), erased, subject);
+
+        let pool = self.pool.clone();
+        let response = JsonFile::with_key_pool(Some(&format!("{{\"erased\":{}}}", erased)), pool).unwrap();
+        Ok(ScriptResult::Json(leak(Box::new(response))))
+    }
+
+    /// All database files of a site, as a tarball; requires the site's admin key
+    fn dump_database(&self, body: OpaqueJsonPointer) -> Result<ScriptResult, ()> {
+        let params = get_back(body);
+        let site = self.admin_site(&params, "dump")?;
+
+        Ok(ScriptResult::Bytes {
+            content_type: "application/x-tar",
+            bytes: site.dump_database()?,
+        })
+    }
+
+    /// Publishes (or removes, if `key_authorization` is null) an ACME HTTP-01
+    /// challenge on the hostnames of a site; requires the site's admin key
+    fn acme_challenge(&self, body: OpaqueJsonPointer) -> Result<ScriptResult, ()> {
+        let params = get_back(body);
+        let site = self.admin_site(&params, "acme")?;
+
+        let get = |prop| params.get(&JsonPath::new().i_str(prop));
+        let token = get("token").as_string().ok_or_else(|| log::error!("Invalid token in acme request"))?;
+        if token.is_empty() || !token.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err(log::error!("Invalid token in acme request"));
+        }
+
+        match get("key_authorization") {
+            JsonValue::String(key_authorization) => self.sites.set_acme_challenge(site.hostname(), token, key_authorization),
+            JsonValue::Null => self.sites.remove_acme_challenge(site.hostname(), token),
+            _ => return Err(log::error!("Invalid key_authorization in acme request")),
+        }
+
+        let pool = self.pool.clone();
+        let response = JsonFile::with_key_pool(Some("{\"success\":true}"), pool).unwrap();
+        Ok(ScriptResult::Json(leak(Box::new(response))))
+    }
+
+    /// Sets (or removes, if `value` is null) a secret environment variable of
+    /// a site, which its scripts see immediately; requires the site's admin key
+    fn set_secret(&self, body: OpaqueJsonPointer) -> Result<ScriptResult, ()> {
+        let params = get_back(body);
+        let site = self.admin_site(&params, "secret")?;
+
+        let get = |prop| params.get(&JsonPath::new().i_str(prop));
+        let name = get("name").as_string().ok_or_else(|| log::error!("Invalid name in secret request"))?;
+        if name.is_empty() {
+            return Err(log::error!("Invalid name in secret request"));
+        }
+
+        let value = match get("value") {
+            JsonValue::String(value) => Some(value.to_string()),
+            JsonValue::Null => None,
+            _ => return Err(log::error!("Invalid value in secret request")),
+        };
+
+        let site_secrets = self.secrets(site.hostname());
+        let mut site_secrets = site_secrets.write().unwrap();
+        site_secrets.retain(|(n, _)| n != &**name);
+        if let Some(value) = value {
+            site_secrets.push((name.to_string(), value));
+        }
+        core::mem::drop(site_secrets);
+
+        if let Some(secret_store) = &self.secret_store {
+            let secrets = self.secrets.lock().unwrap();
+            let all = secrets.iter().map(|(hostname, s)| (hostname.clone(), s.read().unwrap().clone()));
+            secret_store.save(&all.collect::<Vec<_>>())?;
+        }
+
+        log::info!("{}: secret {} updated", site.hostname(), name);
+        let pool = self.pool.clone();
+        let response = JsonFile::with_key_pool(Some("{\"success\":true}"), pool).unwrap();
+        Ok(ScriptResult::Json(leak(Box::new(response))))
+    }
+
+    /// Overrides (or resets, if `level` is null) the log level of a site, or
+    /// of one of its modules if `module` is set; requires the site's admin key
+    fn set_log_level(&self, body: OpaqueJsonPointer) -> Result<ScriptResult, ()> {
+        let params = get_back(body);
+        let site = self.admin_site(&params, "log_level")?;
+
+        let get = |prop| params.get(&JsonPath::new().i_str(prop));
+        let level = match get("level") {
+            JsonValue::String(level) => Some(level.parse().map_err(|_| log::error!("Invalid level in log_level request"))?),
+            JsonValue::Null => None,
+            _ => return Err(log::error!("Invalid level in log_level request")),
+        };
+
+        let module = match get("module") {
+            JsonValue::String(module) => Some(module.clone()),
+            JsonValue::Null => None,
+            _ => return Err(log::error!("Invalid module in log_level request")),
+        };
+
+        super::logging::set_site_level(site.hostname(), module.as_deref(), level);
+        match level {
+            Some(level) => log::info!("{}: log level of {} set to {}", site.hostname(), module.as_deref().unwrap_or("all modules"), level),
+            None => log::info!("{}: log level of {} reset", site.hostname(), module.as_deref().unwrap_or("all modules")),
+        }
+
+        let pool = self.pool.clone();
+        let response = JsonFile::with_key_pool(Some("{\"success\":true}"), pool).unwrap();
+        Ok(ScriptResult::Json(leak(Box::new(response))))
+    }
+
+    /// Writes the database files of another environment (`environment`, like
+    /// `staging`) over the site's ones; requires the site's admin key
+    fn promote_database(&self, body: OpaqueJsonPointer) -> Result<ScriptResult, ()> {
+        let params = get_back(body);
+        let site = self.admin_site(&params, "promote")?;
+
+        let environment = params.get(&JsonPath::new().i_str("environment")).as_string();
+        let environment = environment.ok_or_else(|| log::error!("Invalid environment in promote request"))?;
+        let promoted = site.promote_database(environment)?;
+
+        let pool = self.pool.clone();
+        let response = JsonFile::with_key_pool(Some(&format!("{{\"promoted\":{}}}", promoted)), pool).unwrap();
+        Ok(ScriptResult::Json(leak(Box::new(response))))
+    }
+
+    /// Service bundles can be uploaded for a new site by anyone who can
+    /// claim its hostname (see [`ClaimPolicy`]); database dumps
+    /// (`"kind": "restore"`) only for existing ones.
+    fn request_upload(&self, body: OpaqueJsonPointer) -> Result<ScriptResult, ()> {
         let params = get_back(body);
         let get = |prop| params.get(&JsonPath::new().i_str(prop));
         let get_str = |prop| get(prop).as_string().ok_or_else(|| log::error!("Invalid {} in upload request", prop));
@@ -115,9 +436,37 @@ impl Site for Deployer {
         let site = get_str("site")?;
         let submitted_key = get_hex("key")?;
         let size_bytes: usize = get_str("size_bytes")?.parse().map_err(|_| log::error!("Invalid size_bytes in upload request"))?;
+        let digest = match get("sha256") {
+            JsonValue::Null => None,
+            _ => Some(get_hex("sha256")?),
+        };
+
+        let environment = match get("environment") {
+            JsonValue::Null => None,
+            _ => Some(get_str("environment")?.clone()),
+        };
+
+        let kind = match get("kind") {
+            JsonValue::Null => UploadKind::Service(environment),
+            _ => match &**get_str("kind")? {
+                "service" => UploadKind::Service(environment),
+                "restore" => UploadKind::Restore,
+                _ => return Err(log::error!("Invalid kind in upload request")),
+            },
+        };
 
         if size_bytes > self.max_size_bytes {
-            return Err(log::error!("Service CPIO is too big"));
+            return Err(log::error!("Upload is too big"));
+        }
+
+        if kind == UploadKind::Restore {
+            self.admin_site(&params, "restore")?;
+        }
+
+        // claims can take a DNS lookup: they're checked without the lock
+        let claimed = self.admins.lock().unwrap().get(site).is_some();
+        if let (false, Some(claims)) = (claimed, &self.claims) {
+            claims.check(site, &submitted_key)?;
         }
 
         let mut admins = self.admins.lock().unwrap();
@@ -126,6 +475,7 @@ impl Site for Deployer {
                 return Err(log::error!("Invalid signature"));
             }
         } else {
+            log::info!("{} was claimed", site);
             admins.insert_ref(site, submitted_key);
         }
         core::mem::drop(admins);
@@ -136,7 +486,7 @@ impl Site for Deployer {
             let number: u64 = rand::random();
             let token = format!("{:x}", number);
             if pending_uploads.get(&token).is_none() {
-                pending_uploads.insert(token.clone(), (upload, site.clone()));
+                pending_uploads.insert(token.clone(), (upload, site.clone(), digest, kind));
                 break token;
             }
         };
@@ -144,7 +494,7 @@ impl Site for Deployer {
 
         let token_json = format!("{:?}", token);
 
-        let pool = self.pool().clone();
+        let pool = self.pool.clone();
         let response = JsonFile::with_key_pool(Some(&token_json), pool).unwrap();
         let json_ptr = leak(Box::new(response));
 
@@ -161,6 +511,7 @@ fn get_back(json: OpaqueJsonPointer) -> Box<JsonFile> {
     unsafe { Box::from_raw(json as *mut JsonFile) }
 }
 
+#[allow(clippy::zero_prefixed_literal)]
 static HEX_TO_WORD: [u8; 256] = {
     const __: u8 = 255; // not a hex digit
     [
@@ -189,14 +540,14 @@ pub(crate) fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
         let mut ret = [0; N];
         let mut iter = hex.as_bytes().iter();
 
-        for i in 0..N {
+        for byte in ret.iter_mut() {
             let hw = HEX_TO_WORD[*iter.next().unwrap() as usize];
             let lw = HEX_TO_WORD[*iter.next().unwrap() as usize];
             if hw == 255 || lw == 255 {
                 return None;
             }
 
-            ret[i] = (hw << 4) | lw;
+            *byte = (hw << 4) | lw;
         }
 
         Some(ret)
@@ -0,0 +1,271 @@
+//! Emails sent by scripts with `Request::send_email`, through the SMTP server of the site
+//!
+//! Example config:
+//!
+//! ```json
+//! "email": {
+//!     "server": "smtp.example.com:465",
+//!     "security": "tls",
+//!     "username": "noreply@example.com",
+//!     "password_env": "SMTP_PASSWORD",
+//!     "from": "Example <noreply@example.com>",
+//!     "max_per_hour": 100
+//! }
+//! ```
+//!
+//! `security` is `tls` (implicit TLS, usually port 465), `starttls` (usually
+//! port 587) or `none`, for relays on the same host. The password is either
+//! `password` or the environment variable named by `password_env`, which
+//! keeps it out of the bundle.
+
+use super::env::{Env, EnvValue};
+use moth::request::encode_base64;
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, OwnedTrustAnchor, StreamOwned, ServerName};
+use std::{net::{TcpStream, ToSocketAddrs}, io::{Read, Write}, sync::{Arc, Mutex, OnceLock}};
+use std::{time::{Duration, Instant}, collections::VecDeque};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const HOUR: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Security {
+    Tls,
+    StartTls,
+    None,
+}
+
+pub struct Mailer {
+    /// `host:port`
+    server: String,
+    security: Security,
+    /// Announced in `EHLO`
+    hostname: String,
+    credentials: Option<(String, EnvValue)>,
+    from: String,
+    max_per_hour: usize,
+    /// Times of the emails sent in the last hour
+    sent: Mutex<VecDeque<Instant>>,
+}
+
+impl Mailer {
+    pub fn parse(file: &JsonFile, path: &JsonPath, hostname: &str) -> Result<Option<Self>, ()> {
+        match file.get(path) {
+            JsonValue::Object(_) => (),
+            JsonValue::Null => return Ok(None),
+            _ => return Err(log::error!("Invalid email config (must be an object)")),
+        }
+
+        let get = |prop| file.get(&path.clone().i_str(prop));
+        let get_str = |prop| match get(prop) {
+            JsonValue::String(s) => Ok(Some(s.to_string())),
+            JsonValue::Null => Ok(None),
+            _ => Err(log::error!("Invalid email config ({} must be a string)", prop)),
+        };
+
+        let server = get_str("server")?.ok_or_else(|| log::error!("Invalid email config (missing server)"))?;
+        let from = get_str("from")?.ok_or_else(|| log::error!("Invalid email config (missing from)"))?;
+        if !is_header_safe(&from) || !address_of(&from).contains('@') {
+            return Err(log::error!("Invalid email config (invalid from address)"));
+        }
+
+        let security = match get_str("security")?.as_deref() {
+            Some("tls") | None => Security::Tls,
+            Some("starttls") => Security::StartTls,
+            Some("none") => Security::None,
+            Some(_) => return Err(log::error!("Invalid email config (security must be tls/starttls/none)")),
+        };
+
+        let password = EnvValue::parse(file, path, "password")?;
+        let credentials = match (get_str("username")?, password) {
+            (Some(username), Some(password)) => Some((username, password)),
+            (None, None) => None,
+            _ => return Err(log::error!("Invalid email config (username & password go together)")),
+        };
+
+        let max_per_hour = match get("max_per_hour") {
+            JsonValue::Number(max) if *max >= 0.0 => *max as usize,
+            JsonValue::Null => 100,
+            _ => return Err(log::error!("Invalid email config (max_per_hour must be a positive number)")),
+        };
+
+        Ok(Some(Self {
+            server,
+            security,
+            hostname: hostname.to_string(),
+            credentials,
+            from,
+            max_per_hour,
+            sent: Mutex::new(VecDeque::new()),
+        }))
+    }
+
+    /// Sends a plain text email; fails if the hourly limit is reached
+    pub fn send(&self, env: &Env, to: &str, subject: &str, body: &str) -> Result<(), ()> {
+        if !is_header_safe(to) || !is_header_safe(subject) || to.contains(['<', '>', ',']) || !to.contains('@') {
+            return Err(log::error!("Refused to send an email to {:?} (invalid recipient or subject)", to));
+        }
+
+        let mut sent = self.sent.lock().unwrap();
+        while sent.front().is_some_and(|time| time.elapsed() > HOUR) {
+            sent.pop_front();
+        }
+
+        if sent.len() >= self.max_per_hour {
+            return Err(log::error!("Refused to send an email to {}: hourly limit reached", to));
+        }
+
+        sent.push_back(Instant::now());
+        core::mem::drop(sent);
+
+        let credentials = match &self.credentials {
+            Some((username, password)) => Some((username.as_str(), password.resolve(env)?)),
+            None => None,
+        };
+
+        let message = self.message(to, subject, body);
+        let result = self.transaction(credentials, to, &message);
+        result.map_err(|e| log::error!("Couldn't send email to {} via {}: {}", to, self.server, e))
+    }
+
+    fn message(&self, to: &str, subject: &str, body: &str) -> String {
+        let subject = match subject.is_ascii() {
+            true => subject.to_string(),
+            false => format!("=?UTF-8?B?{}?=", encode_base64(subject.as_bytes())),
+        };
+
+        let mut message = format!("From: {}\r\nTo: {}\r\nSubject: {}\r\n", self.from, to, subject);
+        message += "MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n";
+
+        for line in body.lines() {
+            // lines starting with a dot would be taken for the end of the data
+            if line.starts_with('.') {
+                message.push('.');
+            }
+
+            message += line;
+            message += "\r\n";
+        }
+
+        message
+    }
+
+    fn transaction(&self, credentials: Option<(&str, String)>, to: &str, message: &str) -> Result<(), String> {
+        let host = self.server.rsplit_once(':').map(|(host, _)| host).unwrap_or(&self.server);
+        let address = self.server.to_socket_addrs().ok().and_then(|mut a| a.next()).ok_or("unknown host")?;
+        let socket = TcpStream::connect_timeout(&address, TIMEOUT).map_err(|e| e.to_string())?;
+        socket.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+        socket.set_write_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+
+        let mut connection = match self.security {
+            Security::Tls => Connection::tls(socket, host)?,
+            _ => Connection::Plain(socket),
+        };
+
+        connection.expect(220)?;
+        connection.command(&format!("EHLO {}", self.hostname), 250)?;
+
+        if self.security == Security::StartTls {
+            connection.command("STARTTLS", 220)?;
+            let Connection::Plain(socket) = connection else { unreachable!() };
+            connection = Connection::tls(socket, host)?;
+            connection.command(&format!("EHLO {}", self.hostname), 250)?;
+        }
+
+        if let Some((username, password)) = credentials {
+            let plain = encode_base64(format!("\0{}\0{}", username, password).as_bytes());
+            connection.command(&format!("AUTH PLAIN {}", plain), 235)?;
+        }
+
+        connection.command(&format!("MAIL FROM:<{}>", address_of(&self.from)), 250)?;
+        connection.command(&format!("RCPT TO:<{}>", to), 250)?;
+        connection.command("DATA", 354)?;
+        connection.command(&format!("{}.", message), 250)?;
+        let _ = connection.command("QUIT", 221);
+
+        Ok(())
+    }
+}
+
+enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Connection {
+    fn tls(socket: TcpStream, host: &str) -> Result<Self, String> {
+        let name = ServerName::try_from(host).map_err(|e| e.to_string())?;
+        let connection = ClientConnection::new(tls_config(), name).map_err(|e| e.to_string())?;
+        Ok(Self::Tls(Box::new(StreamOwned::new(connection, socket))))
+    }
+
+    fn stream(&mut self) -> &mut dyn ReadWrite {
+        match self {
+            Self::Plain(socket) => socket,
+            Self::Tls(stream) => &mut **stream,
+        }
+    }
+
+    fn command(&mut self, command: &str, expected: u16) -> Result<(), String> {
+        let stream = self.stream();
+        stream.write_all(command.as_bytes()).and_then(|()| stream.write_all(b"\r\n")).map_err(|e| e.to_string())?;
+        stream.flush().map_err(|e| e.to_string())?;
+        self.expect(expected)
+    }
+
+    /// Reads a (possibly multi-line) reply, which must have the `expected` code
+    fn expect(&mut self, expected: u16) -> Result<(), String> {
+        let stream = self.stream();
+        loop {
+            let mut line = Vec::new();
+            let mut byte = [0];
+            while line.last() != Some(&b'\n') {
+                match stream.read(&mut byte) {
+                    Ok(1) => line.push(byte[0]),
+                    Ok(_) => return Err("connection closed".into()),
+                    Err(e) => return Err(e.to_string()),
+                }
+            }
+
+            let line = String::from_utf8_lossy(&line);
+            let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+            if code != Some(expected) {
+                return Err(format!("unexpected reply: {}", line.trim_end()));
+            }
+
+            // "250-" announces more lines, "250 " is the last one
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+}
+
+trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+fn tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+        }));
+
+        let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+        Arc::new(config)
+    }).clone()
+}
+
+/// Header values can't contain line breaks
+fn is_header_safe(value: &str) -> bool {
+    !value.contains(['\r', '\n'])
+}
+
+/// `Name <user@example.com>` => `user@example.com`
+fn address_of(mailbox: &str) -> &str {
+    match mailbox.rsplit_once('<') {
+        Some((_, address)) => address.trim_end_matches('>'),
+        None => mailbox,
+    }
+}
@@ -0,0 +1,73 @@
+//! Environment variables of sites, read by scripts with `Request::env`
+//!
+//! Public values come from the `env` object of config.json; secrets are
+//! kept by the server, so they don't end up in bundles, and take precedence.
+
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+use std::sync::{Arc, RwLock};
+
+/// (name, value) pairs of a site, shared between the deployer & the site
+pub type Secrets = Arc<RwLock<Vec<(String, String)>>>;
+
+#[derive(Default)]
+pub struct Env {
+    vars: Vec<(String, String)>,
+    secrets: Secrets,
+}
+
+impl Env {
+    pub fn parse(file: &JsonFile, path: &JsonPath, secrets: Secrets) -> Result<Self, ()> {
+        let fail = || log::error!("Invalid env config: must be an object of strings");
+        let mut vars = Vec::new();
+
+        match file.get(path) {
+            JsonValue::Object(names) => for name in names {
+                let value = file.get(&path.clone().i_str(name)).as_string().ok_or_else(fail)?;
+                vars.push((name.to_string(), value.to_string()));
+            },
+            JsonValue::Null => (),
+            _ => return Err(fail()),
+        }
+
+        Ok(Self { vars, secrets })
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        let secrets = self.secrets.read().unwrap();
+        let mut vars = secrets.iter().chain(self.vars.iter());
+        vars.find(|(n, _)| n == name).map(|(_, value)| value.clone())
+    }
+}
+
+/// Config value given as is, or as the name of an environment variable, so
+/// that it doesn't have to be in the bundle: `"password"` or `"password_env"`
+pub enum EnvValue {
+    Plain(String),
+    Var(String),
+}
+
+impl EnvValue {
+    /// `key` or `<key>_env` in the object at `path`
+    pub fn parse(file: &JsonFile, path: &JsonPath, key: &str) -> Result<Option<Self>, ()> {
+        let var_key = format!("{}_env", key);
+        let get_str = |key: &str| match file.get(&path.clone().i_str(key)) {
+            JsonValue::String(s) => Ok(Some(s.to_string())),
+            JsonValue::Null => Ok(None),
+            _ => Err(log::error!("Invalid config: {} must be a string", key)),
+        };
+
+        match (get_str(key)?, get_str(&var_key)?) {
+            (Some(value), None) => Ok(Some(Self::Plain(value))),
+            (None, Some(name)) => Ok(Some(Self::Var(name))),
+            (None, None) => Ok(None),
+            (Some(_), Some(_)) => Err(log::error!("Invalid config: both {} and {} are set", key, var_key)),
+        }
+    }
+
+    pub fn resolve(&self, env: &Env) -> Result<String, ()> {
+        match self {
+            Self::Plain(value) => Ok(value.clone()),
+            Self::Var(name) => env.get(name).ok_or_else(|| log::error!("Missing environment variable: {}", name)),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Paths of a site exported with `moth --export`, see [`moth::export`]
+//!
+//! These are the routes of config.json to `ro` scripts, assets & documents,
+//! outside of `[auth]` subtrees; static directories are exported with all
+//! their assets. Routes with parameters are exported with the values of the
+//! `export` config, given for each `[param]` step as an array or as a `ro`
+//! callback returning one:
+//!
+//! ```json
+//! "export": { "/blog/[param]": "list_posts", "/docs/[param]": ["install", "faq"] }
+//! ```
+//!
+//! Callbacks get `{ "route", "params" }` as body, `params` being the values of
+//! the previous steps, which are also their path variables. Other routes with
+//! parameters are left out.
+
+use moth::{ScriptHost, ScriptResult, RequestInfo, push_json_str};
+use lmfu::{json::{JsonFile, Path as JsonPath, Value as JsonValue}, strpool::{Pool, PoolStr}};
+use super::crawling::{SPECIAL_KEYS, SITEMAP, ROBOTS, script_access};
+
+enum Values {
+    List(Vec<String>),
+    Callback(PoolStr),
+}
+
+#[derive(Default)]
+pub struct ExportPlan {
+    /// Routes & asset paths, with `[param]` steps
+    routes: Vec<String>,
+    /// Values of the `[param]` steps ending each route
+    params: Vec<(String, Values)>,
+}
+
+impl ExportPlan {
+    /// `assets` lists the files of the bundle
+    pub fn parse(file: &JsonFile, pool: &Pool, routes_path: &JsonPath, path: &JsonPath, assets: &[String]) -> Result<Self, ()> {
+        let steps = match file.get(path) {
+            JsonValue::Object(steps) => steps.iter().collect::<Vec<_>>(),
+            JsonValue::Null => Vec::new(),
+            _ => return Err(log::error!("Invalid export config (must be an object)")),
+        };
+
+        let mut params = Vec::new();
+        for route in steps {
+            let step_path = path.clone().i_str(route);
+            let fail = || log::error!("Invalid export config ({} must be a callback or an array of values)", route);
+            let values = match file.get(&step_path) {
+                JsonValue::String(callback) => Values::Callback(pool.intern(callback)),
+                JsonValue::Array(length) => {
+                    let mut values = Vec::with_capacity(*length);
+                    for i in 0..*length {
+                        let value = file.get(&step_path.clone().i_num(i)).as_string().ok_or_else(fail)?;
+                        values.push(value.to_string());
+                    }
+
+                    Values::List(values)
+                },
+                _ => return Err(fail()),
+            };
+
+            if !route.ends_with("/[param]") {
+                return Err(log::error!("Invalid export config ({} must end with a [param] step)", route));
+            }
+
+            params.push((route.to_string(), values));
+        }
+
+        let mut routes = Vec::new();
+        walk(file, routes_path, "", assets, &mut routes);
+        Ok(Self { routes, params })
+    }
+
+    /// Paths to export; runs the callbacks listing parameter values
+    pub fn paths(&self, site: &impl ScriptHost) -> Result<Vec<String>, ()> {
+        let mut paths = Vec::new();
+
+        for route in &self.routes {
+            // (path, parameter values) of the steps so far
+            let mut prefixes = vec![(String::new(), Vec::new())];
+            let mut sub_route = String::new();
+
+            for step in route.split('/').filter(|s| !s.is_empty()) {
+                sub_route.push('/');
+                sub_route.push_str(step);
+
+                if step != "[param]" {
+                    prefixes.iter_mut().for_each(|(path, _)| *path += &format!("/{}", step));
+                    continue;
+                }
+
+                let Some((_, values)) = self.params.iter().find(|(r, _)| *r == sub_route) else {
+                    log::info!("Not exporting {}: no values for {}", route, sub_route);
+                    prefixes.clear();
+                    break;
+                };
+
+                let mut next = Vec::new();
+                for (path, vars) in prefixes {
+                    for value in values.get(site, &sub_route, &vars)? {
+                        let mut vars = vars.clone();
+                        vars.push(value.clone());
+                        next.push((format!("{}/{}", path, value), vars));
+                    }
+                }
+
+                prefixes = next;
+            }
+
+            for (path, _) in prefixes {
+                paths.push(match path.is_empty() {
+                    true => "/".to_string(),
+                    false => path,
+                });
+            }
+        }
+
+        Ok(paths)
+    }
+}
+
+impl Values {
+    fn get(&self, site: &impl ScriptHost, route: &str, vars: &[String]) -> Result<Vec<String>, ()> {
+        let values = match self {
+            Values::List(values) => values.clone(),
+            Values::Callback(callback) => {
+                let mut body = String::from("{\"route\":");
+                push_json_str(&mut body, route);
+                body += ",\"params\":[";
+                for (i, var) in vars.iter().enumerate() {
+                    if i > 0 {
+                        body.push(',');
+                    }
+
+                    push_json_str(&mut body, var);
+                }
+                body += "]}";
+
+                let info = RequestInfo { route: route.into(), ..Default::default() };
+                let body = site.parse_json(&body, 0)?;
+                let json = match site.process_script(callback.clone(), true, vars, &info, body, 0) {
+                    Ok(ScriptResult::Json(json)) => site.dump_json(json, 0)?,
+                    _ => return Err(log::error!("Export callback {} failed or returned no JSON", callback)),
+                };
+
+                let fail = || log::error!("Export callback {} must return an array of strings", callback);
+                let file = JsonFile::new(Some(&json)).map_err(|_| fail())?;
+                let mut values = Vec::new();
+                for (_, _, item_path) in file.iter_array(&JsonPath::new()) {
+                    values.push(file.get(&item_path).as_string().ok_or_else(fail)?.to_string());
+                }
+
+                values
+            },
+        };
+
+        // values become path steps
+        let invalid = |value: &String| value.is_empty() || value.contains(['/', '?', '#']) || value == "." || value == "..";
+        match values.iter().find(|v| invalid(v)) {
+            Some(value) => Err(log::error!("Invalid value of {} for export: {:?}", route, value)),
+            None => Ok(values),
+        }
+    }
+}
+
+fn walk(file: &JsonFile, path: &JsonPath, route: &str, assets: &[String], routes: &mut Vec<String>) {
+    let url = match route.is_empty() {
+        true => "/",
+        false => route,
+    };
+
+    let public = |key| matches!(file.get(&path.clone().i_str(key)), JsonValue::Null);
+    if let Some(access) = script_access(file, path) {
+        if access == "ro" && public("auth") {
+            routes.push(url.to_string());
+        }
+
+        return;
+    }
+
+    match file.get(path) {
+        JsonValue::String(target) => match target.as_str() {
+            "[upload]" => (),
+            SITEMAP | ROBOTS => routes.push(url.to_string()),
+            file if assets.iter().any(|a| a == file) => routes.push(url.to_string()),
+            directory => {
+                let prefix = format!("{}/", directory.trim_end_matches('/'));
+                for asset in assets.iter().filter_map(|a| a.strip_prefix(&prefix)) {
+                    routes.push(format!("{}/{}", route, asset));
+                }
+            },
+        },
+        JsonValue::Object(keys) => {
+            if !public("[auth]") {
+                return;
+            }
+
+            for key in keys.iter().filter(|k| !SPECIAL_KEYS.contains(&&***k)) {
+                let sub_route = match &**key {
+                    "[empty]" => route.to_string(),
+                    key => format!("{}/{}", route, key),
+                };
+
+                walk(file, &path.clone().i_str(key), &sub_route, assets, routes);
+            }
+        },
+        _ => (),
+    }
+}
@@ -1,55 +1,142 @@
-use wasmi::{TypedFunc, Memory, AsContext, core::Trap};
-use rustgit::{Repository, FileType};
-use super::{Pool, wasm::Caller};
-use std::sync::{Arc, RwLock};
+use super::wasm::{Caller, Linker, StoreContext as Store, Memory, AsContext, CoreExports, F64, Trap, trap};
+use rustgit::{EntryType, FileType};
+use super::{Pool, database::Database, query::{Filter, compare}, captcha::Captcha, retention::subject_files, cache::Cache, i18n::Catalogs, env::Env, email::Mailer, webhook::Webhooks, host_json::{self, HostJson, Leaf}, history, services, uploads::{Uploads, Target}, storage::{Storage, SharedStorage}};
+use moth::{RequestInfo, renderer::escape_html, push_json_str, trace};
+use std::sync::{Arc, OnceLock};
+use core::cell::Cell;
 use core::mem::replace;
 use super::PoolStr;
-use lmfu::LiteMap;
-
-type Store<'a> = wasmi::StoreContext<'a, Handle>;
+use super::component::Realloc;
+use lmfu::{LiteMap, json::{JsonFile, Path as JsonPath, Value as JsonValue, parse_path}};
+use core::cmp::Ordering;
+use std::borrow::Cow;
+use std::time::Duration;
 
 pub enum RepositoryHandle {
     None,
-    ReadOnly(Arc<RwLock<Repository>>),
-    ReadWrite(Arc<RwLock<Repository>>),
+    ReadOnly(SharedStorage),
+    ReadWrite(SharedStorage),
 }
 
-pub struct Handle {
+/// What the host functions of a site use besides its database, built once
+/// per site & shared by its threads
+pub struct SiteContext {
     pub pool: Pool,
+    /// `scheme://host` prefix of absolute URLs
+    pub canonical_base: Arc<str>,
+    pub captcha: Option<Arc<Captcha>>,
+    pub cache: Arc<Cache>,
+    pub i18n: Arc<Catalogs>,
+    pub env: Arc<Env>,
+    pub email: Option<Arc<Mailer>>,
+    pub webhooks: Option<Arc<Webhooks>>,
+    pub uploads: Arc<Uploads>,
+    /// Routes of the site, in the format of `RequestInfo::route`
+    pub routes: Arc<[String]>,
+}
+
+impl SiteContext {
+    /// The context of handles which aren't initialized, shared so that
+    /// [`Handle::new`] doesn't allocate
+    fn empty() -> Arc<Self> {
+        static EMPTY: OnceLock<Arc<SiteContext>> = OnceLock::new();
+        EMPTY.get_or_init(|| Arc::new(Self {
+            pool: Pool::get_static_pool(),
+            canonical_base: Arc::from(""),
+            captcha: None,
+            cache: Arc::new(Cache::new(0)),
+            i18n: Arc::default(),
+            env: Arc::default(),
+            email: None,
+            webhooks: None,
+            uploads: Arc::new(Uploads::new(0)),
+            routes: Arc::new([]),
+        })).clone()
+    }
+}
+
+pub struct Handle {
     repo: RepositoryHandle,
     pub token: u64,
     template: Option<PoolStr>,
     parameters: LiteMap<PoolStr, String>,
     db_path: String,
+    transaction: Transaction,
+    request: RequestInfo,
+    database: Option<Arc<Database>>,
+    site: Arc<SiteContext>,
+    /// Documents opened by the script with `json_open` / `json_new`
+    json_docs: HostJson,
+    /// (topic, json) of the messages published by the script
+    messages: Vec<(String, String)>,
 
-    pub parse_json: Option<TypedFunc<(u64, u64), (u64,)>>,
-    pub malloc: Option<TypedFunc<(u64,), (u64,)>>,
-    pub free: Option<TypedFunc<(u64, u64), ()>>,
+    /// `malloc`, `free` & `parse_json` of core module guests
+    exports: Option<CoreExports>,
+    /// `cabi_realloc` of component guests, which receive JSON as text
+    pub realloc: Option<Realloc>,
+    /// Length of the text last written by [`Handle::write_guest_json`] for a component guest
+    pub json_len: Cell<u64>,
     pub mem: Option<Memory>,
 }
 
-pub type TemplateParams = (PoolStr, LiteMap<PoolStr, String>);
+pub type TemplateParams = (Option<PoolStr>, LiteMap<PoolStr, String>);
+
+/// (file path, file content)
+pub type StagedWrite = (String, Vec<u8>);
+
+/// (file path, hash of the content which the script saw)
+pub type ReadRecord = (String, Option<u64>);
+
+/// Database accesses of a read-write script
+///
+/// Scripts don't lock the repository while they run: when one returns,
+/// the entries it read are checked against the repository, and its writes
+/// are only applied if none of these entries changed in the meantime.
+#[derive(Default)]
+pub struct Transaction {
+    pub reads: Vec<ReadRecord>,
+    pub writes: Vec<StagedWrite>,
+}
+
+pub fn content_hash(content: Option<&[u8]>) -> Option<u64> {
+    use core::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    content.map(|bytes| {
+        bytes.hash(&mut hasher);
+        hasher.finish()
+    })
+}
 
 impl Handle {
     pub fn new() -> Self {
         Self {
-            pool: Pool::get_static_pool(),
             repo: RepositoryHandle::None,
             token: u64::MAX,
             template: None,
             parameters: LiteMap::new(),
             db_path: String::new(),
-            parse_json: None,
-            malloc: None,
-            free: None,
+            transaction: Transaction::default(),
+            request: RequestInfo::default(),
+            database: None,
+            site: SiteContext::empty(),
+            json_docs: HostJson::default(),
+            messages: Vec::new(),
+            exports: None,
+            realloc: None,
+            json_len: Cell::new(0),
             mem: None,
         }
     }
 
-    pub fn repo(&self, will_write: bool) -> Result<Arc<RwLock<Repository>>, Trap> {
+    pub fn repo(&self, will_write: bool) -> Result<SharedStorage, Trap> {
+        // scripts of requests whose client gave up stop at their next database access
+        if self.request.expired() {
+            return Err(trap("Request timeout exceeded"));
+        }
+
         match (&self.repo, will_write) {
-            (RepositoryHandle::None, _) => Err(Trap::new("Nested internal call")),
-            (RepositoryHandle::ReadOnly (_  ),  true) => Err(Trap::new("RW/RO barrier")),
+            (RepositoryHandle::None, _) => Err(trap("Nested internal call")),
+            (RepositoryHandle::ReadOnly (_  ),  true) => Err(trap("RW/RO barrier")),
             (RepositoryHandle::ReadWrite(arc), false) => Ok(arc.clone()),
             (RepositoryHandle::ReadOnly (arc), false) => Ok(arc.clone()),
             (RepositoryHandle::ReadWrite(arc),  true) => Ok(arc.clone()),
@@ -58,28 +145,40 @@ impl Handle {
 
     pub fn init(
         &mut self,
-        parse_json: TypedFunc<(u64, u64), (u64,)>,
-        malloc: TypedFunc<(u64,), (u64,)>,
-        free: TypedFunc<(u64, u64), ()>,
+        exports: CoreExports,
+        mem: Memory,
+        site: Arc<SiteContext>,
+    ) {
+        self.exports = Some(exports);
+        self.mem = Some(mem);
+        self.site = site;
+    }
+
+    /// Like [`Handle::init`], for the main module of a component
+    pub fn init_component(
+        &mut self,
+        realloc: Realloc,
         mem: Memory,
-        pool: Pool,
+        site: Arc<SiteContext>,
     ) {
-        self.parse_json = Some(parse_json);
-        self.malloc = Some(malloc);
-        self.free = Some(free);
+        self.realloc = Some(realloc);
         self.mem = Some(mem);
-        self.pool = pool;
+        self.site = site;
+    }
+
+    pub fn site(&self) -> Arc<SiteContext> {
+        self.site.clone()
     }
 
     pub fn read_mem<'a>(&self, store: &'a Store, ptr: usize, len: usize) -> Result<&'a [u8], Trap> {
-        let fail = || Trap::new("Invalid Pointer");
+        let fail = || trap("Invalid Pointer");
         let range = ptr..(ptr + len);
         let mem = self.mem.unwrap();
         mem.data(store).get(range).ok_or_else(fail)
     }
 
     pub fn read_mem_str<'a>(&self, store: &'a Store, ptr: usize, len: usize) -> Result<&'a str, Trap> {
-        let fail = || Trap::new("Invalid Bytes");
+        let fail = || trap("Invalid Bytes");
         let slice = self.read_mem(store, ptr, len)?;
         core::str::from_utf8(slice).ok().ok_or_else(fail)
     }
@@ -90,27 +189,328 @@ impl Handle {
         self.db_path.reserve(path_len);
 
         self.db_path.push_str(self.read_mem_str(&store, tn_ptr, tn_len)?);
-        self.db_path.push_str("/");
+        self.db_path.push('/');
         self.db_path.push_str(self.read_mem_str(&store, key_ptr, key_len)?);
         self.db_path.push_str(".json");
 
         Ok(&*self.db_path)
     }
 
-    pub fn prepare(&mut self, read_only: bool, repo: Arc<RwLock<Repository>>, token: u64) {
+    /// Allocates `bytes` in guest memory, writing their length at `out_len_ptr`
+    pub fn write_guest_bytes(&self, caller: &mut Caller, bytes: &[u8], out_len_ptr: u64) -> Result<u64, Trap> {
+        let len = bytes.len() as u64;
+        let ptr = self.guest_alloc(caller, len)?;
+
+        let fail = |e| trap(format!("{:?}", e));
+        let mem = self.mem.unwrap();
+        mem.write(&mut *caller, ptr as _, bytes).map_err(fail)?;
+        mem.write(&mut *caller, out_len_ptr as _, &len.to_le_bytes()).map_err(fail)?;
+
+        Ok(ptr)
+    }
+
+    fn guest_alloc(&self, caller: &mut Caller, len: u64) -> Result<u64, Trap> {
+        match &self.realloc {
+            Some(realloc) => Ok(realloc.call(&mut *caller, (0, 0, 1, len as i32))?.0 as u32 as u64),
+            None => Ok(self.exports.as_ref().unwrap().malloc.call(&mut *caller, (len,))?.0),
+        }
+    }
+
+    /// Parses `json` in guest memory, returning a pointer to a `JsonFile`
+    ///
+    /// Component guests get the text itself, whose length is kept in `json_len`.
+    pub fn write_guest_json(&self, caller: &mut Caller, json: &[u8]) -> Result<u64, Trap> {
+        let len = json.len() as u64;
+
+        let ptr = self.guest_alloc(caller, len)?;
+        self.mem.unwrap().write(&mut *caller, ptr as _, json).unwrap();
+
+        if self.realloc.is_some() {
+            self.json_len.set(len);
+            return Ok(ptr);
+        }
+
+        let exports = self.exports.as_ref().unwrap(/* no realloc: core module */);
+        let json_ptr = exports.parse_json.call(&mut *caller, (ptr, len))?.0;
+        exports.free.call(&mut *caller, (ptr, len))?;
+
+        Ok(json_ptr)
+    }
+
+    /// Keys of the entries of a table, including pending writes, sorted
+    pub fn table_keys(&self, repo: &dyn Storage, table: &str) -> Result<Vec<String>, Trap> {
+        let mut span = trace::span("db list");
+        span.attribute("moth.db.table", table);
+
+        let mut keys = Vec::new();
+        let mut push = |name: &str| if let Some(key) = name.strip_suffix(".json") {
+            keys.push(key.to_string());
+        };
+
+        match repo.for_each_entry(table, EntryType::File, &mut |name, _| push(name)) {
+            Ok(()) | Err(rustgit::Error::PathError) => (),
+            Err(e) => return Err(trap(format!("Repository::for_each_entry(): {:?}", e))),
+        }
+
+        let prefix = format!("{}/", table);
+        for (path, _) in &self.transaction.writes {
+            match path.strip_prefix(&prefix) {
+                Some(name) if !name.contains('/') => push(name),
+                _ => (),
+            }
+        }
+
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Reads a file as the script sees it: its own pending writes come first
+    ///
+    /// Reads from the repository are recorded for rw scripts, see [`Transaction`],
+    /// and go through the file cache of the database, which only rw scripts fill.
+    pub fn read_path<'a>(&'a mut self, repo: &'a dyn Storage, path: &str) -> Result<Option<Cow<'a, [u8]>>, Trap> {
+        let staged = self.transaction.writes.iter().rposition(|(p, _)| p == path);
+        if let Some(i) = staged {
+            return Ok(Some(Cow::Borrowed(self.transaction.writes[i].1.as_slice())));
+        }
+
+        let mut span = trace::span("db read");
+        span.attribute("moth.db.path", path);
+
+        let cached = self.database.as_ref().and_then(|db| db.cached_file(path));
+        span.attribute("moth.db.cached", cached.is_some() as i64);
+        let result = match cached {
+            Some(content) => Ok(Cow::Owned(content)),
+            None => repo.read_file(path).map(Cow::Borrowed),
+        };
+
+        if let RepositoryHandle::ReadWrite(_) = self.repo {
+            let record = (path.to_string(), content_hash(result.as_deref().ok()));
+            self.transaction.reads.push(record);
+        }
+
+        match result {
+            Ok(content) => {
+                // replicas can lag behind: their files mustn't reach the cache
+                let primary = matches!(self.repo, RepositoryHandle::ReadWrite(_));
+                if let (Cow::Borrowed(content), Some(db), true) = (&content, &self.database, primary) {
+                    db.cache_file(path, content);
+                }
+
+                Ok(Some(content))
+            },
+            Err(rustgit::Error::PathError) => Ok(None),
+            Err(e) => Err(trap(format!("Repository::read_file(): {:?}", e))),
+        }
+    }
+
+    pub fn read_entry_str<'a>(&'a mut self, repo: &'a dyn Storage, table: &str, key: &str) -> Result<Option<Cow<'a, str>>, Trap> {
+        let path = format!("{}/{}.json", table, key);
+        let fail = || trap(format!("Invalid entry: {}", path));
+
+        let text = match self.read_path(repo, &path)? {
+            Some(Cow::Borrowed(bytes)) => core::str::from_utf8(bytes).map(Cow::Borrowed).ok(),
+            Some(Cow::Owned(bytes)) => String::from_utf8(bytes).map(Cow::Owned).ok(),
+            None => return Ok(None),
+        };
+
+        text.map(Some).ok_or_else(fail)
+    }
+
+    /// Writes an entry right away, outside of the script's transaction
+    fn write_immediately(&mut self, repo: &mut dyn Storage, path: &str, bytes: Vec<u8>) -> Result<(), Trap> {
+        if self.transaction.writes.iter().any(|(p, _)| p == path) {
+            return Err(trap(format!("{} has a pending write in this script", path)));
+        }
+
+        let mut span = trace::span("db write");
+        span.attribute("moth.db.path", path);
+
+        let hash = content_hash(Some(&bytes));
+        let fail = |e| {
+            span.fail();
+            trap(format!("Repository::stage(): {:?}", e))
+        };
+        repo.stage(path, Some((bytes, FileType::RegularFile))).map_err(fail)?;
+
+        if let Some(database) = &self.database {
+            database.record_writes(core::iter::once(path));
+        }
+
+        // this write must not be seen as a conflict when the transaction is applied
+        for (read_path, read_hash) in &mut self.transaction.reads {
+            if read_path == path {
+                *read_hash = hash;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Erases files right away, dropping the script's own pending writes to them
+    fn erase_immediately(&mut self, repo: &mut dyn Storage, paths: &[String]) -> Result<(), Trap> {
+        let mut span = trace::span("db erase");
+        span.attribute("moth.db.files", paths.len() as i64);
+        self.transaction.writes.retain(|(path, _)| !paths.contains(path));
+
+        let erased = self.database.as_ref().map(|database| database.erase_files(repo, paths));
+        if !matches!(erased, Some(Ok(_))) {
+            span.fail();
+            return Err(trap("Failed to erase database files"));
+        }
+
+        // these removals must not be seen as conflicts when the transaction is applied
+        for (read_path, read_hash) in &mut self.transaction.reads {
+            if paths.contains(read_path) {
+                *read_hash = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn prepare(
+        &mut self,
+        read_only: bool,
+        repo: SharedStorage,
+        database: Arc<Database>,
+        token: u64,
+        request: RequestInfo,
+    ) {
         self.token = token;
+        self.request = request;
+        self.database = Some(database);
+        self.messages.clear();
         self.repo = match read_only {
             true  => RepositoryHandle::ReadOnly (repo),
             false => RepositoryHandle::ReadWrite(repo),
         };
     }
 
+    /// Writes are buffered here until the script returns successfully
+    pub fn take_transaction(&mut self) -> Transaction {
+        core::mem::take(&mut self.transaction)
+    }
+
+    /// Messages published by the last call, to be sent if it succeeded
+    pub fn take_messages(&mut self) -> Vec<(String, String)> {
+        core::mem::take(&mut self.messages)
+    }
+
+    /// Clears the state of the last call, keeping what was set by `init`
     pub fn reset(&mut self) -> Option<TemplateParams> {
-        let this = replace(self, Self::new());
-        this.template.map(|t| (t, this.parameters))
+        self.repo = RepositoryHandle::None;
+        self.token = u64::MAX;
+        self.transaction = Transaction::default();
+        self.request = RequestInfo::default();
+        self.database = None;
+        self.json_docs.clear();
+
+        let template = self.template.take();
+        let parameters = replace(&mut self.parameters, LiteMap::new());
+        match (template, parameters.is_empty()) {
+            (None, true) => None,
+            (template, _) => Some((template, parameters)),
+        }
+    }
+}
+
+/// Moves the handle out of the store while a host function calls into the guest
+///
+/// The handle is put back when the guard is dropped, so that a trap returned
+/// halfway through a host function doesn't leave a blank handle in the instance.
+struct HandleGuard<'a, 'b> {
+    caller: &'a mut Caller<'b>,
+    handle: Handle,
+}
+
+impl<'a, 'b> HandleGuard<'a, 'b> {
+    fn take(caller: &'a mut Caller<'b>) -> Self {
+        let handle = replace(caller.data_mut(), Handle::new());
+        Self { caller, handle }
+    }
+
+    fn split(&mut self) -> (&mut Handle, &mut Caller<'b>) {
+        (&mut self.handle, self.caller)
+    }
+}
+
+impl Drop for HandleGuard<'_, '_> {
+    fn drop(&mut self) {
+        core::mem::swap(self.caller.data_mut(), &mut self.handle);
     }
 }
 
+/// Allocates `len` bytes in the memory of a component guest, with its `cabi_realloc`
+pub fn guest_realloc(caller: &mut Caller, align: i32, len: i32) -> Result<i32, Trap> {
+    let mut guard = HandleGuard::take(caller);
+    let (handle, caller) = guard.split();
+
+    let realloc = handle.realloc.as_ref().ok_or_else(|| trap("Not a component"))?;
+    Ok(realloc.call(caller, (0, 0, align, len))?.0)
+}
+
+/// Links the host functions of `moth_abi`
+pub fn define(linker: &mut Linker) -> Option<()> {
+    let module = moth_abi::IMPORT_MODULE;
+    linker.func_wrap(module, "read_table_entry", read_table_entry).ok()?;
+    linker.func_wrap(module, "read_table_entries", read_table_entries).ok()?;
+    linker.func_wrap(module, "json_open", json_open).ok()?;
+    linker.func_wrap(module, "json_new", json_new).ok()?;
+    linker.func_wrap(module, "json_get_str", json_get_str).ok()?;
+    linker.func_wrap(module, "json_get_num", json_get_num).ok()?;
+    linker.func_wrap(module, "json_set_str", json_set_str).ok()?;
+    linker.func_wrap(module, "json_set_num", json_set_num).ok()?;
+    linker.func_wrap(module, "json_save", json_save).ok()?;
+    linker.func_wrap(module, "json_close", json_close).ok()?;
+    linker.func_wrap(module, "write_table_entry", write_table_entry).ok()?;
+    linker.func_wrap(module, "request_id", request_id).ok()?;
+    linker.func_wrap(module, "request_subdomain", request_subdomain).ok()?;
+    linker.func_wrap(module, "request_client_ip", request_client_ip).ok()?;
+    linker.func_wrap(module, "request_scheme", request_scheme).ok()?;
+    linker.func_wrap(module, "request_csrf_token", request_csrf_token).ok()?;
+    linker.func_wrap(module, "request_locale", request_locale).ok()?;
+    linker.func_wrap(module, "request_header", request_header).ok()?;
+    linker.func_wrap(module, "site_routes", site_routes).ok()?;
+    linker.func_wrap(module, "request_method", request_method).ok()?;
+    linker.func_wrap(module, "request_body", request_body).ok()?;
+    linker.func_wrap(module, "request_url", request_url).ok()?;
+    linker.func_wrap(module, "request_route", request_route).ok()?;
+    linker.func_wrap(module, "request_remainder", request_remainder).ok()?;
+    linker.func_wrap(module, "increment_counter", increment_counter).ok()?;
+    linker.func_wrap(module, "entry_hash", entry_hash).ok()?;
+    linker.func_wrap(module, "cas_entry", cas_entry).ok()?;
+    linker.func_wrap(module, "patch_table_entry", patch_table_entry).ok()?;
+    linker.func_wrap(module, "entry_history", entry_history).ok()?;
+    linker.func_wrap(module, "read_table_entry_at", read_table_entry_at).ok()?;
+    linker.func_wrap(module, "sql_query", sql_query).ok()?;
+    linker.func_wrap(module, "search_table", search_table).ok()?;
+    linker.func_wrap(module, "call_service", call_service).ok()?;
+    linker.func_wrap(module, "publish", publish).ok()?;
+    linker.func_wrap(module, "upload_token", upload_token).ok()?;
+    linker.func_wrap(module, "write_blob", write_blob).ok()?;
+    linker.func_wrap(module, "read_blob", read_blob).ok()?;
+    linker.func_wrap(module, "read_blob_type", read_blob_type).ok()?;
+    linker.func_wrap(module, "query_table", query_table).ok()?;
+    linker.func_wrap(module, "read_table_page", read_table_page).ok()?;
+    linker.func_wrap(module, "db_sync_status", db_sync_status).ok()?;
+    linker.func_wrap(module, "cache_get", cache_get).ok()?;
+    linker.func_wrap(module, "env_var", env_var).ok()?;
+    linker.func_wrap(module, "cache_put", cache_put).ok()?;
+    linker.func_wrap(module, "erase_subject", erase_subject).ok()?;
+    linker.func_wrap(module, "verify_captcha", verify_captcha).ok()?;
+    linker.func_wrap(module, "translate", translate).ok()?;
+    linker.func_wrap(module, "verify_webhook", verify_webhook).ok()?;
+    linker.func_wrap(module, "send_email", send_email).ok()?;
+    linker.func_wrap(module, "absolute_url", absolute_url).ok()?;
+    linker.func_wrap(module, "set_template_name", set_template_name).ok()?;
+    linker.func_wrap(module, "set_template_param", set_template_param).ok()?;
+    linker.func_wrap(module, "set_template_param_raw", set_template_param_raw).ok()?;
+
+    Some(())
+}
+
 pub fn read_table_entry(
     mut caller: Caller,
     _db_token: u64,
@@ -118,35 +518,211 @@ pub fn read_table_entry(
     tp: u64,
     kl: u64, // key
     kp: u64,
-    _out_json_len_ptr: u64,
 ) -> /* out_json_ptr */ Result<u64, Trap> {
-    let mut handle = replace(caller.data_mut(), Handle::new());
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
     let repo = handle.repo(false)?;
     let repo = repo.read().unwrap();
 
-    let file_path = handle.db_path(caller.as_context(), tl as _, tp as _, kl as _, kp as _)?;
-    match repo.read_file(file_path) {
-        Ok(slice) => {
-            let len = slice.len() as u64;
+    let path = handle.db_path(caller.as_context(), tl as _, tp as _, kl as _, kp as _)?.to_string();
+
+    // the script must see its own pending writes
+    let content = handle.read_path(&**repo, &path)?.map(Cow::into_owned);
+    match content {
+        Some(content) => handle.write_guest_json(caller, &content),
+        None => Ok(0),
+    }
+}
+
+pub fn read_table_entries(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // keys, one per line
+    kp: u64,
+) -> /* out_json_ptr */ Result<u64, Trap> {
+    let handle = caller.data();
+    let ctx = caller.as_context();
+    let table = handle.read_mem_str(&ctx, tp as _, tl as _)?.to_string();
+    let keys = handle.read_mem_str(&ctx, kp as _, kl as _)?.to_string();
+
+    write_entries(&mut caller, &table, keys.split('\n'))
+}
 
-            let ptr = handle.malloc.unwrap().call(&mut caller, (len,))?.0;
-            handle.mem.unwrap().write(&mut caller, ptr as _, slice).unwrap();
+/// Writes a `{ key: entry or null }` object in guest memory, see [`Handle::write_guest_json`]
+pub fn write_entries<'a>(
+    caller: &mut Caller,
+    table: &str,
+    keys: impl Iterator<Item = &'a str>,
+) -> /* out_json_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(caller);
+    let (handle, caller) = guard.split();
+    let repo = handle.repo(false)?;
+    let repo = repo.read().unwrap();
 
-            let json_ptr = handle.parse_json.unwrap().call(&mut caller, (ptr, len))?.0;
-            handle.free.unwrap().call(&mut caller, (ptr, len))?;
+    // { key: entry or null }, parsed once by the guest
+    let mut output = String::from("{");
+    let mut seen = Vec::new();
+    for key in keys.filter(|key| !key.is_empty()) {
+        if seen.contains(&key) {
+            continue;
+        }
 
-            let _ = replace(caller.data_mut(), handle);
+        if !seen.is_empty() {
+            output.push(',');
+        }
 
-            Ok(json_ptr)
-        },
-        Err(rustgit::Error::PathError) => {
-            let _ = replace(caller.data_mut(), handle);
-            Ok(0)
-        },
-        Err(e) => Err(Trap::new(format!("read_table_entry: {:?}", e))),
+        push_json_str(&mut output, key);
+        output.push(':');
+
+        // the script must see its own pending writes
+        match handle.read_entry_str(&**repo, table, key)? {
+            Some(text) => output.push_str(&text),
+            None => output.push_str("null"),
+        }
+
+        seen.push(key);
+    }
+    output.push('}');
+
+    handle.write_guest_json(caller, output.as_bytes())
+}
+
+pub fn json_open(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+) -> /* json handle */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let repo = handle.repo(false)?;
+    let repo = repo.read().unwrap();
+
+    let ctx = caller.as_context();
+    let table = handle.read_mem_str(&ctx, tp as _, tl as _)?.to_string();
+    let key = handle.read_mem_str(&ctx, kp as _, kl as _)?.to_string();
+
+    // the script must see its own pending writes
+    let file = match handle.read_entry_str(&**repo, &table, &key)? {
+        Some(text) => Some(JsonFile::new(Some(&text)).map_err(|e| trap(format!("Invalid entry: {:?}", e)))?),
+        None => None,
+    };
+
+    let json = file.map(|file| handle.json_docs.insert(file)).unwrap_or(0);
+    Ok(json)
+}
+
+pub fn json_new(mut caller: Caller, _db_token: u64) -> /* json handle */ Result<u64, Trap> {
+    let handle = caller.data_mut();
+    handle.repo(false)?;
+
+    let file = JsonFile::new(None).map_err(|e| trap(format!("{:?}", e)))?;
+    Ok(handle.json_docs.insert(file))
+}
+
+pub fn json_get_str(
+    mut caller: Caller,
+    _db_token: u64,
+    json: u64,
+    pl: u64, // path
+    pp: u64,
+    out_len_ptr: u64,
+) -> /* out_str_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let ctx = caller.as_context();
+    let path = handle.read_mem_str(&ctx, pp as _, pl as _)?;
+    let value = host_json::get(handle.json_docs.get(json)?, path).as_string().cloned();
+
+    match value {
+        Some(string) => handle.write_guest_bytes(caller, string.as_bytes(), out_len_ptr),
+        None => Ok(0),
     }
 }
 
+// `F64` is `f64` itself with wasmtime
+#[cfg_attr(feature = "wasmtime", allow(clippy::useless_conversion))]
+pub fn json_get_num(
+    caller: Caller,
+    _db_token: u64,
+    json: u64,
+    pl: u64, // path
+    pp: u64,
+) -> /* NaN if missing */ Result<F64, Trap> {
+    let handle = caller.data();
+    let ctx = caller.as_context();
+    let path = handle.read_mem_str(&ctx, pp as _, pl as _)?;
+    let value = host_json::get(handle.json_docs.get(json)?, path).as_num();
+    Ok(value.unwrap_or(f64::NAN).into())
+}
+
+pub fn json_set_str(
+    mut caller: Caller,
+    _db_token: u64,
+    json: u64,
+    pl: u64, // path
+    pp: u64,
+    vl: u64, // value
+    vp: u64,
+) -> Result<(), Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+
+    let ctx = caller.as_context();
+    let path = handle.read_mem_str(&ctx, pp as _, pl as _)?;
+    let value = handle.read_mem_str(&ctx, vp as _, vl as _)?;
+    host_json::set(handle.json_docs.get_mut(json)?, path, Leaf::String(value))?;
+    Ok(())
+}
+
+#[cfg_attr(feature = "wasmtime", allow(clippy::useless_conversion))]
+pub fn json_set_num(
+    mut caller: Caller,
+    _db_token: u64,
+    json: u64,
+    pl: u64, // path
+    pp: u64,
+    value: F64,
+) -> Result<(), Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+
+    let ctx = caller.as_context();
+    let path = handle.read_mem_str(&ctx, pp as _, pl as _)?;
+    host_json::set(handle.json_docs.get_mut(json)?, path, Leaf::Number(value.into()))?;
+    Ok(())
+}
+
+/// Writes a document to an entry, like `write_table_entry`
+pub fn json_save(
+    mut caller: Caller,
+    _db_token: u64,
+    json: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+) -> Result<(), Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    handle.repo(true)?;
+
+    let file = handle.json_docs.get(json)?;
+    let bytes = file.dump(&JsonPath::new()).map_err(|e| trap(format!("{:?}", e)))?.as_bytes().to_vec();
+
+    let file_path = handle.db_path(caller.as_context(), tl as _, tp as _, kl as _, kp as _)?.to_string();
+    handle.transaction.writes.push((file_path, bytes));
+    Ok(())
+}
+
+pub fn json_close(mut caller: Caller, _db_token: u64, json: u64) -> Result<(), Trap> {
+    caller.data_mut().json_docs.remove(json)
+}
+
 pub fn write_table_entry(
     mut caller: Caller,
     _db_token: u64,
@@ -157,60 +733,883 @@ pub fn write_table_entry(
     json_len: u64,
     json_ptr: u64,
 ) -> Result<(), Trap> {
-    let mut handle = replace(caller.data_mut(), Handle::new());
-    let repo = handle.repo(true)?;
-    let mut repo = repo.write().unwrap();
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    handle.repo(true)?;
 
     let (jp, jl) = (json_ptr as usize, json_len as usize);
     let bytes = handle.read_mem(&caller.as_context(), jp, jl)?.to_vec();
 
-    let fail = |e| Trap::new(format!("Repository::stage(): {:?}", e));
-    let file_path = handle.db_path(caller.as_context(), tl as _, tp as _, kl as _, kp as _)?;
-    repo.stage(file_path, Some((bytes, FileType::RegularFile))).map_err(fail)?;
-
-    let _ = replace(caller.data_mut(), handle);
+    let file_path = handle.db_path(caller.as_context(), tl as _, tp as _, kl as _, kp as _)?.to_string();
+    handle.transaction.writes.push((file_path, bytes));
     Ok(())
 }
 
-pub fn set_template_name(
+/// (blob content, content type)
+pub fn blob_paths(table: &str, key: &str) -> (String, String) {
+    (format!("{}/{}.bin", table, key), format!("{}/{}.mime", table, key))
+}
+
+fn valid_content_type(content_type: &str) -> bool {
+    !content_type.is_empty() && !content_type.contains(|c: char| c.is_ascii_control() || !c.is_ascii())
+}
+
+pub fn write_blob(
     mut caller: Caller,
     _db_token: u64,
-    name_len: u64,
-    name_ptr: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+    cl: u64, // content type
+    cp: u64,
+    bl: u64, // bytes
+    bp: u64,
 ) -> Result<(), Trap> {
-    let mut handle = replace(caller.data_mut(), Handle::new());
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    handle.repo(true)?;
+
+    let max_size = handle.database.as_ref().map(|db| db.max_blob_size).unwrap_or(0);
+    if bl as usize > max_size {
+        return Err(trap(format!("write_blob: blob exceeds {} bytes", max_size)));
+    }
 
-    let (np, nl) = (name_ptr as usize, name_len as usize);
     let ctx = caller.as_context();
-    let template = handle.read_mem_str(&ctx, np, nl)?;
+    let table = handle.read_mem_str(&ctx, tp as _, tl as _)?;
+    let key = handle.read_mem_str(&ctx, kp as _, kl as _)?;
+    let (data_path, type_path) = blob_paths(table, key);
 
-    handle.template = Some(handle.pool.intern(template));
+    let content_type = handle.read_mem_str(&ctx, cp as _, cl as _)?;
+    if !valid_content_type(content_type) {
+        return Err(trap("write_blob: invalid content type"));
+    }
+
+    let content_type = content_type.as_bytes().to_vec();
+    let bytes = handle.read_mem(&ctx, bp as _, bl as _)?.to_vec();
 
-    let _ = replace(caller.data_mut(), handle);
+    handle.transaction.writes.push((data_path, bytes));
+    handle.transaction.writes.push((type_path, content_type));
     Ok(())
 }
 
-pub fn set_template_param(
-    mut caller: Caller,
+pub fn read_blob(
+    caller: Caller,
     _db_token: u64,
-    key_len: u64,
-    key_ptr: u64,
-    value_len: u64,
-    value_ptr: u64,
-) -> Result<(), Trap> {
-    let mut handle = replace(caller.data_mut(), Handle::new());
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+    out_len_ptr: u64,
+) -> /* out_ptr, 0 if missing */ Result<u64, Trap> {
+    return_blob_file(caller, tl, tp, kl, kp, out_len_ptr, |(data_path, _)| data_path)
+}
+
+pub fn read_blob_type(
+    caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+    out_len_ptr: u64,
+) -> /* out_ptr, 0 if missing */ Result<u64, Trap> {
+    return_blob_file(caller, tl, tp, kl, kp, out_len_ptr, |(_, type_path)| type_path)
+}
+
+fn return_blob_file(
+    mut caller: Caller,
+    tl: u64,
+    tp: u64,
+    kl: u64,
+    kp: u64,
+    out_len_ptr: u64,
+    select: fn((String, String)) -> String,
+) -> Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let repo = handle.repo(false)?;
+    let repo = repo.read().unwrap();
+
     let ctx = caller.as_context();
+    let table = handle.read_mem_str(&ctx, tp as _, tl as _)?;
+    let key = handle.read_mem_str(&ctx, kp as _, kl as _)?;
+    let path = select(blob_paths(table, key));
 
-    let (kp, kl) = (key_ptr as usize, key_len as usize);
-    let key = handle.read_mem_str(&ctx, kp, kl)?;
-    let key = handle.pool.intern(key);
+    let bytes = handle.read_path(&**repo, &path)?.map(|bytes| bytes.to_vec());
+    match bytes {
+        Some(bytes) => handle.write_guest_bytes(caller, &bytes, out_len_ptr),
+        None => Ok(0),
+    }
+}
 
-    let (vp, vl) = (value_ptr as usize, value_len as usize);
-    let value = handle.read_mem_str(&ctx, vp, vl)?.to_string();
+pub fn env_var(
+    mut caller: Caller,
+    _db_token: u64,
+    nl: u64, // name
+    np: u64,
+    out_len_ptr: u64,
+) -> /* out_str_ptr, 0 if missing */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let value = handle.site.env.get(handle.read_mem_str(&caller.as_context(), np as _, nl as _)?);
 
-    handle.parameters.insert(key, value);
+    match value {
+        Some(value) => handle.write_guest_bytes(caller, value.as_bytes(), out_len_ptr),
+        None => Ok(0),
+    }
+}
 
-    let _ = replace(caller.data_mut(), handle);
-    Ok(())
+pub fn cache_get(
+    mut caller: Caller,
+    _db_token: u64,
+    kl: u64, // key
+    kp: u64,
+    out_len_ptr: u64,
+) -> /* out_ptr, 0 if missing */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let value = handle.site.cache.get(handle.read_mem_str(&caller.as_context(), kp as _, kl as _)?);
+
+    match value {
+        Some(value) => handle.write_guest_bytes(caller, &value, out_len_ptr),
+        None => Ok(0),
+    }
 }
 
+pub fn cache_put(
+    mut caller: Caller,
+    _db_token: u64,
+    kl: u64, // key
+    kp: u64,
+    vl: u64, // value
+    vp: u64,
+    ttl_secs: u64,
+) -> Result<(), Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+
+    let ctx = caller.as_context();
+    let key = handle.read_mem_str(&ctx, kp as _, kl as _)?;
+    let value = handle.read_mem(&ctx, vp as _, vl as _)?.to_vec();
+    handle.site.cache.put(key, value, Duration::from_secs(ttl_secs));
+    Ok(())
+}
+
+pub fn erase_subject(
+    mut caller: Caller,
+    _db_token: u64,
+    pl: u64, // table prefix
+    pp: u64,
+    kl: u64, // subject key
+    kp: u64,
+) -> /* number of erased files */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let repo = handle.repo(true)?;
+    let mut repo = repo.write().unwrap();
+
+    let ctx = caller.as_context();
+    let table_prefix = handle.read_mem_str(&ctx, pp as _, pl as _)?;
+    let subject_key = handle.read_mem_str(&ctx, kp as _, kl as _)?;
+
+    let fail = || trap("erase_subject: failed to list database files");
+    let paths = subject_files(&**repo, table_prefix, subject_key).ok().ok_or_else(fail)?;
+    handle.erase_immediately(&mut **repo, &paths)?;
+    Ok(paths.len() as u64)
+}
+
+pub fn verify_captcha(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // provider token
+    tp: u64,
+) -> /* 1 if valid, 0 otherwise */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let fail = || trap("verify_captcha: no captcha config");
+
+    let ctx = caller.as_context();
+    handle.site.captcha.as_ref().ok_or_else(fail).and_then(|captcha| {
+        let token = handle.read_mem_str(&ctx, tp as _, tl as _)?;
+        Ok(captcha.verify(token) as u64)
+    })
+}
+
+pub fn verify_webhook(
+    mut caller: Caller,
+    _db_token: u64,
+    pl: u64, // provider
+    pp: u64,
+    sl: u64, // signature header
+    sp: u64,
+) -> /* 1 if valid, 0 otherwise */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let fail = || trap("verify_webhook: no webhooks config");
+
+    let ctx = caller.as_context();
+    handle.site.webhooks.as_ref().ok_or_else(fail).and_then(|webhooks| {
+        let provider = handle.read_mem_str(&ctx, pp as _, pl as _)?;
+        let signature = handle.read_mem_str(&ctx, sp as _, sl as _)?;
+        let valid = webhooks.verify(&handle.site.env, provider, signature, &handle.request.body);
+        valid.map(|valid| valid as u64).map_err(|()| trap("verify_webhook: unconfigured provider or missing secret"))
+    })
+}
+
+pub fn send_email(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // recipient
+    tp: u64,
+    sl: u64, // subject
+    sp: u64,
+    bl: u64, // body
+    bp: u64,
+) -> /* 1 if sent, 0 otherwise */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let fail = || trap("send_email: no email config");
+
+    let ctx = caller.as_context();
+    handle.site.email.as_ref().ok_or_else(fail).and_then(|email| {
+        let to = handle.read_mem_str(&ctx, tp as _, tl as _)?;
+        let subject = handle.read_mem_str(&ctx, sp as _, sl as _)?;
+        let body = handle.read_mem_str(&ctx, bp as _, bl as _)?;
+        Ok(email.send(&handle.site.env, to, subject, body).is_ok() as u64)
+    })
+}
+
+pub fn request_id(caller: Caller, _db_token: u64, out_len_ptr: u64) -> Result<u64, Trap> {
+    return_request_str(caller, out_len_ptr, |request| &request.id)
+}
+
+pub fn request_subdomain(caller: Caller, _db_token: u64, out_len_ptr: u64) -> Result<u64, Trap> {
+    return_request_str(caller, out_len_ptr, |request| &request.subdomain)
+}
+
+pub fn request_client_ip(caller: Caller, _db_token: u64, out_len_ptr: u64) -> Result<u64, Trap> {
+    return_request_str(caller, out_len_ptr, |request| &request.client_ip)
+}
+
+pub fn request_scheme(caller: Caller, _db_token: u64, out_len_ptr: u64) -> Result<u64, Trap> {
+    return_request_str(caller, out_len_ptr, |request| &request.scheme)
+}
+
+pub fn request_csrf_token(caller: Caller, _db_token: u64, out_len_ptr: u64) -> Result<u64, Trap> {
+    return_request_str(caller, out_len_ptr, |request| &request.csrf_token)
+}
+
+pub fn request_locale(mut caller: Caller, _db_token: u64, out_len_ptr: u64) -> Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let locale = handle.site.i18n.pick(&handle.request.accept_language);
+    handle.write_guest_bytes(caller, locale.as_bytes(), out_len_ptr)
+}
+
+pub fn request_header(
+    mut caller: Caller,
+    _db_token: u64,
+    nl: u64, // name
+    np: u64,
+    out_len_ptr: u64,
+) -> /* out_str_ptr, 0 if missing */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    handle.read_mem_str(&caller.as_context(), np as _, nl as _).map(|name| {
+        let header = handle.request.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name));
+        header.map(|(_, value)| value)
+    }).and_then(|value| match value {
+        Some(value) => handle.write_guest_bytes(caller, value.as_bytes(), out_len_ptr),
+        None => Ok(0),
+    })
+}
+
+pub fn request_method(caller: Caller, _db_token: u64, out_len_ptr: u64) -> Result<u64, Trap> {
+    return_request_str(caller, out_len_ptr, |request| &request.method)
+}
+
+pub fn request_url(caller: Caller, _db_token: u64, out_len_ptr: u64) -> Result<u64, Trap> {
+    return_request_str(caller, out_len_ptr, |request| &request.url)
+}
+
+pub fn request_route(caller: Caller, _db_token: u64, out_len_ptr: u64) -> Result<u64, Trap> {
+    return_request_str(caller, out_len_ptr, |request| &request.route)
+}
+
+pub fn request_remainder(caller: Caller, _db_token: u64, out_len_ptr: u64) -> Result<u64, Trap> {
+    return_request_str(caller, out_len_ptr, |request| &request.remainder)
+}
+
+pub fn request_body(mut caller: Caller, _db_token: u64, out_len_ptr: u64) -> /* out_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    handle.write_guest_bytes(caller, &handle.request.body, out_len_ptr)
+}
+
+pub fn site_routes(mut caller: Caller, _db_token: u64, out_len_ptr: u64) -> /* out_str_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    handle.write_guest_bytes(caller, handle.site.routes.join("\n").as_bytes(), out_len_ptr)
+}
+
+fn return_request_str(
+    mut caller: Caller,
+    out_len_ptr: u64,
+    select: fn(&RequestInfo) -> &String,
+) -> /* out_str_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let string = select(&handle.request);
+    handle.write_guest_bytes(caller, string.as_bytes(), out_len_ptr)
+}
+
+pub fn query_table(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    fl: u64, // filter
+    fp: u64,
+) -> /* out_json_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let repo = handle.repo(false)?;
+    let repo = repo.read().unwrap();
+
+    let ctx = caller.as_context();
+    let table = handle.read_mem_str(&ctx, tp as _, tl as _)?.to_string();
+    let filter = handle.read_mem_str(&ctx, fp as _, fl as _)?;
+    let filter = Filter::parse(filter).map_err(|_| trap("query_table: invalid filter"))?;
+
+    let mut output = String::from("[");
+    for key in handle.table_keys(&**repo, &table)? {
+        let text = handle.read_entry_str(&**repo, &table, &key)?.unwrap(/* listed */);
+        let entry = parse_entry(&text, &table, &key)?;
+
+        if filter.matches(&entry) {
+            push_entry(&mut output, &text);
+        }
+    }
+    output.push(']');
+
+    handle.write_guest_json(caller, output.as_bytes())
+}
+
+pub fn read_table_page(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    offset: u64,
+    limit: u64,
+    sl: u64, // sort key
+    sp: u64,
+) -> /* out_json_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let repo = handle.repo(false)?;
+    let repo = repo.read().unwrap();
+
+    let ctx = caller.as_context();
+    let table = handle.read_mem_str(&ctx, tp as _, tl as _)?.to_string();
+    let sort_key = handle.read_mem_str(&ctx, sp as _, sl as _)?;
+    let (descending, sort_key) = match sort_key.strip_prefix('-') {
+        Some(sort_key) => (true, sort_key.to_string()),
+        None => (false, sort_key.to_string()),
+    };
+
+    let mut keys = handle.table_keys(&**repo, &table)?;
+
+    // sorting by content requires reading every entry
+    if !sort_key.is_empty() {
+        let path = JsonPath::from(parse_path(&sort_key));
+        let mut sorted = Vec::with_capacity(keys.len());
+
+        for key in keys {
+            let text = handle.read_entry_str(&**repo, &table, &key)?.unwrap(/* listed */);
+            let value = parse_entry(&text, &table, &key)?.get(&path).clone();
+            sorted.push((value, key));
+        }
+
+        // numbers, then strings, then other values
+        let rank = |value: &JsonValue| match value {
+            JsonValue::Number(_) => 0,
+            JsonValue::String(_) => 1,
+            _ => 2,
+        };
+
+        sorted.sort_by(|(a, _), (b, _)| {
+            let ordering = compare(a, b).unwrap_or(Ordering::Equal);
+            rank(a).cmp(&rank(b)).then(ordering)
+        });
+        keys = sorted.into_iter().map(|(_, key)| key).collect();
+    }
+
+    if descending {
+        keys.reverse();
+    }
+
+    let mut output = String::from("[");
+    for key in keys.iter().skip(offset as _).take(limit as _) {
+        let text = handle.read_entry_str(&**repo, &table, key)?.unwrap(/* listed */);
+        push_entry(&mut output, &text);
+    }
+    output.push(']');
+
+    handle.write_guest_json(caller, output.as_bytes())
+}
+
+fn parse_entry(text: &str, table: &str, key: &str) -> Result<JsonFile, Trap> {
+    let fail = || trap(format!("Invalid entry: {}/{}", table, key));
+    JsonFile::new(Some(text)).ok().ok_or_else(fail)
+}
+
+fn push_entry(output: &mut String, text: &str) {
+    if output.len() > 1 {
+        output.push(',');
+    }
+
+    output.push_str(text);
+}
+
+/// Missing entries have a hash of zero
+fn entry_hash_u64(content: Option<&[u8]>) -> u64 {
+    content_hash(content).unwrap_or(0)
+}
+
+pub fn increment_counter(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+    delta: i64,
+) -> /* new value */ Result<i64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let repo = handle.repo(true)?;
+    let mut repo = repo.write().unwrap();
+
+    let path = handle.db_path(caller.as_context(), tl as _, tp as _, kl as _, kp as _)?.to_string();
+    let fail = || trap(format!("increment_counter: {} isn't an integer", path));
+
+    let value = match repo.read_file(&path) {
+        Ok(bytes) => {
+            let text = core::str::from_utf8(bytes).ok().ok_or_else(fail)?;
+            text.trim().parse::<i64>().ok().ok_or_else(fail)?
+        },
+        Err(rustgit::Error::PathError) => 0,
+        Err(e) => return Err(trap(format!("increment_counter: {:?}", e))),
+    };
+
+    let value = value.checked_add(delta).ok_or_else(|| trap("increment_counter: overflow"))?;
+    handle.write_immediately(&mut **repo, &path, value.to_string().into_bytes())?;
+    Ok(value)
+}
+
+pub fn entry_hash(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+) -> Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let repo = handle.repo(false)?;
+    let repo = repo.read().unwrap();
+
+    let path = handle.db_path(caller.as_context(), tl as _, tp as _, kl as _, kp as _)?;
+    let hash = match repo.read_file(path) {
+        Ok(bytes) => entry_hash_u64(Some(bytes)),
+        Err(rustgit::Error::PathError) => entry_hash_u64(None),
+        Err(e) => return Err(trap(format!("entry_hash: {:?}", e))),
+    };
+    Ok(hash)
+}
+
+pub fn cas_entry(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+    expected_hash: u64,
+    json_len: u64,
+    json_ptr: u64,
+) -> /* 1 if swapped */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let repo = handle.repo(true)?;
+    let mut repo = repo.write().unwrap();
+
+    let (jp, jl) = (json_ptr as usize, json_len as usize);
+    let bytes = handle.read_mem(&caller.as_context(), jp, jl)?.to_vec();
+    let path = handle.db_path(caller.as_context(), tl as _, tp as _, kl as _, kp as _)?.to_string();
+
+    let current = match repo.read_file(&path) {
+        Ok(bytes) => entry_hash_u64(Some(bytes)),
+        Err(rustgit::Error::PathError) => entry_hash_u64(None),
+        Err(e) => return Err(trap(format!("cas_entry: {:?}", e))),
+    };
+
+    let swapped = current == expected_hash;
+    if swapped {
+        handle.write_immediately(&mut **repo, &path, bytes)?;
+    }
+    Ok(swapped as u64)
+}
+
+pub fn patch_table_entry(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+    patch_len: u64,
+    patch_ptr: u64,
+) -> /* 1 if applied */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let repo = handle.repo(true)?;
+    let mut repo = repo.write().unwrap();
+
+    let (pp, pl) = (patch_ptr as usize, patch_len as usize);
+    let patch = handle.read_mem(&caller.as_context(), pp, pl)?.to_vec();
+    let patch = String::from_utf8(patch).map_err(|_| trap("patch_table_entry: invalid patch"))?;
+    let path = handle.db_path(caller.as_context(), tl as _, tp as _, kl as _, kp as _)?.to_string();
+
+    let entry = match repo.read_file(&path) {
+        Ok(bytes) => Some(core::str::from_utf8(bytes).map_err(|_| trap(format!("patch_table_entry: {} isn't valid JSON", path)))?),
+        Err(rustgit::Error::PathError) => None,
+        Err(e) => return Err(trap(format!("patch_table_entry: {:?}", e))),
+    };
+
+    let patched = moth_abi::json_patch::apply(entry, &patch, push_json_str).map_err(|e| trap(format!("patch_table_entry: {}", e)))?;
+    let applied = patched.is_some();
+    if let Some(json) = patched {
+        handle.write_immediately(&mut **repo, &path, json.into_bytes())?;
+    }
+    Ok(applied as u64)
+}
+
+pub fn sql_query(
+    mut caller: Caller,
+    _db_token: u64,
+    sl: u64, // statement
+    sp: u64,
+    pl: u64, // parameters
+    pp: u64,
+) -> /* out_json_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let read_only = match handle.repo {
+        RepositoryHandle::ReadOnly(_) => true,
+        RepositoryHandle::ReadWrite(_) => false,
+        RepositoryHandle::None => return Err(trap("Nested internal call")),
+    };
+
+    let database = handle.database.as_ref().ok_or_else(|| trap("Nested internal call"))?;
+    let sql = database.sql.as_ref().ok_or_else(|| trap("sql_query: the site has no SQLite database"))?;
+
+    let ctx = caller.as_context();
+    let statement = handle.read_mem_str(&ctx, sp as _, sl as _)?;
+    let params = handle.read_mem_str(&ctx, pp as _, pl as _)?;
+
+    let mut span = trace::span("sql query");
+    let rows = sql.query(statement, params, read_only).map_err(|e| {
+        span.fail();
+        trap(format!("sql_query: {}", e))
+    })?;
+
+    core::mem::drop(span);
+    handle.write_guest_json(caller, rows.as_bytes())
+}
+
+pub fn search_table(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    ql: u64, // query
+    qp: u64,
+    limit: u64,
+) -> /* out_json_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let database = handle.database.as_ref().ok_or_else(|| trap("Nested internal call"))?;
+
+    let ctx = caller.as_context();
+    let table = handle.read_mem_str(&ctx, tp as _, tl as _)?;
+    let query = handle.read_mem_str(&ctx, qp as _, ql as _)?;
+
+    let mut span = trace::span("db search");
+    span.attribute("moth.db.table", table);
+    let results = database.search(table, query, limit as _).map_err(|e| {
+        span.fail();
+        trap(format!("search_table: {}", e))
+    })?;
+
+    core::mem::drop(span);
+    handle.write_guest_json(caller, results.as_bytes())
+}
+
+pub fn publish(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // topic
+    tp: u64,
+    jl: u64, // json message
+    jp: u64,
+) -> Result<(), Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+
+    let ctx = caller.as_context();
+    let result = handle.read_mem_str(&ctx, tp as _, tl as _).and_then(|topic| {
+        let json = handle.read_mem_str(&ctx, jp as _, jl as _)?;
+        match JsonFile::new(Some(json)) {
+            Ok(_) => Ok((topic.to_string(), json.to_string())),
+            Err(_) => Err(trap("publish: invalid JSON message")),
+        }
+    });
+
+    result.map(|message| handle.messages.push(message))
+}
+
+pub fn upload_token(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+    cl: u64, // content type
+    cp: u64,
+    max_size: u64,
+    bl: u64, // callback, empty for none
+    bp: u64,
+    out_len_ptr: u64,
+) -> /* out_str_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    handle.database.as_ref().ok_or_else(|| trap("Nested internal call"))?;
+    if max_size as usize > handle.site.uploads.max_size {
+        return Err(trap(format!("upload_token: uploads can't exceed {} bytes", handle.site.uploads.max_size)));
+    }
+
+    let ctx = caller.as_context();
+    let table = handle.read_mem_str(&ctx, tp as _, tl as _)?;
+    let key = handle.read_mem_str(&ctx, kp as _, kl as _)?;
+    let content_type = handle.read_mem_str(&ctx, cp as _, cl as _)?;
+    if !valid_content_type(content_type) {
+        return Err(trap("upload_token: invalid content type"));
+    }
+
+    let callback = handle.read_mem_str(&ctx, bp as _, bl as _)?;
+    let token = handle.site.uploads.create(Target {
+        table: table.to_string(),
+        key: key.to_string(),
+        content_type: content_type.to_string(),
+        max_size: max_size as _,
+        callback: (!callback.is_empty()).then(|| callback.to_string()),
+    });
+
+    handle.write_guest_bytes(caller, token.as_bytes(), out_len_ptr)
+}
+
+pub fn call_service(
+    mut caller: Caller,
+    _db_token: u64,
+    hl: u64, // hostname
+    hp: u64,
+    cl: u64, // callback
+    cp: u64,
+    jl: u64, // json body
+    jp: u64,
+) -> /* out_json_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let read_only = match handle.repo {
+        RepositoryHandle::ReadOnly(_) => true,
+        RepositoryHandle::ReadWrite(_) => false,
+        RepositoryHandle::None => return Err(trap("Nested internal call")),
+    };
+
+    let ctx = caller.as_context();
+    let hostname = handle.read_mem_str(&ctx, hp as _, hl as _)?;
+    let callback = handle.read_mem_str(&ctx, cp as _, cl as _)?;
+    let json = handle.read_mem_str(&ctx, jp as _, jl as _)?;
+
+    let mut span = trace::span("service call");
+    span.attribute("moth.service", hostname);
+    let response = services::call(hostname, callback, json, read_only, &handle.request).map_err(|e| {
+        span.fail();
+        trap(format!("call_service: {}", e))
+    })?;
+
+    core::mem::drop(span);
+    handle.write_guest_json(caller, response.as_bytes())
+}
+
+pub fn entry_history(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+    limit: u64,
+) -> /* out_json_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let path = handle.db_path(caller.as_context(), tl as _, tp as _, kl as _, kp as _)?.to_string();
+
+    let fail = || trap("entry_history: failed to fetch the database history");
+    let history = handle.database.as_ref().ok_or_else(|| trap("Nested internal call"))?.history().map_err(|()| fail())?;
+    let json = history::revisions_json(&history.revisions(&path, limit as _));
+    handle.write_guest_json(caller, json.as_bytes())
+}
+
+pub fn read_table_entry_at(
+    mut caller: Caller,
+    _db_token: u64,
+    tl: u64, // table name
+    tp: u64,
+    kl: u64, // key
+    kp: u64,
+    rl: u64, // revision
+    rp: u64,
+) -> /* out_json_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let revision = handle.read_mem_str(&caller.as_context(), rp as _, rl as _)?.to_string();
+    let path = handle.db_path(caller.as_context(), tl as _, tp as _, kl as _, kp as _)?.to_string();
+
+    let fail = || trap("read_table_entry_at: failed to fetch the database history");
+    let history = handle.database.as_ref().ok_or_else(|| trap("Nested internal call"))?.history().map_err(|()| fail())?;
+    let content = history.read(&path, &revision).map_err(|()| trap(format!("read_table_entry_at: unknown revision {}", revision)))?;
+    match content {
+        Some(content) => handle.write_guest_json(caller, content),
+        None => Ok(0),
+    }
+}
+
+pub fn db_sync_status(mut caller: Caller, _db_token: u64) -> /* out_json_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+
+    let fail = || trap("Nested internal call");
+    handle.database.as_ref().ok_or_else(fail)
+        .map(|database| database.health.status().to_json())
+        .and_then(|json| handle.write_guest_json(caller, json.as_bytes()))
+}
+
+pub fn absolute_url(
+    mut caller: Caller,
+    _db_token: u64,
+    path_len: u64,
+    path_ptr: u64,
+    out_len_ptr: u64,
+) -> /* out_str_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+
+    let (pp, pl) = (path_ptr as usize, path_len as usize);
+    handle.read_mem_str(&caller.as_context(), pp, pl)
+        .map(|path| join_url(&handle.site.canonical_base, path))
+        .and_then(|url| handle.write_guest_bytes(caller, url.as_bytes(), out_len_ptr))
+}
+
+pub fn translate(
+    mut caller: Caller,
+    _db_token: u64,
+    key_len: u64,
+    key_ptr: u64,
+    out_len_ptr: u64,
+) -> /* out_str_ptr */ Result<u64, Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let locale = handle.site.i18n.pick(&handle.request.accept_language);
+
+    let (kp, kl) = (key_ptr as usize, key_len as usize);
+    handle.read_mem_str(&caller.as_context(), kp, kl)
+        .map(|key| handle.site.i18n.translate(locale, key))
+        .and_then(|text| handle.write_guest_bytes(caller, text.as_bytes(), out_len_ptr))
+}
+
+/// Joins the canonical `scheme://host` of a site and a path
+pub fn join_url(base: &str, path: &str) -> String {
+    format!("{}/{}", base, path.trim_start_matches('/'))
+}
+
+pub fn set_template_name(
+    mut caller: Caller,
+    _db_token: u64,
+    name_len: u64,
+    name_ptr: u64,
+) -> Result<(), Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+
+    let (np, nl) = (name_ptr as usize, name_len as usize);
+    let ctx = caller.as_context();
+    let template = handle.read_mem_str(&ctx, np, nl)?;
+
+    handle.template = Some(handle.site.pool.intern(template));
+    Ok(())
+}
+
+pub fn set_template_param(
+    caller: Caller,
+    _db_token: u64,
+    key_len: u64,
+    key_ptr: u64,
+    value_len: u64,
+    value_ptr: u64,
+) -> Result<(), Trap> {
+    insert_template_param(caller, key_len, key_ptr, value_len, value_ptr, true)
+}
+
+pub fn set_template_param_raw(
+    caller: Caller,
+    _db_token: u64,
+    key_len: u64,
+    key_ptr: u64,
+    value_len: u64,
+    value_ptr: u64,
+) -> Result<(), Trap> {
+    insert_template_param(caller, key_len, key_ptr, value_len, value_ptr, false)
+}
+
+fn insert_template_param(
+    mut caller: Caller,
+    key_len: u64,
+    key_ptr: u64,
+    value_len: u64,
+    value_ptr: u64,
+    escape: bool,
+) -> Result<(), Trap> {
+    let mut guard = HandleGuard::take(&mut caller);
+    let (handle, caller) = guard.split();
+    let ctx = caller.as_context();
+
+    let (kp, kl) = (key_ptr as usize, key_len as usize);
+    let key = handle.read_mem_str(&ctx, kp, kl)?;
+    let key = handle.site.pool.intern(key);
+
+    let (vp, vl) = (value_ptr as usize, value_len as usize);
+    let value = handle.read_mem_str(&ctx, vp, vl)?;
+    let value = match escape {
+        true => escape_html(value),
+        false => value.to_string(),
+    };
+
+    handle.parameters.insert(key, value);
+    Ok(())
+}
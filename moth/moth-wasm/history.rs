@@ -0,0 +1,149 @@
+//! Past revisions of database entries
+//!
+//! The history is the one of the remote branch: each sync pushes a commit, so
+//! the revisions of an entry are the commits which changed its file. Writes
+//! which weren't pushed yet aren't part of it. It's fetched from the remote
+//! for each call, which is slow; this is meant for audit pages.
+
+use rustgit::{Repository, Hash, Error as GitError};
+use rustgit::internals::{
+    ObjectStore, ObjectType, PackfileReader, TreeIter,
+    CommitField, get_commit_field, get_commit_field_hash,
+};
+use moth::push_json_str;
+use lmfu::HashSet;
+
+/// Commits fetched from the remote; older revisions aren't listed
+pub const MAX_DEPTH: usize = 500;
+
+/// A commit which changed an entry
+pub struct Revision {
+    pub hash: Hash,
+    /// Unix timestamp of the commit
+    pub timestamp: u64,
+    pub message: String,
+    /// The entry was erased by this commit
+    pub erased: bool,
+}
+
+/// Commits of the remote branch, newest first
+pub struct History {
+    objects: ObjectStore,
+    commits: Vec<Hash>,
+}
+
+impl History {
+    /// Takes a fresh clone of the remote branch
+    pub fn new(mut clone: Repository) -> Result<Self, ()> {
+        let git_error = |e: GitError| log::error!("Failed to read the database history: {:?}", e);
+
+        // rustgit doesn't expose the head of a clone: committing on top of
+        // it gives a handle on its history, which can then be packed
+        let signature = ("moth", "moth@localhost");
+        let marker = clone.commit("", signature, signature, None).map_err(git_error)?;
+
+        let mut packfile = Vec::new();
+        let heads = [("history", marker)];
+        clone.pack(HashSet::new(), &heads, &mut packfile, |_, _| ()).map_err(git_error)?;
+
+        let mut objects = ObjectStore::new();
+        let mut reader = PackfileReader::from_file(packfile).map_err(git_error)?;
+        reader.read_all_objects(&mut objects).map_err(git_error)?;
+
+        let mut commits = Vec::new();
+        let mut next = parent(&objects, marker);
+        while let Some(hash) = next.filter(|hash| objects.has(*hash)) {
+            commits.push(hash);
+            next = parent(&objects, hash);
+        }
+
+        Ok(Self { objects, commits })
+    }
+
+    /// Blob of a file in a commit
+    fn blob(&self, commit: Hash, path: &str) -> Option<Hash> {
+        let commit = self.objects.get_as(commit, ObjectType::Commit)?;
+        let mut current = get_commit_field_hash(commit, CommitField::Tree).ok()??;
+
+        for node in path.split('/') {
+            let tree = self.objects.get_as(current, ObjectType::Tree)?;
+            let mut entries = TreeIter::new(tree);
+            current = loop {
+                match entries.next().ok()? {
+                    Some((name, hash, _)) if name == node => break hash,
+                    Some(_) => continue,
+                    None => return None,
+                }
+            };
+        }
+
+        Some(current)
+    }
+
+    /// The `limit` latest revisions of a file, newest first
+    pub fn revisions(&self, path: &str, limit: usize) -> Vec<Revision> {
+        let mut revisions = Vec::new();
+        let blobs: Vec<_> = self.commits.iter().map(|commit| self.blob(*commit, path)).collect();
+
+        for (i, commit) in self.commits.iter().enumerate() {
+            if revisions.len() == limit {
+                break;
+            }
+
+            // the oldest fetched commit is only a revision if it has no parent
+            let previous = match blobs.get(i + 1) {
+                Some(blob) => *blob,
+                None if parent(&self.objects, *commit).is_some() => break,
+                None => None,
+            };
+
+            if blobs[i] == previous {
+                continue;
+            }
+
+            let content = self.objects.get_as(*commit, ObjectType::Commit).unwrap_or_default();
+            let field = |field| get_commit_field(content, field).ok().flatten().unwrap_or_default();
+
+            revisions.push(Revision {
+                hash: *commit,
+                timestamp: field(CommitField::CommitterTimestamp).parse().unwrap_or(0),
+                message: field(CommitField::Message).trim_end().to_string(),
+                erased: blobs[i].is_none(),
+            });
+        }
+
+        revisions
+    }
+
+    /// Content of a file as of a revision; None if it didn't exist then
+    ///
+    /// Fails if the revision isn't one of the fetched commits.
+    pub fn read(&self, path: &str, revision: &str) -> Result<Option<&[u8]>, ()> {
+        let hash = Hash::from_hex(revision).filter(|hash| self.commits.contains(hash));
+        let hash = hash.ok_or_else(|| log::error!("Unknown database revision: {}", revision))?;
+        let blob = self.blob(hash, path);
+        Ok(blob.and_then(|blob| self.objects.get_as(blob, ObjectType::Blob)))
+    }
+}
+
+fn parent(objects: &ObjectStore, commit: Hash) -> Option<Hash> {
+    let content = objects.get_as(commit, ObjectType::Commit)?;
+    get_commit_field_hash(content, CommitField::Parent(0)).ok()?
+}
+
+/// `[{ "revision", "timestamp", "message", "erased" }]`
+pub fn revisions_json(revisions: &[Revision]) -> String {
+    let mut json = String::from("[");
+    for (i, revision) in revisions.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+
+        json += &format!("{{\"revision\":\"{}\",\"timestamp\":{},\"message\":", revision.hash, revision.timestamp);
+        push_json_str(&mut json, &revision.message);
+        json += &format!(",\"erased\":{}}}", revision.erased);
+    }
+
+    json.push(']');
+    json
+}
@@ -0,0 +1,88 @@
+//! JSON documents kept by the host during a script call
+//!
+//! Scripts read & modify them by path instead of moving whole files across
+//! the wasm boundary. Paths are dot-separated and numeric steps index arrays,
+//! like sort keys: `items.0.name`. Documents are dropped when the call ends.
+
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue, parse_path};
+use super::wasm::{Trap, trap};
+
+#[derive(Default)]
+pub struct HostJson(Vec<Option<JsonFile>>);
+
+impl HostJson {
+    /// Returns the handle of the document, which is never zero
+    pub fn insert(&mut self, file: JsonFile) -> u64 {
+        let free = self.0.iter().position(Option::is_none);
+        let index = free.unwrap_or_else(|| {
+            self.0.push(None);
+            self.0.len() - 1
+        });
+
+        self.0[index] = Some(file);
+        index as u64 + 1
+    }
+
+    pub fn get(&self, handle: u64) -> Result<&JsonFile, Trap> {
+        let file = self.0.get((handle as usize).wrapping_sub(1)).and_then(Option::as_ref);
+        file.ok_or_else(|| trap("Invalid JSON handle"))
+    }
+
+    pub fn get_mut(&mut self, handle: u64) -> Result<&mut JsonFile, Trap> {
+        let file = self.0.get_mut((handle as usize).wrapping_sub(1)).and_then(Option::as_mut);
+        file.ok_or_else(|| trap("Invalid JSON handle"))
+    }
+
+    pub fn remove(&mut self, handle: u64) -> Result<(), Trap> {
+        self.get(handle)?;
+        self.0[handle as usize - 1] = None;
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+pub fn get<'a>(file: &'a JsonFile, path: &str) -> &'a JsonValue {
+    file.get(&JsonPath::from(parse_path(path)))
+}
+
+pub enum Leaf<'a> {
+    String(&'a str),
+    Number(f64),
+}
+
+/// Sets the value at `path`, creating missing objects & arrays on the way
+///
+/// Array items can be appended, using the length of the array as index.
+pub fn set(file: &mut JsonFile, path: &str, value: Leaf) -> Result<(), Trap> {
+    let fail = || trap(format!("Can't set JSON value at {}", path));
+    let mut current = JsonPath::new();
+
+    for step in path.split('.').filter(|step| !step.is_empty()) {
+        let index = step.parse::<usize>().ok();
+
+        if let (JsonValue::Null, index) = (file.get(&current), index) {
+            match index {
+                Some(_) => file.set_array(&current),
+                None => file.set_object(&current),
+            }
+        }
+
+        current = match (file.get(&current), index) {
+            (JsonValue::Array(length), Some(index)) if index < *length => current.i_num(index),
+            (JsonValue::Array(length), Some(index)) if index == *length => file.push(current),
+            // numeric steps index arrays, so they can't name properties
+            (JsonValue::Object(_), None) => file.prop(current, step),
+            _ => return Err(fail()),
+        };
+    }
+
+    match value {
+        Leaf::String(string) => file.set_string(&current, string.into()),
+        Leaf::Number(number) => file.set_number(&current, number),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,106 @@
+//! Translation catalogs of sites: `i18n/<locale>.json` files of their bundle
+//!
+//! Catalogs are flat JSON objects: `{ "greeting": "Bonjour" }`. Templates
+//! translate keys with the `t` filter and the `locale` parameter, which is
+//! set automatically: `{{ "greeting" | t: locale }}`.
+
+use lmfu::{HashMap, json::{JsonFile, Path as JsonPath, Value as JsonValue}};
+use core::str::from_utf8;
+
+pub const DIRECTORY: &str = "i18n/";
+
+#[derive(Default)]
+pub struct Catalogs {
+    /// (locale, key => translation), sorted by locale
+    locales: Vec<(String, HashMap<str, String>)>,
+    /// Index in `locales`
+    default: usize,
+}
+
+impl Catalogs {
+    /// `files` are (asset name, content); `default` defaults to `en`, or to the first locale
+    pub fn parse(files: Vec<(String, Box<[u8]>)>, default: Option<&str>) -> Result<Self, ()> {
+        let mut locales = Vec::new();
+
+        for (name, content) in files {
+            let locale = name.strip_prefix(DIRECTORY).and_then(|n| n.strip_suffix(".json")).unwrap();
+            let fail = || log::error!("Invalid translation catalog: {}", name);
+            let json = from_utf8(&content).map_err(|_| fail())?;
+            let json = JsonFile::new(Some(json)).map_err(|_| fail())?;
+
+            let keys = match json.get(&JsonPath::new()) {
+                JsonValue::Object(keys) => Ok(keys),
+                _ => Err(fail()),
+            }?;
+
+            let mut translations = HashMap::new();
+            for key in keys {
+                let translation = json.get(&JsonPath::new().i_str(key)).as_string().ok_or_else(fail)?;
+                translations.insert_ref(&**key, translation.to_string());
+            }
+
+            locales.push((locale.to_string(), translations));
+        }
+
+        locales.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let default = match default {
+            Some(default) => locales.iter().position(|(l, _)| l == default)
+                .ok_or_else(|| log::error!("Invalid i18n config: no catalog for default locale {}", default))?,
+            None => locales.iter().position(|(l, _)| l == "en").unwrap_or(0),
+        };
+
+        Ok(Self { locales, default })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locales.is_empty()
+    }
+
+    pub fn default_locale(&self) -> &str {
+        self.locales.get(self.default).map(|(l, _)| l.as_str()).unwrap_or("")
+    }
+
+    /// Best locale for an `Accept-Language` header; the default one if none matches
+    ///
+    /// Example: with `en` & `fr` catalogs, `fr-CH, de;q=0.9` gives `fr`.
+    pub fn pick(&self, accept_language: &str) -> &str {
+        let mut ranges: Vec<(&str, f32)> = accept_language.split(',').filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts.find_map(|p| p.trim().strip_prefix("q=")).map(str::parse);
+            match quality {
+                Some(Ok(quality)) => Some((tag, quality)),
+                Some(Err(_)) => None,
+                None => Some((tag, 1.0)),
+            }
+        }).collect();
+
+        // stable: equal qualities keep the client's order
+        ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        for (tag, quality) in ranges {
+            if quality <= 0.0 {
+                break;
+            }
+
+            let primary = tag.split('-').next().unwrap();
+            let exact = self.locales.iter().find(|(l, _)| l.eq_ignore_ascii_case(tag));
+            let partial = || self.locales.iter().find(|(l, _)| l.eq_ignore_ascii_case(primary));
+            if let Some((locale, _)) = exact.or_else(partial) {
+                return locale;
+            }
+        }
+
+        self.default_locale()
+    }
+
+    /// Falls back to the default locale, then to the key itself
+    pub fn translate(&self, locale: &str, key: &str) -> String {
+        let catalog = self.locales.iter().find(|(l, _)| l == locale).map(|(_, c)| c);
+        let default = self.locales.get(self.default).map(|(_, c)| c);
+
+        let translation = catalog.and_then(|c| c.get(key)).or_else(|| default.and_then(|c| c.get(key)));
+        translation.cloned().unwrap_or_else(|| key.to_string())
+    }
+}
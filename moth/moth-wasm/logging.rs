@@ -0,0 +1,239 @@
+//! Log records of the server, as text or JSON lines
+//!
+//! JSON lines have the time, level, site & request ID of each record (see
+//! `moth::log_context`), its module & its message:
+//!
+//! ```json
+//! {"ts":"2026-10-16T13:41:30.123Z","level":"ERROR","site":"example.com","request_id":"6ad22a4a-0","module":"moth::script","message":"Script get_post failed"}
+//! ```
+//!
+//! The level of a record is the most specific one set: for its site & module,
+//! for its site, for its module, then the default level. Site levels are set
+//! at runtime with `log_level` admin requests. Records can be copied to a file.
+//!
+//! Errors logged by a thread can also be captured, for the deployer to send
+//! the reasons of a failed deployment back to the client.
+
+use moth::{log_context, push_json_str, scheduler::civil_from_days};
+use log::{Log, Record, Metadata, Level, LevelFilter};
+use std::{sync::{RwLock, Mutex}, fs::{File, OpenOptions}, io::Write, path::PathBuf, cell::RefCell};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Format {
+    /// `13:41:30 [ERROR] message`
+    Text,
+    Json,
+}
+
+/// The `logging` object of the server config
+pub struct LogConfig {
+    pub format: Format,
+    pub level: LevelFilter,
+    /// (module, level); submodules are included
+    pub modules: Vec<(String, LevelFilter)>,
+    /// Records are appended to this file too
+    pub file: Option<PathBuf>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: Format::Text,
+            level: LevelFilter::Info,
+            modules: Vec::new(),
+            file: None,
+        }
+    }
+}
+
+struct Settings {
+    format: Format,
+    level: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    /// (site hostname, module, level), set at runtime
+    sites: Vec<(String, Option<String>, LevelFilter)>,
+    file: Option<Mutex<File>>,
+}
+
+struct Logger(RwLock<Settings>);
+
+thread_local! {
+    /// Messages of the errors logged by the thread during [`capture_errors`]
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+static LOGGER: Logger = Logger(RwLock::new(Settings {
+    format: Format::Text,
+    level: LevelFilter::Info,
+    modules: Vec::new(),
+    sites: Vec::new(),
+    file: None,
+}));
+
+/// True if `module` is `prefix` or one of its submodules
+fn in_module(module: &str, prefix: &str) -> bool {
+    match module.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+impl Settings {
+    fn level(&self, site: Option<&str>, module: &str) -> LevelFilter {
+        let site_levels = || self.sites.iter().filter(|(s, _, _)| Some(s.as_str()) == site);
+
+        let site_module = site_levels().filter_map(|(_, m, level)| Some((m.as_deref()?, *level)));
+        if let Some((_, level)) = site_module.filter(|(m, _)| in_module(module, m)).max_by_key(|(m, _)| m.len()) {
+            return level;
+        }
+
+        if let Some((_, _, level)) = site_levels().find(|(_, m, _)| m.is_none()) {
+            return *level;
+        }
+
+        let modules = self.modules.iter().filter(|(m, _)| in_module(module, m));
+        match modules.max_by_key(|(m, _)| m.len()) {
+            Some((_, level)) => *level,
+            None => self.level,
+        }
+    }
+
+    /// Most verbose of all levels, below which records aren't even built
+    fn max_level(&self) -> LevelFilter {
+        let levels = self.modules.iter().map(|(_, level)| *level);
+        let levels = levels.chain(self.sites.iter().map(|(_, _, level)| *level));
+        levels.fold(self.level, Ord::max)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let settings = self.0.read().unwrap();
+        log_context::current(|site, _| metadata.level() <= settings.level(site, metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Error {
+            CAPTURED.with(|captured| if let Some(errors) = &mut *captured.borrow_mut() {
+                errors.push(record.args().to_string());
+            });
+        }
+
+        let settings = self.0.read().unwrap();
+        let line = log_context::current(|site, request_id| {
+            if record.level() > settings.level(site, record.target()) {
+                return None;
+            }
+
+            Some(match settings.format {
+                Format::Text => text_line(record),
+                Format::Json => json_line(record, site, request_id),
+            })
+        });
+
+        let Some(line) = line else { return };
+        let _ = match record.level() {
+            Level::Error => std::io::stderr().write_all(line.as_bytes()),
+            _ => std::io::stdout().write_all(line.as_bytes()),
+        };
+
+        if let Some(file) = &settings.file {
+            let _ = file.lock().unwrap().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// (seconds, milliseconds) since the unix epoch
+fn now() -> (u64, u64) {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_millis() as u64)
+}
+
+fn time_of_day(secs: u64) -> String {
+    format!("{:02}:{:02}:{:02}", (secs / 3600) % 24, (secs / 60) % 60, secs % 60)
+}
+
+fn text_line(record: &Record) -> String {
+    let (secs, _) = now();
+    format!("{} [{}] {}\n", time_of_day(secs), record.level(), record.args())
+}
+
+fn json_line(record: &Record, site: Option<&str>, request_id: Option<&str>) -> String {
+    let (secs, millis) = now();
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let mut line = format!("{{\"ts\":\"{:04}-{:02}-{:02}T{}.{:03}Z\",\"level\":\"{}\"", year, month, day, time_of_day(secs), millis, record.level());
+
+    line += ",\"site\":";
+    match site {
+        Some(site) => push_json_str(&mut line, site),
+        None => line += "null",
+    }
+
+    line += ",\"request_id\":";
+    match request_id {
+        Some(request_id) => push_json_str(&mut line, request_id),
+        None => line += "null",
+    }
+
+    line += ",\"module\":";
+    push_json_str(&mut line, record.target());
+
+    // messages start with the request ID, which has its own field here
+    let message = record.args().to_string();
+    let prefix = request_id.map(|id| format!("[{}] ", id));
+    let message = prefix.and_then(|prefix| message.strip_prefix(&prefix)).unwrap_or(&message);
+    line += ",\"message\":";
+    push_json_str(&mut line, message);
+
+    line += "}\n";
+    line
+}
+
+/// Runs `f`, returning the messages of the errors it logged on this thread too
+pub fn capture_errors<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+    let previous = CAPTURED.with(|captured| captured.borrow_mut().replace(Vec::new()));
+    let result = f();
+    let errors = CAPTURED.with(|captured| core::mem::replace(&mut *captured.borrow_mut(), previous));
+    (result, errors.unwrap_or_default())
+}
+
+/// Installs the logger, writing text lines at `level` until [`configure`] is called
+pub fn init(level: LevelFilter) {
+    let mut settings = LOGGER.0.write().unwrap();
+    settings.level = level;
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(settings.max_level());
+    }
+}
+
+pub fn configure(config: LogConfig) -> Result<(), ()> {
+    let file = match &config.file {
+        Some(path) => match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => return Err(log::error!("Failed to open log file {}: {}", path.display(), e)),
+        },
+        None => None,
+    };
+
+    let mut settings = LOGGER.0.write().unwrap();
+    settings.format = config.format;
+    settings.level = config.level;
+    settings.modules = config.modules;
+    settings.file = file;
+    log::set_max_level(settings.max_level());
+    Ok(())
+}
+
+/// Overrides (or resets, if `level` is None) the level of a site, or of one of its modules
+pub fn set_site_level(site: &str, module: Option<&str>, level: Option<LevelFilter>) {
+    let mut settings = LOGGER.0.write().unwrap();
+    settings.sites.retain(|(s, m, _)| !(s == site && m.as_deref() == module));
+    if let Some(level) = level {
+        settings.sites.push((site.to_string(), module.map(str::to_string), level));
+    }
+
+    log::set_max_level(settings.max_level());
+}
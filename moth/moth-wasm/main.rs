@@ -1,119 +1,176 @@
-use moth::{serve, Site, Sites, ScriptResult, OpaqueJsonPointer, Endpoint, EndpointMap};
+#![allow(clippy::unit_arg, clippy::result_unit_err, clippy::too_many_arguments)]
+
+use moth::renderer::{template_content_type, escape_html};
+use moth::{testing::Harness, record, schema::Schema, BodySchema};
+use moth::{serve_all, serve_listeners, systemd, Sites, Routing, StaticAssets, StaticBody, ScriptHost, UploadSink, TemplateRenderer, SiteDatabase, IpRange, IpFilter, Auth, CsrfSecret, HeaderOverrides, BodyMode, ScriptResult, RequestInfo, OpaqueJsonPointer, Endpoint, ScriptEndpoint, EndpointMap, UploadTimeouts, Job, Schedule, Subscription, TemplateDefaults, Methods, Preview, SyncStatus, Priority, ScriptError, ScriptErrors, Timeout, Surface, DEFAULT_TIMEOUT};
 use lmfu::json::{JsonFile, Value as JsonValue, Path as JsonPath};
 use rustgit::{Remote, Repository, Reference, Error as GitError};
-use std::{sync::{Arc, RwLock, Mutex}, io::Read, env::args};
+use std::{sync::{Arc, RwLock, Mutex, MutexGuard, OnceLock}, io::{Read, Write}, env::args};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::path::{Path, PathBuf, Component};
 use lmfu::strpool::{Pool, PoolStr};
 use upon::{Engine as UponEngine};
-use lmfu::{LiteMap, HashMap};
+use lmfu::{LiteMap, HashMap, ArcStr};
 use core::str::from_utf8;
-use cpio::NewcReader;
+use cpio::{NewcReader, NewcBuilder, write_cpio};
 
 mod wasm;
+#[cfg(not(feature = "wasmtime"))]
+mod wasmi_runtime;
+#[cfg(feature = "wasmtime")]
+mod wasmtime_runtime;
+mod wasi;
+mod component;
 mod handle;
 mod deploy;
+mod database;
+mod query;
+mod captcha;
+mod retention;
+mod tarball;
+mod cache;
+mod i18n;
+mod assets;
+mod bundle;
+mod env;
+mod secrets;
+mod email;
+mod webhook;
+mod crawling;
+mod host_json;
+mod config;
+pub(crate) mod logging;
+mod otlp;
+mod history;
+mod replica;
+mod storage;
+mod sql;
+mod search;
+mod services;
+mod uploads;
+mod export;
+mod callbacks;
+mod claims;
+mod policy;
 
 use wasm::WasmThread;
-use handle::{Handle, TemplateParams};
+use handle::{Handle, SiteContext, TemplateParams, join_url};
 use deploy::Deployer;
+use database::{Database, Upstream, SyncConfig, ConflictPolicy};
+use storage::{Storage, Directory};
+use sql::SqlStore;
+use search::Search;
+use services::{Services, ChainLink};
+use uploads::Uploads;
+use export::ExportPlan;
+use callbacks::Callbacks;
+use captcha::Captcha;
+use i18n::Catalogs;
+use retention::Retention;
+use cache::Cache;
+use bundle::Asset;
+use env::{Env, Secrets};
+use secrets::SecretStore;
+use email::Mailer;
+use webhook::Webhooks;
+use crawling::Documents;
+use config::{ServerConfig, SiteConfig};
 
 fn init_logger() {
-    use simplelog::*;
-    let config = ConfigBuilder::new().set_location_level(LevelFilter::Off).build();
-    let _ = SimpleLogger::init(LevelFilter::Info, config);
+    logging::init(log::LevelFilter::Info);
 }
 
-struct WasmApp {
+const CPIO_REGULAR_FILE_MODE: u32 = 0o100_000;
+
+
+/// Assets with these extensions are registered as templates
+const TEMPLATE_EXTENSIONS: &[&str] = &["html", "htm", "xml", "txt"];
+
+pub(crate) struct WasmApp {
     pool: Pool,
     name: PoolStr,
     domain: PoolStr,
     routes: Endpoint,
     on_404: Endpoint,
-    upon_engine: UponEngine<'static>,
-    threads: RwLock<Vec<Mutex<WasmThread>>>,
-    assets: HashMap<str, Box<[u8]>>,
-    repo: RwLock<Arc<RwLock<Repository>>>,
-    #[allow(dead_code)]
-    db_remote: Remote,
+    jobs: Vec<Job>,
+    subscriptions: Vec<Subscription>,
+    preview: Option<Preview>,
+    request_id_header: bool,
+    hostnames: Vec<String>,
+    /// Route prefixes served on site ports
+    surfaces: Vec<Surface>,
+    ip_filter: Option<IpFilter>,
+    csrf_secret: Option<CsrfSecret>,
+    security_headers: Option<HeaderOverrides>,
+    /// (status code, template)
+    error_documents: Vec<(u16, PoolStr)>,
+    upon_engine: RwLock<UponEngine<'static>>,
+    canonical_base: Arc<str>,
+    i18n: Arc<Catalogs>,
+    dev_bundle: Option<DevBundle>,
+    /// Instances are created on first use by each thread,
+    /// except the first one which runs migrations
+    threads: RwLock<Vec<OnceLock<Mutex<WasmThread>>>>,
+    assets: HashMap<str, Asset>,
+    database: Arc<Database>,
+    /// (name, branch) of the database environments of the site
+    environments: Vec<(String, ArcStr)>,
+    /// Callbacks which other sites can call
+    services: Services,
+    uploads: Arc<Uploads>,
+    /// Paths of `moth --export`
+    export: ExportPlan,
+    script_errors: Mutex<ScriptErrors>,
 }
 
-impl Site for WasmApp {
-    fn pool(&self) -> &Pool { &self.pool }
-    fn name(&self) -> &str { &self.name }
+impl Routing for WasmApp {
     fn hostname(&self) -> &str { &self.domain }
+    fn name(&self) -> &str { &self.name }
     fn routes(&self) -> &Endpoint { &self.routes }
     fn on_404(&self) -> &Endpoint { &self.on_404 }
+    fn hostnames(&self) -> &[String] { &self.hostnames }
+    fn preview(&self) -> Option<&Preview> { self.preview.as_ref() }
+    fn request_id_header(&self) -> bool { self.request_id_header }
+    fn ip_filter(&self) -> Option<&IpFilter> { self.ip_filter.as_ref() }
+    fn csrf_secret(&self) -> Option<&CsrfSecret> { self.csrf_secret.as_ref() }
+    fn security_headers(&self) -> Option<&HeaderOverrides> { self.security_headers.as_ref() }
+    fn surfaces(&self) -> &[Surface] { &self.surfaces }
+}
 
-    fn check_upload_token(&self, _token: &str) -> Option<usize> {
-        /*todo*/ None
-    }
+impl StaticAssets for WasmApp {
+    fn open_static(&self, path: &str) -> Option<StaticBody> {
+        let Some(dev_bundle) = &self.dev_bundle else {
+            return self.assets.get(path).and_then(|a| a.content(path)).map(StaticBody::Memory);
+        };
 
-    fn upload_progress(&self, _token: &str, _to_append: &[u8]) {
-        /*todo*/
-    }
+        // URL steps end up in asset paths
+        if !Path::new(path).components().all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
 
-    fn end_of_upload(&self, _token: &str, _success: bool) {
-        /*todo*/
+        let file = std::fs::File::open(dev_bundle.directory.join(path)).ok()?;
+        let metadata = file.metadata().ok().filter(|m| m.is_file())?;
+        Some(StaticBody::File(file, metadata.len()))
     }
+}
 
-    fn render_template(&self, name: PoolStr, parameters: LiteMap<PoolStr, String>) -> Result<String, ()> {
-        /*let asset = match self.assets.get(&name) {
-            Some(asset) => Ok(asset),
-            None => Err(log::error!("Missing template: {}", name)),
-        }?;
-
-        let template_src = match core::str::from_utf8(asset) {
-            Ok(template) => Ok(template),
-            Err(_) => Err(log::error!("Invalid bytes in template: {}", name)),
-        }?;*/
-
-        let template = match self.upon_engine.compile("Hello {{ user.name }}!") {
-            Ok(template) => Ok(template),
-            Err(e) => Err(log::error!("Failed to compile template {}: {}", name, e)),
-        }?;
-
-        let renderer = template.render_from_fn(|members| {
-            use upon::*;
+impl ScriptHost for WasmApp {
+    fn jobs(&self) -> &[Job] { &self.jobs }
+    fn subscriptions(&self) -> &[Subscription] { &self.subscriptions }
 
-            if members.len() == 1 {
-                let ValueMember { op, access } = members[0];
-                if let (ValueAccessOp::Direct, ValueAccess::Key(key)) = (op, access) {
-                    match parameters.get_by(|probe| (**probe).cmp(key)) {
-                        Some(string) => Ok(Value::String(string.clone())),
-                        None => Err(format!("Unknown template parameter: {}", key)),
-                    }
-                } else {
-                    Err("Invalid access: only direct references are supported".into())
-                }
-            } else {
-                Err("Invalid access: only direct references are supported".into())
-            }
-        });
-
-        match renderer.to_string() {
-            Ok(output) => Ok(output),
-            Err(e) => Err(log::error!("Failed to render template {}: {}", name, e)),
-        }
-    }
-
-    fn open_static(&self, path: &str) -> Option<&[u8]> {
-        self.assets.get(path).map(|a| &**a)
+    fn script_errors(&self) -> ScriptErrors {
+        self.script_errors.lock().unwrap().clone()
     }
 
     fn prepare_tls(&self, script_threads: usize) {
         let mut threads = self.threads.write().unwrap();
-
-        while threads.len() < script_threads {
-            let new_thread = {
-                let first_thread = threads[0].lock().unwrap();
-                Mutex::new(first_thread.clone())
-            };
-            threads.push(new_thread);
-        }
+        let missing = script_threads.saturating_sub(threads.len());
+        threads.extend((0..missing).map(|_| OnceLock::new()));
     }
 
     fn parse_json(&self, json: &str, thread_index: usize) -> Result<OpaqueJsonPointer, ()> {
         let threads = self.threads.read().unwrap();
-        let mut thread = threads[thread_index].lock().unwrap();
+        let mut thread = self.lock_thread(&threads, thread_index);
 
         match thread.parse_json(json) {
             Ok(opaq_ptr) => Ok(opaq_ptr),
@@ -123,7 +180,7 @@ impl Site for WasmApp {
 
     fn dump_json(&self, json: OpaqueJsonPointer, thread_index: usize) -> Result<String, ()> {
         let threads = self.threads.read().unwrap();
-        let mut thread = threads[thread_index].lock().unwrap();
+        let mut thread = self.lock_thread(&threads, thread_index);
 
         match thread.dump_json(json) {
             Ok(string) => Ok(string),
@@ -136,35 +193,237 @@ impl Site for WasmApp {
         script: PoolStr,
         read_only: bool,
         path_vars: &[String],
+        info: &RequestInfo,
         body: OpaqueJsonPointer,
         thread_index: usize,
     ) -> Result<ScriptResult, ()> {
+        // the instance of this thread is busy if the site called a service which called it back
+        let busy = || log::error!("[{}] {} is already running a script on this thread", info.id, self.name);
+        let _link = ChainLink::enter(&self.domain, thread_index).ok_or_else(busy)?;
+
         let threads = self.threads.read().unwrap();
-        let mut thread = threads[thread_index].lock().unwrap();
+        let mut thread = self.lock_thread(&threads, thread_index);
 
         let db_token = 0;
-        let result = thread.call_script_fn(&*script, read_only, &self.repo, db_token, body, path_vars);
+        let result = thread.call_script_fn(&script, read_only, &self.database, db_token, body, path_vars, info);
         let script_result = match result {
-            Ok(script_result) => script_result,
-            Err(trap) => return Err(log::error!("{}", trap)),
+            Ok(script_result) => {
+                // like writes, messages are only sent if the script succeeds
+                for (topic, json) in thread.take_messages() {
+                    services::publish(&self.domain, &topic, json);
+                }
+
+                script_result
+            },
+            Err(trap) => {
+                self.script_errors.lock().unwrap().push(ScriptError {
+                    time: database::now(),
+                    request_id: info.id.clone(),
+                    callback: script.to_string(),
+                    message: trap.to_string(),
+                });
+
+                return Err(log::error!("[{}] {}", info.id, trap));
+            },
         };
 
         match script_result {
             (None, Some(json_ptr)) => Ok(ScriptResult::Json(json_ptr)),
-            (Some((template, parameters)), None) => Ok(ScriptResult::Template { template, parameters }),
-            (_, _) => Err(()),
+            (Some((template, mut parameters)), json_ptr) => {
+                if !self.i18n.is_empty() {
+                    let locale = self.i18n.pick(&info.accept_language);
+                    let _ = parameters.try_insert(self.pool.intern("locale"), locale.to_string());
+                }
+
+                // with both, the Accept header of the request picks one
+                Ok(match json_ptr {
+                    Some(json_body) => ScriptResult::Negotiated { template, parameters, json_body },
+                    None => ScriptResult::Template { template, parameters },
+                })
+            },
+            // the route might provide a template
+            (None, None) => Ok(ScriptResult::Template { template: None, parameters: LiteMap::new() }),
+        }
+    }
+
+    fn accepts_service_call(&self, callback: &str, caller: &str) -> bool {
+        self.services.accepts(callback, caller)
+    }
+}
+
+impl UploadSink for WasmApp {
+    fn check_upload_token(&self, token: &str) -> Option<usize> {
+        self.uploads.max_size(token)
+    }
+
+    fn upload_progress(&self, token: &str, to_append: &[u8]) {
+        self.uploads.append(token, to_append);
+    }
+
+    fn end_of_upload(&self, token: &str, success: bool) -> Result<(), String> {
+        self.uploads.finish(token, success, &self.database, &self.domain)
+    }
+
+    fn chunked_progress(&self, token: &str) -> Option<(usize, usize)> {
+        self.uploads.progress(token)
+    }
+
+    fn append_chunk(&self, token: &str, chunk: &[u8]) {
+        self.uploads.append_chunk(token, chunk);
+    }
+}
+
+impl TemplateRenderer for WasmApp {
+    fn render_template(&self, name: PoolStr, parameters: LiteMap<PoolStr, String>) -> Result<String, ()> {
+        self.reload_templates();
+
+        let upon_engine = self.upon_engine.read().unwrap();
+        let template = match upon_engine.get_template(&name) {
+            Some(template) => Ok(template),
+            None => Err(log::error!("Missing template: {}", name)),
+        }?;
+
+        let context = parameters.iter().map(|(k, v)| (k.to_string(), upon::Value::String(v.clone())));
+        let context = upon::Value::Map(context.collect());
+        let renderer = template.render_from(&context);
+
+        match renderer.to_string() {
+            Ok(output) => Ok(output),
+            Err(e) => Err(log::error!("Failed to render template {}: {}", name, e)),
         }
     }
+
+    fn error_document(&self, code: u16, path: &str) -> Option<(&'static str, Vec<u8>)> {
+        let (_, template) = self.error_documents.iter().find(|(c, _)| *c == code)?;
+
+        let mut parameters = LiteMap::new();
+        parameters.insert(self.pool.intern("status"), code.to_string());
+        parameters.insert(self.pool.intern("path"), escape_html(path));
+        if !self.i18n.is_empty() {
+            parameters.insert(self.pool.intern("locale"), self.i18n.default_locale().to_string());
+        }
+
+        let document = self.render_template(template.clone(), parameters).ok()?;
+        Some((template_content_type(template), document.into_bytes()))
+    }
+}
+
+impl SiteDatabase for WasmApp {
+    fn sync_schedule(&self) -> Option<&Schedule> { self.database.sync.as_ref().map(|s| &s.schedule) }
+
+    fn sync_database(&self) {
+        let _ = self.database.sync();
+    }
+
+    fn sync_status(&self) -> Option<SyncStatus> {
+        Some(self.database.health.status())
+    }
+
+    fn revision(&self) -> Option<u64> {
+        Some(self.database.revision())
+    }
+
+    fn enforce_retention(&self) {
+        if let Ok(erased @ 1..) = self.database.enforce_retention() {
+            log::info!("{}: erased {} expired entries", self.name, erased);
+        }
+    }
+
+    fn erase_subject(&self, table_prefix: &str, subject_key: &str, rewrite_history: bool) -> Result<usize, ()> {
+        // the entries would be erased & committed, but left in the history
+        if rewrite_history {
+            self.database.upstream()?;
+        }
+
+        let erased = self.database.erase_subject(table_prefix, subject_key)?;
+
+        match rewrite_history {
+            true => self.database.rewrite_history()?,
+            false => self.database.sync_now()?,
+        }
+
+        Ok(erased)
+    }
+
+    fn dump_database(&self) -> Result<Vec<u8>, ()> {
+        self.database.dump()
+    }
+
+    fn restore_database(&self, dump: &[u8]) -> Result<(), ()> {
+        let restored = self.database.restore(dump)?;
+        log::info!("{}: restored {} files", self.name, restored);
+        self.database.sync_now()
+    }
+
+    fn promote_database(&self, environment: &str) -> Result<usize, ()> {
+        let branch = self.environments.iter().find(|(name, _)| name == environment).map(|(_, branch)| branch);
+        let branch = branch.ok_or_else(|| log::error!("No such database environment: {}", environment))?;
+
+        let promoted = self.database.promote(branch)?;
+        log::info!("{}: promoted {} files from {}", self.name, promoted, environment);
+        self.database.sync_now()?;
+        Ok(promoted)
+    }
 }
 
 impl WasmApp {
-    pub fn new(cpio: &[u8], hostname: &str) -> Result<Self, ()> {
+    /// Locks the wasm instance of a thread, creating it if needed
+    fn lock_thread<'a>(&self, threads: &'a [OnceLock<Mutex<WasmThread>>], index: usize) -> MutexGuard<'a, WasmThread> {
+        let thread = threads[index].get_or_init(|| {
+            let first = threads[0].get().unwrap(/* set in new() */);
+            let first = self.lock_instance(first);
+            Mutex::new(self.new_instance(&first))
+        });
+
+        self.lock_instance(thread)
+    }
+
+    /// If a thread panicked while using this instance, or if a script trapped
+    /// in it, its state can't be trusted so it is replaced by a new one
+    fn lock_instance<'a>(&self, thread: &'a Mutex<WasmThread>) -> MutexGuard<'a, WasmThread> {
+        let mut guard = match thread.lock() {
+            Ok(guard) if !guard.needs_rebuild() => return guard,
+            Ok(guard) => {
+                log::warn!("{}: re-creating a wasm instance after a trap", self.name);
+                guard
+            },
+            Err(poisoned) => {
+                log::warn!("{}: re-creating a wasm instance after a panic", self.name);
+                poisoned.into_inner()
+            },
+        };
+
+        *guard = self.new_instance(&guard);
+        thread.clear_poison();
+        guard
+    }
+
+    /// Instantiates the module of `model` again, sharing its compiled code
+    fn new_instance(&self, model: &WasmThread) -> WasmThread {
+        let mut new_thread = model.clone();
+        if let Err(trap) = new_thread.call_init_fn(&self.database, 0) {
+            log::error!("Init callback failed: {}", trap);
+        }
+
+        new_thread
+    }
+
+    /// With `dev_bundle`, templates & static assets are read from this
+    /// directory, which has the layout of the bundle, instead of `cpio`.
+    /// `secrets` override the `env` config of the bundle. `environment`
+    /// selects a database branch among `database.environments`.
+    pub fn new(cpio: &[u8], hostname: &str, environment: Option<&str>, dev_bundle: Option<PathBuf>, secrets: Secrets) -> Result<Self, ()> {
         let mut site_wasm = None;
         let mut config_json = None;
         let pool = Pool::new();
-        let mut assets: HashMap<str, Box<[u8]>> = HashMap::new();
+        let mut assets: HashMap<str, Asset> = HashMap::new();
+        let mut templates = Vec::new();
+        let mut catalogs = Vec::new();
+        let mut asset_names = Vec::new();
+        let mut index = None;
 
         let mut file = cpio;
+        let mut first_entry = true;
         loop {
             let mut reader = NewcReader::new(file).map_err(|_| log::error!("Invalid CPIO archive"))?;
             if reader.entry().is_trailer() {
@@ -172,20 +431,33 @@ impl WasmApp {
             }
 
             let size = reader.entry().file_size() as usize;
-            let read_content = |reader: &mut NewcReader<_>| {
-                let mut content = Vec::with_capacity(size);
-                assert_eq!(reader.read_to_end(&mut content).ok(), Some(size));
-                content.into_boxed_slice()
-            };
+            let mut content = Vec::with_capacity(size);
+            assert_eq!(reader.read_to_end(&mut content).ok(), Some(size));
+            let content = content.into_boxed_slice();
+
+            let name = reader.entry().name();
+            let entry = index.as_ref().and_then(|i: &bundle::Index| i.get(name)).copied();
 
-            match reader.entry().name() {
-                "site.wasm" => site_wasm = Some(read_content(&mut reader)),
-                "config.json" => config_json = Some(read_content(&mut reader)),
+            match name {
+                bundle::INDEX if first_entry => index = Some(bundle::parse_index(&content)?),
+                bundle::INDEX => return Err(log::error!("Invalid bundle: {} must be the first file", bundle::INDEX)),
+                "site.wasm" => site_wasm = Some(bundle::unpack(name, &content, entry.as_ref())?),
+                "config.json" => config_json = Some(bundle::unpack(name, &content, entry.as_ref())?),
                 _ => {
-                    let content = read_content(&mut reader);
-                    assets.insert_ref(reader.entry().name(), content);
+                    if is_template(name) {
+                        templates.push((name.to_string(), bundle::unpack(name, &content, entry.as_ref())?));
+                    }
+
+                    if name.starts_with(i18n::DIRECTORY) && name.ends_with(".json") {
+                        catalogs.push((name.to_string(), bundle::unpack(name, &content, entry.as_ref())?));
+                    }
+
+                    asset_names.push(name.to_string());
+                    assets.insert_ref(name, Asset::new(content, entry));
                 },
             }
+
+            first_entry = false;
             file = reader.finish().map_err(|_| log::error!("Invalid CPIO archive"))?;
         }
 
@@ -204,39 +476,126 @@ impl WasmApp {
             Err(e) => Err(log::error!("Invalid config.json: {:?}", e)),
         }?;
 
-        let routes = parse_routes(&config, &pool, &JsonPath::new().i_str("routes"))?;
-        let on_404 = parse_routes(&config, &pool, &JsonPath::new().i_str("on_404"))?;
+        let settings = SiteConfig::parse(&config)?;
+        let canonical_host = settings.canonical_host.unwrap_or(hostname);
+        let canonical_base: Arc<str> = Arc::from(format!("{}://{}", settings.canonical_scheme, canonical_host));
+
+        let preview = parse_preview(&config, &JsonPath::new().i_str("preview"))?;
+        let routes_path = JsonPath::new().i_str("routes");
+        let crawling_path = JsonPath::new().i_str("crawling");
+        let documents = Documents::new(&config, &routes_path, &crawling_path, &canonical_base, preview.is_some())?;
+        let route_list: Arc<[String]> = crawling::list_routes(&config, &routes_path).into();
+
+        let routes = parse_routes(&config, &pool, &routes_path, &documents)?;
+        let on_404 = parse_routes(&config, &pool, &JsonPath::new().i_str("on_404"), &documents)?;
+        let jobs = parse_jobs(&config, &pool, &JsonPath::new().i_str("jobs"))?;
+        let subscriptions = parse_subscriptions(&config, &pool, &JsonPath::new().i_str("subscriptions"))?;
+
+        let security_headers = parse_security_headers(&config, &JsonPath::new().i_str("security_headers"))?;
+        let csrf_secret = (has_csrf_routes(&routes) || has_csrf_routes(&on_404)).then(CsrfSecret::random);
+
+        let hostnames = parse_hostnames(&config, &JsonPath::new().i_str("hostnames"))?;
+        let surfaces = parse_surfaces(&config, &JsonPath::new().i_str("surfaces"))?;
+        let allow_path = JsonPath::new().i_str("allow_ips");
+        let ip_filter = parse_ip_filter(&config, &allow_path, &JsonPath::new().i_str("deny_ips"))?;
+        let error_documents = parse_error_documents(&config, &pool, &JsonPath::new().i_str("errors"))?;
+        if let Some((_, template)) = error_documents.iter().find(|(_, t)| !templates.iter().any(|(name, _)| name == &**t)) {
+            return Err(log::error!("Invalid error document: {} is not a template", template));
+        }
 
         let db_path = JsonPath::new().i_str("database");
 
-        let db_remote = match Remote::parse(&config, &db_path) {
-            Ok(db_remote) => Ok(db_remote),
-            Err(e) => Err(log::error!("Invalid remote database access config: {:?}", e)),
-        }?;
+        let environments = settings.environments.iter().map(|env| (env.name.to_string(), env.branch.clone())).collect();
+        let (storage, upstream): (Box<dyn Storage>, _) = match settings.directory {
+            Some(_) if environment.is_some() => return Err(log::error!("The database is a directory: it has no environments")),
+            Some(directory) => (Box::new(Directory::open(directory)?), None),
+            None => {
+                let remote = match Remote::parse(&config, &db_path) {
+                    Ok(db_remote) => Ok(db_remote),
+                    Err(e) => Err(log::error!("Invalid remote database access config: {:?}", e)),
+                }?;
 
-        let branch = match config.get(&db_path.i_str("branch")).as_string() {
-            Some(string) => Ok(string),
-            None => Err(log::error!("Invalid branch config: must be a string")),
-        }?;
+                let branch = settings.branch(hostname, environment)?;
+                let mut repo = Repository::new();
+
+                // quick bypass toggle
+                if true {
+                    match repo.clone(&remote, Reference::Branch(branch), Some(1)) {
+                        Ok(()) | Err(GitError::NoSuchReference) => Ok(()),
+                        Err(e) => Err(log::error!("Failed to clone database: {:?}", e)),
+                    }?;
+                }
+
+                (Box::new(repo), Some(Upstream { remote, branch: branch.clone() }))
+            },
+        };
+
+        let i18n = Arc::new(Catalogs::parse(catalogs, settings.default_locale)?);
 
-        let mut repo = Repository::new();
+        let mut upon_engine = new_upon_engine(&canonical_base, &i18n);
+        register_templates(&mut upon_engine, templates)?;
 
-        // quick bypass toggle
-        if true {
-            match repo.clone(&db_remote, Reference::Branch(branch), Some(1)) {
-                Ok(()) | Err(GitError::NoSuchReference) => Ok(()),
-                Err(e) => Err(log::error!("Failed to clone database: {:?}", e)),
-            }?;
+        let captcha = Captcha::parse(&config, &JsonPath::new().i_str("captcha"))?.map(Arc::new);
+
+        let cache = Arc::new(Cache::new(settings.cache_size));
+        let env = Arc::new(Env::parse(&config, &JsonPath::new().i_str("env"), secrets)?);
+        let email = Mailer::parse(&config, &JsonPath::new().i_str("email"), hostname)?.map(Arc::new);
+        let webhooks = Webhooks::parse(&config, &JsonPath::new().i_str("webhooks"))?.map(Arc::new);
+
+        let uploads = Arc::new(Uploads::new(settings.max_upload_size));
+
+        match site_wasm.as_deref().map(Callbacks::parse).transpose()?.flatten() {
+            Some(callbacks) => callbacks.check(&config)?,
+            None => log::warn!("site.wasm has no {} section: callbacks weren't checked", callbacks::SECTION),
         }
 
-        let wasm_thread = match site_wasm {
-            Some(site_wasm) => match WasmThread::new(&site_wasm, pool.clone()) {
+        let context = Arc::new(SiteContext {
+            pool: pool.clone(),
+            canonical_base: canonical_base.clone(),
+            captcha,
+            cache,
+            i18n: i18n.clone(),
+            env,
+            email,
+            webhooks,
+            uploads: uploads.clone(),
+            routes: route_list,
+        });
+
+        let mut wasm_thread = match site_wasm {
+            Some(site_wasm) => match WasmThread::new(&site_wasm, context) {
                 Some(wasm_thread) => Ok(wasm_thread),
                 None => Err(log::error!("Failed to instantiate site.wasm")),
             },
             None => Err(log::error!("no site.wasm")),
         }?;
 
+        let retention = Retention::parse(&config, &db_path.clone().i_str("retention"))?;
+        let sync = parse_sync(&config, &db_path.clone().i_str("sync"))?;
+        let search = Search::parse(&config, &db_path.i_str("search"))?;
+        let database = Database::new(
+            storage,
+            upstream,
+            hostname,
+            settings.max_blob_size,
+            settings.sqlite.map(SqlStore::open).transpose()?,
+            search,
+            settings.file_cache_size,
+            retention,
+            sync,
+        );
+        let database = Arc::new(database);
+
+        if let Err(trap) = wasm_thread.call_init_fn(&database, 0) {
+            return Err(log::error!("Init callback failed: {}", trap));
+        }
+
+        let migrations = parse_migrations(&config, &pool, &JsonPath::new().i_str("migrations"))?;
+        run_migrations(&mut wasm_thread, &database, &migrations)?;
+
+        let services = Services::parse(&config, &JsonPath::new().i_str("services"))?;
+        let export = ExportPlan::parse(&config, &pool, &routes_path, &JsonPath::new().i_str("export"), &asset_names)?;
+
         let domain = pool.intern(hostname);
         let name = domain.clone();
 
@@ -246,103 +605,521 @@ impl WasmApp {
             domain,
             routes,
             on_404,
-            upon_engine: UponEngine::new(),
-            threads: RwLock::new(vec![Mutex::new(wasm_thread)]),
+            jobs,
+            subscriptions,
+            preview,
+            request_id_header: settings.request_id_header,
+            hostnames,
+            surfaces,
+            ip_filter,
+            csrf_secret,
+            security_headers,
+            error_documents,
+            upon_engine: RwLock::new(upon_engine),
+            canonical_base,
+            i18n,
+            dev_bundle: dev_bundle.map(DevBundle::new),
+            threads: RwLock::new(vec![OnceLock::from(Mutex::new(wasm_thread))]),
             assets,
-            repo: RwLock::new(Arc::new(RwLock::new(repo))),
-            db_remote,
+            database,
+            environments,
+            services,
+            uploads,
+            export,
+            script_errors: Mutex::new(ScriptErrors::default()),
         })
     }
 }
 
+fn is_template(asset_name: &str) -> bool {
+    match asset_name.rsplit_once('.') {
+        Some((_, ext)) => TEMPLATE_EXTENSIONS.contains(&ext),
+        None => false,
+    }
+}
+
+fn new_upon_engine(canonical_base: &Arc<str>, i18n: &Arc<Catalogs>) -> UponEngine<'static> {
+    let mut upon_engine = UponEngine::new();
+    let base = canonical_base.clone();
+    upon_engine.add_filter("absolute_url", move |path: &str| join_url(&base, path));
+    let i18n = i18n.clone();
+    upon_engine.add_filter("t", move |key: &str, locale: &str| i18n.translate(locale, key));
+    upon_engine
+}
+
+/// Local bundle directory of a site in development
+struct DevBundle {
+    directory: PathBuf,
+    /// (newest modification time, number) of the templates currently compiled
+    templates_state: Mutex<(SystemTime, usize)>,
+}
+
+impl DevBundle {
+    fn new(directory: PathBuf) -> Self {
+        log::warn!("Serving templates & static assets from {}", directory.display());
+        Self { directory, templates_state: Mutex::new((UNIX_EPOCH, 0)) }
+    }
+}
+
+impl WasmApp {
+    /// Recompiles the templates of a development bundle if they changed
+    fn reload_templates(&self) {
+        let Some(dev_bundle) = &self.dev_bundle else { return };
+        let directory = &dev_bundle.directory;
+
+        let files = match bundle_files(directory) {
+            Ok(files) => files,
+            Err(e) => return log::error!("Couldn't read {}: {}", directory.display(), e),
+        };
+
+        let name = |path: &PathBuf| path.strip_prefix(directory).unwrap().to_string_lossy().replace('\\', "/");
+        let files: Vec<_> = files.into_iter().filter(|path| is_template(&name(path))).collect();
+        let modified = |path: &PathBuf| path.metadata().and_then(|m| m.modified()).unwrap_or(UNIX_EPOCH);
+        let state = (files.iter().map(modified).max().unwrap_or(UNIX_EPOCH), files.len());
+
+        let mut templates_state = dev_bundle.templates_state.lock().unwrap();
+        if *templates_state == state {
+            return;
+        }
+
+        // only retried after another change
+        *templates_state = state;
+
+        let mut templates = Vec::new();
+        for path in files {
+            match std::fs::read(&path) {
+                Ok(source) => templates.push((name(&path), source.into_boxed_slice())),
+                Err(e) => return log::error!("Couldn't read {}: {}", path.display(), e),
+            }
+        }
+
+        let mut upon_engine = new_upon_engine(&self.canonical_base, &self.i18n);
+        if register_templates(&mut upon_engine, templates).is_ok() {
+            *self.upon_engine.write().unwrap() = upon_engine;
+            log::info!("{}: reloaded templates from {}", self.name, directory.display());
+        }
+    }
+}
+
+/// Registers templates under their asset name, so they can include each other
+fn register_templates(upon_engine: &mut UponEngine<'static>, templates: Vec<(String, Box<[u8]>)>) -> Result<(), ()> {
+    for (name, source) in templates {
+        let source = match String::from_utf8(source.into_vec()) {
+            Ok(source) => Ok(source),
+            Err(_) => Err(log::error!("Invalid bytes in template: {}", name)),
+        }?;
+
+        if let Err(e) = upon_engine.add_template(name.clone(), source) {
+            return Err(log::error!("Failed to compile template {}: {}", name, e));
+        }
+    }
+
+    Ok(())
+}
+
+/// Files of a bundle directory, sorted
+fn bundle_files(directory: &Path) -> std::io::Result<Vec<PathBuf>> {
+    fn visit(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            match path.is_dir() {
+                true => visit(&path, files)?,
+                false => files.push(path),
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    visit(directory, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+/// Reads a bundle, either a CPIO archive or a directory laid out like one
+fn read_bundle(path: &Path) -> std::io::Result<Vec<u8>> {
+    if !path.is_dir() {
+        return std::fs::read(path);
+    }
+
+    let mut inputs = Vec::new();
+    for file in bundle_files(path)? {
+        let name = file.strip_prefix(path).unwrap().to_string_lossy().replace('\\', "/");
+        let builder = NewcBuilder::new(&name).mode(CPIO_REGULAR_FILE_MODE);
+        inputs.push((builder, std::fs::File::open(file)?));
+    }
+
+    write_cpio(inputs.into_iter(), Vec::new())
+}
+
+pub(crate) fn load_bundle(bundle: &str, hostname: &str) -> WasmApp {
+    let cpio = match read_bundle(Path::new(bundle)) {
+        Ok(cpio) => cpio,
+        Err(e) => panic!("Failed to read bundle {}: {}", bundle, e),
+    };
+
+    match WasmApp::new(&cpio, hostname, None, None, Secrets::default()) {
+        Ok(site) => site,
+        Err(()) => panic!("Failed to load bundle {}", bundle),
+    }
+}
+
+/// Runs a single request through a bundle, without listening
+fn request_mode(args: &[String]) {
+    let [bundle, method, url, body @ ..] = args else {
+        return println!("Usage: moth --request BUNDLE METHOD URL [BODY]");
+    };
+
+    init_logger();
+
+    let harness = Harness::new(Box::new(load_bundle(bundle, "localhost")));
+    let body = body.first().map(String::as_str).unwrap_or("");
+    let response = harness.request(method, url, body.as_bytes());
+
+    eprintln!("{} {}", response.status, response.content_type.unwrap_or("-"));
+    let _ = std::io::stdout().write_all(&response.body);
+}
+
+/// Writes the pages & assets of a bundle to a directory, see [`export`]
+fn export_mode(args: &[String]) {
+    let [bundle, hostname, directory] = args else {
+        return println!("Usage: moth --export BUNDLE HOSTNAME OUT_DIR");
+    };
+
+    init_logger();
+
+    let app = load_bundle(bundle, hostname);
+    let Ok(paths) = app.export.paths(&app) else {
+        std::process::exit(1);
+    };
+
+    let harness = Harness::new(Box::new(app));
+    let Ok(exported) = moth::export::export(&harness, &paths, Path::new(directory)) else {
+        std::process::exit(1);
+    };
+
+    println!("Exported {} files to {}, skipped {} paths", exported.files.len(), directory, exported.skipped.len());
+    if !exported.skipped.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Re-runs the recorded requests of a site through a bundle, and
+/// reports responses which differ from the recorded ones
+fn replay_mode(args: &[String]) {
+    let [bundle, directory, hostname] = args else {
+        return println!("Usage: moth --replay BUNDLE RECORDING_DIR HOSTNAME");
+    };
+
+    init_logger();
+
+    let Ok(records) = record::load(Path::new(directory)) else {
+        std::process::exit(1);
+    };
+
+    let harness = Harness::new(Box::new(load_bundle(bundle, hostname)));
+    let (mut replayed, mut differences) = (0, 0);
+
+    for (request, recorded) in records.iter().filter(|(r, _)| r.site == *hostname) {
+        let response = harness.request(&request.method, &request.url, &request.body);
+        replayed += 1;
+
+        let Some(recorded) = recorded else {
+            println!("[{}] {} {}: {} (no recorded response)", request.id, request.method, request.url, response.status);
+            continue;
+        };
+
+        if recorded.status != response.status {
+            differences += 1;
+            println!("[{}] {} {}: status {} => {}", request.id, request.method, request.url, recorded.status, response.status);
+        } else if recorded.body != response.body {
+            differences += 1;
+            println!("[{}] {} {}: different body ({} => {} bytes)", request.id, request.method, request.url, recorded.body.len(), response.body.len());
+        } else if recorded.content_type.as_deref() != response.content_type {
+            differences += 1;
+            println!("[{}] {} {}: content type {:?} => {:?}", request.id, request.method, request.url, recorded.content_type, response.content_type);
+        }
+    }
+
+    println!("Replayed {} requests, {} different responses", replayed, differences);
+    if differences > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Config file & overriding flags: `[--listen-addr 0.0.0.0:80 | --threads=4]... [config.json]`
+fn server_arguments(arguments: &[String]) -> (Option<&str>, Vec<(&'static str, String)>) {
+    let mut filename = None;
+    let mut overrides = Vec::new();
+    let mut arguments = arguments.iter();
+
+    while let Some(argument) = arguments.next() {
+        if argument == "-h" || argument == "--help" {
+            continue;
+        }
+
+        if !argument.starts_with("--") {
+            match filename {
+                None => filename = Some(argument.as_str()),
+                Some(_) => panic!("Unexpected argument {} (only one config file can be used)", argument),
+            }
+
+            continue;
+        }
+
+        let (flag, value) = match argument.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (argument.as_str(), arguments.next().cloned()),
+        };
+
+        let Some(key) = config::server_flag(flag) else {
+            panic!("Unknown flag {} (see --help)", flag);
+        };
+
+        match value {
+            Some(value) => overrides.push((key, value)),
+            None => panic!("Missing value of flag {}", flag),
+        }
+    }
+
+    (filename, overrides)
+}
+
 fn main() {
     let pool = Pool::get_static_pool();
 
-    let filename = args().last();
-    let filename = filename.as_deref().unwrap_or("-h");
+    let arguments: Vec<String> = args().skip(1).collect();
+    if arguments.first().map(String::as_str) == Some("--request") {
+        return request_mode(&arguments[1..]);
+    }
+
+    if arguments.first().map(String::as_str) == Some("--replay") {
+        return replay_mode(&arguments[1..]);
+    }
+
+    if arguments.first().map(String::as_str) == Some("--export") {
+        return export_mode(&arguments[1..]);
+    }
+
+    let (filename, overrides) = server_arguments(&arguments);
+    let help = arguments.iter().any(|a| a == "-h" || a == "--help");
 
-    if filename == "-h" || filename == "--help" {
+    if help || (arguments.is_empty() && !config::has_env_overrides()) {
         println!("Usage:");
-        println!("    moth config.json     Start the server with a configuration file");
+        println!("    moth [FLAGS] [config.json]");
+        println!("                         Start the server with a configuration file and/or flags");
         println!("    moth -h/--help       Print this usage info");
-        println!("");
+        println!("    moth --request BUNDLE METHOD URL [BODY]");
+        println!("                         Run a request through a site bundle (CPIO file or directory)");
+        println!("                         and print the response; the status & content type go to stderr");
+        println!("    moth --replay BUNDLE RECORDING_DIR HOSTNAME");
+        println!("                         Run the requests of a site recorded with 'record_dir' through a");
+        println!("                         bundle and report the responses which differ");
+        println!("    moth --export BUNDLE HOSTNAME OUT_DIR");
+        println!("                         Write the pages & assets of a bundle to a directory, for static");
+        println!("                         hosting (see the export config of sites in 'cargo moth --help')");
+        println!();
         println!("The configuration file must be a valid JSON file with the following properties:");
         println!("    request_threads      Number of threads handling incoming requests");
         println!("    script_threads       Number of threads handling script executions");
         println!("    render_threads       Number of threads handling template renderings");
+        println!("    threads              Number of threads of the kinds above which aren't set on their own");
+        println!("                         Thread numbers are either a number, \"auto\" (one per CPU, growing");
+        println!("                         up to four per CPU) or {{ \"min\": 2, \"max\": 8 }}; threads are");
+        println!("                         added while work waits in their queue, up to the maximum");
+        println!("    upload_threads       Optional number of threads streaming uploads, in the same format");
+        println!("                         (default: {{ \"min\": 1, \"max\": 32 }}); uploads wait for a thread,");
+        println!("                         and are refused (503) once as many as the maximum are waiting");
         println!("    max_service_cpio_mb  Maximum file of uploaded service bundles");
         println!("    hostname             Hostname for the deployment service");
         println!("    listen_addr          Listening address (example: 0.0.0.0:80)");
+        println!("    listen_addrs         Array of listening addresses, instead of listen_addr");
+        println!("                         (example: [\"0.0.0.0:80\", \"[::]:80\"])");
+        println!("    trusted_proxies      Optional array of addresses/CIDR ranges of reverse proxies, whose");
+        println!("                         X-Forwarded-For & X-Forwarded-Proto headers are used");
+        println!("    fallback             Optional handling of requests with a missing/unknown Host header,");
+        println!("                         which get a 502 response otherwise; one of:");
+        println!("    |-- site             Hostname of the site serving them");
+        println!("    `-- redirect         Base URL they're redirected to (example: https://example.com)");
+        println!("    dev_bundles          Optional object mapping hostnames of sites in development to local");
+        println!("                         bundle directories, from which their templates & static assets");
+        println!("                         are read (& reloaded on change) instead of deployed bundles");
+        println!("    secrets              Optional object mapping site hostnames to objects of secret");
+        println!("                         environment variables, which scripts read with Request::env");
+        println!("    secrets_store        Optional encrypted file keeping secrets set with 'cargo moth secret':");
+        println!("    |-- file             Path of the file, created if missing");
+        println!("    `-- key              Hex-encoded 256-bit AES key (64 hex digits)");
+        println!("    record_dir           Optional directory where all requests & responses are saved, for");
+        println!("                         debugging with --replay; this includes credentials & personal data");
+        println!("    logging              Optional log settings:");
+        println!("    |-- format           \"text\" (default) or \"json\", for one JSON object per line with the");
+        println!("    |                    time, level, site, request ID, module & message of each record");
+        println!("    |-- level            off/error/warn/info/debug/trace (default: info)");
+        println!("    |-- modules          Object of levels for some modules (example: {{ \"moth::script\": \"debug\" }})");
+        println!("    `-- file             Optional file where records are appended too");
+        println!("                         The level of a site can be changed with 'cargo moth log-level'");
+        println!("    tracing              Optional export of request traces to an OpenTelemetry collector:");
+        println!("    |-- endpoint         OTLP/HTTP traces URL (example: http://localhost:4318/v1/traces)");
+        println!("    |-- service_name     Optional service name of the spans (default: moth)");
+        println!("    `-- headers          Optional object of headers sent to the collector");
+        println!("                         Requests get spans for their script, rendering, wasm calls &");
+        println!("                         database operations; traceparent headers are honored");
+        println!("    claims               Optional proof of ownership required to deploy a new hostname,");
+        println!("                         which anyone can claim otherwise; one of these must hold:");
+        println!("    |-- allow            Array of hostnames which can be claimed (\"*.example.com\" matches");
+        println!("    |                    subdomains)");
+        println!("    |-- dns              true if a TXT record of the hostname can bind it to the admin key");
+        println!("    |                    of the deployer, as printed by 'cargo moth claim'");
+        println!("    `-- resolver         DNS-over-HTTPS (JSON) URL for these records, which implies dns");
+        println!("                         (default: {})", claims::DEFAULT_RESOLVER);
+        println!("    bundle_policy        Optional limits of uploaded bundles, whose violations are sent back");
+        println!("    |                    to 'cargo moth' instead of deploying them:");
+        println!("    |-- max_assets       Maximum number of files (default: 10000)");
+        println!("    |-- max_asset_kb     Maximum size of each file, once unpacked");
+        println!("    |-- reserved_hostnames");
+        println!("    |                    Array of hostnames which sites can't serve, besides the deployment");
+        println!("    |                    server's (\"*.example.com\" matches subdomains)");
+        println!("    |-- reserved_routes  Array of paths which sites can't route, nor anything under them");
+        println!("    `-- start_fuel       Fuel (~ wasm instructions) within which the start functions of");
+        println!("                         site.wasm must return (default: 1000000); site.wasm can't import");
+        println!("                         functions which the server doesn't provide");
+        println!("    site_ports           Optional array of ports of listen_addrs on which sites serve their");
+        println!("                         surfaces only, like an admin UI on 8443; a site declaring a surface");
+        println!("                         on another port is rejected at deploy time");
+        println!();
+        println!("Each property can be set with a flag (--listen-addr 0.0.0.0:80) or an environment variable");
+        println!("(MOTH_LISTEN_ADDR=0.0.0.0:80), which override the configuration file; flags take precedence.");
+        println!("Their values are JSON (--threads '{{\"min\": 2, \"max\": 8}}') or plain strings; null unsets");
+        println!("the property. The configuration file is optional when everything is set this way.");
+        println!();
+        println!("Under systemd, listening sockets passed with socket activation (LISTEN_FDS) are used instead");
+        println!("of listen_addr(s), which are then optional; Type=notify services are notified once requests");
+        println!("are served (READY=1), and get WATCHDOG=1 notifications if WatchdogSec is set.");
 
         return;
     }
 
-    let config = match std::fs::read_to_string(&filename) {
-        Ok(file) => file,
-        Err(_) => panic!("Failed to read config file {}", filename),
+    let config = match filename {
+        Some(filename) => match std::fs::read_to_string(filename) {
+            Ok(file) => file,
+            Err(_) => panic!("Failed to read config file {}", filename),
+        },
+        None => "{}".into(),
     };
 
-    let config = match JsonFile::with_key_pool(Some(&config), pool) {
+    let mut config = match JsonFile::with_key_pool(Some(&config), pool) {
         Ok(file) => file,
         Err(e) => panic!("Failed to parse config file: {}", e),
     };
 
-    let get = |prop| config.get(&JsonPath::new().i_str(prop));
-    let get_num = |prop| match get(prop).as_num() {
-        Some(value) => value as usize,
-        None => panic!("Invalid property '{}' in config file", prop),
+    init_logger();
+
+    let Ok(listeners) = systemd::listeners() else {
+        std::process::exit(1);
     };
-    let get_str = |prop| match get(prop).as_string() {
-        Some(string) => string.clone(),
-        None => panic!("Invalid property '{}' in config file", prop),
+
+    let overridden = config::apply_overrides(&mut config, &overrides);
+    let Ok(config) = overridden.and_then(|()| ServerConfig::parse(&config, !listeners.is_empty())) else {
+        std::process::exit(1);
     };
 
-    const MB: usize = 1024 * 1024;
-    let request_threads = get_num("request_threads");
-    let script_threads = get_num("request_threads");
-    let render_threads = get_num("render_threads");
-    let upload_limit = get_num("max_service_cpio_mb") * MB;
-    let hostname = get_str("hostname");
-    let listen_addr = get_str("listen_addr");
+    if logging::configure(config.logging).is_err() {
+        std::process::exit(1);
+    }
 
-    init_logger();
+    if let Some(tracing) = config.tracing {
+        otlp::start(tracing);
+    }
+
+    let mut sites = Sites::new(config.request_threads, config.script_threads, config.render_threads, config.upload_threads);
+
+    if let Some(trusted_proxies) = config.trusted_proxies {
+        sites = sites.with_trusted_proxies(trusted_proxies);
+    }
+
+    if let Some(fallback) = config.fallback {
+        sites = sites.with_fallback(fallback);
+    }
+
+    if !config.site_ports.is_empty() {
+        sites = sites.with_site_ports(config.site_ports.clone());
+    }
 
-    let sites = Sites::new(request_threads, script_threads, render_threads);
-    let deployer = Deployer::new(hostname, upload_limit, sites.clone());
+    if let Some(directory) = config.record_dir {
+        sites = sites.with_recording(directory);
+    }
+
+    let mut secrets = config.secrets;
+    let secret_store = config.secrets_store.map(|(file, key)| SecretStore::new(file, key));
+
+    if let Some(secret_store) = &secret_store {
+        let Ok(stored) = secret_store.load() else { panic!("Failed to load secrets_store") };
+        for (hostname, site_secrets) in stored {
+            let index = secrets.iter().position(|(h, _)| *h == hostname);
+            let current = match index {
+                Some(index) => &mut secrets[index].1,
+                None => { secrets.push((hostname, Vec::new())); &mut secrets.last_mut().unwrap().1 },
+            };
+
+            // stored values were set more recently
+            for (name, value) in site_secrets {
+                current.retain(|(n, _)| *n != name);
+                current.push((name, value));
+            }
+        }
+    }
+
+    let deployer = Deployer::new(config.hostname, config.max_service_cpio_size, sites.clone(), config.dev_bundles, secrets, secret_store, config.claims, config.bundle_policy, config.site_ports.clone());
     sites.insert(Box::new(deployer));
+    services::init(sites.clone());
+
+    if !listeners.is_empty() {
+        if !config.listen_addrs.is_empty() {
+            log::info!("Ignoring listen_addr(s): using the sockets passed by systemd");
+        }
 
-    serve(listen_addr.as_str(), sites);
+        return serve_listeners(listeners, sites);
+    }
+
+    let listen_addrs: Vec<&str> = config.listen_addrs.iter().map(String::as_str).collect();
+    serve_all(&listen_addrs, sites);
 }
 
-fn parse_routes(file: &JsonFile, pool: &Pool, path: &JsonPath) -> Result<Endpoint, ()> {
+fn parse_routes(file: &JsonFile, pool: &Pool, path: &JsonPath, documents: &Documents) -> Result<Endpoint, ()> {
     match file.get(path) {
         JsonValue::Array(length) => {
-            if *length != 2 {
-                return Err(log::error!("Invalid route (array != 2 items)"));
+            if let JsonValue::String(s) = file.get(&path.clone().i_num(0)) {
+                if s == "[upload]" {
+                    return parse_upload_route(file, path, *length);
+                }
             }
 
-            // access (rw/ro)
-            let read_only = match file.get(&path.clone().i_num(0)) {
-                JsonValue::String(s) if s == "ro" => true,
-                JsonValue::String(s) if s == "rw" => false,
-                _ => return Err(log::error!("Invalid route (access must be ro/rw)")),
-            };
-
-            let fn_name = match file.get(&path.clone().i_num(1)) {
-                JsonValue::String(fn_name) => pool.intern(fn_name),
-                _ => return Err(log::error!("Invalid route (function name must be a string)")),
-            };
+            if *length != 2 && *length != 3 {
+                return Err(log::error!("Invalid route (array must have 2 or 3 items)"));
+            }
 
-            Ok(Endpoint::ScriptExec(read_only, fn_name))
+            let options = (*length == 3).then(|| path.clone().i_num(2));
+            parse_script_route(file, pool, &path.clone().i_num(0), &path.clone().i_num(1), options.as_ref())
+        },
+        JsonValue::Object(_) if crawling::is_script_object(file, path) => {
+            parse_script_route(file, pool, &path.clone().i_str("access"), &path.clone().i_str("callback"), Some(path))
         },
         JsonValue::Object(keys) => {
             let mut items = HashMap::new();
             let mut default = None;
             let mut wildcard = None;
+            let allow_path = path.clone().i_str("[allow_ips]");
+            let deny_path = path.clone().i_str("[deny_ips]");
+            let ip_filter = parse_ip_filter(file, &allow_path, &deny_path)?;
 
-            for key in keys {
-                let sub_path = path.clone().i_str(&key);
-                let value = parse_routes(file, pool, &sub_path)?;
+            let auth = parse_auth(file, pool, &path.clone().i_str("[auth]"))?;
+            let security_headers = parse_security_headers(file, &path.clone().i_str("[headers]"))?;
+
+            let special_keys = ["[allow_ips]", "[deny_ips]", "[auth]", "[headers]"];
+            for key in keys.iter().filter(|k| !special_keys.contains(&&***k)) {
+                let sub_path = path.clone().i_str(key);
+                let value = parse_routes(file, pool, &sub_path, documents)?;
                 match &**key {
                     "[param]" => wildcard = Some(Box::new(value)),
                     "[empty]" => default = Some(Box::new(value)),
@@ -350,18 +1127,540 @@ fn parse_routes(file: &JsonFile, pool: &Pool, path: &JsonPath) -> Result<Endpoin
                 }
             }
 
-            Ok(Endpoint::Dir(EndpointMap {
+            let dir = Endpoint::Dir(EndpointMap {
                 default,
                 wildcard,
                 items,
-            }))
+                ip_filter,
+                security_headers,
+            });
+
+            match auth {
+                Some(auth) => Ok(Endpoint::Protected { auth, inner: Box::new(dir) }),
+                None => Ok(dir),
+            }
         },
         JsonValue::String(endpoint_path) => match endpoint_path.as_str() {
-            "[upload]" => Ok(Endpoint::Upload),
-            path => Ok(Endpoint::Static(pool.intern(&path))),
+            "[upload]" => Ok(Endpoint::Upload(UploadTimeouts::default())),
+            crawling::SITEMAP => Ok(Endpoint::Document(documents.sitemap.0, documents.sitemap.1.clone())),
+            crawling::ROBOTS => Ok(Endpoint::Document(documents.robots.0, documents.robots.1.clone())),
+            path => Ok(Endpoint::Static(pool.intern(path))),
         },
         JsonValue::Number(_) => Err(log::error!("Invalid route (number)")),
         JsonValue::Boolean(_) => Err(log::error!("Invalid route (true/false)")),
         JsonValue::Null => Err(log::error!("Invalid route (null)")),
     }
 }
+
+/// Formats: `["ro", "callback", { options }]`, or an object with the access & callback
+/// among the options: `{ "callback": "x", "access": "rw", "methods": ["POST"] }`
+fn parse_script_route(file: &JsonFile, pool: &Pool, access_path: &JsonPath, callback_path: &JsonPath, options: Option<&JsonPath>) -> Result<Endpoint, ()> {
+    // access (rw/ro)
+    let read_only = match file.get(access_path) {
+        JsonValue::String(s) if s == "ro" => true,
+        JsonValue::String(s) if s == "rw" => false,
+        _ => return Err(log::error!("Invalid route (access must be ro/rw)")),
+    };
+
+    let fn_name = match file.get(callback_path) {
+        JsonValue::String(fn_name) => pool.intern(fn_name),
+        _ => return Err(log::error!("Invalid route (function name must be a string)")),
+    };
+
+    let (template_defaults, priority, body_mode, csrf, etag, timeout, coalesce, cache, schema, methods, max_body, auth) = match options {
+        Some(options) => {
+            let template_defaults = parse_template_defaults(file, pool, options)?;
+            let csrf = match file.get(&options.clone().i_str("csrf")) {
+                JsonValue::Boolean(csrf) => *csrf,
+                JsonValue::Null => false,
+                _ => return Err(log::error!("Invalid route (csrf must be a boolean)")),
+            };
+
+            let etag = match file.get(&options.clone().i_str("etag")) {
+                JsonValue::Boolean(etag) => *etag,
+                JsonValue::Null => false,
+                _ => return Err(log::error!("Invalid route (etag must be a boolean)")),
+            };
+
+            let coalesce = match file.get(&options.clone().i_str("coalesce")) {
+                JsonValue::Boolean(coalesce) if *coalesce && !read_only => {
+                    return Err(log::error!("Invalid route (only ro routes can coalesce requests)"));
+                },
+                JsonValue::Boolean(coalesce) => *coalesce,
+                JsonValue::Null => false,
+                _ => return Err(log::error!("Invalid route (coalesce must be a boolean)")),
+            };
+
+            let cache = match file.get(&options.clone().i_str("cache")) {
+                JsonValue::Boolean(cache) if *cache && !read_only => {
+                    return Err(log::error!("Invalid route (only ro routes can cache responses)"));
+                },
+                JsonValue::Boolean(cache) => *cache,
+                JsonValue::Null => false,
+                _ => return Err(log::error!("Invalid route (cache must be a boolean)")),
+            };
+
+            let timeout = parse_timeout(file, options)?;
+            let body_mode = parse_body_mode(file, options)?;
+            let schema = parse_schema(file, options, body_mode)?;
+            let methods = parse_methods(file, &options.clone().i_str("methods"))?;
+            let max_body = match file.get(&options.clone().i_str("max_body_kb")) {
+                JsonValue::Number(kb) if *kb >= 0.0 => Some((*kb * 1024.0) as usize),
+                JsonValue::Null => None,
+                _ => return Err(log::error!("Invalid route (max_body_kb must be a number)")),
+            };
+
+            let auth = match file.get(&options.clone().i_str("auth")) {
+                JsonValue::String(callback) => Some(Auth::Callback(pool.intern(callback))),
+                _ => parse_auth(file, pool, &options.clone().i_str("auth"))?,
+            };
+
+            (template_defaults, parse_priority(file, options)?, body_mode, csrf, etag, timeout, coalesce, cache, schema, methods, max_body, auth)
+        },
+        None => (TemplateDefaults::default(), Priority::default(), BodyMode::default(), false, false, DEFAULT_TIMEOUT, false, false, None, None, None, None),
+    };
+
+    let endpoint = Endpoint::ScriptExec(ScriptEndpoint {
+        read_only,
+        script_name: fn_name,
+        template_defaults: Arc::new(template_defaults),
+        priority,
+        body_mode,
+        csrf_protected: csrf,
+        etag,
+        timeout,
+        coalesced: coalesce,
+        cached: cache,
+        schema,
+        methods,
+        max_body,
+    });
+    match auth {
+        Some(auth) => Ok(Endpoint::Protected { auth, inner: Box::new(endpoint) }),
+        None => Ok(endpoint),
+    }
+}
+
+/// Format: `["GET", "POST"]`
+fn parse_methods(file: &JsonFile, path: &JsonPath) -> Result<Methods, ()> {
+    match file.get(path) {
+        JsonValue::Array(_) => (),
+        JsonValue::Null => return Ok(None),
+        _ => return Err(log::error!("Invalid route (methods must be an array of HTTP methods)")),
+    }
+
+    let mut methods = Vec::new();
+    for (_, _, item_path) in file.iter_array(path) {
+        match file.get(&item_path).as_string() {
+            Some(method) if !method.is_empty() && method.bytes().all(|b| b.is_ascii_alphabetic()) => {
+                methods.push(method.to_ascii_uppercase());
+            },
+            _ => return Err(log::error!("Invalid route (methods must be an array of HTTP methods)")),
+        }
+    }
+
+    Ok(Some(methods.into()))
+}
+
+/// Sites need a CSRF secret if some of their routes check tokens
+fn has_csrf_routes(endpoint: &Endpoint) -> bool {
+    match endpoint {
+        Endpoint::ScriptExec(script) => script.csrf_protected,
+        Endpoint::Dir(map) => {
+            let children = map.default.iter().chain(map.wildcard.iter()).map(|e| &**e);
+            children.chain(map.items.hash_to_value.iter_values()).any(has_csrf_routes)
+        },
+        Endpoint::Protected { inner, .. } => has_csrf_routes(inner),
+        _ => false,
+    }
+}
+
+fn parse_template_defaults(file: &JsonFile, pool: &Pool, path: &JsonPath) -> Result<TemplateDefaults, ()> {
+    let mut defaults = TemplateDefaults::default();
+
+    if file.get(path).as_object().is_none() {
+        return Err(log::error!("Invalid route (third array item must be an object)"));
+    }
+
+    match file.get(&path.clone().i_str("template")) {
+        JsonValue::String(template) => defaults.template = Some(pool.intern(template)),
+        JsonValue::Null => (),
+        _ => return Err(log::error!("Invalid route (template must be a string)")),
+    }
+
+    let params_path = path.clone().i_str("params");
+    match file.get(&params_path) {
+        JsonValue::Object(keys) => for key in keys {
+            match file.get(&params_path.clone().i_str(key)) {
+                JsonValue::String(value) => { defaults.parameters.insert(key.clone(), value.to_string()); },
+                _ => return Err(log::error!("Invalid route (template params must be strings)")),
+            }
+        },
+        JsonValue::Null => (),
+        _ => return Err(log::error!("Invalid route (params must be an object)")),
+    }
+
+    Ok(defaults)
+}
+
+/// Format: `{ "Content-Security-Policy": "default-src 'self'", "Strict-Transport-Security": null }`
+///
+/// Null values remove a default header.
+fn parse_security_headers(file: &JsonFile, path: &JsonPath) -> Result<Option<HeaderOverrides>, ()> {
+    let names = match file.get(path) {
+        JsonValue::Object(names) => names,
+        JsonValue::Null => return Ok(None),
+        _ => return Err(log::error!("Invalid security headers config (must be an object)")),
+    };
+
+    let mut overrides = HeaderOverrides::default();
+    for name in names {
+        let value = match file.get(&path.clone().i_str(name)) {
+            JsonValue::String(value) => Some(value.to_string()),
+            JsonValue::Null => None,
+            _ => return Err(log::error!("Invalid security headers config (values must be strings or null)")),
+        };
+
+        overrides.set(name, value)?;
+    }
+
+    Ok(Some(overrides))
+}
+
+/// Format: one of `{ "basic": { "username": "admin", "password": "..." } }`,
+/// `{ "bearer": "token" }` or `{ "callback": "check_token" }`
+fn parse_auth(file: &JsonFile, pool: &Pool, path: &JsonPath) -> Result<Option<Auth>, ()> {
+    if *file.get(path) == JsonValue::Null {
+        return Ok(None);
+    }
+
+    let get_str = |path: JsonPath| file.get(&path).as_string();
+    let basic_path = path.clone().i_str("basic");
+    let basic = (get_str(basic_path.clone().i_str("username")), get_str(basic_path.i_str("password")));
+
+    match (basic, get_str(path.clone().i_str("bearer")), get_str(path.clone().i_str("callback"))) {
+        ((Some(username), Some(password)), None, None) => Ok(Some(Auth::basic(username, password))),
+        ((None, None), Some(token), None) => Ok(Some(Auth::bearer(token))),
+        ((None, None), None, Some(callback)) => Ok(Some(Auth::Callback(pool.intern(callback)))),
+        _ => Err(log::error!("Invalid [auth] route config (must have one of basic/bearer/callback)")),
+    }
+}
+
+/// Format: `"allow_ips": ["10.0.0.0/8"], "deny_ips": ["10.0.0.1"]`; both are optional
+fn parse_ip_filter(file: &JsonFile, allow_path: &JsonPath, deny_path: &JsonPath) -> Result<Option<IpFilter>, ()> {
+    let parse_ranges = |path: &JsonPath| {
+        let mut ranges = Vec::new();
+        match file.get(path) {
+            JsonValue::Array(_) => (),
+            JsonValue::Null => return Ok(ranges),
+            _ => return Err(log::error!("Invalid IP filter (must be an array of addresses/CIDR ranges)")),
+        }
+
+        for (_, _, item_path) in file.iter_array(path) {
+            match file.get(&item_path).as_string().map(|range| IpRange::parse(range)) {
+                Some(Ok(range)) => ranges.push(range),
+                _ => return Err(log::error!("Invalid IP filter (items must be addresses/CIDR ranges)")),
+            }
+        }
+
+        Ok(ranges)
+    };
+
+    let filter = IpFilter {
+        allow: parse_ranges(allow_path)?,
+        deny: parse_ranges(deny_path)?,
+    };
+
+    match filter == IpFilter::default() {
+        true => Ok(None),
+        false => Ok(Some(filter)),
+    }
+}
+
+/// Format: `["example.com", "www.example.com", "*.example.com"]`
+/// Format: `{ "8443": "/admin" }`, serving the routes under `/admin` on port 8443
+fn parse_surfaces(file: &JsonFile, path: &JsonPath) -> Result<Vec<Surface>, ()> {
+    let ports = match file.get(path) {
+        JsonValue::Object(ports) => ports,
+        JsonValue::Null => return Ok(Vec::new()),
+        _ => return Err(log::error!("Invalid surfaces config (must be an object)")),
+    };
+
+    let mut surfaces = Vec::new();
+    for port_str in ports.iter() {
+        let port = match port_str.parse() {
+            Ok(port) if port > 0 => port,
+            _ => return Err(log::error!("Invalid surface: {} isn't a port number", port_str)),
+        };
+
+        match file.get(&path.clone().i_str(port_str)) {
+            JsonValue::String(prefix) if prefix.starts_with('/') => surfaces.push(Surface { port, prefix: prefix.to_string() }),
+            _ => return Err(log::error!("Invalid surface on port {} (must be a path prefix)", port)),
+        }
+    }
+
+    Ok(surfaces)
+}
+
+fn parse_hostnames(file: &JsonFile, path: &JsonPath) -> Result<Vec<String>, ()> {
+    let mut hostnames = Vec::new();
+
+    match file.get(path) {
+        JsonValue::Array(_) => (),
+        JsonValue::Null => return Ok(hostnames),
+        _ => return Err(log::error!("Invalid hostnames config (must be an array)")),
+    }
+
+    for (_, _, item_path) in file.iter_array(path) {
+        let hostname = match file.get(&item_path) {
+            JsonValue::String(hostname) => Ok(hostname),
+            _ => Err(log::error!("Invalid hostnames config (items must be strings)")),
+        }?;
+
+        let domain = hostname.strip_prefix("*.").unwrap_or(hostname);
+        if domain.is_empty() || domain.contains(['*', ':', '/']) {
+            return Err(log::error!("Invalid hostname: {} (wildcards must look like *.example.com)", hostname));
+        }
+
+        hostnames.push(hostname.to_string());
+    }
+
+    Ok(hostnames)
+}
+
+/// Format: `{ "404": "404.html", "500": "oops.html" }`
+fn parse_error_documents(file: &JsonFile, pool: &Pool, path: &JsonPath) -> Result<Vec<(u16, PoolStr)>, ()> {
+    let mut documents = Vec::new();
+
+    let codes = match file.get(path) {
+        JsonValue::Object(codes) => codes,
+        JsonValue::Null => return Ok(documents),
+        _ => return Err(log::error!("Invalid errors config (must be an object)")),
+    };
+
+    for code in codes {
+        let template = match file.get(&path.clone().i_str(code)) {
+            JsonValue::String(template) => Ok(pool.intern(template)),
+            _ => Err(log::error!("Invalid errors config (values must be template names)")),
+        }?;
+
+        match code.parse() {
+            Ok(code @ 400..=599) => documents.push((code, template)),
+            _ => return Err(log::error!("Invalid errors config ({} isn't an error status code)", code)),
+        }
+    }
+
+    Ok(documents)
+}
+
+fn parse_body_mode(file: &JsonFile, path: &JsonPath) -> Result<BodyMode, ()> {
+    match file.get(&path.clone().i_str("body")) {
+        JsonValue::String(s) if s == "json" => Ok(BodyMode::Json),
+        JsonValue::String(s) if s == "text" => Ok(BodyMode::Text),
+        JsonValue::String(s) if s == "bytes" => Ok(BodyMode::Bytes),
+        JsonValue::String(s) if s == "none" => Ok(BodyMode::None),
+        JsonValue::Null => Ok(BodyMode::default()),
+        _ => Err(log::error!("Invalid route (body must be json/text/bytes/none)")),
+    }
+}
+
+fn parse_schema(file: &JsonFile, path: &JsonPath, body_mode: BodyMode) -> Result<BodySchema, ()> {
+    let path = path.clone().i_str("schema");
+    match (file.get(&path), body_mode) {
+        (JsonValue::Null, _) => Ok(None),
+        (_, BodyMode::Json) => match Schema::parse(file, &path) {
+            Ok(schema) => Ok(Some(Arc::new(schema))),
+            Err(e) => Err(log::error!("Invalid route (schema: {})", e)),
+        },
+        _ => Err(log::error!("Invalid route (schema requires a json body)")),
+    }
+}
+
+fn parse_priority(file: &JsonFile, path: &JsonPath) -> Result<Priority, ()> {
+    match file.get(&path.clone().i_str("priority")) {
+        JsonValue::String(s) if s == "interactive" => Ok(Priority::Interactive),
+        JsonValue::String(s) if s == "batch" => Ok(Priority::Batch),
+        JsonValue::Null => Ok(Priority::default()),
+        _ => Err(log::error!("Invalid route (priority must be interactive/batch)")),
+    }
+}
+
+fn parse_timeout(file: &JsonFile, path: &JsonPath) -> Result<Timeout, ()> {
+    match file.get(&path.clone().i_str("timeout_secs")) {
+        JsonValue::Number(secs) if *secs > 0.0 => Ok(Duration::from_secs_f64(*secs)),
+        JsonValue::Null => Ok(DEFAULT_TIMEOUT),
+        _ => Err(log::error!("Invalid route (timeout_secs must be a positive number)")),
+    }
+}
+
+fn parse_upload_route(file: &JsonFile, path: &JsonPath, length: usize) -> Result<Endpoint, ()> {
+    if length != 3 {
+        return Err(log::error!("Invalid upload route (array != 3 items)"));
+    }
+
+    let get_secs = |i| match file.get(&path.clone().i_num(i)).as_num() {
+        Some(secs) if secs > 0.0 => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(log::error!("Invalid upload route (timeouts must be positive numbers)")),
+    };
+
+    Ok(Endpoint::Upload(UploadTimeouts {
+        read: get_secs(1)?,
+        total: get_secs(2)?,
+    }))
+}
+
+fn parse_jobs(file: &JsonFile, pool: &Pool, path: &JsonPath) -> Result<Vec<Job>, ()> {
+    let mut jobs = Vec::new();
+
+    match file.get(path) {
+        JsonValue::Array(_) => (),
+        JsonValue::Null => return Ok(jobs),
+        _ => return Err(log::error!("Invalid jobs (must be an array)")),
+    }
+
+    for (_, _, job_path) in file.iter_array(path) {
+        let get_str = |prop| match file.get(&job_path.clone().i_str(prop)) {
+            JsonValue::String(s) => Ok(s),
+            _ => Err(log::error!("Invalid job ({} must be a string)", prop)),
+        };
+
+        let schedule = get_str("schedule")?;
+        let schedule = match Schedule::parse(schedule) {
+            Ok(schedule) => Ok(schedule),
+            Err(()) => Err(log::error!("Invalid job schedule: {}", schedule)),
+        }?;
+
+        let read_only = match get_str("access")?.as_str() {
+            "ro" => true,
+            "rw" => false,
+            _ => return Err(log::error!("Invalid job (access must be ro/rw)")),
+        };
+
+        jobs.push(Job {
+            schedule,
+            callback: pool.intern(get_str("callback")?),
+            read_only,
+        });
+    }
+
+    Ok(jobs)
+}
+
+/// Format: `{ "topic": "callback" }`
+fn parse_subscriptions(file: &JsonFile, pool: &Pool, path: &JsonPath) -> Result<Vec<Subscription>, ()> {
+    let topics = match file.get(path) {
+        JsonValue::Object(topics) => topics,
+        JsonValue::Null => return Ok(Vec::new()),
+        _ => return Err(log::error!("Invalid subscriptions (must be an object)")),
+    };
+
+    let mut subscriptions = Vec::new();
+    for topic in topics.iter() {
+        match file.get(&path.clone().i_str(topic)) {
+            JsonValue::String(callback) => subscriptions.push(Subscription {
+                topic: topic.to_string(),
+                callback: pool.intern(callback),
+            }),
+            _ => return Err(log::error!("Invalid subscription to {} (must be a callback name)", topic)),
+        }
+    }
+
+    Ok(subscriptions)
+}
+
+fn parse_migrations(file: &JsonFile, pool: &Pool, path: &JsonPath) -> Result<Vec<PoolStr>, ()> {
+    let mut migrations = Vec::new();
+
+    match file.get(path) {
+        JsonValue::Array(_) => (),
+        JsonValue::Null => return Ok(migrations),
+        _ => return Err(log::error!("Invalid migrations (must be an array)")),
+    }
+
+    for (_, _, item_path) in file.iter_array(path) {
+        match file.get(&item_path) {
+            JsonValue::String(callback) => migrations.push(pool.intern(callback)),
+            _ => return Err(log::error!("Invalid migration (must be a callback name)")),
+        }
+    }
+
+    Ok(migrations)
+}
+
+/// Runs the migrations which weren't applied to the database yet, in order
+///
+/// The Nth migration brings the database to version N. A failed
+/// migration aborts the deployment; the previous ones stay applied.
+fn run_migrations(thread: &mut WasmThread, database: &Arc<Database>, migrations: &[PoolStr]) -> Result<(), ()> {
+    let version = database.schema_version()?;
+    if version > migrations.len() {
+        return Err(log::error!("Database version is {}, but the service has {} migrations", version, migrations.len()));
+    }
+
+    for (i, callback) in migrations.iter().enumerate().skip(version) {
+        let body = thread.parse_json("null").map_err(|trap| log::error!("{}", trap))?;
+        match thread.call_script_fn(callback, false, database, 0, body, &[], &RequestInfo::default()) {
+            // nobody to respond to, but the json must still be freed
+            Ok((_, Some(json))) => { let _ = thread.dump_json(json); },
+            Ok((_, None)) => (),
+            Err(trap) => return Err(log::error!("Migration {} ({}) failed: {}", i + 1, callback, trap)),
+        }
+
+        database.set_schema_version(i + 1)?;
+        log::info!("Migrated database to version {}", i + 1);
+    }
+
+    if migrations.len() > version {
+        let _ = database.sync_now();
+    }
+
+    Ok(())
+}
+
+fn parse_preview(file: &JsonFile, path: &JsonPath) -> Result<Option<Preview>, ()> {
+    match file.get(path) {
+        JsonValue::Boolean(true) => Ok(Some(Preview::default())),
+        JsonValue::Boolean(false) | JsonValue::Null => Ok(None),
+        JsonValue::Object(_) => {
+            let get_str = |prop| match file.get(&path.clone().i_str(prop)) {
+                JsonValue::String(s) => Ok(s),
+                _ => Err(log::error!("Invalid preview config ({} must be a string)", prop)),
+            };
+
+            Ok(Some(Preview::with_basic_auth(get_str("username")?, get_str("password")?)))
+        },
+        _ => Err(log::error!("Invalid preview config (must be a boolean or an object)")),
+    }
+}
+
+fn parse_sync(file: &JsonFile, path: &JsonPath) -> Result<Option<SyncConfig>, ()> {
+    match file.get(path) {
+        JsonValue::Object(_) => (),
+        JsonValue::Null => return Ok(None),
+        _ => return Err(log::error!("Invalid database sync config (must be an object)")),
+    }
+
+    let get_str = |prop| match file.get(&path.clone().i_str(prop)) {
+        JsonValue::String(s) => Ok(s),
+        _ => Err(log::error!("Invalid database sync config ({} must be a string)", prop)),
+    };
+
+    let schedule = get_str("schedule")?;
+    let schedule = match Schedule::parse(schedule) {
+        Ok(schedule) => Ok(schedule),
+        Err(()) => Err(log::error!("Invalid database sync schedule: {}", schedule)),
+    }?;
+
+    let on_conflict = match file.get(&path.clone().i_str("on_conflict")) {
+        JsonValue::Null => ConflictPolicy::Merge,
+        _ => match ConflictPolicy::parse(get_str("on_conflict")?) {
+            Ok(policy) => policy,
+            Err(()) => return Err(log::error!("Invalid on_conflict (must be ours/theirs/merge)")),
+        },
+    };
+
+    Ok(Some(SyncConfig {
+        schedule,
+        on_conflict,
+    }))
+}
@@ -0,0 +1,168 @@
+//! Export of request traces (see `moth::trace`) to an OpenTelemetry collector
+//!
+//! Spans are sent in batches with OTLP over HTTP, JSON-encoded, from a
+//! thread of their own. Example server config:
+//!
+//! ```json
+//! "tracing": {
+//!     "endpoint": "http://localhost:4318/v1/traces",
+//!     "service_name": "moth-eu-1",
+//!     "headers": { "x-honeycomb-team": "..." }
+//! }
+//! ```
+//!
+//! Spans are dropped, with a warning, while the collector can't keep up.
+
+use moth::{push_json_str, trace::{self, Exporter, SpanData, AttributeValue}};
+use flume::{Sender, Receiver, RecvTimeoutError, TrySendError};
+use std::{sync::atomic::{AtomicBool, Ordering}, time::{Duration, Instant, UNIX_EPOCH, SystemTime}};
+
+const DEFAULT_SERVICE_NAME: &str = "moth";
+/// Spans waiting to be sent
+const QUEUE_SIZE: usize = 8192;
+const MAX_BATCH: usize = 512;
+const BATCH_DELAY: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The `tracing` object of the server config
+pub struct TracingConfig {
+    /// Example: `http://localhost:4318/v1/traces`
+    pub endpoint: String,
+    pub service_name: Option<String>,
+    /// (name, value) of additional request headers, for authentication
+    pub headers: Vec<(String, String)>,
+}
+
+struct OtlpExporter {
+    spans_tx: Sender<SpanData>,
+    /// Spans were dropped since the queue was last available
+    dropping: AtomicBool,
+}
+
+impl Exporter for OtlpExporter {
+    fn export(&self, span: SpanData) {
+        match self.spans_tx.try_send(span) {
+            Ok(()) => self.dropping.store(false, Ordering::Relaxed),
+            Err(TrySendError::Full(_)) => if !self.dropping.swap(true, Ordering::Relaxed) {
+                log::warn!("Trace export queue is full, dropping spans");
+            },
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+}
+
+/// Starts the export thread & enables tracing
+pub fn start(config: TracingConfig) {
+    let (spans_tx, spans_rx) = flume::bounded(QUEUE_SIZE);
+    let spawned = std::thread::Builder::new().name("otlp".into()).spawn(move || exporter(config, spans_rx));
+    if let Err(e) = spawned {
+        return log::error!("Failed to start the trace export thread: {}", e);
+    }
+
+    trace::set_exporter(Box::new(OtlpExporter { spans_tx, dropping: AtomicBool::new(false) }));
+}
+
+fn exporter(config: TracingConfig, spans_rx: Receiver<SpanData>) {
+    let service_name = config.service_name.as_deref().unwrap_or(DEFAULT_SERVICE_NAME);
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now() + BATCH_DELAY;
+
+    loop {
+        let closed = match spans_rx.recv_deadline(deadline) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            },
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        let full = batch.len() >= MAX_BATCH;
+        if full || closed || Instant::now() >= deadline {
+            if !batch.is_empty() {
+                send(&config, service_name, &batch);
+                batch.clear();
+            }
+
+            deadline = Instant::now() + BATCH_DELAY;
+        }
+
+        if closed {
+            return;
+        }
+    }
+}
+
+fn send(config: &TracingConfig, service_name: &str, batch: &[SpanData]) {
+    let body = encode(service_name, batch);
+    let mut request = ureq::post(&config.endpoint).timeout(EXPORT_TIMEOUT).set("Content-Type", "application/json");
+    for (name, value) in &config.headers {
+        request = request.set(name, value);
+    }
+
+    if let Err(e) = request.send_string(&body) {
+        log::error!("Failed to export {} span(s) to {}: {}", batch.len(), config.endpoint, e);
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+/// An `ExportTraceServiceRequest`, in the JSON encoding of OTLP
+fn encode(service_name: &str, batch: &[SpanData]) -> String {
+    let mut json = String::from("{\"resourceSpans\":[{\"resource\":{\"attributes\":[");
+    push_attribute(&mut json, "service.name", &AttributeValue::Str(service_name.into()));
+    json += "]},\"scopeSpans\":[{\"scope\":{\"name\":\"moth\"},\"spans\":[";
+
+    for (i, span) in batch.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+
+        json += &format!("{{\"traceId\":\"{}\",\"spanId\":\"{}\"", hex(&span.trace_id), hex(&span.span_id));
+        if let Some(parent_id) = span.parent_id {
+            json += &format!(",\"parentSpanId\":\"{}\"", hex(&parent_id));
+        }
+
+        json += ",\"name\":";
+        push_json_str(&mut json, &span.name);
+
+        // SPAN_KIND_SERVER or SPAN_KIND_INTERNAL
+        let kind = if span.root { 2 } else { 1 };
+        json += &format!(",\"kind\":{},\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\"", kind, unix_nanos(span.start), unix_nanos(span.end));
+
+        json += ",\"attributes\":[";
+        for (j, (key, value)) in span.attributes.iter().enumerate() {
+            if j > 0 {
+                json.push(',');
+            }
+
+            push_attribute(&mut json, key, value);
+        }
+
+        // STATUS_CODE_ERROR or STATUS_CODE_UNSET
+        json += &format!("],\"status\":{{\"code\":{}}}}}", if span.error { 2 } else { 0 });
+    }
+
+    json += "]}]}]}";
+    json
+}
+
+fn push_attribute(json: &mut String, key: &str, value: &AttributeValue) {
+    *json += "{\"key\":";
+    push_json_str(json, key);
+    match value {
+        AttributeValue::Str(string) => {
+            *json += ",\"value\":{\"stringValue\":";
+            push_json_str(json, string);
+            *json += "}}";
+        },
+        // 64-bit integers are strings in OTLP/JSON
+        AttributeValue::Int(int) => *json += &format!(",\"value\":{{\"intValue\":\"{}\"}}}}", int),
+    }
+}
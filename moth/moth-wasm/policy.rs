@@ -0,0 +1,163 @@
+//! Checks of uploaded bundles, before they're deployed
+//!
+//! The `bundle_policy` of the server config limits what a bundle can contain
+//! & claim; the deployer sends all the violations of a rejected bundle back
+//! to `cargo moth`:
+//! - site.wasm can only import functions which the server provides, and its
+//!   start functions must return within `start_fuel` (see [`run_start`])
+//! - bundles can have up to `max_assets` files, of up to `max_asset_kb` each
+//! - sites can't serve `reserved_hostnames` (nor the deployment server's
+//!   hostname) & can't have routes under `reserved_routes`
+
+use lmfu::json::{JsonFile, Path as JsonPath};
+use cpio::NewcReader;
+use std::io::Read;
+use core::str::from_utf8;
+use super::{bundle, crawling, wasm::{SiteModule, run_start, unknown_imports}, claims::hostname_matches};
+
+const DEFAULT_MAX_ASSETS: usize = 10_000;
+const DEFAULT_START_FUEL: u64 = 1_000_000;
+
+/// site.wasm & config.json, if the bundle has them
+type SiteFiles = (Option<Box<[u8]>>, Option<Box<[u8]>>);
+
+/// The `bundle_policy` object of the server config
+pub struct BundlePolicy {
+    pub max_assets: usize,
+    /// In bytes, once unpacked
+    pub max_asset_size: Option<usize>,
+    /// `*.example.com` matches subdomains
+    pub reserved_hostnames: Vec<String>,
+    /// Route prefixes, like `/.well-known/security.txt`
+    pub reserved_routes: Vec<String>,
+    pub start_fuel: u64,
+}
+
+impl Default for BundlePolicy {
+    fn default() -> Self {
+        Self {
+            max_assets: DEFAULT_MAX_ASSETS,
+            max_asset_size: None,
+            reserved_hostnames: Vec::new(),
+            reserved_routes: Vec::new(),
+            start_fuel: DEFAULT_START_FUEL,
+        }
+    }
+}
+
+impl BundlePolicy {
+    /// Violations of the policy by a bundle of `hostname`; the deployment
+    /// server's hostname is reserved too
+    pub fn check(&self, cpio: &[u8], hostname: &str, deploy_host: &str) -> Vec<String> {
+        let mut violations = Vec::new();
+        let (site_wasm, config) = match self.check_files(cpio, &mut violations) {
+            Ok(files) => files,
+            Err(reason) => return vec![reason],
+        };
+
+        match site_wasm {
+            Some(site_wasm) => match SiteModule::new(&site_wasm) {
+                Ok(module) => {
+                    let unknown = unknown_imports(&module);
+                    violations.extend(unknown.iter().map(|import| format!("site.wasm imports {}, which this server doesn't provide", import)));
+                    if unknown.is_empty() {
+                        violations.extend(run_start(&site_wasm, self.start_fuel).err());
+                    }
+                },
+                Err(e) => violations.push(format!("Failed to compile site.wasm: {}", e)),
+            },
+            None => violations.push("The bundle has no site.wasm".into()),
+        }
+
+        let Some(config) = config else {
+            violations.push("The bundle has no config.json".into());
+            return violations;
+        };
+
+        let Some(config) = from_utf8(&config).ok().and_then(|json| JsonFile::new(Some(json)).ok()) else {
+            violations.push("Invalid config.json".into());
+            return violations;
+        };
+
+        // the site's hostname, its aliases & its canonical host
+        let mut hostnames = vec![hostname.to_string()];
+        let aliases = JsonPath::new().i_str("hostnames");
+        for (_, _, item_path) in config.iter_array(&aliases) {
+            hostnames.extend(config.get(&item_path).as_string().map(|alias| alias.to_string()));
+        }
+
+        let canonical = JsonPath::new().i_str("canonical").i_str("host");
+        hostnames.extend(config.get(&canonical).as_string().map(|host| host.to_string()));
+
+        let reserved = self.reserved_hostnames.iter().map(String::as_str).chain([deploy_host]);
+        for reserved in reserved {
+            for hostname in &hostnames {
+                let hostname = hostname.to_ascii_lowercase();
+                if hostname_matches(reserved, &hostname) || hostname_matches(&hostname, reserved) {
+                    violations.push(format!("Hostname {} is reserved on this server", hostname));
+                }
+            }
+        }
+
+        for key in ["routes", "on_404"] {
+            let routes = crawling::list_routes(&config, &JsonPath::new().i_str(key));
+            for route in routes {
+                if let Some(prefix) = self.reserved_routes.iter().find(|prefix| under(&route, prefix)) {
+                    violations.push(format!("Route {} is reserved on this server ({})", route, prefix));
+                }
+            }
+        }
+
+        violations
+    }
+
+    /// Checks the number & sizes of files, returning site.wasm & config.json
+    fn check_files(&self, cpio: &[u8], violations: &mut Vec<String>) -> Result<SiteFiles, String> {
+        let (mut site_wasm, mut config) = (None, None);
+        let mut index = None;
+        let mut assets = 0;
+        let mut file = cpio;
+
+        loop {
+            let mut reader = NewcReader::new(file).map_err(|_| "Invalid CPIO archive")?;
+            if reader.entry().is_trailer() {
+                break;
+            }
+
+            let name = reader.entry().name().to_string();
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content).map_err(|_| "Invalid CPIO archive")?;
+            let entry = index.as_ref().and_then(|i: &bundle::Index| i.get(&*name)).copied();
+            file = reader.finish().map_err(|_| "Invalid CPIO archive")?;
+
+            let corrupted = || format!("Corrupted bundle file: {}", name);
+            match name.as_str() {
+                bundle::INDEX => index = Some(bundle::parse_index(&content).map_err(|_| format!("Invalid {}", bundle::INDEX))?),
+                "site.wasm" => site_wasm = Some(bundle::unpack(&name, &content, entry.as_ref()).map_err(|_| corrupted())?),
+                "config.json" => config = Some(bundle::unpack(&name, &content, entry.as_ref()).map_err(|_| corrupted())?),
+                _ => {
+                    assets += 1;
+                    let size = entry.map(|entry| entry.size()).unwrap_or(content.len());
+                    if let Some(max_size) = self.max_asset_size.filter(|max_size| size > *max_size) {
+                        violations.push(format!("{} is too big ({} KB, the limit is {} KB)", name, size.div_ceil(1024), max_size / 1024));
+                    }
+                },
+            }
+        }
+
+        if assets > self.max_assets {
+            violations.push(format!("The bundle has too many files ({}, the limit is {})", assets, self.max_assets));
+        }
+
+        Ok((site_wasm, config))
+    }
+}
+
+/// Whether `route` is `prefix` or under it; `[param]` steps of the route match any step
+fn under(route: &str, prefix: &str) -> bool {
+    let mut steps = route.split('/').filter(|step| !step.is_empty());
+    prefix.split('/').filter(|step| !step.is_empty()).all(|expected| match steps.next() {
+        Some(step) => step == expected || step == "[param]",
+        None => false,
+    })
+}
@@ -0,0 +1,101 @@
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue, parse_path};
+use core::cmp::Ordering;
+
+enum Test {
+    Equals(JsonValue),
+    Range(Option<JsonValue>, Option<JsonValue>),
+    Contains(JsonValue),
+}
+
+struct Condition {
+    path: JsonPath,
+    test: Test,
+}
+
+/// Conditions which table entries must all match
+///
+/// Format: one condition object, or an array of them:
+/// - `{ "path": "author.name", "equals": "bob" }`
+/// - `{ "path": "age", "min": 18, "max": 30 }` (inclusive, numbers or strings)
+/// - `{ "path": "tags", "contains": "rust" }` (array item or substring)
+pub struct Filter {
+    conditions: Vec<Condition>,
+}
+
+impl Filter {
+    pub fn parse(json: &str) -> Result<Self, ()> {
+        let file = JsonFile::new(Some(json)).map_err(|_| log::error!("Invalid filter JSON"))?;
+        let root = JsonPath::new();
+        let mut conditions = Vec::new();
+
+        match file.get(&root) {
+            JsonValue::Array(_) => for (_, _, path) in file.iter_array(&root) {
+                conditions.push(parse_condition(&file, &path)?);
+            },
+            _ => conditions.push(parse_condition(&file, &root)?),
+        }
+
+        Ok(Self { conditions })
+    }
+
+    pub fn matches(&self, entry: &JsonFile) -> bool {
+        self.conditions.iter().all(|condition| condition.matches(entry))
+    }
+}
+
+fn parse_condition(file: &JsonFile, path: &JsonPath) -> Result<Condition, ()> {
+    let get = |prop| match file.get(&path.clone().i_str(prop)) {
+        JsonValue::Null => None,
+        JsonValue::Array(_) | JsonValue::Object(_) => None,
+        scalar => Some(scalar.clone()),
+    };
+
+    let entry_path = match file.get(&path.clone().i_str("path")) {
+        JsonValue::String(string) => JsonPath::from(parse_path(string)),
+        _ => return Err(log::error!("Invalid filter (path must be a string)")),
+    };
+
+    let test = match (get("equals"), get("contains"), get("min"), get("max")) {
+        (Some(value), None, None, None) => Test::Equals(value),
+        (None, Some(value), None, None) => Test::Contains(value),
+        (None, None, min, max) if min.is_some() || max.is_some() => Test::Range(min, max),
+        _ => return Err(log::error!("Invalid filter (expected one of equals/contains/min+max)")),
+    };
+
+    Ok(Condition {
+        path: entry_path,
+        test,
+    })
+}
+
+impl Condition {
+    fn matches(&self, entry: &JsonFile) -> bool {
+        let value = entry.get(&self.path);
+
+        match &self.test {
+            Test::Equals(expected) => value == expected,
+            Test::Range(min, max) => {
+                let check = |bound: &Option<JsonValue>, ok: fn(Ordering) -> bool| match bound {
+                    Some(bound) => compare(value, bound).map(ok) == Some(true),
+                    None => true,
+                };
+
+                check(min, Ordering::is_ge) && check(max, Ordering::is_le)
+            },
+            Test::Contains(needle) => match (value, needle) {
+                (JsonValue::String(string), JsonValue::String(needle)) => string.contains(needle.as_str()),
+                (JsonValue::Array(_), needle) => entry.iter_array(&self.path).any(|(_, file, path)| file.get(&path) == needle),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Orders numbers with numbers and strings with strings
+pub fn compare(a: &JsonValue, b: &JsonValue) -> Option<Ordering> {
+    match (a, b) {
+        (JsonValue::Number(a), JsonValue::Number(b)) => a.partial_cmp(b),
+        (JsonValue::String(a), JsonValue::String(b)) => Some(a.as_str().cmp(b.as_str())),
+        _ => None,
+    }
+}
@@ -0,0 +1,115 @@
+//! Read-only copies of a site's repository, one per script thread
+//!
+//! `ro` scripts read the replica of their thread, so they never wait for the
+//! repository lock which rw scripts take to apply their writes. Before each
+//! `ro` call, the replica catches up with the files written since it was last
+//! refreshed. It's rebuilt from scratch when these writes aren't known anymore
+//! (after a pull, or too many writes), or when catching up made it too big:
+//! previous contents of the files it copies aren't freed.
+
+use rustgit::{Repository, FileType, Error as GitError};
+use std::collections::VecDeque;
+use super::{database::Database, retention::all_files, storage::{self, Storage, SharedStorage}};
+
+/// Write batches kept for replicas to catch up
+const MAX_LOG: usize = 1024;
+
+/// Files written by the latest revisions of a database
+#[derive(Default)]
+pub struct WriteLog(VecDeque<(u64, Vec<String>)>);
+
+impl WriteLog {
+    /// `revision` is the one of the database after the write
+    pub fn push(&mut self, revision: u64, paths: Vec<String>) {
+        if self.0.len() == MAX_LOG {
+            self.0.pop_front();
+        }
+
+        self.0.push_back((revision, paths));
+    }
+
+    /// Forgets all writes, for replicas to be rebuilt
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Files written after `revision`; None if some of these writes were forgotten
+    pub fn since(&self, revision: u64) -> Option<impl Iterator<Item = &str>> {
+        let first = self.0.iter().position(|(r, _)| *r == revision.wrapping_add(1))?;
+        Some(self.0.range(first..).flat_map(|(_, paths)| paths.iter().map(String::as_str)))
+    }
+}
+
+pub struct Replica {
+    repo: SharedStorage,
+    /// Revision of the database which it mirrors; None until it's built
+    revision: Option<u64>,
+    /// Files when it was last rebuilt
+    files: usize,
+    /// Files copied since it was last rebuilt
+    copied: usize,
+}
+
+impl Replica {
+    pub fn new() -> Self {
+        Self {
+            repo: storage::shared(Repository::new()),
+            revision: None,
+            files: 0,
+            copied: 0,
+        }
+    }
+
+    /// Catches up with the repository of `database`, then returns the replica's one
+    pub fn refresh(&mut self, database: &Database) -> Result<SharedStorage, ()> {
+        let primary = database.repo.read().unwrap().clone();
+        let primary = primary.read().unwrap();
+
+        // writes are recorded with the repository locked: this can't change now
+        let revision = database.revision();
+        if self.revision == Some(revision) {
+            return Ok(self.repo.clone());
+        }
+
+        let log = database.write_log();
+        let since = self.revision.and_then(|revision| log.since(revision));
+        let caught_up = match since {
+            Some(paths) if self.copied <= self.files => self.copy(&**primary, paths),
+            _ => self.rebuild(&**primary),
+        };
+
+        // after a failure, it's rebuilt on next refresh
+        self.revision = caught_up.is_ok().then_some(revision);
+        caught_up.map(|()| self.repo.clone())
+    }
+
+    fn copy<'a, I: Iterator<Item = &'a str>>(&mut self, primary: &dyn Storage, paths: I) -> Result<(), ()> {
+        let mut replica = self.repo.write().unwrap();
+        for path in paths {
+            let data = match primary.read_file(path) {
+                Ok(bytes) => Some((bytes.to_vec(), FileType::RegularFile)),
+                Err(GitError::PathError) => None,
+                Err(e) => return Err(log::error!("Failed to read {}: {:?}", path, e)),
+            };
+
+            if let Err(e) = replica.stage(path, data) {
+                return Err(log::error!("Failed to copy {} to a replica: {:?}", path, e));
+            }
+
+            self.copied += 1;
+        }
+
+        Ok(())
+    }
+
+    fn rebuild(&mut self, primary: &dyn Storage) -> Result<(), ()> {
+        let files = all_files(primary)?;
+        self.repo = storage::shared(Repository::new());
+        self.files = files.len();
+        self.copied = 0;
+
+        self.copy(primary, files.iter().map(String::as_str))?;
+        self.copied = 0;
+        Ok(())
+    }
+}
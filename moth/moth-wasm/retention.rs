@@ -0,0 +1,117 @@
+use rustgit::{EntryType, Mode, Error as GitError};
+use super::storage::Storage;
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue, parse_path};
+
+/// Entries of matching tables are erased once they're older than `max_age`
+#[derive(Debug, Clone)]
+pub struct Retention {
+    table_prefix: String,
+    /// In seconds
+    max_age: u64,
+    /// Path of the creation time of entries (seconds since the unix epoch)
+    timestamp: String,
+}
+
+impl Retention {
+    /// Format: `[ { "table": "sessions", "days": 30, "timestamp": "created_at" } ]`
+    pub fn parse(file: &JsonFile, path: &JsonPath) -> Result<Vec<Self>, ()> {
+        match file.get(path) {
+            JsonValue::Array(_) => (),
+            JsonValue::Null => return Ok(Vec::new()),
+            _ => return Err(log::error!("Invalid retention config (must be an array)")),
+        }
+
+        let mut policies = Vec::new();
+        for (_, _, path) in file.iter_array(path) {
+            let get_str = |prop| match file.get(&path.clone().i_str(prop)) {
+                JsonValue::String(s) => Ok(s.to_string()),
+                _ => Err(log::error!("Invalid retention config ({} must be a string)", prop)),
+            };
+
+            let days = match file.get(&path.clone().i_str("days")) {
+                JsonValue::Number(days) if *days > 0.0 => Ok(*days),
+                _ => Err(log::error!("Invalid retention config (days must be a positive number)")),
+            }?;
+
+            policies.push(Self {
+                table_prefix: get_str("table")?,
+                max_age: (days * 86400.0) as u64,
+                timestamp: get_str("timestamp")?,
+            });
+        }
+
+        Ok(policies)
+    }
+
+    /// Paths of the expired entries in `repo`
+    pub fn expired(&self, repo: &dyn Storage, now: u64) -> Result<Vec<String>, ()> {
+        let timestamp_path = JsonPath::from(parse_path(&self.timestamp));
+        let mut expired = Vec::new();
+
+        for path in all_files(repo)? {
+            let in_table = table_of(&path).map(|t| t.starts_with(&self.table_prefix)) == Some(true);
+            if !in_table || !path.ends_with(".json") {
+                continue;
+            }
+
+            let entry = repo.read_text(&path).ok().and_then(|json| JsonFile::new(Some(json)).ok());
+            let created = match entry.as_ref().map(|entry| entry.get(&timestamp_path)) {
+                Some(JsonValue::Number(created)) => *created as u64,
+                // entries without a timestamp are kept
+                _ => continue,
+            };
+
+            if created.saturating_add(self.max_age) < now {
+                expired.push(path);
+            }
+        }
+
+        Ok(expired)
+    }
+}
+
+/// Paths of the entries & blobs of a data subject, in tables starting with `table_prefix`
+pub fn subject_files(repo: &dyn Storage, table_prefix: &str, subject_key: &str) -> Result<Vec<String>, ()> {
+    let is_subject_file = |path: &String| match (table_of(path), path.rsplit_once('/')) {
+        (Some(table), Some((_, file))) => table.starts_with(table_prefix) && match file.rsplit_once('.') {
+            Some((key, "json" | "bin" | "mime")) => key == subject_key,
+            _ => false,
+        },
+        _ => false,
+    };
+
+    Ok(all_files(repo)?.into_iter().filter(is_subject_file).collect())
+}
+
+fn table_of(path: &str) -> Option<&str> {
+    path.rsplit_once('/').map(|(table, _)| table)
+}
+
+/// Paths of all files in the repository, including staged ones
+pub fn all_files(repo: &dyn Storage) -> Result<Vec<String>, ()> {
+    let mut files = Vec::new();
+    let mut dirs = vec![String::new()];
+
+    while let Some(dir) = dirs.pop() {
+        let result = repo.for_each_entry(&dir, EntryType::All, &mut |name, mode| {
+            let path = match dir.is_empty() {
+                true => name.to_string(),
+                false => format!("{}/{}", dir, name),
+            };
+
+            match mode {
+                Mode::Directory => dirs.push(path),
+                _ => files.push(path),
+            }
+        });
+
+        match result {
+            Ok(()) => (),
+            // empty repository
+            Err(GitError::PathError) if dir.is_empty() => (),
+            Err(e) => return Err(log::error!("Failed to list database files: {:?}", e)),
+        }
+    }
+
+    Ok(files)
+}
@@ -0,0 +1,287 @@
+//! Full-text search over tables
+//!
+//! Tables listed in `database.search` are indexed by the host, with the
+//! properties of their entries which hold text (strings, or arrays of them):
+//!
+//! ```json
+//! "search": { "posts": ["title", "body", "tags"] }
+//! ```
+//!
+//! The index is built on the first search, then catches up with the writes
+//! which happened since the previous one, like replicas do (see
+//! [`super::replica`]). Words are lowercase runs of letters & digits; matching
+//! entries are ranked with BM25.
+
+use rustgit::EntryType;
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue, parse_path};
+use moth::push_json_str;
+use std::{sync::Mutex, collections::HashMap};
+use super::{storage::Storage, replica::WriteLog};
+
+/// BM25 parameters
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+/// Characters of context around the first match, in snippets
+const SNIPPET_BEFORE: usize = 60;
+const SNIPPET_AFTER: usize = 120;
+
+#[derive(Default)]
+struct TableIndex {
+    /// Words of each entry, with their number of occurrences
+    entries: HashMap<String, Vec<(String, u32)>>,
+    /// Entries having each word, with its number of occurrences
+    postings: HashMap<String, HashMap<String, u32>>,
+    /// Sum of the number of words of the entries
+    total_words: usize,
+}
+
+impl TableIndex {
+    fn remove(&mut self, key: &str) {
+        let Some(words) = self.entries.remove(key) else { return };
+        for (word, count) in words {
+            self.total_words -= count as usize;
+            if let Some(entries) = self.postings.get_mut(&word) {
+                entries.remove(key);
+                if entries.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, key: &str, texts: &[String]) {
+        let mut words: Vec<(String, u32)> = Vec::new();
+        for (_, word) in texts.iter().flat_map(|text| words_of(text)) {
+            match words.iter_mut().find(|(w, _)| *w == word) {
+                Some((_, count)) => *count += 1,
+                None => words.push((word, 1)),
+            }
+        }
+
+        for (word, count) in &words {
+            self.total_words += *count as usize;
+            self.postings.entry(word.clone()).or_default().insert(key.to_string(), *count);
+        }
+
+        self.entries.insert(key.to_string(), words);
+    }
+
+    /// Keys of the matching entries, best first
+    fn rank(&self, query: &[String], limit: usize) -> Vec<(&str, f64)> {
+        let count = self.entries.len() as f64;
+        let average = self.total_words as f64 / count.max(1.0);
+        let mut scores: HashMap<&str, f64> = HashMap::new();
+
+        for word in query {
+            let Some(entries) = self.postings.get(word) else { continue };
+            let matching = entries.len() as f64;
+            let idf = (1.0 + (count - matching + 0.5) / (matching + 0.5)).ln();
+
+            for (key, occurrences) in entries {
+                let length = self.entries[key].iter().map(|(_, c)| *c as f64).sum::<f64>();
+                let tf = *occurrences as f64;
+                let score = idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * length / average));
+                *scores.entry(key.as_str()).or_default() += score;
+            }
+        }
+
+        let mut ranked: Vec<_> = scores.into_iter().collect();
+        ranked.sort_by(|(ka, a), (kb, b)| b.total_cmp(a).then_with(|| ka.cmp(kb)));
+        ranked.truncate(limit);
+        ranked
+    }
+}
+
+/// Lowercase words of a text, with their byte offset
+fn words_of(text: &str) -> impl Iterator<Item = (usize, String)> + '_ {
+    let mut chars = text.char_indices().peekable();
+    core::iter::from_fn(move || {
+        while chars.next_if(|(_, c)| !c.is_alphanumeric()).is_some() {}
+        let (start, _) = *chars.peek()?;
+
+        let mut word = String::new();
+        while let Some((_, c)) = chars.next_if(|(_, c)| c.is_alphanumeric()) {
+            word.extend(c.to_lowercase());
+        }
+
+        Some((start, word))
+    })
+}
+
+struct Indexed {
+    /// Revision of the database which it matches
+    revision: u64,
+    /// Same order as `Search::tables`
+    tables: Vec<TableIndex>,
+}
+
+pub struct Search {
+    /// (table, properties)
+    tables: Vec<(String, Vec<JsonPath>)>,
+    /// None until the first search
+    indexed: Mutex<Option<Indexed>>,
+}
+
+impl Search {
+    /// Format: `{ "table": ["property", "nested.property"] }`
+    pub fn parse(file: &JsonFile, path: &JsonPath) -> Result<Self, ()> {
+        let mut tables = Vec::new();
+        let names = match file.get(path) {
+            JsonValue::Object(names) => names,
+            JsonValue::Null => return Ok(Self { tables, indexed: Mutex::new(None) }),
+            _ => return Err(log::error!("Invalid search config (must be an object)")),
+        };
+
+        for table in names.iter() {
+            let properties_path = path.clone().i_str(table);
+            let length = match file.get(&properties_path) {
+                JsonValue::Array(length) => *length,
+                _ => return Err(log::error!("Invalid search config ({} must be an array of properties)", table)),
+            };
+
+            let mut properties = Vec::new();
+            for i in 0..length {
+                match file.get(&properties_path.clone().i_num(i)) {
+                    JsonValue::String(property) => properties.push(JsonPath::from(parse_path(property))),
+                    _ => return Err(log::error!("Invalid search config ({} must be an array of properties)", table)),
+                }
+            }
+
+            tables.push((table.to_string(), properties));
+        }
+
+        Ok(Self { tables, indexed: Mutex::new(None) })
+    }
+
+    /// Texts of an entry, in the order of the indexed properties
+    fn texts(properties: &[JsonPath], json: &[u8]) -> Vec<String> {
+        let file = core::str::from_utf8(json).ok().and_then(|json| JsonFile::new(Some(json)).ok());
+        let Some(file) = file else { return Vec::new() };
+        let mut texts = Vec::new();
+
+        for property in properties {
+            match file.get(property) {
+                JsonValue::String(text) => texts.push(text.to_string()),
+                JsonValue::Array(length) => for i in 0..*length {
+                    if let JsonValue::String(text) = file.get(&property.clone().i_num(i)) {
+                        texts.push(text.to_string());
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        texts
+    }
+
+    /// (table index, key) of an entry file
+    fn entry_of<'a>(&self, path: &'a str) -> Option<(usize, &'a str)> {
+        let (table, file) = path.rsplit_once('/')?;
+        let key = file.strip_suffix(".json")?;
+        let i = self.tables.iter().position(|(name, _)| name == table)?;
+        Some((i, key))
+    }
+
+    fn index_entry(&self, index: &mut TableIndex, i: usize, storage: &dyn Storage, key: &str) {
+        index.remove(key);
+        let path = format!("{}/{}.json", self.tables[i].0, key);
+        if let Ok(json) = storage.read_file(&path) {
+            index.insert(key, &Self::texts(&self.tables[i].1, json));
+        }
+    }
+
+    fn build(&self, storage: &dyn Storage) -> Vec<TableIndex> {
+        let mut tables = Vec::new();
+        for (i, (table, _)) in self.tables.iter().enumerate() {
+            let mut keys = Vec::new();
+            let _ = storage.for_each_entry(table, EntryType::File, &mut |name, _| {
+                if let Some(key) = name.strip_suffix(".json") {
+                    keys.push(key.to_string());
+                }
+            });
+
+            let mut index = TableIndex::default();
+            for key in keys {
+                self.index_entry(&mut index, i, storage, &key);
+            }
+
+            tables.push(index);
+        }
+
+        tables
+    }
+
+    /// Must be called with the repository read-locked, for `log` to match `revision`
+    ///
+    /// Returns `[{ "key", "score", "snippet" }]`, best first; errors are for the script.
+    pub fn query(&self, storage: &dyn Storage, revision: u64, log: &WriteLog, table: &str, query: &str, limit: usize) -> Result<String, String> {
+        let i = self.tables.iter().position(|(name, _)| name == table);
+        let i = i.ok_or_else(|| format!("{} isn't indexed (see database.search)", table))?;
+
+        let mut indexed = self.indexed.lock().unwrap();
+        let since = indexed.as_ref().and_then(|indexed| match indexed.revision == revision {
+            true => Some(None),
+            false => log.since(indexed.revision).map(Some),
+        });
+
+        match (indexed.as_mut(), since) {
+            (Some(_), Some(None)) => (),
+            (Some(indexed), Some(Some(paths))) => {
+                for (t, key) in paths.filter_map(|path| self.entry_of(path)) {
+                    self.index_entry(&mut indexed.tables[t], t, storage, key);
+                }
+
+                indexed.revision = revision;
+            },
+            _ => *indexed = Some(Indexed { revision, tables: self.build(storage) }),
+        }
+
+        let index = &indexed.as_ref().unwrap(/* just built */).tables[i];
+        let words: Vec<String> = words_of(query).map(|(_, word)| word).collect();
+        let mut json = String::from("[");
+
+        for (key, score) in index.rank(&words, limit) {
+            if json.len() > 1 {
+                json.push(',');
+            }
+
+            let path = format!("{}/{}.json", table, key);
+            let texts = storage.read_file(&path).map(|json| Self::texts(&self.tables[i].1, json));
+            let snippet = texts.ok().and_then(|texts| snippet(&texts, &words)).unwrap_or_default();
+
+            json += "{\"key\":";
+            push_json_str(&mut json, key);
+            json += &format!(",\"score\":{},\"snippet\":", score);
+            push_json_str(&mut json, &snippet);
+            json.push('}');
+        }
+
+        json.push(']');
+        Ok(json)
+    }
+}
+
+/// Context of the first match, in the first text which has one
+fn snippet(texts: &[String], words: &[String]) -> Option<String> {
+    for text in texts {
+        let Some((offset, _)) = words_of(text).find(|(_, word)| words.contains(word)) else { continue };
+
+        let before = text[..offset].char_indices().rev().nth(SNIPPET_BEFORE.saturating_sub(1)).map(|(i, _)| i);
+        let after = text[offset..].char_indices().nth(SNIPPET_AFTER).map(|(i, _)| offset + i);
+
+        let mut snippet = String::new();
+        if before.is_some_and(|start| start > 0) {
+            snippet.push('…');
+        }
+
+        snippet += text[before.unwrap_or(0)..after.unwrap_or(text.len())].trim();
+        if after.is_some() {
+            snippet.push('…');
+        }
+
+        return Some(snippet);
+    }
+
+    None
+}
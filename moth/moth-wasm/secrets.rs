@@ -0,0 +1,90 @@
+//! Encrypted file keeping the secrets set with `cargo moth secret set`
+//!
+//! The file holds a random nonce followed by a JSON object sealed with
+//! AES-256-GCM: `{ "example.com": { "STRIPE_KEY": "..." } }`.
+
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+use ring::aead::{LessSafeKey, UnboundKey, Nonce, Aad, AES_256_GCM, NONCE_LEN};
+use std::{path::PathBuf, fs, io::ErrorKind};
+use core::str::from_utf8;
+
+/// (hostname, (name, value) pairs)
+pub type SiteSecrets = (String, Vec<(String, String)>);
+
+pub struct SecretStore {
+    path: PathBuf,
+    key: LessSafeKey,
+}
+
+impl SecretStore {
+    pub fn new(path: PathBuf, key: [u8; 32]) -> Self {
+        let key = UnboundKey::new(&AES_256_GCM, &key).unwrap(/* the key has the right length */);
+        Self { path, key: LessSafeKey::new(key) }
+    }
+
+    /// Secrets of all sites; none if the file doesn't exist yet
+    pub fn load(&self) -> Result<Vec<SiteSecrets>, ()> {
+        let display = self.path.display();
+        let mut sealed = match fs::read(&self.path) {
+            Ok(sealed) => sealed,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(log::error!("Couldn't read {}: {}", display, e)),
+        };
+
+        let fail = || log::error!("Couldn't decrypt {}: invalid file or key", display);
+        if sealed.len() < NONCE_LEN {
+            return Err(fail());
+        }
+
+        let (nonce, ciphertext) = sealed.split_at_mut(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
+        let json = self.key.open_in_place(nonce, Aad::empty(), ciphertext).map_err(|_| fail())?;
+        let json = from_utf8(json).map_err(|_| fail())?;
+        let json = JsonFile::new(Some(json)).map_err(|_| fail())?;
+
+        let mut secrets = Vec::new();
+        if let JsonValue::Object(hostnames) = json.get(&JsonPath::new()) {
+            for hostname in hostnames {
+                let path = JsonPath::new().i_str(hostname);
+                let JsonValue::Object(names) = json.get(&path) else { return Err(fail()) };
+
+                let mut site_secrets = Vec::new();
+                for name in names {
+                    let value = json.get(&path.clone().i_str(name)).as_string().ok_or_else(fail)?;
+                    site_secrets.push((name.to_string(), value.to_string()));
+                }
+
+                secrets.push((hostname.to_string(), site_secrets));
+            }
+        }
+
+        Ok(secrets)
+    }
+
+    /// Replaces the file with the secrets of all sites
+    pub fn save(&self, secrets: &[SiteSecrets]) -> Result<(), ()> {
+        let mut json = JsonFile::new(None).unwrap();
+        json.set_object(&JsonPath::new());
+
+        for (hostname, site_secrets) in secrets {
+            let site_path = json.prop(JsonPath::new(), hostname);
+            json.set_object(&site_path);
+
+            for (name, value) in site_secrets {
+                let path = json.prop(site_path.clone(), name);
+                json.set_string(&path, value.as_str().into());
+            }
+        }
+
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut sealed = json.dump(&JsonPath::new()).unwrap().as_bytes().to_vec();
+        let seal = self.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed);
+        seal.map_err(|_| log::error!("Couldn't encrypt secrets"))?;
+
+        // replaced at once, so a crash can't leave a truncated file
+        let fail = |e| log::error!("Couldn't write {}: {}", self.path.display(), e);
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, [&nonce[..], &sealed].concat()).map_err(fail)?;
+        fs::rename(&tmp_path, &self.path).map_err(fail)
+    }
+}
@@ -0,0 +1,141 @@
+//! Calls & messages between sites of this server
+//!
+//! Messages of `Request::publish` go through the bus of the server, see
+//! [`moth::bus`]. For `Request::call_service`, a site exposes callbacks to
+//! other sites in its config.json, with the hostnames allowed to call each:
+//!
+//! ```json
+//! "services": { "quote": ["shop.example.com"] }
+//! ```
+//!
+//! The callback runs on the thread of the calling script, with its request
+//! info; `ro` scripts can only make `ro` calls. A site which is already part
+//! of the call chain can't be called again, since its wasm instance is busy.
+
+use lmfu::{json::{JsonFile, Path as JsonPath, Value as JsonValue}, strpool::Pool};
+use moth::{Sites, ScriptResult, RequestInfo};
+use std::{sync::OnceLock, cell::RefCell};
+
+/// Sites of the server, set once they're created
+static SITES: OnceLock<Sites> = OnceLock::new();
+
+thread_local! {
+    /// Sites running a script on this thread, and its index for them; the
+    /// last one is the caller of `call`
+    static CALL_CHAIN: RefCell<Vec<(String, usize)>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn init(sites: Sites) {
+    let _ = SITES.set(sites);
+}
+
+/// (callback, hostnames allowed to call it)
+#[derive(Default)]
+pub struct Services(Vec<(String, Vec<String>)>);
+
+impl Services {
+    pub fn parse(file: &JsonFile, path: &JsonPath) -> Result<Self, ()> {
+        let callbacks = match file.get(path) {
+            JsonValue::Object(callbacks) => callbacks,
+            JsonValue::Null => return Ok(Self::default()),
+            _ => return Err(log::error!("Invalid services config (must be an object)")),
+        };
+
+        let mut services = Vec::new();
+        for callback in callbacks.iter() {
+            let callers_path = path.clone().i_str(callback);
+            let fail = || log::error!("Invalid services config ({} must be an array of hostnames)", callback);
+            let length = match file.get(&callers_path) {
+                JsonValue::Array(length) => Ok(*length),
+                _ => Err(fail()),
+            }?;
+
+            let mut callers = Vec::new();
+            for i in 0..length {
+                match file.get(&callers_path.clone().i_num(i)) {
+                    JsonValue::String(caller) => callers.push(caller.to_ascii_lowercase()),
+                    _ => return Err(fail()),
+                }
+            }
+
+            services.push((callback.to_string(), callers));
+        }
+
+        Ok(Self(services))
+    }
+
+    pub fn accepts(&self, callback: &str, caller: &str) -> bool {
+        let callers = self.0.iter().find(|(name, _)| name == callback).map(|(_, callers)| callers);
+        callers.is_some_and(|callers| callers.iter().any(|c| c.eq_ignore_ascii_case(caller)))
+    }
+}
+
+/// Marks a site as running a script on this thread, until it's dropped
+pub struct ChainLink;
+
+impl ChainLink {
+    /// None if the site is already running a script on this thread
+    pub fn enter(hostname: &str, thread_index: usize) -> Option<Self> {
+        CALL_CHAIN.with_borrow_mut(|chain| match chain.iter().any(|(h, _)| h == hostname) {
+            true => None,
+            false => {
+                chain.push((hostname.to_string(), thread_index));
+                Some(Self)
+            },
+        })
+    }
+}
+
+impl Drop for ChainLink {
+    fn drop(&mut self) {
+        CALL_CHAIN.with_borrow_mut(|chain| chain.pop());
+    }
+}
+
+/// Queues a message for the subscribers of `topic`; `json` must be valid
+pub fn publish(publisher: &str, topic: &str, json: String) {
+    match SITES.get() {
+        Some(sites) => sites.publish(publisher, topic, json),
+        None => log::warn!("No subscribers outside of a server: dropped a message on {}", topic),
+    }
+}
+
+/// Queues a rw execution of a callback of a site, see [`Sites::queue_callback`]
+pub fn queue_callback(hostname: &str, callback: &str, json: String) {
+    match SITES.get() {
+        Some(sites) => sites.queue_callback(hostname, callback, json),
+        None => log::warn!("No script threads outside of a server: {} won't run", callback),
+    }
+}
+
+/// Runs `callback` of the site serving `hostname`, with `json` as request body
+///
+/// Returns the JSON response of the callback; errors are for the script.
+pub fn call(hostname: &str, callback: &str, json: &str, read_only: bool, info: &RequestInfo) -> Result<String, String> {
+    let chain = CALL_CHAIN.with_borrow(|chain| chain.clone());
+    let (caller, thread_index) = chain.last().ok_or("services can only be called by request & job scripts")?;
+
+    let sites = SITES.get().ok_or("no other sites on this server")?;
+    let site = sites.get(&hostname.to_ascii_lowercase()).ok_or_else(|| format!("no such site: {}", hostname))?;
+    if !site.accepts_service_call(callback, caller) {
+        return Err(format!("{} doesn't allow {} to call {}", hostname, caller, callback));
+    }
+
+    if chain.iter().any(|(h, _)| h == site.hostname()) {
+        return Err(format!("{} is already part of the call chain", hostname));
+    }
+
+    let thread_index = *thread_index;
+
+    let body = site.parse_json(json, thread_index).map_err(|()| "invalid JSON body".to_string())?;
+    let callback = Pool::get_static_pool().intern(callback);
+    let result = site.process_script(callback, read_only, &[], info, body, thread_index);
+
+    let json = match result {
+        Ok(ScriptResult::Json(json) | ScriptResult::Negotiated { json_body: json, .. }) => json,
+        Ok(_) => return Err(format!("{} didn't return JSON", hostname)),
+        Err(()) => return Err(format!("the callback of {} failed", hostname)),
+    };
+
+    site.dump_json(json, thread_index).map_err(|()| format!("{} returned invalid JSON", hostname))
+}
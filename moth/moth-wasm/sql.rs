@@ -0,0 +1,133 @@
+//! SQLite databases of sites, for relational queries
+//!
+//! A site gets one with a file of the server in its config.json:
+//!
+//! ```json
+//! "database": { ..., "sqlite": "/var/lib/moth/example.com.sqlite3" }
+//! ```
+//!
+//! Scripts run parameterized statements with `Request::sql_query`. Parameters
+//! are a JSON array (for `?1`, `?2`…) or object (for `:name`); rows come back
+//! as an array of objects, by column name, with blobs as base64 strings.
+//! Statements run immediately: they aren't part of the transaction of a
+//! script, and `ro` scripts can only run read-only ones.
+
+use rusqlite::{Connection, ToSql, params_from_iter, types::{Value as SqlValue, ValueRef}};
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+use moth::{push_json_str, request::encode_base64};
+use std::sync::Mutex;
+
+pub struct SqlStore(Mutex<Connection>);
+
+enum Params {
+    Positional(Vec<SqlValue>),
+    Named(Vec<(String, SqlValue)>),
+}
+
+fn sql_value(value: &JsonValue) -> Result<SqlValue, String> {
+    match value {
+        JsonValue::Null => Ok(SqlValue::Null),
+        JsonValue::Boolean(boolean) => Ok(SqlValue::Integer(*boolean as i64)),
+        JsonValue::Number(n) if n.fract() == 0.0 && n.abs() < 2f64.powi(53) => Ok(SqlValue::Integer(*n as i64)),
+        JsonValue::Number(n) => Ok(SqlValue::Real(*n)),
+        JsonValue::String(string) => Ok(SqlValue::Text(string.to_string())),
+        _ => Err("parameters must be strings, numbers, booleans or null".into()),
+    }
+}
+
+/// An empty string means no parameters
+fn parse_params(json: &str) -> Result<Params, String> {
+    if json.is_empty() {
+        return Ok(Params::Positional(Vec::new()));
+    }
+
+    let file = JsonFile::new(Some(json)).map_err(|_| "parameters aren't valid JSON")?;
+    let root = JsonPath::new();
+
+    match file.get(&root) {
+        JsonValue::Array(length) => {
+            let values = (0..*length).map(|i| sql_value(file.get(&root.clone().i_num(i))));
+            Ok(Params::Positional(values.collect::<Result<_, _>>()?))
+        },
+        JsonValue::Object(names) => {
+            let mut values = Vec::new();
+            for name in names.iter() {
+                let value = sql_value(file.get(&root.clone().i_str(name)))?;
+                let name = match name.starts_with([':', '@', '$']) {
+                    true => name.to_string(),
+                    false => format!(":{}", name),
+                };
+
+                values.push((name, value));
+            }
+
+            Ok(Params::Named(values))
+        },
+        _ => Err("parameters must be an array or an object".into()),
+    }
+}
+
+impl SqlStore {
+    /// Creates the file if needed
+    pub fn open(path: &str) -> Result<Self, ()> {
+        match Connection::open(path) {
+            Ok(connection) => Ok(Self(Mutex::new(connection))),
+            Err(e) => Err(log::error!("Failed to open SQLite database {}: {}", path, e)),
+        }
+    }
+
+    /// Runs a statement; returns its rows as JSON
+    ///
+    /// Errors are for the script: invalid statements or parameters, failed constraints…
+    pub fn query(&self, sql: &str, params: &str, read_only: bool) -> Result<String, String> {
+        let params = parse_params(params)?;
+        let connection = self.0.lock().unwrap();
+        let mut statement = connection.prepare(sql).map_err(|e| e.to_string())?;
+
+        if read_only && !statement.readonly() {
+            return Err("ro scripts can only run read-only statements".into());
+        }
+
+        let columns: Vec<String> = statement.column_names().into_iter().map(str::to_string).collect();
+        let rows = match &params {
+            Params::Positional(values) => statement.query(params_from_iter(values)),
+            Params::Named(values) => {
+                let named: Vec<(&str, &dyn ToSql)> = values.iter().map(|(name, value)| (name.as_str(), value as &dyn ToSql)).collect();
+                statement.query(named.as_slice())
+            },
+        };
+
+        let mut rows = rows.map_err(|e| e.to_string())?;
+        let mut json = String::from("[");
+
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            if json.len() > 1 {
+                json.push(',');
+            }
+
+            json.push('{');
+            for (i, column) in columns.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+
+                push_json_str(&mut json, column);
+                json.push(':');
+
+                match row.get_ref(i).map_err(|e| e.to_string())? {
+                    ValueRef::Null => json += "null",
+                    ValueRef::Integer(n) => json += &n.to_string(),
+                    ValueRef::Real(n) if n.is_finite() => json += &n.to_string(),
+                    ValueRef::Real(_) => json += "null",
+                    ValueRef::Text(text) => push_json_str(&mut json, &String::from_utf8_lossy(text)),
+                    ValueRef::Blob(blob) => push_json_str(&mut json, &encode_base64(blob)),
+                }
+            }
+
+            json.push('}');
+        }
+
+        json.push(']');
+        Ok(json)
+    }
+}
@@ -0,0 +1,174 @@
+//! Where the tables of a site are kept
+//!
+//! By default, it's the git repository of the database, which is synced with
+//! its remote. Sites which don't need a remote can keep their tables in a
+//! directory of the server instead:
+//!
+//! ```json
+//! "database": { "directory": "/var/lib/moth/example.com" }
+//! ```
+//!
+//! Writes then reach the disk when they're applied; syncs, environments,
+//! history & history rewrites aren't available.
+
+use rustgit::{Repository, EntryType, FileType, Mode, Error as GitError};
+use std::{sync::{Arc, RwLock}, collections::BTreeMap, path::PathBuf, fs};
+
+/// The storage of a site; the outer lock of [`super::database::Database`] allows replacing it
+pub type SharedStorage = Arc<RwLock<Box<dyn Storage>>>;
+
+/// File operations behind tables, with the semantics of a git repository
+///
+/// Missing files are reported as [`GitError::PathError`]; other backends log
+/// their own failures and report them as [`GitError::InvalidObject`].
+pub trait Storage: Send + Sync {
+    fn read_file(&self, path: &str) -> Result<&[u8], GitError>;
+
+    /// Writes a file, or removes it if `data` is None
+    fn stage(&mut self, path: &str, data: Option<(Vec<u8>, FileType)>) -> Result<(), GitError>;
+
+    /// Names & modes of the entries of a directory; fails if it doesn't exist
+    fn for_each_entry(&self, dir: &str, entry_type: EntryType, callback: &mut dyn FnMut(&str, Mode)) -> Result<(), GitError>;
+
+    /// The git repository, for syncs
+    fn repository(&mut self) -> Option<&mut Repository> {
+        None
+    }
+
+    fn read_text(&self, path: &str) -> Result<&str, GitError> {
+        core::str::from_utf8(self.read_file(path)?).map_err(|_| GitError::InvalidObject)
+    }
+}
+
+pub fn shared<S: Storage + 'static>(storage: S) -> SharedStorage {
+    Arc::new(RwLock::new(Box::new(storage)))
+}
+
+impl Storage for Repository {
+    fn read_file(&self, path: &str) -> Result<&[u8], GitError> {
+        Repository::read_file(self, path)
+    }
+
+    fn stage(&mut self, path: &str, data: Option<(Vec<u8>, FileType)>) -> Result<(), GitError> {
+        Repository::stage(self, path, data)
+    }
+
+    fn for_each_entry(&self, dir: &str, entry_type: EntryType, callback: &mut dyn FnMut(&str, Mode)) -> Result<(), GitError> {
+        Repository::for_each_entry(self, dir, entry_type, |name, mode, _hash| callback(name, mode))
+    }
+
+    fn repository(&mut self) -> Option<&mut Repository> {
+        Some(self)
+    }
+}
+
+/// Files of a directory of the server
+///
+/// They're all loaded when the site is deployed, since reads borrow them.
+pub struct Directory {
+    root: PathBuf,
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl Directory {
+    /// Loads the files of `root`, which is created if needed
+    pub fn open(root: &str) -> Result<Self, ()> {
+        let root = PathBuf::from(root);
+        let fail = |e| log::error!("Failed to open database directory {}: {}", root.display(), e);
+        fs::create_dir_all(&root).map_err(fail)?;
+
+        let mut files = BTreeMap::new();
+        let mut dirs = vec![String::new()];
+
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(root.join(&dir)).map_err(fail)? {
+                let entry = entry.map_err(fail)?;
+                let Some(name) = entry.file_name().to_str().map(str::to_string) else { continue };
+                let path = match dir.is_empty() {
+                    true => name,
+                    false => format!("{}/{}", dir, name),
+                };
+
+                match entry.file_type().map_err(fail)?.is_dir() {
+                    true => dirs.push(path),
+                    false => {
+                        files.insert(path, fs::read(entry.path()).map_err(fail)?);
+                    },
+                }
+            }
+        }
+
+        Ok(Self { root, files })
+    }
+
+    /// Refuses paths which could leave the directory
+    fn check(path: &str) -> Result<(), GitError> {
+        match path.split('/').any(|part| matches!(part, "" | "." | "..")) {
+            true => Err(GitError::PathError),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Storage for Directory {
+    fn read_file(&self, path: &str) -> Result<&[u8], GitError> {
+        self.files.get(path).map(Vec::as_slice).ok_or(GitError::PathError)
+    }
+
+    fn stage(&mut self, path: &str, data: Option<(Vec<u8>, FileType)>) -> Result<(), GitError> {
+        Self::check(path)?;
+        let file = self.root.join(path);
+        let fail = |e| {
+            log::error!("Failed to write {}: {}", file.display(), e);
+            GitError::InvalidObject
+        };
+
+        match data {
+            Some((bytes, _)) => {
+                if let Some(parent) = file.parent() {
+                    fs::create_dir_all(parent).map_err(fail)?;
+                }
+
+                fs::write(&file, &bytes).map_err(fail)?;
+                self.files.insert(path.to_string(), bytes);
+            },
+            None => if self.files.remove(path).is_some() {
+                fs::remove_file(&file).map_err(fail)?;
+            },
+        }
+
+        Ok(())
+    }
+
+    fn for_each_entry(&self, dir: &str, entry_type: EntryType, callback: &mut dyn FnMut(&str, Mode)) -> Result<(), GitError> {
+        let prefix = match dir.is_empty() {
+            true => String::new(),
+            false => format!("{}/", dir),
+        };
+
+        let mut found = false;
+        let mut last_dir = None;
+        for path in self.files.range(prefix.clone()..).map(|(path, _)| path) {
+            let Some(rest) = path.strip_prefix(&prefix) else { break };
+            found = true;
+
+            let (name, mode) = match rest.split_once('/') {
+                Some((name, _)) if last_dir == Some(name) => continue,
+                Some((name, _)) => {
+                    last_dir = Some(name);
+                    (name, Mode::Directory)
+                },
+                None => (rest, Mode::RegularFile),
+            };
+
+            if mode.matches(entry_type) {
+                callback(name, mode);
+            }
+        }
+
+        match found || dir.is_empty() {
+            true => Ok(()),
+            false => Err(GitError::PathError),
+        }
+    }
+}
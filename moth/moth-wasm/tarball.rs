@@ -0,0 +1,113 @@
+const BLOCK: usize = 512;
+
+/// Appends a regular file to a (ustar) tarball
+pub fn append_file(tarball: &mut Vec<u8>, path: &str, content: &[u8]) -> Result<(), ()> {
+    let mut header = [0u8; BLOCK];
+
+    // long paths are split between the prefix & name fields
+    let (prefix, name) = match path.len() {
+        0..=100 => ("", path),
+        _ => match path.as_bytes()[..path.len().min(156)].iter().rposition(|b| *b == b'/') {
+            Some(i) if path.len() - i - 1 <= 100 => (&path[..i], &path[i + 1..]),
+            _ => return Err(log::error!("Path is too long for a tarball: {}", path)),
+        },
+    };
+
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], content.len() as u64);
+    write_octal(&mut header[136..148], 0);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // the checksum is computed with its own field set to spaces
+    header[148..156].fill(b' ');
+    let checksum = header.iter().map(|b| *b as u64).sum();
+    write_octal(&mut header[148..155], checksum);
+
+    tarball.extend_from_slice(&header);
+    tarball.extend_from_slice(content);
+    tarball.resize(tarball.len().next_multiple_of(BLOCK), 0);
+
+    Ok(())
+}
+
+/// Appends the two empty blocks which mark the end of a tarball
+pub fn finish(tarball: &mut Vec<u8>) {
+    tarball.resize(tarball.len() + 2 * BLOCK, 0);
+}
+
+/// Returns (path, content) for each regular file of a tarball
+///
+/// Directories are skipped; `./` prefixes are removed from paths.
+pub fn read_files(tarball: &[u8]) -> Result<Vec<(String, &[u8])>, ()> {
+    let mut files = Vec::new();
+    let mut long_name = None;
+    let mut offset = 0;
+
+    while let Some(header) = tarball.get(offset..offset + BLOCK) {
+        if header.iter().all(|b| *b == 0) {
+            return Ok(files);
+        }
+
+        let size = read_octal(&header[124..136]).ok_or_else(|| log::error!("Invalid tarball entry size"))?;
+        let start = offset + BLOCK;
+        let content = tarball.get(start..start + size).ok_or_else(|| log::error!("Truncated tarball"))?;
+        offset = start + size.next_multiple_of(BLOCK);
+
+        let path = match long_name.take() {
+            Some(path) => path,
+            None => match (read_str(&header[345..500]), read_str(&header[..100])) {
+                (Some(""), Some(name)) => name.to_string(),
+                (Some(prefix), Some(name)) => format!("{}/{}", prefix, name),
+                _ => return Err(log::error!("Invalid tarball entry name")),
+            },
+        };
+
+        match header[156] {
+            b'0' | 0 => (),
+            // GNU long name: applies to the next entry
+            b'L' => {
+                let name = read_str(content).ok_or_else(|| log::error!("Invalid tarball entry name"))?;
+                long_name = Some(name.to_string());
+                continue;
+            },
+            // directories, links, pax headers...
+            _ => continue,
+        }
+
+        let path = path.trim_start_matches("./");
+        if path.is_empty() || path.split('/').any(|part| part.is_empty() || part == "..") {
+            return Err(log::error!("Invalid path in tarball: {:?}", path));
+        }
+
+        files.push((path.to_string(), content));
+    }
+
+    Err(log::error!("Truncated tarball"))
+}
+
+/// Fills `field` with zero-padded octal digits & a NUL terminator
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let octal = format!("{:0width$o}", value, width = digits);
+    field[..digits].copy_from_slice(&octal.as_bytes()[octal.len() - digits..]);
+    field[digits] = 0;
+}
+
+fn read_octal(field: &[u8]) -> Option<usize> {
+    let digits = read_str(field)?.trim_matches(' ');
+    match digits.is_empty() {
+        true => Some(0),
+        false => usize::from_str_radix(digits, 8).ok(),
+    }
+}
+
+fn read_str(field: &[u8]) -> Option<&str> {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).ok()
+}
@@ -0,0 +1,150 @@
+//! Files which the clients of a site upload into blobs
+//!
+//! A script gives the client a token with `Request::upload_token`; the client
+//! then sends the file as the body of a request to an `[upload]` route of the
+//! site, followed by the token: `POST /files/upload/<token>`. Once received,
+//! the file is written as a blob, then the callback given with the token (if
+//! any) runs in the background with `{ "table", "key", "size" }` as body.
+//!
+//! Large files can be sent in chunks, and resumed after a disconnect, see
+//! [`moth::upload`]. Files are kept in memory while they're uploaded, up to
+//! `max_upload_kb` (config.json). Tokens expire an hour after they're given
+//! or after the last chunk; after a failed upload, the client can retry with
+//! its token.
+
+use std::{sync::{Mutex, RwLock}, collections::HashMap};
+use moth::push_json_str;
+use super::{database::{Database, now}, handle::blob_paths, services};
+
+const TOKEN_LIFETIME_SECS: u64 = 3600;
+
+/// Where an upload goes
+pub struct Target {
+    pub table: String,
+    pub key: String,
+    pub content_type: String,
+    pub max_size: usize,
+    /// Runs once the blob is written
+    pub callback: Option<String>,
+}
+
+/// Received so far
+struct Progress {
+    bytes: Vec<u8>,
+    chunks: usize,
+    /// Pushed back by each chunk
+    expires: u64,
+}
+
+struct Pending {
+    target: Target,
+    progress: Mutex<Progress>,
+}
+
+/// Pending uploads of a site, by token
+pub struct Uploads {
+    /// Size limit of the uploads of the site
+    pub max_size: usize,
+    pending: RwLock<HashMap<String, Pending>>,
+}
+
+impl Uploads {
+    pub fn new(max_size: usize) -> Self {
+        Self { max_size, pending: RwLock::new(HashMap::new()) }
+    }
+
+    pub fn create(&self, target: Target) -> String {
+        let mut pending = self.pending.write().unwrap();
+        let time = now();
+        pending.retain(|_, upload| upload.progress.get_mut().unwrap().expires > time);
+
+        let token = loop {
+            let token = format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>());
+            if !pending.contains_key(&token) {
+                break token;
+            }
+        };
+
+        let progress = Progress { bytes: Vec::new(), chunks: 0, expires: time + TOKEN_LIFETIME_SECS };
+        pending.insert(token.clone(), Pending { target, progress: Mutex::new(progress) });
+        token
+    }
+
+    /// Runs `f` on the progress of an upload, if its token is valid
+    fn with_progress<T>(&self, token: &str, f: impl FnOnce(&Target, &mut Progress) -> T) -> Option<T> {
+        let pending = self.pending.read().unwrap();
+        let upload = pending.get(token)?;
+        let mut progress = upload.progress.lock().unwrap();
+        (progress.expires > now()).then(|| f(&upload.target, &mut progress))
+    }
+
+    /// Maximum size of an upload, if its token is valid
+    pub fn max_size(&self, token: &str) -> Option<usize> {
+        self.with_progress(token, |target, _| target.max_size)
+    }
+
+    /// (chunks, bytes) received, if the token is valid
+    pub fn progress(&self, token: &str) -> Option<(usize, usize)> {
+        self.with_progress(token, |_, progress| (progress.chunks, progress.bytes.len()))
+    }
+
+    pub fn append(&self, token: &str, bytes: &[u8]) {
+        self.with_progress(token, |_, progress| progress.bytes.extend_from_slice(bytes));
+    }
+
+    pub fn append_chunk(&self, token: &str, chunk: &[u8]) {
+        self.with_progress(token, |_, progress| {
+            progress.bytes.extend_from_slice(chunk);
+            progress.chunks += 1;
+            progress.expires = now() + TOKEN_LIFETIME_SECS;
+        });
+    }
+
+    /// Writes the blob of a successful upload; clears a failed one
+    ///
+    /// Fails with the reason to give to the client if the token isn't valid
+    /// or the blob can't be written; the client can then retry with its token.
+    pub fn finish(&self, token: &str, success: bool, database: &Database, hostname: &str) -> Result<(), String> {
+        if !success {
+            self.with_progress(token, |_, progress| {
+                progress.bytes.clear();
+                progress.chunks = 0;
+            });
+
+            return Ok(());
+        }
+
+        let upload = self.pending.write().unwrap().remove(token);
+        let Some(Pending { target, progress }) = upload else {
+            log::error!("Unknown upload token");
+            return Err("Unknown or expired upload token".into());
+        };
+
+        let progress = progress.into_inner().unwrap();
+        if progress.expires <= now() {
+            log::error!("Expired upload token");
+            return Err("Unknown or expired upload token".into());
+        }
+
+        let size = progress.bytes.len();
+        let (data_path, type_path) = blob_paths(&target.table, &target.key);
+        let files = vec![(data_path, progress.bytes), (type_path, target.content_type.clone().into_bytes())];
+        if database.write_files(files).is_err() {
+            let progress = Progress { bytes: Vec::new(), chunks: 0, expires: now() + TOKEN_LIFETIME_SECS };
+            self.pending.write().unwrap().insert(token.into(), Pending { target, progress: Mutex::new(progress) });
+            return Err("Failed to store the upload".into());
+        }
+
+        if let Some(callback) = target.callback {
+            let mut json = String::from("{\"table\":");
+            push_json_str(&mut json, &target.table);
+            json += ",\"key\":";
+            push_json_str(&mut json, &target.key);
+            json += &format!(",\"size\":{}}}", size);
+
+            services::queue_callback(hostname, &callback, json);
+        }
+
+        Ok(())
+    }
+}
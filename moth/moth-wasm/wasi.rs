@@ -0,0 +1,135 @@
+//! Subset of WASI preview 1 for guests built for `wasm32-wasip1`
+//!
+//! Sites have no file system, arguments or environment: stdout & stderr
+//! are sent to the log, and clocks & randomness are provided.
+
+use std::time::{SystemTime, UNIX_EPOCH, Instant};
+use std::sync::OnceLock;
+use rand::RngCore;
+use super::wasm::{Caller, Linker, Memory, Extern, Trap, trap};
+
+pub const MODULE: &str = "wasi_snapshot_preview1";
+
+const SUCCESS: i32 = 0;
+const EBADF: i32 = 8;
+const EFAULT: i32 = 21;
+const EINVAL: i32 = 28;
+const ENOSYS: i32 = 52;
+
+const CLOCK_REALTIME: i32 = 0;
+const CLOCK_MONOTONIC: i32 = 1;
+
+const STDOUT: i32 = 1;
+const STDERR: i32 = 2;
+
+pub fn define(linker: &mut Linker) -> Option<()> {
+    linker.func_wrap(MODULE, "fd_write", fd_write).ok()?;
+    linker.func_wrap(MODULE, "clock_time_get", clock_time_get).ok()?;
+    linker.func_wrap(MODULE, "clock_res_get", clock_res_get).ok()?;
+    linker.func_wrap(MODULE, "random_get", random_get).ok()?;
+    linker.func_wrap(MODULE, "proc_exit", proc_exit).ok()?;
+    linker.func_wrap(MODULE, "sched_yield", || SUCCESS).ok()?;
+
+    // no arguments, no environment variables
+    linker.func_wrap(MODULE, "args_sizes_get", write_zeros).ok()?;
+    linker.func_wrap(MODULE, "environ_sizes_get", write_zeros).ok()?;
+    linker.func_wrap(MODULE, "args_get", |_: i32, _: i32| SUCCESS).ok()?;
+    linker.func_wrap(MODULE, "environ_get", |_: i32, _: i32| SUCCESS).ok()?;
+
+    // no files; EBADF on fd_prestat_get ends the search for preopened directories
+    linker.func_wrap(MODULE, "fd_prestat_get", |_: i32, _: i32| EBADF).ok()?;
+    linker.func_wrap(MODULE, "fd_prestat_dir_name", |_: i32, _: i32, _: i32| EBADF).ok()?;
+    linker.func_wrap(MODULE, "fd_fdstat_get", |_: i32, _: i32| EBADF).ok()?;
+    linker.func_wrap(MODULE, "fd_read", |_: i32, _: i32, _: i32, _: i32| EBADF).ok()?;
+    linker.func_wrap(MODULE, "fd_seek", |_: i32, _: i64, _: i32, _: i32| EBADF).ok()?;
+    linker.func_wrap(MODULE, "fd_close", |_: i32| EBADF).ok()?;
+    linker.func_wrap(MODULE, "poll_oneoff", |_: i32, _: i32, _: i32, _: i32| ENOSYS).ok()?;
+
+    Some(())
+}
+
+fn memory(caller: &mut Caller) -> Option<Memory> {
+    caller.get_export("memory").and_then(Extern::into_memory)
+}
+
+fn write(caller: &mut Caller, ptr: i32, bytes: &[u8]) -> i32 {
+    let result = memory(caller).map(|mem| mem.write(caller, ptr as u32 as usize, bytes));
+    match result {
+        Some(Ok(())) => SUCCESS,
+        _ => EFAULT,
+    }
+}
+
+fn write_zeros(mut caller: Caller, count_ptr: i32, size_ptr: i32) -> i32 {
+    match write(&mut caller, count_ptr, &0u32.to_le_bytes()) {
+        SUCCESS => write(&mut caller, size_ptr, &0u32.to_le_bytes()),
+        errno => errno,
+    }
+}
+
+fn fd_write(mut caller: Caller, fd: i32, iovs_ptr: i32, iovs_len: i32, nwritten_ptr: i32) -> i32 {
+    if fd != STDOUT && fd != STDERR {
+        return EBADF;
+    }
+
+    let Some(mem) = memory(&mut caller) else {
+        return EFAULT;
+    };
+
+    let mut output = Vec::new();
+    for i in 0..(iovs_len as u32 as usize) {
+        // struct ciovec { buf: u32, buf_len: u32 }
+        let mut iov = [0; 8];
+        let iov_ptr = iovs_ptr as u32 as usize + i * iov.len();
+        if mem.read(&caller, iov_ptr, &mut iov).is_err() {
+            return EFAULT;
+        }
+
+        let buf = u32::from_le_bytes(iov[..4].try_into().unwrap()) as usize;
+        let buf_len = u32::from_le_bytes(iov[4..].try_into().unwrap()) as usize;
+        let Some(bytes) = mem.data(&caller).get(buf..buf + buf_len) else {
+            return EFAULT;
+        };
+
+        output.extend_from_slice(bytes);
+    }
+
+    let text = String::from_utf8_lossy(&output);
+    for line in text.lines().filter(|l| !l.is_empty()) {
+        match fd {
+            STDOUT => log::info!("site.wasm: {}", line),
+            _ => log::warn!("site.wasm: {}", line),
+        }
+    }
+
+    write(&mut caller, nwritten_ptr, &(output.len() as u32).to_le_bytes())
+}
+
+fn clock_time_get(mut caller: Caller, clock_id: i32, _precision: i64, time_ptr: i32) -> i32 {
+    static START: OnceLock<Instant> = OnceLock::new();
+
+    let nanos = match clock_id {
+        CLOCK_REALTIME => SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos(),
+        CLOCK_MONOTONIC => START.get_or_init(Instant::now).elapsed().as_nanos(),
+        _ => return EINVAL,
+    };
+
+    write(&mut caller, time_ptr, &(nanos as u64).to_le_bytes())
+}
+
+fn clock_res_get(mut caller: Caller, clock_id: i32, res_ptr: i32) -> i32 {
+    match clock_id {
+        CLOCK_REALTIME | CLOCK_MONOTONIC => write(&mut caller, res_ptr, &1000u64.to_le_bytes()),
+        _ => EINVAL,
+    }
+}
+
+fn random_get(mut caller: Caller, buf_ptr: i32, buf_len: i32) -> i32 {
+    let mut bytes = vec![0; buf_len as u32 as usize];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    write(&mut caller, buf_ptr, &bytes)
+}
+
+fn proc_exit(_caller: Caller, code: i32) -> Result<(), Trap> {
+    Err(trap(format!("site.wasm exited with code {}", code)))
+}
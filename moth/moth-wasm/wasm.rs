@@ -1,186 +1,693 @@
-use wasmi::{Engine, Module, Instance, Func, TypedFunc, Value, Memory, core::Trap};
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
-use super::{Pool, Handle, TemplateParams};
-use moth::OpaqueJsonPointer;
-use rustgit::Repository;
+//! WebAssembly runtime
+//!
+//! [`WasmThread`] runs the scripts of a [`SiteInstance`]. Instances & host
+//! functions only use the types re-exported here (`Caller`, `Store`,
+//! `TypedFunc`, `Trap`…), whose APIs are common to the engines: [`Engine`]
+//! implements the rest of [`Runtime`]. It is `wasmi_runtime::Wasmi`
+//! (interpreter), or `wasmtime_runtime::Wasmtime` (JIT) with the `wasmtime`
+//! feature.
+//!
+//! site.wasm is either a core module following `moth_abi`, or a component
+//! of the `site` world of moth-abi/wit/moth.wit (see [`super::component`]).
+
+use std::sync::{Arc, Weak, Mutex, RwLockReadGuard};
+use sha2::{Sha256, Digest};
+use super::{Handle, TemplateParams, handle::{Transaction, SiteContext, content_hash}};
+use super::{database::Database, replica::Replica, storage::SharedStorage, component};
+use moth::{OpaqueJsonPointer, RequestInfo, trace};
+use rustgit::FileType;
 use lmfu::ArrayVec;
 
-pub(crate) type Caller<'a> = wasmi::Caller<'a, Handle>;
-type Linker = wasmi::Linker<Handle>;
-type Store = wasmi::Store<Handle>;
+#[cfg(not(feature = "wasmtime"))]
+use super::wasmi_runtime as engine;
+#[cfg(feature = "wasmtime")]
+use super::wasmtime_runtime as engine;
 
-pub enum RepoBorrow<'a> {
-    ReadOnly(RwLockReadGuard<'a, Arc<RwLock<Repository>>>),
-    ReadWrite(RwLockWriteGuard<'a, Arc<RwLock<Repository>>>),
+pub(crate) use engine::{Module, Instance, Func, TypedFunc, Memory, Extern, AsContext, IntoFunc, WasmParams, WasmResults, F64};
+pub(crate) use engine::{Caller, Linker, Store, StoreContext, Trap, trap};
+
+/// The runtime of this build
+#[cfg(not(feature = "wasmtime"))]
+pub type Engine = engine::Wasmi;
+
+/// The runtime of this build
+#[cfg(feature = "wasmtime")]
+pub type Engine = engine::Wasmtime;
+
+/// What a WebAssembly engine provides beyond the common types above
+pub trait Runtime {
+    /// Compiles a core module, whose instances consume fuel if `metered`
+    fn compile(bytes: &[u8], metered: bool) -> Result<Module, String>;
+
+    fn add_fuel(store: &mut Store, fuel: u64) -> Result<(), String>;
+
+    /// Instantiates a module & runs its start function
+    fn instantiate(linker: &Linker, store: &mut Store, module: &Module) -> Result<Instance, String>;
+
+    /// Links functions which trap to the imports of `module` which `linker` lacks
+    fn define_traps(linker: &mut Linker, store: &mut Store, module: &Module);
+
+    /// Calls a function taking & returning `i64`s, whose arity is only known at run time
+    fn call(store: &mut Store, func: Func, params: &[i64]) -> Result<i64, Trap>;
+}
+
+/// Times a rw script runs again when another script modified an entry which
+/// it read, before it fails
+const CONFLICT_RETRIES: usize = 3;
+
+/// Failure of a rw script, see [`RepoBorrow::apply`]
+enum ScriptFailure {
+    /// Another script modified this entry, which the script read; it can run again
+    Conflict(String),
+    Trap(Trap),
+}
+
+impl From<Trap> for ScriptFailure {
+    fn from(trap: Trap) -> Self {
+        Self::Trap(trap)
+    }
+}
+
+impl From<ScriptFailure> for Trap {
+    fn from(failure: ScriptFailure) -> Self {
+        match failure {
+            ScriptFailure::Conflict(path) => trap(format!("Write conflict on {}", path)),
+            ScriptFailure::Trap(trap) => trap,
+        }
+    }
+}
+
+/// Prevents the repository from being replaced while a rw script runs
+///
+/// The repository itself is only locked for each access, see [`Transaction`].
+/// `ro` scripts read the [`Replica`] of their thread instead, so they never
+/// wait for rw scripts or syncs.
+pub struct RepoBorrow<'a> {
+    /// Held until the script returns; None when reading a replica
+    _outer: Option<RwLockReadGuard<'a, SharedStorage>>,
+    repo: SharedStorage,
+    database: &'a Arc<Database>,
 }
 
 impl<'a> RepoBorrow<'a> {
-    fn repo_arc(&self) -> Arc<RwLock<Repository>> {
-        match self {
-            Self::ReadOnly(guard) => (*guard).clone(),
-            Self::ReadWrite(guard) => (*guard).clone(),
+    fn new(database: &'a Arc<Database>) -> Self {
+        let outer = database.repo.read().unwrap();
+        Self {
+            repo: (*outer).clone(),
+            _outer: Some(outer),
+            database,
         }
     }
+
+    fn replica(database: &'a Arc<Database>, replica: &mut Replica) -> Result<Self, Trap> {
+        let fail = |()| trap("Failed to refresh the database replica");
+        Ok(Self {
+            _outer: None,
+            repo: replica.refresh(database).map_err(fail)?,
+            database,
+        })
+    }
+
+    fn repo_arc(&self) -> SharedStorage {
+        self.repo.clone()
+    }
+
+    fn database(&self) -> Arc<Database> {
+        self.database.clone()
+    }
+
+    /// Applies the writes of a script which returned successfully, all or none of them
+    ///
+    /// Fails if an entry which the script read was modified by another script since then.
+    fn apply(&self, transaction: Transaction) -> Result<(), ScriptFailure> {
+        if transaction.writes.is_empty() {
+            return Ok(());
+        }
+
+        let mut span = trace::span("db commit");
+        span.attribute("moth.db.writes", transaction.writes.len() as i64);
+
+        let repo = self.repo_arc();
+        let mut repo = repo.write().unwrap();
+
+        for (path, hash) in transaction.reads {
+            if content_hash(repo.read_file(&path).ok()) != hash {
+                span.fail();
+                return Err(ScriptFailure::Conflict(path));
+            }
+        }
+
+        // previous content of the staged entries, so that they're all applied or none is
+        let mut staged: Vec<(String, _)> = Vec::with_capacity(transaction.writes.len());
+        for (path, bytes) in transaction.writes {
+            let previous = repo.read_file(&path).ok().map(|bytes| (bytes.to_vec(), FileType::RegularFile));
+            if let Err(e) = repo.stage(&path, Some((bytes, FileType::RegularFile))) {
+                span.fail();
+                for (path, previous) in staged.into_iter().rev() {
+                    if let Err(e) = repo.stage(&path, previous) {
+                        log::error!("Failed to roll back {}: {:?}", path, e);
+                    }
+                }
+
+                return Err(trap(format!("Repository::stage(): {:?}", e)).into());
+            }
+
+            staged.push((path, previous));
+        }
+
+        self.database.record_writes(staged.iter().map(|(path, _)| path.as_str()));
+
+        Ok(())
+    }
 }
 
-pub struct WasmThread {
-    module: Arc<Module>,
-    instance: Arc<Instance>,
-    store: Store,
+/// Compiled modules by SHA-256 of their bytecode, so that
+/// redeploying an unchanged site.wasm skips compilation
+static MODULES: Mutex<Vec<([u8; 32], Weak<SiteModule>)>> = Mutex::new(Vec::new());
+
+fn compile(bytes: &[u8]) -> Option<Arc<SiteModule>> {
+    let digest: [u8; 32] = Sha256::digest(bytes).into();
+    let mut modules = MODULES.lock().unwrap();
+    modules.retain(|(_, module)| module.strong_count() > 0);
+
+    let cached = modules.iter().find(|(d, _)| *d == digest);
+    if let Some(module) = cached.and_then(|(_, module)| module.upgrade()) {
+        log::info!("Reusing compiled site.wasm");
+        return Some(module);
+    }
+
+    let module = match SiteModule::new(bytes) {
+        Ok(module) => Arc::new(module),
+        Err(e) => {
+            log::error!("Failed to compile site.wasm: {}", e);
+            return None;
+        },
+    };
+
+    modules.push((digest, Arc::downgrade(&module)));
+    Some(module)
+}
 
-    parse_json: TypedFunc<(u64, u64), (u64,)>,
+/// Imports of a module which the host doesn't provide, as `module::name`
+pub fn unknown_imports(module: &SiteModule) -> Vec<String> {
+    let is_component = module.is_component;
+    let known = |module_name: &str, name: &str| match module_name {
+        moth_abi::IMPORT_MODULE => !is_component && moth_abi::HOST_FUNCTIONS.contains(&name),
+        super::wasi::MODULE => true,
+        _ => is_component && component::provides(module_name, name),
+    };
+
+    let imports = module.imports().into_iter();
+    let unknown = imports.filter(|(module_name, name)| !known(module_name, name));
+    unknown.map(|(module_name, name)| format!("{}::{}", module_name, name)).collect()
+}
+
+/// Rejects modules importing functions which the host doesn't provide
+fn check_imports(module: &SiteModule) -> Option<()> {
+    let unknown = unknown_imports(module);
+    for import in &unknown {
+        log::error!("site.wasm imports {}, which this server doesn't provide", import);
+    }
+
+    unknown.is_empty().then_some(())
+}
+
+/// Modules which don't export their ABI version are assumed to implement version 1
+fn check_version(instance: &Instance, store: &mut Store) -> Option<()> {
+    let version = match instance.get_typed_func::<(), (u32,)>(&mut *store, moth_abi::VERSION_EXPORT) {
+        Ok(version_fn) => version_fn.call(store, ()).ok()?.0,
+        Err(_) => 1,
+    };
+
+    if version != moth_abi::VERSION {
+        log::error!("site.wasm implements moth ABI v{}, this server implements v{}", version, moth_abi::VERSION);
+        return None;
+    }
+
+    Some(())
+}
+
+/// A function which the module must export
+pub fn export<P: WasmParams, R: WasmResults>(instance: &Instance, store: &mut Store, name: &str) -> Option<TypedFunc<P, R>> {
+    let func = instance.get_typed_func(store, name);
+    func.map_err(|_| log::error!("site.wasm doesn't export {} correctly", name)).ok()
+}
+
+/// A compiled module: the main module of components is run on its own
+pub struct SiteModule {
+    module: Module,
+    is_component: bool,
+}
+
+impl SiteModule {
+    /// Compiles a core module, or the main module of a component
+    pub fn new(bytes: &[u8]) -> Result<Self, String> {
+        Self::compile(bytes, false)
+    }
+
+    fn compile(bytes: &[u8], metered: bool) -> Result<Self, String> {
+        let is_component = component::is_component(bytes);
+        let module = match is_component {
+            true => component::main_module(bytes, metered)?,
+            false => Engine::compile(bytes, metered)?,
+        };
+
+        Ok(Self { module, is_component })
+    }
+
+    /// Imports of the module, as (module, name)
+    fn imports(&self) -> Vec<(String, String)> {
+        let imports = self.module.imports();
+        imports.map(|import| (import.module().to_string(), import.name().to_string())).collect()
+    }
+}
+
+/// Instantiates a module & runs its start functions (`_initialize`, `_start`)
+/// with `fuel`, so that deployments of modules which don't start quickly fail
+///
+/// WASI functions are available; moth host functions trap.
+pub fn run_start(bytes: &[u8], fuel: u64) -> Result<(), String> {
+    let module = SiteModule::compile(bytes, true);
+    let module = module.map_err(|e| format!("Failed to compile site.wasm: {}", e))?.module;
+
+    let mut store = Store::new(module.engine(), Handle::new());
+    Engine::add_fuel(&mut store, fuel)?;
+
+    let mut linker = Linker::new(module.engine());
+    super::wasi::define(&mut linker).ok_or("Failed to define WASI functions")?;
+    Engine::define_traps(&mut linker, &mut store, &module);
+
+    let fail = |e: &dyn core::fmt::Display| format!("site.wasm failed to start with {} fuel: {}", fuel, e);
+    let instance = Engine::instantiate(&linker, &mut store, &module).map_err(|e| fail(&e))?;
+
+    for name in ["_initialize", "_start"] {
+        if let Ok(start) = instance.get_typed_func::<(), ()>(&mut store, name) {
+            start.call(&mut store, ()).map_err(|e| fail(&e))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_mem(store: &Store, mem: Memory, ptr: u64, len: usize) -> Result<&[u8], Trap> {
+    let fail = || trap("Invalid Pointer");
+    let range = (ptr as usize)..(ptr as usize + len);
+    mem.data(store).get(range).ok_or_else(fail)
+}
+
+fn write_mem(store: &mut Store, mem: Memory, ptr: u64, bytes: &[u8]) -> Result<(), Trap> {
+    mem.write(store, ptr as _, bytes).map_err(|e| trap(format!("{:?}", e)))
+}
+
+/// Exports of a core module, following `moth_abi`
+#[derive(Clone)]
+pub struct CoreExports {
+    pub parse_json: TypedFunc<(u64, u64), (u64,)>,
     dump_json: TypedFunc<(u64,), (u64,)>,
     json_dump_len: TypedFunc<(u64,), (u64,)>,
     json_dump_ptr: TypedFunc<(u64,), (u64,)>,
     free_json_dump: TypedFunc<(u64,), ()>,
-    malloc: TypedFunc<(u64,), (u64,)>,
-    free: TypedFunc<(u64, u64), ()>,
-    mem: Memory,
+    init: Option<TypedFunc<(u64,), ()>>,
+    pub malloc: TypedFunc<(u64,), (u64,)>,
+    pub free: TypedFunc<(u64, u64), ()>,
 }
 
-impl WasmThread {
-    fn from_module(module: Arc<Module>, pool: Pool) -> Option<Self> {
-        let mut linker: Linker = Linker::new(module.engine());
-        let mut store = Store::new(module.engine(), Handle::new());
+impl CoreExports {
+    fn new(instance: &Instance, store: &mut Store) -> Option<Self> {
+        check_version(instance, store)?;
 
-        let read_table_entry_fn = Func::wrap(&mut store, super::handle::read_table_entry);
-        linker.define("host", "read_table_entry", read_table_entry_fn).ok()?;
+        Some(Self {
+            malloc: export(instance, store, moth_abi::MALLOC)?,
+            free: export(instance, store, moth_abi::FREE)?,
+            parse_json: export(instance, store, moth_abi::PARSE_JSON)?,
+            dump_json: export(instance, store, moth_abi::DUMP_JSON)?,
+            json_dump_len: export(instance, store, moth_abi::JSON_DUMP_LEN)?,
+            json_dump_ptr: export(instance, store, moth_abi::JSON_DUMP_PTR)?,
+            free_json_dump: export(instance, store, moth_abi::FREE_JSON_DUMP)?,
+            init: instance.get_typed_func(&mut *store, moth_abi::INIT).ok(),
+        })
+    }
 
-        let write_table_entry_fn = Func::wrap(&mut store, super::handle::write_table_entry);
-        linker.define("host", "write_table_entry", write_table_entry_fn).ok()?;
+    fn parse_json(&self, store: &mut Store, mem: Memory, json: &str) -> Result<OpaqueJsonPointer, Trap> {
+        let len = json.len() as u64;
+        let str_ptr = self.malloc.call(&mut *store, (len,))?.0;
+        write_mem(store, mem, str_ptr, json.as_bytes())?;
+        let json_ptr = self.parse_json.call(&mut *store, (str_ptr, len))?.0;
+        self.free.call(&mut *store, (str_ptr, len))?;
+        Ok(json_ptr as _)
+    }
 
-        let set_template_name_fn = Func::wrap(&mut store, super::handle::set_template_name);
-        linker.define("host", "set_template_name", set_template_name_fn).ok()?;
+    fn dump_json(&self, store: &mut Store, mem: Memory, json: OpaqueJsonPointer) -> Result<String, Trap> {
+        let arcstr_ptr = self.dump_json.call(&mut *store, (json as _,))?.0;
+        let ptr = self.json_dump_ptr.call(&mut *store, (arcstr_ptr,))?.0;
+        let len = self.json_dump_len.call(&mut *store, (arcstr_ptr,))?.0;
+        let slice = read_mem(store, mem, ptr, len as _)?;
 
-        let set_template_param_fn = Func::wrap(&mut store, super::handle::set_template_param);
-        linker.define("host", "set_template_param", set_template_param_fn).ok()?;
+        let fail = || trap("Invalid Bytes");
+        let dump = core::str::from_utf8(slice).ok().ok_or_else(fail)?.to_string();
 
-        let instance = linker
-            .instantiate(&mut store, &module).ok()?
-            .start(&mut store).ok()?;
+        self.free_json_dump.call(&mut *store, (arcstr_ptr,))?;
 
-        let malloc = instance.get_typed_func::<(u64,), (u64,)>(&store, "__rs_malloc").ok()?;
-        let free = instance.get_typed_func::<(u64, u64), ()>(&store, "__rs_free").ok()?;
-        let parse_json = instance.get_typed_func::<(u64, u64), (u64,)>(&store, "__parse_json").ok()?;
-        let dump_json = instance.get_typed_func::<(u64,), (u64,)>(&store, "__dump_json").ok()?;
-        let json_dump_len = instance.get_typed_func::<(u64,), (u64,)>(&store, "__json_dump_len").ok()?;
-        let json_dump_ptr = instance.get_typed_func::<(u64,), (u64,)>(&store, "__json_dump_ptr").ok()?;
-        let free_json_dump = instance.get_typed_func::<(u64,), ()>(&store, "__free_json_dump").ok()?;
-        let mem = instance.get_memory(&store, "memory")?;
+        Ok(dump)
+    }
+
+    fn call_callback(
+        &self,
+        store: &mut Store,
+        mem: Memory,
+        func: Func,
+        db_token: u64,
+        body: OpaqueJsonPointer,
+        params: &[String],
+    ) -> Result<OpaqueJsonPointer, Trap> {
+        // max: 7 parameters (exc. the id+body pair)
+        let mut values: ArrayVec<i64, 16> = ArrayVec::new();
+        values.push(db_token as _);
+        values.push(body as _);
+
+        let len_sum = params.iter().fold(0, |a, s| a + s.len()) as u64;
+        let params_ptr = self.malloc.call(&mut *store, (len_sum,))?.0;
+
+        let mut ptr = params_ptr;
+        for string in params {
+            write_mem(store, mem, ptr, string.as_bytes())?;
+            let len = string.len() as u64;
+            values.push(ptr as _);
+            values.push(len as _);
+            ptr += len;
+        }
+
+        let json = Engine::call(store, func, &values)?;
+        self.free.call(&mut *store, (params_ptr, len_sum))?;
+
+        Ok(json as _)
+    }
+}
+
+// there's one per instance
+#[allow(clippy::large_enum_variant)]
+enum Exports {
+    Core(CoreExports),
+    Component(component::Exports),
+}
+
+/// An instance of a site's module
+///
+/// Instances hide the ABI of their module: JSON documents are identified by
+/// opaque pointers, whether they're kept by the guest or by the host.
+pub struct SiteInstance {
+    module: Arc<SiteModule>,
+    instance: Instance,
+    store: Store,
+    exports: Exports,
+    mem: Memory,
+}
+
+impl SiteInstance {
+    /// Links the host functions, then instantiates & initializes the module;
+    /// fails if it lacks a required export or has another ABI version
+    fn new(module: Arc<SiteModule>, site: Arc<SiteContext>) -> Option<Self> {
+        let mut linker = Linker::new(module.module.engine());
+        let mut store = Store::new(module.module.engine(), Handle::new());
+
+        match module.is_component {
+            true => component::define(&mut linker, &mut store)?,
+            false => super::handle::define(&mut linker)?,
+        }
 
-        store.data_mut().init(parse_json, malloc, free, mem, pool);
+        super::wasi::define(&mut linker)?;
+
+        check_imports(&module)?;
+
+        let instance = Engine::instantiate(&linker, &mut store, &module.module);
+        let instance = instance.map_err(|e| log::error!("Failed to instantiate site.wasm: {}", e)).ok()?;
+
+        // WASI reactors (wasm32-wasip1 libraries) must be initialized first
+        if let Ok(initialize) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+            initialize.call(&mut store, ()).ok()?;
+        }
+
+        let mem = instance.get_memory(&mut store, "memory")?;
+        let exports = match module.is_component {
+            true => {
+                let exports = component::Exports::new(&instance, &mut store)?;
+                let realloc = export(&instance, &mut store, component::REALLOC)?;
+                store.data_mut().init_component(realloc, mem, site);
+                Exports::Component(exports)
+            },
+            false => {
+                let exports = CoreExports::new(&instance, &mut store)?;
+                store.data_mut().init(exports.clone(), mem, site);
+                Exports::Core(exports)
+            },
+        };
 
         Some(Self {
             module,
-            instance: Arc::new(instance),
+            instance,
             store,
-            malloc,
-            free,
-            parse_json,
-            dump_json,
-            json_dump_len,
-            json_dump_ptr,
-            free_json_dump,
+            exports,
             mem,
         })
     }
 
-    pub fn new(bytes: &[u8], pool: Pool) -> Option<Self> {
-        let module = Module::new(&Engine::default(), bytes).unwrap();
-        Self::from_module(Arc::new(module), pool)
+    /// State of the host functions
+    fn handle(&self) -> &Handle {
+        self.store.data()
+    }
+
+    fn handle_mut(&mut self) -> &mut Handle {
+        self.store.data_mut()
+    }
+
+    fn parse_json(&mut self, json: &str) -> Result<OpaqueJsonPointer, Trap> {
+        match &mut self.exports {
+            Exports::Core(exports) => exports.parse_json(&mut self.store, self.mem, json),
+            Exports::Component(exports) => Ok(exports.parse_json(json)),
+        }
+    }
+
+    /// Releases the document
+    fn dump_json(&mut self, json: OpaqueJsonPointer) -> Result<String, Trap> {
+        match &mut self.exports {
+            Exports::Core(exports) => exports.dump_json(&mut self.store, self.mem, json),
+            Exports::Component(exports) => exports.dump_json(json),
+        }
     }
 
-    fn malloc(&mut self, size: usize) -> Result<u64, Trap> {
-        Ok(self.malloc.call(&mut self.store, (size as _,))?.0)
+    fn has_init(&self) -> bool {
+        match &self.exports {
+            Exports::Core(exports) => exports.init.is_some(),
+            Exports::Component(exports) => exports.has_init(),
+        }
     }
 
-    fn free(&mut self, ptr: u64, size: usize) -> Result<(), Trap> {
-        self.free.call(&mut self.store, (ptr, size as _,))
+    fn call_init(&mut self, db_token: u64) -> Result<(), Trap> {
+        match &self.exports {
+            Exports::Core(exports) => match &exports.init {
+                Some(init) => init.call(&mut self.store, (db_token,)),
+                None => Err(trap(format!("Missing export: {}", moth_abi::INIT))),
+            },
+            Exports::Component(exports) => exports.call_init(&mut self.store),
+        }
     }
 
-    fn write_mem(&mut self, ptr: u64, buf: &[u8]) -> Result<(), Trap> {
-        self.mem.write(&mut self.store, ptr as _, buf).map_err(|e| Trap::new(format!("{:?}", e)))
+    /// Calls a script callback, which takes ownership of `body` & returns a
+    /// JSON pointer (0 if none)
+    fn call_callback(
+        &mut self,
+        name: &str,
+        db_token: u64,
+        body: OpaqueJsonPointer,
+        params: &[String],
+    ) -> Result<OpaqueJsonPointer, Trap> {
+        match &mut self.exports {
+            Exports::Core(exports) => {
+                let fail = || trap(format!("Missing callback: {}", name));
+                let func = self.instance.get_func(&mut self.store, name).ok_or_else(fail)?;
+                exports.call_callback(&mut self.store, self.mem, func, db_token, body, params)
+            },
+            Exports::Component(exports) => exports.call_callback(&mut self.store, self.mem, name, body, params),
+        }
+    }
+}
+
+/// An instance of a site's module, running its scripts
+pub struct WasmThread {
+    instance: SiteInstance,
+    replica: Replica,
+    /// Set when a script trapped: the state of the guest can't be trusted anymore
+    trapped: bool,
+    /// Number of JSON documents of this instance still held by the server
+    live_json: usize,
+}
+
+impl WasmThread {
+    fn from_module(module: Arc<SiteModule>, site: Arc<SiteContext>) -> Option<Self> {
+        Some(Self {
+            instance: SiteInstance::new(module, site)?,
+            replica: Replica::new(),
+            trapped: false,
+            live_json: 0,
+        })
+    }
+
+    pub fn new(bytes: &[u8], site: Arc<SiteContext>) -> Option<Self> {
+        Self::from_module(compile(bytes)?, site)
     }
 
     pub fn parse_json(&mut self, json: &str) -> Result<OpaqueJsonPointer, Trap> {
-        let len = json.len();
-        let str_ptr = self.malloc(len)?;
-        self.write_mem(str_ptr, json.as_bytes())?;
-        let json_ptr = self.parse_json.call(&mut self.store, (str_ptr, len as _,))?.0;
-        self.free(str_ptr, len)?;
-        Ok(json_ptr as _)
+        let json_ptr = self.instance.parse_json(json)?;
+        self.live_json += 1;
+        Ok(json_ptr)
+    }
+
+    /// Messages published by the last script call, see [`Handle::take_messages`]
+    pub fn take_messages(&mut self) -> Vec<(String, String)> {
+        self.instance.handle_mut().take_messages()
     }
 
     pub fn dump_json(&mut self, json: OpaqueJsonPointer) -> Result<String, Trap> {
-        let arcstr_ptr = self.dump_json.call(&mut self.store, (json as _,))?.0;
-        let ptr = self.json_dump_ptr.call(&mut self.store, (arcstr_ptr,))?.0;
-        let len = self.json_dump_len.call(&mut self.store, (arcstr_ptr,))?.0;
-        let (ptr, len) = (ptr as usize, len as usize);
+        self.live_json = self.live_json.saturating_sub(1);
+        self.instance.dump_json(json)
+    }
 
-        let fail = || Trap::new("Invalid Pointer");
-        let range = ptr..(ptr + len);
-        let slice = self.mem.data(&self.store).get(range).ok_or_else(fail)?;
+    /// Whether a script trapped in this instance, which should then be replaced
+    /// once the server holds no JSON pointer into its memory anymore
+    pub fn needs_rebuild(&self) -> bool {
+        self.trapped && self.live_json == 0
+    }
 
-        let fail = || Trap::new("Invalid Bytes");
-        let dump = core::str::from_utf8(slice).ok().ok_or_else(fail)?.to_string();
+    /// Runs the service's `#[moth_init]` export, if any, with read-write database access
+    ///
+    /// Like rw scripts, it runs again on write conflicts.
+    pub fn call_init_fn(
+        &mut self,
+        database: &Arc<Database>,
+        db_token: u64,
+    ) -> Result<(), Trap> {
+        if !self.instance.has_init() {
+            return Ok(());
+        }
 
-        self.free_json_dump.call(&mut self.store, (arcstr_ptr,))?;
+        let mut attempts = 0;
+        loop {
+            match self.try_init_fn(database, db_token) {
+                Err(ScriptFailure::Conflict(path)) if attempts < CONFLICT_RETRIES => {
+                    log::warn!("Write conflict on {}, running {} again", path, moth_abi::INIT);
+                    attempts += 1;
+                },
+                result => return result.map_err(Trap::from),
+            }
+        }
+    }
 
-        Ok(dump)
+    fn try_init_fn(
+        &mut self,
+        database: &Arc<Database>,
+        db_token: u64,
+    ) -> Result<(), ScriptFailure> {
+        let repo_borrow = RepoBorrow::new(database);
+
+        self.instance.handle_mut().prepare(false, repo_borrow.repo_arc(), repo_borrow.database(), db_token, RequestInfo::default());
+        let result = self.instance.call_init(db_token);
+        let transaction = self.instance.handle_mut().take_transaction();
+        self.instance.handle_mut().reset();
+
+        result?;
+        repo_borrow.apply(transaction)
     }
 
+    /// rw scripts run again when another script modified an entry which they
+    /// read, with a copy of their body; their results (response, messages)
+    /// are only kept from the run whose writes were applied
     pub fn call_script_fn(
         &mut self,
         fn_name: &str,
         read_only: bool,
-        repo: &RwLock<Arc<RwLock<Repository>>>,
+        database: &Arc<Database>,
         db_token: u64,
         req_body: OpaqueJsonPointer,
         req_params: &[String],
+        request: &RequestInfo,
     ) -> Result<(Option<TemplateParams>, Option<OpaqueJsonPointer>), Trap> {
-        // max: 7 parameters (exc. the id+body pair)
-        let mut inputs: ArrayVec<Value, 16> = ArrayVec::new();
-
-        inputs.push(Value::I64(db_token as _));
-        inputs.push(Value::I64(req_body as _));
+        // scripts take ownership of their body
+        let body_copy = match (read_only, req_body) {
+            (false, 1..) => Some(self.dump_json(req_body)?),
+            _ => None,
+        };
 
-        let len_sum = req_params.iter().fold(0, |a, s| a + s.len());
-        let params = self.malloc(len_sum)?;
+        let mut body = match &body_copy {
+            Some(json) => self.parse_json(json)?,
+            None => req_body,
+        };
 
-        let mut ptr = params;
-        for string in req_params {
-            self.write_mem(ptr, string.as_bytes())?;
-            let len = string.len() as u64;
-            inputs.push(Value::I64(ptr as _));
-            inputs.push(Value::I64(len as _));
-            ptr += len;
+        let mut attempts = 0;
+        loop {
+            match self.try_script_fn(fn_name, read_only, database, db_token, body, req_params, request) {
+                Err(ScriptFailure::Conflict(path)) if attempts < CONFLICT_RETRIES => {
+                    log::warn!("[{}] Write conflict on {}, running {} again", request.id, path, fn_name);
+                    attempts += 1;
+                    if let Some(json) = &body_copy {
+                        body = self.parse_json(json)?;
+                    }
+                },
+                result => {
+                    self.trapped |= matches!(result, Err(ScriptFailure::Trap(_)));
+                    return result.map_err(Trap::from);
+                },
+            }
         }
+    }
 
-        let mut outputs = [Value::I64(0)];
-        let fail = || Trap::new(format!("Missing callback: {}", fn_name));
-        let func = self.instance.get_func(&self.store, fn_name).ok_or_else(fail)?;
-
+    fn try_script_fn(
+        &mut self,
+        fn_name: &str,
+        read_only: bool,
+        database: &Arc<Database>,
+        db_token: u64,
+        req_body: OpaqueJsonPointer,
+        req_params: &[String],
+        request: &RequestInfo,
+    ) -> Result<(Option<TemplateParams>, Option<OpaqueJsonPointer>), ScriptFailure> {
         let repo_borrow = match read_only {
-            true  => RepoBorrow::ReadOnly (repo. read().unwrap()),
-            false => RepoBorrow::ReadWrite(repo.write().unwrap()),
+            true => RepoBorrow::replica(database, &mut self.replica)?,
+            false => RepoBorrow::new(database),
         };
 
-        self.store.data_mut().prepare(read_only, repo_borrow.repo_arc(), db_token);
-        match func.call(&mut self.store, &inputs, &mut outputs) {
-            Ok(()) => (),
-            Err(wasmi::Error::Trap(trap)) => return Err(trap),
-            Err(e) => return Err(Trap::new(format!("Wasmi error: {:?}", e))),
+        self.instance.handle_mut().prepare(read_only, repo_borrow.repo_arc(), repo_borrow.database(), db_token, request.clone());
+        let mut span = trace::span("wasm call");
+        span.attribute("moth.script", fn_name);
+        let result = self.instance.call_callback(fn_name, db_token, req_body, req_params);
+        if result.is_err() {
+            span.fail();
         }
-        let template = self.store.data_mut().reset();
+        core::mem::drop(span);
 
-        core::mem::drop(repo_borrow);
-        self.free(params, len_sum)?;
+        let transaction = self.instance.handle_mut().take_transaction();
+        let template = self.instance.handle_mut().reset();
 
-        let fail = || Trap::new("Wrong fn signature");
-        let json = match outputs[0].i64().ok_or_else(fail)? {
+        // on failure, staged writes are dropped: nothing reaches the repository
+        // the script took ownership of its body
+        self.live_json = self.live_json.saturating_sub((req_body != 0) as usize);
+        let json = match result? {
             0 => None,
-            json_ptr => Some(json_ptr as _),
+            json_ptr => Some(json_ptr),
         };
+        self.live_json += json.is_some() as usize;
+
+        let applied = repo_borrow.apply(transaction);
+        core::mem::drop(repo_borrow);
+
+        if let Err(failure) = applied {
+            // the response & messages of this run are dropped
+            if let Some(json) = json {
+                self.dump_json(json)?;
+            }
+
+            self.take_messages();
+            return Err(failure);
+        }
 
         Ok((template, json))
     }
@@ -188,7 +695,7 @@ impl WasmThread {
 
 impl Clone for WasmThread {
     fn clone(&self) -> Self {
-        Self::from_module(self.module.clone(), self.store.data().pool.clone())
+        Self::from_module(self.instance.module.clone(), self.instance.handle().site())
             .unwrap(/* if it worked once, it should work twice */)
     }
 }
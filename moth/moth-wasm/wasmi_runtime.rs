@@ -0,0 +1,60 @@
+//! [`Runtime`] of the wasmi interpreter, the default engine
+
+use wasmi::{Config, Value, ExternType};
+use super::{Handle, wasm::Runtime};
+
+pub(crate) use wasmi::{Module, Instance, Func, TypedFunc, Memory, Extern, AsContext, IntoFunc, WasmParams, WasmResults};
+pub(crate) use wasmi::core::{Trap, F64};
+pub(crate) type Caller<'a> = wasmi::Caller<'a, Handle>;
+pub(crate) type Linker = wasmi::Linker<Handle>;
+pub(crate) type Store = wasmi::Store<Handle>;
+pub(crate) type StoreContext<'a> = wasmi::StoreContext<'a, Handle>;
+
+pub(crate) fn trap(message: impl Into<String>) -> Trap {
+    Trap::new(message)
+}
+
+/// The wasmi interpreter
+pub struct Wasmi;
+
+impl Runtime for Wasmi {
+    fn compile(bytes: &[u8], metered: bool) -> Result<Module, String> {
+        let mut config = Config::default();
+        config.consume_fuel(metered);
+        let engine = wasmi::Engine::new(&config);
+        Module::new(&engine, bytes).map_err(|e| e.to_string())
+    }
+
+    fn add_fuel(store: &mut Store, fuel: u64) -> Result<(), String> {
+        store.add_fuel(fuel).map_err(|e| format!("Failed to set fuel: {}", e))
+    }
+
+    fn instantiate(linker: &Linker, store: &mut Store, module: &Module) -> Result<Instance, String> {
+        let instance = linker.instantiate(&mut *store, module).map_err(|e| e.to_string())?;
+        instance.start(store).map_err(|e| e.to_string())
+    }
+
+    fn define_traps(linker: &mut Linker, store: &mut Store, module: &Module) {
+        for import in module.imports() {
+            if let ExternType::Func(func_type) = import.ty() {
+                let name = import.name().to_string();
+                let stub = move |_: Caller, _: &[Value], _: &mut [Value]| Err(trap(format!("{} can't be called on start", name)));
+                // modules can import a function twice; functions of `linker` are kept
+                let _ = linker.define(import.module(), import.name(), Func::new(&mut *store, func_type.clone(), stub));
+            }
+        }
+    }
+
+    fn call(store: &mut Store, func: Func, params: &[i64]) -> Result<i64, Trap> {
+        let params: Vec<_> = params.iter().copied().map(Value::I64).collect();
+        let mut outputs = [Value::I64(0)];
+
+        match func.call(&mut *store, &params, &mut outputs) {
+            Ok(()) => (),
+            Err(wasmi::Error::Trap(trap)) => return Err(trap),
+            Err(e) => return Err(trap(format!("Wasmi error: {:?}", e))),
+        }
+
+        outputs[0].i64().ok_or_else(|| trap("Wrong fn signature"))
+    }
+}
@@ -0,0 +1,62 @@
+//! [`Runtime`] of wasmtime, a JIT compiler (`wasmtime` feature)
+
+use wasmtime::{Config, Val};
+use std::sync::OnceLock;
+use super::{Handle, wasm::Runtime};
+
+pub(crate) use wasmtime::{Module, Instance, Func, TypedFunc, Memory, Extern, AsContext, IntoFunc, WasmParams, WasmResults};
+pub(crate) type Trap = wasmtime::Error;
+pub(crate) type F64 = f64;
+pub(crate) type Caller<'a> = wasmtime::Caller<'a, Handle>;
+pub(crate) type Linker = wasmtime::Linker<Handle>;
+pub(crate) type Store = wasmtime::Store<Handle>;
+pub(crate) type StoreContext<'a> = wasmtime::StoreContext<'a, Handle>;
+
+pub(crate) fn trap(message: impl Into<String>) -> Trap {
+    Trap::msg(message.into())
+}
+
+/// Engines are shared by the modules which they compiled
+static ENGINES: [OnceLock<wasmtime::Engine>; 2] = [OnceLock::new(), OnceLock::new()];
+
+fn engine(metered: bool) -> Result<&'static wasmtime::Engine, String> {
+    if let Some(engine) = ENGINES[metered as usize].get() {
+        return Ok(engine);
+    }
+
+    let mut config = Config::new();
+    config.consume_fuel(metered);
+    // traps of host functions are displayed as they are
+    config.wasm_backtrace(false);
+
+    let engine = wasmtime::Engine::new(&config).map_err(|e| e.to_string())?;
+    Ok(ENGINES[metered as usize].get_or_init(|| engine))
+}
+
+/// The wasmtime JIT compiler
+pub struct Wasmtime;
+
+impl Runtime for Wasmtime {
+    fn compile(bytes: &[u8], metered: bool) -> Result<Module, String> {
+        Module::new(engine(metered)?, bytes).map_err(|e| e.to_string())
+    }
+
+    fn add_fuel(store: &mut Store, fuel: u64) -> Result<(), String> {
+        store.set_fuel(fuel).map_err(|e| format!("Failed to set fuel: {}", e))
+    }
+
+    fn instantiate(linker: &Linker, store: &mut Store, module: &Module) -> Result<Instance, String> {
+        linker.instantiate(store, module).map_err(|e| e.to_string())
+    }
+
+    fn define_traps(linker: &mut Linker, _store: &mut Store, module: &Module) {
+        let _ = linker.define_unknown_imports_as_traps(module);
+    }
+
+    fn call(store: &mut Store, func: Func, params: &[i64]) -> Result<i64, Trap> {
+        let params: Vec<_> = params.iter().copied().map(Val::I64).collect();
+        let mut outputs = [Val::I64(0)];
+        func.call(&mut *store, &params, &mut outputs)?;
+        outputs[0].i64().ok_or_else(|| trap("Wrong fn signature"))
+    }
+}
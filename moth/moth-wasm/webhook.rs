@@ -0,0 +1,103 @@
+//! Signature verification of incoming webhooks, for `Request::verify_webhook`
+//!
+//! Example config; secrets can also be given as is, with `secret`:
+//!
+//! ```json
+//! "webhooks": {
+//!     "github": { "secret_env": "GITHUB_WEBHOOK_SECRET" },
+//!     "stripe": { "secret_env": "STRIPE_WEBHOOK_SECRET", "tolerance_secs": 300 }
+//! }
+//! ```
+
+use super::{env::{Env, EnvValue}, deploy::decode_hex, database::now};
+use lmfu::json::{JsonFile, Path as JsonPath, Value as JsonValue};
+use ring::hmac;
+
+const DEFAULT_TOLERANCE_SECS: u64 = 300;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Provider {
+    /// `X-Hub-Signature-256: sha256=<hex HMAC-SHA256 of the body>`
+    GitHub,
+    /// `Stripe-Signature: t=<unix time>,v1=<hex HMAC-SHA256 of "<t>.<body>">`
+    Stripe,
+}
+
+impl Provider {
+    pub fn parse(provider: &str) -> Result<Self, ()> {
+        match provider {
+            "github" => Ok(Self::GitHub),
+            "stripe" => Ok(Self::Stripe),
+            _ => Err(()),
+        }
+    }
+}
+
+struct Webhook {
+    provider: Provider,
+    secret: EnvValue,
+    /// Maximum age of Stripe-style signatures
+    tolerance_secs: u64,
+}
+
+pub struct Webhooks(Vec<Webhook>);
+
+impl Webhooks {
+    pub fn parse(file: &JsonFile, path: &JsonPath) -> Result<Option<Self>, ()> {
+        let providers = match file.get(path) {
+            JsonValue::Object(providers) => providers,
+            JsonValue::Null => return Ok(None),
+            _ => return Err(log::error!("Invalid webhooks config (must be an object)")),
+        };
+
+        let mut webhooks = Vec::new();
+        for provider in providers {
+            let webhook_path = path.clone().i_str(provider);
+            let fail = || log::error!("Invalid webhooks config for {}", provider);
+
+            let provider = Provider::parse(provider)
+                .map_err(|()| log::error!("Invalid webhook provider {} (must be github/stripe)", provider))?;
+
+            let secret = EnvValue::parse(file, &webhook_path, "secret")?.ok_or_else(fail)?;
+            let tolerance_secs = match file.get(&webhook_path.i_str("tolerance_secs")) {
+                JsonValue::Number(secs) if *secs >= 0.0 => *secs as u64,
+                JsonValue::Null => DEFAULT_TOLERANCE_SECS,
+                _ => return Err(fail()),
+            };
+
+            webhooks.push(Webhook { provider, secret, tolerance_secs });
+        }
+
+        Ok(Some(Self(webhooks)))
+    }
+
+    /// Checks the signature header of a webhook request against its raw body
+    ///
+    /// Fails if the provider isn't configured or if its secret is missing.
+    pub fn verify(&self, env: &Env, provider: &str, signature: &str, body: &[u8]) -> Result<bool, ()> {
+        let provider = Provider::parse(provider).map_err(|()| log::error!("Unknown webhook provider: {}", provider))?;
+        let fail = || log::error!("No webhook config for {:?}", provider);
+        let webhook = self.0.iter().find(|w| w.provider == provider).ok_or_else(fail)?;
+        let key = hmac::Key::new(hmac::HMAC_SHA256, webhook.secret.resolve(env)?.as_bytes());
+
+        Ok(match provider {
+            Provider::GitHub => {
+                let tag = signature.strip_prefix("sha256=").and_then(decode_hex::<32>);
+                tag.is_some_and(|tag| hmac::verify(&key, body, &tag).is_ok())
+            },
+            Provider::Stripe => {
+                let parts = || signature.split(',').filter_map(|part| part.trim().split_once('='));
+                let timestamp = parts().find(|(k, _)| *k == "t").and_then(|(_, t)| t.parse::<u64>().ok());
+                let Some(timestamp) = timestamp else { return Ok(false) };
+
+                if now().abs_diff(timestamp) > webhook.tolerance_secs {
+                    return Ok(false);
+                }
+
+                let signed = [format!("{}.", timestamp).as_bytes(), body].concat();
+                let mut tags = parts().filter(|(k, _)| *k == "v1").filter_map(|(_, tag)| decode_hex::<32>(tag));
+                tags.any(|tag| hmac::verify(&key, &signed, &tag).is_ok())
+            },
+        })
+    }
+}
@@ -0,0 +1,53 @@
+//! Site bundles for the integration tests, whose modules are written in WAT
+
+use std::{fs, path::PathBuf, sync::Mutex};
+
+/// Writes `config.json` & `site.wasm` to a fresh directory, returning its path
+///
+/// `{db}` in `config` is replaced with a fresh database directory.
+pub fn bundle(name: &str, config: &str, wat: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("moth-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let bundle = dir.join("bundle");
+    let db = dir.join("db");
+    fs::create_dir_all(&bundle).unwrap();
+    fs::create_dir_all(&db).unwrap();
+
+    let config = config.replace("{db}", db.to_str().unwrap());
+    fs::write(bundle.join("config.json"), config).unwrap();
+    fs::write(bundle.join("site.wasm"), wat::parse_str(wat).unwrap()).unwrap();
+
+    bundle.to_str().unwrap().to_string()
+}
+
+pub fn fixture(name: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    fs::read_to_string(path).unwrap()
+}
+
+static ERRORS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Keeps errors, for [`errors`]
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Error
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            ERRORS.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Errors logged since the last call
+pub fn errors() -> Vec<String> {
+    let _ = log::set_logger(&Logger);
+    log::set_max_level(log::LevelFilter::Error);
+    core::mem::take(&mut ERRORS.lock().unwrap())
+}
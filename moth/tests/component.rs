@@ -0,0 +1,46 @@
+//! Component-model guests, see `moth-wasm/component.rs`
+
+/// The server, for its bundle loading; its `main` isn't used
+#[allow(dead_code)]
+#[path = "../moth-wasm/main.rs"]
+mod server;
+
+mod common;
+
+use moth::testing::Harness;
+
+const CONFIG: &str = r#"{
+    "routes": { "api": { "echo": { "callback": "echo", "access": "ro", "methods": ["POST"] } } },
+    "on_404": {},
+    "database": { "directory": "{db}" }
+}"#;
+
+#[test]
+fn component_round_trip() {
+    let bundle = common::bundle("component", CONFIG, &common::fixture("component.wat"));
+    let harness = Harness::new(Box::new(server::load_bundle(&bundle, "localhost")));
+
+    let response = harness.post("/api/echo", r#"{"a":1}"#);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), r#"[{"a":1},"POST"]"#);
+
+    // the bump allocator of the guest keeps going
+    let response = harness.post("/api/echo", "[]");
+    assert_eq!(response.text(), r#"[[],"POST"]"#);
+}
+
+#[test]
+fn component_needing_an_adapter() {
+    // the main module of a wasm32-wasip2 guest imports WASI 0.2 interfaces
+    let wat = common::fixture("component.wat").replace(
+        r#"(import "wasi_snapshot_preview1" "fd_write""#,
+        r#"(import "wasi:cli/stdout@0.2.0" "get-stdout""#,
+    );
+
+    let bundle = common::bundle("component-wasip2", CONFIG, &wat);
+    common::errors();
+    assert!(std::panic::catch_unwind(|| server::load_bundle(&bundle, "localhost")).is_err());
+
+    let errors = common::errors();
+    assert!(errors.iter().any(|e| e.contains("wasi:cli/stdout@0.2.0::get-stdout") && e.contains("wasm32-wasip1")), "{:?}", errors);
+}
@@ -0,0 +1,102 @@
+;; A guest of the `site` world (moth-abi/wit/moth.wit), laid out like the
+;; output of wit-component for wasm32-wasip1:
+;; - $main is the guest's own module;
+;; - $adapter stands for the WASI preview 1 adapter;
+;; - the imports of $main are lowered through the table of $shim, which
+;;   $fixup fills once the memory & realloc of $main exist.
+;;
+;; Its callback responds with `[body, method]`.
+(component
+  (import "moth:site/request@1.0.0" (instance $request
+    (export "method" (func (result string)))
+  ))
+
+  (core module $main
+    (import "moth:site/request@1.0.0" "method" (func $method (param i32)))
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+
+    (memory (export "memory") 1)
+    (global $heap (mut i32) (i32.const 1024))
+
+    ;; bump allocator
+    (func $realloc (export "cabi_realloc") (param $old i32) (param $old_size i32) (param $align i32) (param $size i32) (result i32)
+      (local $ptr i32)
+      (local.set $ptr
+        (i32.and
+          (i32.add (global.get $heap) (i32.sub (local.get $align) (i32.const 1)))
+          (i32.sub (i32.const 0) (local.get $align))))
+      (global.set $heap (i32.add (local.get $ptr) (local.get $size)))
+      (local.get $ptr))
+
+    (func (export "callback")
+      (param $name i32) (param $name_len i32)
+      (param $body i32) (param $body_len i32)
+      (param $params i32) (param $params_len i32)
+      (result i32)
+      (local $method i32) (local $method_len i32) (local $out i32) (local $len i32) (local $at i32)
+
+      ;; string: (ptr, len) at the return pointer
+      (call $method (i32.const 16))
+      (local.set $method (i32.load (i32.const 16)))
+      (local.set $method_len (i32.load (i32.const 20)))
+
+      ;; [ body ," method "]
+      (local.set $len (i32.add (i32.add (local.get $body_len) (local.get $method_len)) (i32.const 5)))
+      (local.set $out (call $realloc (i32.const 0) (i32.const 0) (i32.const 1) (local.get $len)))
+      (i32.store8 (local.get $out) (i32.const 0x5b))
+      (local.set $at (i32.add (local.get $out) (i32.const 1)))
+      (memory.copy (local.get $at) (local.get $body) (local.get $body_len))
+      (local.set $at (i32.add (local.get $at) (local.get $body_len)))
+      (i32.store8 (local.get $at) (i32.const 0x2c))
+      (i32.store8 offset=1 (local.get $at) (i32.const 0x22))
+      (local.set $at (i32.add (local.get $at) (i32.const 2)))
+      (memory.copy (local.get $at) (local.get $method) (local.get $method_len))
+      (local.set $at (i32.add (local.get $at) (local.get $method_len)))
+      (i32.store8 (local.get $at) (i32.const 0x22))
+      (i32.store8 offset=1 (local.get $at) (i32.const 0x5d))
+
+      ;; option<string>: discriminant, then (ptr, len) at +4
+      (i32.store8 (i32.const 32) (i32.const 1))
+      (i32.store (i32.const 36) (local.get $out))
+      (i32.store (i32.const 40) (local.get $len))
+      (i32.const 32))
+
+    (func (export "cabi_post_callback") (param i32))
+  )
+
+  (core module $adapter
+    (func (export "fd_write") (param i32 i32 i32 i32) (result i32)
+      (i32.const 52))
+  )
+
+  (core module $shim
+    (type $lowered (func (param i32)))
+    (table (export "$imports") 1 1 funcref)
+    (func (export "method") (param i32)
+      (call_indirect (type $lowered) (local.get 0) (i32.const 0)))
+  )
+
+  (core module $fixup
+    (type $lowered (func (param i32)))
+    (import "" "method" (func $method (type $lowered)))
+    (import "" "$imports" (table 1 1 funcref))
+    (elem (i32.const 0) func $method)
+  )
+
+  (core instance $shim (instantiate $shim))
+  (core instance $adapter (instantiate $adapter))
+  (core instance $main (instantiate $main
+    (with "moth:site/request@1.0.0" (instance (export "method" (func $shim "method"))))
+    (with "wasi_snapshot_preview1" (instance $adapter))
+  ))
+
+  (core func $method (canon lower (func $request "method")
+    (memory $main "memory") (realloc (func $main "cabi_realloc"))))
+  (core instance (instantiate $fixup
+    (with "" (instance (export "method" (func $method)) (export "$imports" (table $shim "$imports"))))
+  ))
+
+  (func (export "callback") (param "name" string) (param "body" string) (param "path-params" (list string)) (result (option string))
+    (canon lift (core func $main "callback")
+      (memory $main "memory") (realloc (func $main "cabi_realloc")) (post-return (func $main "cabi_post_callback"))))
+)
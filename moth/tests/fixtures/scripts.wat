@@ -0,0 +1,126 @@
+;; Core module of tests/script_runs.rs
+;;
+;; JSON values are 16-byte boxes of (text pointer, text length); memory is
+;; never freed. Counters are single digits, in the database or in the cache.
+(module
+  (import "host" "read_table_entry" (func $read_table_entry (param i64 i64 i64 i64 i64) (result i64)))
+  (import "host" "write_table_entry" (func $write_table_entry (param i64 i64 i64 i64 i64 i64 i64)))
+  (import "host" "cache_get" (func $cache_get (param i64 i64 i64 i64) (result i64)))
+  (import "host" "cache_put" (func $cache_put (param i64 i64 i64 i64 i64 i64)))
+
+  (memory (export "memory") 2)
+  (global $heap (mut i64) (i64.const 4096))
+
+  ;; table & key of the database counter
+  (data (i32.const 16) "counters")
+  (data (i32.const 32) "shared")
+  ;; cache key set by `release`
+  (data (i32.const 48) "release")
+  ;; 80: cache key, 88: length out-parameter, 96: digit
+
+  (func $malloc (export "__rs_malloc") (param $size i64) (result i64)
+    (local $ptr i64)
+    (local.set $ptr (global.get $heap))
+    (global.set $heap (i64.add (global.get $heap) (local.get $size)))
+    (local.get $ptr))
+
+  (func (export "__rs_free") (param i64 i64))
+
+  (func $parse (export "__parse_json") (param $ptr i64) (param $len i64) (result i64)
+    (local $box i64)
+    (local.set $box (call $malloc (i64.const 16)))
+    (i64.store (i32.wrap_i64 (local.get $box)) (local.get $ptr))
+    (i64.store offset=8 (i32.wrap_i64 (local.get $box)) (local.get $len))
+    (local.get $box))
+
+  (func (export "__dump_json") (param $box i64) (result i64)
+    (local.get $box))
+
+  (func (export "__json_dump_len") (param $box i64) (result i64)
+    (i64.load offset=8 (i32.wrap_i64 (local.get $box))))
+
+  (func (export "__json_dump_ptr") (param $box i64) (result i64)
+    (i64.load (i32.wrap_i64 (local.get $box))))
+
+  (func (export "__free_json_dump") (param i64))
+
+  (func (export "__moth_abi_version") (result i32)
+    (i32.const 1))
+
+  ;; value of the digit at `ptr`, 0 if `ptr` is 0
+  (func $digit (param $ptr i64) (result i64)
+    (if (result i64) (i64.eqz (local.get $ptr))
+      (then (i64.const 0))
+      (else (i64.sub (i64.load8_u (i32.wrap_i64 (local.get $ptr))) (i64.const 48)))))
+
+  ;; JSON number of a digit
+  (func $number (param $value i64) (result i64)
+    (local $ptr i64)
+    (local.set $ptr (call $malloc (i64.const 1)))
+    (i64.store8 (i32.wrap_i64 (local.get $ptr)) (i64.add (local.get $value) (i64.const 48)))
+    (call $parse (local.get $ptr) (i64.const 1)))
+
+  (func $read_counter (param $token i64) (result i64)
+    (local $box i64)
+    (local.set $box (call $read_table_entry (local.get $token) (i64.const 8) (i64.const 16) (i64.const 6) (i64.const 32)))
+    (if (result i64) (i64.eqz (local.get $box))
+      (then (i64.const 0))
+      (else (call $digit (i64.load (i32.wrap_i64 (local.get $box)))))))
+
+  (func $cached (param $token i64) (param $key_ptr i64) (param $key_len i64) (result i64)
+    (call $digit (call $cache_get (local.get $token) (local.get $key_len) (local.get $key_ptr) (i64.const 88))))
+
+  ;; increments the cache counter of the path parameter, returning its new value
+  (func $run (param $token i64) (param $key_ptr i64) (param $key_len i64) (result i64)
+    (local $value i64)
+    (local.set $value (i64.add (call $cached (local.get $token) (local.get $key_ptr) (local.get $key_len)) (i64.const 1)))
+    (i64.store8 (i32.const 96) (i64.add (local.get $value) (i64.const 48)))
+    (call $cache_put (local.get $token) (local.get $key_len) (local.get $key_ptr) (i64.const 1) (i64.const 96) (i64.const 0))
+    (local.get $value))
+
+  ;; rw, "a" or "b": increments the database counter once the other one read it too
+  (func (export "bump") (param $token i64) (param $body i64) (param $pp i64) (param $pl i64) (result i64)
+    (local $value i64)
+    (local.set $value (i64.add (call $read_counter (local.get $token)) (i64.const 1)))
+    (call $cache_put (local.get $token) (local.get $pl) (local.get $pp) (i64.const 1) (local.get $pp) (i64.const 0))
+
+    ;; "a" <=> "b"
+    (i64.store8 (i32.const 80) (i64.xor (i64.load8_u (i32.wrap_i64 (local.get $pp))) (i64.const 3)))
+    (loop $wait
+      (br_if $wait (i64.eqz (call $cache_get (local.get $token) (i64.const 1) (i64.const 80) (i64.const 88)))))
+
+    (i64.store8 (i32.const 96) (i64.add (local.get $value) (i64.const 48)))
+    (call $write_table_entry (local.get $token) (i64.const 8) (i64.const 16) (i64.const 6) (i64.const 32) (i64.const 1) (i64.const 96))
+    (call $number (local.get $value)))
+
+  ;; rw: writes the body as database counter, returning it
+  (func (export "write") (param $token i64) (param $body i64) (result i64)
+    (local $ptr i64)
+    (local $len i64)
+    (local.set $ptr (i64.load (i32.wrap_i64 (local.get $body))))
+    (local.set $len (i64.load offset=8 (i32.wrap_i64 (local.get $body))))
+    (call $write_table_entry (local.get $token) (i64.const 8) (i64.const 16) (i64.const 6) (i64.const 32) (local.get $len) (local.get $ptr))
+    (local.get $body))
+
+  (func (export "count") (param $token i64) (param $body i64) (result i64)
+    (call $number (call $read_counter (local.get $token))))
+
+  (func (export "runs") (param $token i64) (param $body i64) (param $pp i64) (param $pl i64) (result i64)
+    (call $number (call $run (local.get $token) (local.get $pp) (local.get $pl))))
+
+  ;; like `runs`, returning once `release` ran
+  (func (export "slow_runs") (param $token i64) (param $body i64) (param $pp i64) (param $pl i64) (result i64)
+    (local $value i64)
+    (local.set $value (call $run (local.get $token) (local.get $pp) (local.get $pl)))
+    (loop $wait
+      (br_if $wait (i64.eqz (call $cache_get (local.get $token) (i64.const 7) (i64.const 48) (i64.const 88)))))
+    (call $number (local.get $value)))
+
+  (func (export "peek") (param $token i64) (param $body i64) (param $pp i64) (param $pl i64) (result i64)
+    (call $number (call $cached (local.get $token) (local.get $pp) (local.get $pl))))
+
+  (func (export "release") (param $token i64) (param $body i64) (result i64)
+    (i64.store8 (i32.const 96) (i64.const 49))
+    (call $cache_put (local.get $token) (i64.const 7) (i64.const 48) (i64.const 1) (i64.const 96) (i64.const 0))
+    (call $number (i64.const 1)))
+)
@@ -0,0 +1,102 @@
+//! Script runs which the host repeats or shares: write conflicts of rw
+//! scripts, coalescing & response caching of ro routes
+
+/// The server, for its bundle loading; its `main` isn't used
+#[allow(dead_code)]
+#[path = "../moth-wasm/main.rs"]
+mod server;
+
+mod common;
+
+use moth::testing::Harness;
+use std::{thread, time::Duration};
+
+const CONFIG: &str = r#"{
+    "routes": {
+        "api": {
+            "bump": { "[param]": { "callback": "bump", "access": "rw", "methods": ["POST"] } },
+            "write": { "callback": "write", "access": "rw", "methods": ["POST"] },
+            "count": { "callback": "count", "access": "ro" },
+            "runs": { "[param]": { "callback": "runs", "access": "ro", "cache": true } },
+            "slow": { "[param]": { "callback": "slow_runs", "access": "ro", "coalesce": true } },
+            "peek": { "[param]": { "callback": "peek", "access": "ro" } },
+            "release": { "callback": "release", "access": "ro" }
+        }
+    },
+    "on_404": {},
+    "database": { "directory": "{db}" }
+}"#;
+
+/// Coalesced requests & cached responses are shared between
+/// harnesses of the same hostname, so each test has its own
+fn harness(hostname: &str, script_threads: usize) -> Harness {
+    let bundle = common::bundle(hostname, CONFIG, &common::fixture("scripts.wat"));
+    Harness::new(Box::new(server::load_bundle(&bundle, hostname))).with_script_threads(script_threads)
+}
+
+#[test]
+fn write_conflict() {
+    let harness = &harness("conflict.localhost", 2);
+    common::errors();
+
+    // both read the counter before either writes it, so one of them conflicts
+    let mut bumps: Vec<_> = thread::scope(|scope| {
+        let bumps = ["a", "b"].map(|p| scope.spawn(move || harness.post(&format!("/api/bump/{}", p), "{}")));
+        bumps.map(|bump| bump.join().unwrap()).into()
+    });
+
+    bumps.sort_by_key(|bump| bump.text());
+    assert!(bumps.iter().all(|bump| bump.status == 200), "{:?}", bumps);
+    assert_eq!(bumps.iter().map(|bump| bump.text()).collect::<Vec<_>>(), ["1", "2"]);
+    assert_eq!(harness.get("/api/count").text(), "2");
+    assert_eq!(common::errors(), Vec::<String>::new());
+}
+
+#[test]
+fn coalescing() {
+    let harness = harness("coalesce.localhost", 5);
+
+    let responses: Vec<_> = thread::scope(|scope| {
+        let leader = scope.spawn(|| harness.get("/api/slow/x"));
+        while harness.get("/api/peek/x").text() != "1" {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let waiters: Vec<_> = (0..3).map(|_| scope.spawn(|| harness.get("/api/slow/x"))).collect();
+        // lets the waiters join the leader, which is still running
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(harness.get("/api/release").status, 200);
+
+        let mut responses = vec![leader.join().unwrap()];
+        responses.extend(waiters.into_iter().map(|waiter| waiter.join().unwrap()));
+        responses
+    });
+
+    for response in &responses {
+        assert_eq!((response.status, response.text()), (200, "1".into()));
+        assert!(response.is_json());
+    }
+
+    // the script ran once
+    assert_eq!(harness.get("/api/peek/x").text(), "1");
+}
+
+#[test]
+fn response_cache() {
+    let harness = harness("cache.localhost", 1);
+    assert_eq!(harness.get("/api/runs/x").text(), "1");
+    assert_eq!(harness.get("/api/runs/y").text(), "1");
+
+    let cached = harness.get("/api/runs/x");
+    assert_eq!((cached.status, cached.text()), (200, "1".into()));
+    assert!(cached.is_json());
+    assert_eq!(harness.get("/api/peek/x").text(), "1");
+
+    // only GET & HEAD responses are cached
+    assert_eq!(harness.post("/api/runs/x", "{}").text(), "2");
+
+    // writes invalidate cached responses
+    assert_eq!(harness.post("/api/write", "5").text(), "5");
+    assert_eq!(harness.get("/api/runs/x").text(), "3");
+    assert_eq!(harness.get("/api/runs/x").text(), "3");
+}